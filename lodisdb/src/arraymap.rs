@@ -1,6 +1,7 @@
 use std::{hash::Hash, sync::Arc};
 
 use crate::{
+    batch::Batch,
     common::{DBValue, DataType, Direction},
     crypto::siphash,
     data::LodisData,
@@ -33,11 +34,15 @@ impl LodisData for ArrayMap {
         self.list.prefix()
     }
 
-    fn remove(&self) -> Result<()> {
-        self.list.remove()?;
-        self.map.remove()?;
+    fn remove_with_batch(&self, batch: &mut Batch) -> Result<()> {
+        self.list.remove_with_batch(batch)?;
+        self.map.remove_with_batch(batch)?;
         Ok(())
     }
+
+    fn prefixes(&self) -> Vec<&[u8]> {
+        vec![self.list.prefix(), self.map.prefix()]
+    }
 }
 
 /// Array Map
@@ -60,10 +65,16 @@ impl LodisData for ArrayMap {
 /// ```
 impl ArrayMap {
     pub fn new(name: String, db: Arc<DB>) -> ArrayMap {
+        ArrayMap::new_in("", name, db)
+    }
+
+    /// The ArrayMap named `name` in the namespace, which does not share data with the other
+    /// namespaces
+    pub fn new_in(namespace: &str, name: String, db: Arc<DB>) -> ArrayMap {
         let flag = TYPE.flag();
-        let mut list = List::new(name.to_string() + "@list", db.clone());
+        let mut list = List::new_in(namespace, name.to_string() + "@list", db.clone());
         list.prefix[0] = flag[0];
-        let mut map = Map::new(name.to_string() + "@map", db.clone());
+        let mut map = Map::new_in(namespace, name.to_string() + "@map", db.clone());
         map.prefix[0] = flag[0];
 
         ArrayMap {
@@ -73,6 +84,10 @@ impl ArrayMap {
         }
     }
 
+    fn batch(&self) -> Batch {
+        Batch::new(self.db().clone())
+    }
+
     fn key_hash<K>(&self, key: K) -> [u8; 8]
     where
         K: Hash + AsRef<[u8]>,
@@ -84,14 +99,28 @@ impl ArrayMap {
         self.list.length()
     }
 
+    pub fn length_with_batch(&self, batch: &Batch) -> Result<u32> {
+        self.list.length_with_batch(batch)
+    }
+
     pub fn get<K>(&self, key: K) -> Result<Option<DBValue>>
+    where
+        K: Hash + AsRef<[u8]>,
+    {
+        Batch::read(self.db(), |batch| self.get_with_batch(key, batch))
+    }
+
+    pub fn get_with_batch<K>(&self, key: K, batch: &Batch) -> Result<Option<DBValue>>
     where
         K: Hash + AsRef<[u8]>,
     {
         let key_hash = self.key_hash(&key);
-        if let Some(DBValue::Direct(v)) = self.map.get(&key_hash)? {
+        if let Some(DBValue::Direct(v)) = self.map.get_with_batch(&key_hash, batch)? {
             let index_key = DBValue::IndexKey(v);
-            if let Some(DBValue::Direct(v)) = self.list.index_with_abs(index_key.index())? {
+            if let Some(DBValue::Direct(v)) = self
+                .list
+                .index_with_abs_with_batch(index_key.index(), batch)?
+            {
                 return Ok(Some(DBValue::KeyhashValue(v)));
             } else {
                 return Err(DBError::DBValueNotMatch(
@@ -104,9 +133,15 @@ impl ArrayMap {
 
     // Randomly returning a item
     pub fn random(&self) -> Result<Option<(DBValue, DBValue)>> {
-        if let Some(DBValue::Direct(v)) = self.list.random()? {
+        Batch::read(self.db(), |batch| self.random_with_batch(batch))
+    }
+
+    pub fn random_with_batch(&self, batch: &Batch) -> Result<Option<(DBValue, DBValue)>> {
+        if let Some(DBValue::Direct(v)) = self.list.random_with_batch(batch)? {
             let keyhash_value = DBValue::KeyhashValue(v);
-            if let Some(DBValue::Direct(index_key)) = self.map.get(keyhash_value.keyhash())? {
+            if let Some(DBValue::Direct(index_key)) =
+                self.map.get_with_batch(keyhash_value.keyhash(), batch)?
+            {
                 let index_key = DBValue::IndexKey(index_key);
                 return Ok(Some((index_key, keyhash_value)));
             } else {
@@ -119,14 +154,28 @@ impl ArrayMap {
     }
 
     pub fn exists<K>(&self, key: K) -> Result<bool>
+    where
+        K: Hash + AsRef<[u8]>,
+    {
+        Batch::read(self.db(), |batch| self.exists_with_batch(key, batch))
+    }
+
+    pub fn exists_with_batch<K>(&self, key: K, batch: &Batch) -> Result<bool>
     where
         K: Hash + AsRef<[u8]>,
     {
         let key_hash = self.key_hash(&key);
-        self.map.exists(&key_hash)
+        self.map.exists_with_batch(&key_hash, batch)
     }
 
-    fn set_new_pair<U>(&self, key_hash: U, key: U, value: U, direction: Direction) -> Result<()>
+    fn set_new_pair<U>(
+        &self,
+        key_hash: U,
+        key: U,
+        value: U,
+        direction: Direction,
+        batch: &mut Batch,
+    ) -> Result<()>
     where
         U: AsRef<[u8]>,
     {
@@ -135,27 +184,31 @@ impl ArrayMap {
             Direction::Forward => {
                 indexes = self
                     .list
-                    .push(&[[key_hash.as_ref(), value.as_ref()].concat()])?;
+                    .push_with_batch(&[[key_hash.as_ref(), value.as_ref()].concat()], batch)?;
             }
             Direction::Reverse => {
                 indexes = self
                     .list
-                    .push_left(&[[key_hash.as_ref(), value.as_ref()].concat()])?;
+                    .push_left_with_batch(&[[key_hash.as_ref(), value.as_ref()].concat()], batch)?;
             }
         }
-        self.map.set(
+        self.map.set_with_batch(
             &key_hash,
             [&u32_to_u8x4(indexes[0])[..], key.as_ref()].concat(),
+            batch,
         )?;
         Ok(())
     }
 
-    fn set_list_item<U>(&self, index: u32, key_hash: U, value: U) -> Result<()>
+    fn set_list_item<U>(&self, index: u32, key_hash: U, value: U, batch: &mut Batch) -> Result<()>
     where
         U: AsRef<[u8]>,
     {
-        self.list
-            .set_by_absindex(index, [key_hash.as_ref(), value.as_ref()].concat())?;
+        self.list.set_by_absindex_with_batch(
+            index,
+            [key_hash.as_ref(), value.as_ref()].concat(),
+            batch,
+        )?;
         Ok(())
     }
 
@@ -164,21 +217,32 @@ impl ArrayMap {
     //     -----------+
     //                |
     pub fn push<K, V>(&self, pairs: &[(K, V)]) -> Result<()>
+    where
+        K: Hash + AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.push_with_batch(pairs, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn push_with_batch<K, V>(&self, pairs: &[(K, V)], batch: &mut Batch) -> Result<()>
     where
         K: Hash + AsRef<[u8]>,
         V: AsRef<[u8]>,
     {
         for (key, value) in pairs {
             let key_hash = self.key_hash(&key);
-            if let Some(DBValue::Direct(v)) = self.map.get(&key_hash)? {
+            if let Some(DBValue::Direct(v)) = self.map.get_with_batch(&key_hash, batch)? {
                 let v = DBValue::IndexKey(v);
-                self.set_list_item(v.index(), &key_hash[..], value.as_ref())?;
+                self.set_list_item(v.index(), &key_hash[..], value.as_ref(), batch)?;
             } else {
                 self.set_new_pair(
                     &key_hash[..],
                     key.as_ref(),
                     value.as_ref(),
                     Direction::Forward,
+                    batch,
                 )?;
             }
         }
@@ -190,13 +254,23 @@ impl ArrayMap {
     //     -----------+
     //                |
     pub fn pushnx<K, V>(&self, pairs: &[(K, V)]) -> Result<()>
+    where
+        K: Hash + AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.pushnx_with_batch(pairs, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn pushnx_with_batch<K, V>(&self, pairs: &[(K, V)], batch: &mut Batch) -> Result<()>
     where
         K: Hash + AsRef<[u8]>,
         V: AsRef<[u8]>,
     {
         for (key, value) in pairs {
             let key_hash = self.key_hash(&key);
-            if self.map.exists(&key_hash)? {
+            if self.map.exists_with_batch(&key_hash, batch)? {
                 continue;
             }
             self.set_new_pair(
@@ -204,6 +278,7 @@ impl ArrayMap {
                 key.as_ref(),
                 value.as_ref(),
                 Direction::Forward,
+                batch,
             )?;
         }
         Ok(())
@@ -214,21 +289,32 @@ impl ArrayMap {
     //     +-----------
     //     |
     pub fn push_left<K, V>(&self, pairs: &[(K, V)]) -> Result<()>
+    where
+        K: Hash + AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.push_left_with_batch(pairs, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn push_left_with_batch<K, V>(&self, pairs: &[(K, V)], batch: &mut Batch) -> Result<()>
     where
         K: Hash + AsRef<[u8]>,
         V: AsRef<[u8]>,
     {
         for (key, value) in pairs {
             let key_hash = self.key_hash(&key);
-            if let Some(DBValue::Direct(v)) = self.map.get(&key_hash)? {
+            if let Some(DBValue::Direct(v)) = self.map.get_with_batch(&key_hash, batch)? {
                 let v = DBValue::IndexKey(v);
-                self.set_list_item(v.index(), &key_hash[..], value.as_ref())?;
+                self.set_list_item(v.index(), &key_hash[..], value.as_ref(), batch)?;
             } else {
                 self.set_new_pair(
                     &key_hash[..],
                     key.as_ref(),
                     value.as_ref(),
                     Direction::Reverse,
+                    batch,
                 )?;
             }
         }
@@ -236,13 +322,23 @@ impl ArrayMap {
     }
 
     pub fn pushnx_left<K, V>(&self, pairs: &[(K, V)]) -> Result<()>
+    where
+        K: Hash + AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.pushnx_left_with_batch(pairs, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn pushnx_left_with_batch<K, V>(&self, pairs: &[(K, V)], batch: &mut Batch) -> Result<()>
     where
         K: Hash + AsRef<[u8]>,
         V: AsRef<[u8]>,
     {
         for (key, value) in pairs {
             let key_hash = self.key_hash(&key);
-            if self.map.exists(&key_hash)? {
+            if self.map.exists_with_batch(&key_hash, batch)? {
                 continue;
             }
             self.set_new_pair(
@@ -250,6 +346,7 @@ impl ArrayMap {
                 key.as_ref(),
                 value.as_ref(),
                 Direction::Reverse,
+                batch,
             )?;
         }
         Ok(())
@@ -257,20 +354,29 @@ impl ArrayMap {
 
     // Increase the value only if the value is an integer string
     pub fn increase<K>(&self, key: K, incr: i64) -> Result<()>
+    where
+        K: Hash + AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.increase_with_batch(key, incr, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn increase_with_batch<K>(&self, key: K, incr: i64, batch: &mut Batch) -> Result<()>
     where
         K: Hash + AsRef<[u8]>,
     {
         let key_hash = self.key_hash(&key);
 
         // Change old value
-        if let Some(DBValue::Direct(v)) = self.map.get(&key_hash)? {
+        if let Some(DBValue::Direct(v)) = self.map.get_with_batch(&key_hash, batch)? {
             let index_key = DBValue::IndexKey(v);
             let index = index_key.index();
-            if let Some(DBValue::Direct(v)) = self.list.index_with_abs(index)? {
+            if let Some(DBValue::Direct(v)) = self.list.index_with_abs_with_batch(index, batch)? {
                 if let Some(val_str) = DBValue::KeyhashValue(v).to_utf8() {
                     if let Ok(val_int) = val_str.parse::<i64>() {
                         let new_val = (val_int + incr).to_string();
-                        self.set_list_item(index, &key_hash[..], &new_val.as_bytes())?;
+                        self.set_list_item(index, &key_hash[..], &new_val.as_bytes(), batch)?;
                     } else {
                         return Err(DBError::IsNotNumeric);
                     }
@@ -290,18 +396,83 @@ impl ArrayMap {
                 key.as_ref(),
                 &new_val.as_bytes(),
                 Direction::Forward,
+                batch,
             )?;
         }
         Ok(())
     }
 
+    // Set the value of a field, only if its value equals `expected`,
+    // or the field does not exist if `expected` is None.
+    // A new field is appended from right.
+    // Return whether the value is set.
+    pub fn cas<K, V>(&self, key: K, expected: Option<&[u8]>, value: V) -> Result<bool>
+    where
+        K: Hash + AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        let swapped = self.cas_with_batch(key, expected, value, &mut batch)?;
+        batch.commit()?;
+        Ok(swapped)
+    }
+
+    pub fn cas_with_batch<K, V>(
+        &self,
+        key: K,
+        expected: Option<&[u8]>,
+        value: V,
+        batch: &mut Batch,
+    ) -> Result<bool>
+    where
+        K: Hash + AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let key_hash = self.key_hash(&key);
+
+        if let Some(DBValue::Direct(v)) = self.map.get_with_batch(&key_hash, batch)? {
+            let index = DBValue::IndexKey(v).index();
+            if let Some(DBValue::Direct(v)) = self.list.index_with_abs_with_batch(index, batch)? {
+                if Some(DBValue::KeyhashValue(v).value()) != expected {
+                    return Ok(false);
+                }
+                self.set_list_item(index, &key_hash[..], value.as_ref(), batch)?;
+            } else {
+                return Err(DBError::DBValueNotMatch(
+                    "ArrayMap.cas: list value is not DBValue::Direct(DBVector)".to_owned(),
+                ));
+            }
+        } else {
+            if expected.is_some() {
+                return Ok(false);
+            }
+            self.set_new_pair(
+                &key_hash[..],
+                key.as_ref(),
+                value.as_ref(),
+                Direction::Forward,
+                batch,
+            )?;
+        }
+        Ok(true)
+    }
+
     pub fn pop(&self) -> Result<Option<(DBValue, DBValue)>> {
-        let value = self.list.pop()?;
+        let mut batch = self.batch();
+        let value = self.pop_with_batch(&mut batch)?;
+        batch.commit()?;
+        Ok(value)
+    }
+
+    pub fn pop_with_batch(&self, batch: &mut Batch) -> Result<Option<(DBValue, DBValue)>> {
+        let value = self.list.pop_with_batch(batch)?;
         if let Some(DBValue::Direct(v)) = value {
             let keyhash_value = DBValue::KeyhashValue(v);
-            if let Some(DBValue::Direct(index_key)) = self.map.get(keyhash_value.keyhash())? {
+            if let Some(DBValue::Direct(index_key)) =
+                self.map.get_with_batch(keyhash_value.keyhash(), batch)?
+            {
                 let index_key = DBValue::IndexKey(index_key);
-                self.map.delete(keyhash_value.keyhash())?;
+                self.map.delete_with_batch(keyhash_value.keyhash(), batch)?;
                 return Ok(Some((index_key, keyhash_value)));
             } else {
                 return Err(DBError::DBValueNotMatch(
@@ -313,12 +484,21 @@ impl ArrayMap {
     }
 
     pub fn pop_left(&self) -> Result<Option<(DBValue, DBValue)>> {
-        let value = self.list.pop_left()?;
+        let mut batch = self.batch();
+        let value = self.pop_left_with_batch(&mut batch)?;
+        batch.commit()?;
+        Ok(value)
+    }
+
+    pub fn pop_left_with_batch(&self, batch: &mut Batch) -> Result<Option<(DBValue, DBValue)>> {
+        let value = self.list.pop_left_with_batch(batch)?;
         if let Some(DBValue::Direct(v)) = value {
             let keyhash_value = DBValue::KeyhashValue(v);
-            if let Some(DBValue::Direct(index_key)) = self.map.get(keyhash_value.keyhash())? {
+            if let Some(DBValue::Direct(index_key)) =
+                self.map.get_with_batch(keyhash_value.keyhash(), batch)?
+            {
                 let index_key = DBValue::IndexKey(index_key);
-                self.map.delete(keyhash_value.keyhash())?;
+                self.map.delete_with_batch(keyhash_value.keyhash(), batch)?;
                 return Ok(Some((index_key, keyhash_value)));
             } else {
                 return Err(DBError::DBValueNotMatch(
@@ -330,12 +510,21 @@ impl ArrayMap {
     }
 
     pub fn pop_random(&self) -> Result<Option<(DBValue, DBValue)>> {
-        let value = self.list.pop_random()?;
+        let mut batch = self.batch();
+        let value = self.pop_random_with_batch(&mut batch)?;
+        batch.commit()?;
+        Ok(value)
+    }
+
+    pub fn pop_random_with_batch(&self, batch: &mut Batch) -> Result<Option<(DBValue, DBValue)>> {
+        let value = self.list.pop_random_with_batch(batch)?;
         if let Some(DBValue::Direct(v)) = value {
             let keyhash_value = DBValue::KeyhashValue(v);
-            if let Some(DBValue::Direct(index_key)) = self.map.get(keyhash_value.keyhash())? {
+            if let Some(DBValue::Direct(index_key)) =
+                self.map.get_with_batch(keyhash_value.keyhash(), batch)?
+            {
                 let index_key = DBValue::IndexKey(index_key);
-                self.map.delete(keyhash_value.keyhash())?;
+                self.map.delete_with_batch(keyhash_value.keyhash(), batch)?;
                 return Ok(Some((index_key, keyhash_value)));
             } else {
                 return Err(DBError::DBValueNotMatch(
@@ -351,12 +540,26 @@ impl ArrayMap {
         start: u32,
         end: u32,
         direction: Direction,
+    ) -> Result<Vec<(DBValue, DBValue)>> {
+        Batch::read(self.db(), |batch| {
+            self.range_with_batch(start, end, direction, batch)
+        })
+    }
+
+    pub fn range_with_batch(
+        &self,
+        start: u32,
+        end: u32,
+        direction: Direction,
+        batch: &Batch,
     ) -> Result<Vec<(DBValue, DBValue)>> {
         let mut vec = Vec::new();
-        for value in self.list.range(start, end, direction)? {
+        for value in self.list.range_with_batch(start, end, direction, batch)? {
             if let DBValue::Direct(v) = value {
                 let keyhash_value = DBValue::KeyhashValue(v);
-                if let Some(DBValue::Direct(index_key)) = self.map.get(keyhash_value.keyhash())? {
+                if let Some(DBValue::Direct(index_key)) =
+                    self.map.get_with_batch(keyhash_value.keyhash(), batch)?
+                {
                     let index_key = DBValue::IndexKey(index_key);
                     vec.push((index_key, keyhash_value));
                 } else {
@@ -374,8 +577,12 @@ impl ArrayMap {
     }
 
     pub fn keys(&self) -> Result<Vec<DBValue>> {
+        Batch::read(self.db(), |batch| self.keys_with_batch(batch))
+    }
+
+    pub fn keys_with_batch(&self, batch: &Batch) -> Result<Vec<DBValue>> {
         let mut vec = Vec::new();
-        for value in self.map.values()? {
+        for value in self.map.values_with_batch(batch)? {
             if let DBValue::DirectB(v) = value {
                 vec.push(DBValue::IndexKeyB(v));
             } else {
@@ -388,8 +595,12 @@ impl ArrayMap {
     }
 
     pub fn values(&self) -> Result<Vec<DBValue>> {
+        Batch::read(self.db(), |batch| self.values_with_batch(batch))
+    }
+
+    pub fn values_with_batch(&self, batch: &Batch) -> Result<Vec<DBValue>> {
         let mut vec = Vec::new();
-        for value in self.list.all()? {
+        for value in self.list.all_with_batch(batch)? {
             if let DBValue::Direct(v) = value {
                 vec.push(DBValue::KeyhashValue(v));
             } else {
@@ -402,11 +613,17 @@ impl ArrayMap {
     }
 
     pub fn all(&self) -> Result<Vec<(DBValue, DBValue)>> {
+        Batch::read(self.db(), |batch| self.all_with_batch(batch))
+    }
+
+    pub fn all_with_batch(&self, batch: &Batch) -> Result<Vec<(DBValue, DBValue)>> {
         let mut vec = Vec::new();
-        for key in self.map.values()? {
+        for key in self.map.values_with_batch(batch)? {
             if let DBValue::DirectB(k) = key {
                 let k = DBValue::IndexKeyB(k);
-                if let Some(DBValue::Direct(v)) = self.list.index_with_abs(k.index())? {
+                if let Some(DBValue::Direct(v)) =
+                    self.list.index_with_abs_with_batch(k.index(), batch)?
+                {
                     let v = DBValue::KeyhashValue(v);
                     vec.push((k, v));
                 } else {
@@ -424,25 +641,41 @@ impl ArrayMap {
     }
 
     pub fn delete<K>(&self, key: K) -> Result<()>
+    where
+        K: Hash + AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.delete_with_batch(key, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn delete_with_batch<K>(&self, key: K, batch: &mut Batch) -> Result<()>
     where
         K: Hash + AsRef<[u8]>,
     {
         let key_hash = self.key_hash(&key);
-        if let Some(DBValue::Direct(v)) = self.map.get(&key_hash)? {
+        if let Some(DBValue::Direct(v)) = self.map.get_with_batch(&key_hash, batch)? {
             let index_key = DBValue::IndexKey(v);
             // Delete the element which has the key
-            self.map.delete(&key_hash)?;
-            self.list.delete_with_abs_index(index_key.index())?;
+            self.map.delete_with_batch(&key_hash, batch)?;
+            self.list
+                .delete_with_abs_index_with_batch(index_key.index(), batch)?;
 
             // Set moved element in list to right index
-            if let Some(DBValue::Direct(v)) = self.list.index_with_abs(index_key.index())? {
+            if let Some(DBValue::Direct(v)) = self
+                .list
+                .index_with_abs_with_batch(index_key.index(), batch)?
+            {
                 let keyhash_value = DBValue::KeyhashValue(v);
-                if let Some(DBValue::Direct(v)) = self.map.get(keyhash_value.keyhash())? {
+                if let Some(DBValue::Direct(v)) =
+                    self.map.get_with_batch(keyhash_value.keyhash(), batch)?
+                {
                     let old_index_key = DBValue::IndexKey(v);
                     // set new index + key
-                    self.map.set(
+                    self.map.set_with_batch(
                         keyhash_value.keyhash(),
                         [&u32_to_u8x4(index_key.index())[..], old_index_key.key()].concat(),
+                        batch,
                     )?;
                 } else {
                     return Err(DBError::DBValueNotMatch(
@@ -467,7 +700,7 @@ mod test_arraymap {
         u32::MAX as MAX_U32,
     };
 
-    use crate::{common::Direction, utils::u8x4_to_u32};
+    use crate::{batch::Batch, common::Direction, data::LodisData, utils::u8x4_to_u32};
 
     #[test]
     fn test_arraymap() {
@@ -529,6 +762,14 @@ mod test_arraymap {
                 assert_eq!(length, 3);
                 arraymap.delete(b"incr").unwrap();
 
+                assert_eq!(arraymap.cas(b"cas", Some(b"x"), b"1").unwrap(), false);
+                assert_eq!(arraymap.cas(b"cas", None, b"1").unwrap(), true);
+                assert_eq!(arraymap.cas(b"cas", None, b"2").unwrap(), false);
+                assert_eq!(arraymap.cas(b"cas", Some(b"1"), b"2").unwrap(), true);
+                let v = arraymap.get(b"cas").unwrap().unwrap();
+                assert_eq!(&*v, b"2");
+                arraymap.delete(b"cas").unwrap();
+
                 let (k, v) = arraymap.pop().unwrap().unwrap();
                 assert_eq!(&*k, b"a1");
                 assert_eq!(&*v, b"A1");
@@ -562,4 +803,60 @@ mod test_arraymap {
         let opts = Options::default();
         assert!(DB::destroy(&opts, path).is_ok());
     }
+
+    #[test]
+    fn test_arraymap_copy() {
+        let path = "test-arraymap-db2";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let source = super::ArrayMap::new("abc".to_owned(), db.clone());
+            let target = super::ArrayMap::new("def".to_owned(), db.clone());
+            source
+                .push(&[(b"a1", b"A1"), (b"a2", b"A2"), (b"a3", b"A3")])
+                .unwrap();
+
+            // Copy 2 records at a time, over the records of the list and the map
+            let mut after: Option<Vec<u8>> = None;
+            let mut rounds = 0;
+            loop {
+                let mut batch = Batch::new(db.clone());
+                after = source
+                    .copy_to_with_batch(&target, after.as_deref(), 2, &mut batch)
+                    .unwrap();
+                batch.commit().unwrap();
+                rounds += 1;
+                if after.is_none() {
+                    break;
+                }
+            }
+            assert!(rounds > 2);
+
+            assert_eq!(target.length().unwrap(), 3);
+            assert_eq!(&*target.get(b"a2").unwrap().unwrap(), b"A2");
+            let keys = target.keys().unwrap();
+            let source_keys = source.keys().unwrap();
+            let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+            let source_keys: Vec<&[u8]> = source_keys.iter().map(|key| key.as_ref()).collect();
+            assert_eq!(keys, source_keys);
+            target.push(&[(b"a4", b"A4")]).unwrap();
+            assert_eq!(target.length().unwrap(), 4);
+            assert_eq!(source.length().unwrap(), 3);
+
+            loop {
+                let mut batch = Batch::new(db.clone());
+                let removed = source.remove_part_with_batch(2, &mut batch).unwrap();
+                batch.commit().unwrap();
+                if removed == 0 {
+                    break;
+                }
+                assert!(removed <= 2);
+            }
+            assert_eq!(source.length().unwrap(), 0);
+            assert!(source.get(b"a1").unwrap().is_none());
+            assert_eq!(&*target.get(b"a1").unwrap().unwrap(), b"A1");
+        }
+
+        let opts = Options::default();
+        assert!(DB::destroy(&opts, path).is_ok());
+    }
 }
@@ -0,0 +1,155 @@
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_void},
+    path::Path,
+    ptr,
+};
+
+use librocksdb_sys as ffi;
+use rocksdb::{
+    backup::{BackupEngine, BackupEngineOptions, RestoreOptions},
+    DB,
+};
+
+pub use rocksdb::backup::BackupEngineInfo as BackupInfo;
+
+use crate::error::{DBError, Result};
+
+/// Take an incremental backup of the live `db` into the backup directory `path`,
+/// then purge old backups and keep the latest `keep` of them. `keep` 0 keeps all backups.
+///
+/// Return the information of the new backup.
+pub fn create_backup<P: AsRef<Path>>(db: &DB, path: P, keep: usize) -> Result<BackupInfo> {
+    let mut engine = BackupEngine::open(&BackupEngineOptions::default(), path)?;
+    engine.create_new_backup_flush(db, true)?;
+    if keep > 0 {
+        engine.purge_old_backups(keep)?;
+    }
+    engine
+        .get_backup_info()
+        .into_iter()
+        .max_by_key(|info| info.backup_id)
+        .ok_or_else(|| DBError::BackupError("no backup is created".to_string()))
+}
+
+/// Return the information of all backups in the backup directory `path`, from old to new.
+pub fn list_backups<P: AsRef<Path>>(path: P) -> Result<Vec<BackupInfo>> {
+    let engine = BackupEngine::open(&BackupEngineOptions::default(), path)?;
+    let mut infos = engine.get_backup_info();
+    infos.sort_by_key(|info| info.backup_id);
+    Ok(infos)
+}
+
+/// Restore the backup `backup_id` in the backup directory `path` into `db_path`.
+/// If `backup_id` is None, restore the latest backup.
+///
+/// The db at `db_path` must not be opened.
+pub fn restore_backup<P, Q>(path: P, db_path: Q, backup_id: Option<u32>) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let backup_id = match backup_id {
+        Some(backup_id) => backup_id,
+        None => {
+            let mut engine = BackupEngine::open(&BackupEngineOptions::default(), path)?;
+            engine.restore_from_latest_backup(&db_path, &db_path, &RestoreOptions::default())?;
+            return Ok(());
+        }
+    };
+
+    // rocksdb crate only restores the latest backup, so restore a chosen one with rocksdb c api.
+    let c_path = to_cstring(path.as_ref())?;
+    let c_db_path = to_cstring(db_path.as_ref())?;
+    let mut err: *mut c_char = ptr::null_mut();
+    unsafe {
+        let options = ffi::rocksdb_options_create();
+        let engine = ffi::rocksdb_backup_engine_open(options, c_path.as_ptr(), &mut err);
+        if err.is_null() {
+            let restore_options = ffi::rocksdb_restore_options_create();
+            ffi::rocksdb_backup_engine_restore_db_from_backup(
+                engine,
+                c_db_path.as_ptr(),
+                c_db_path.as_ptr(),
+                restore_options,
+                backup_id,
+                &mut err,
+            );
+            ffi::rocksdb_restore_options_destroy(restore_options);
+            ffi::rocksdb_backup_engine_close(engine);
+        }
+        ffi::rocksdb_options_destroy(options);
+
+        if !err.is_null() {
+            let msg = CStr::from_ptr(err).to_string_lossy().into_owned();
+            ffi::rocksdb_free(err as *mut c_void);
+            return Err(DBError::BackupError(msg));
+        }
+    }
+    Ok(())
+}
+
+fn to_cstring(path: &Path) -> Result<CString> {
+    CString::new(path.to_string_lossy().as_bytes())
+        .map_err(|_| DBError::BackupError(format!("invalid path: {:?}", path)))
+}
+
+#[cfg(test)]
+mod test_backup {
+    use rocksdb::{Options, DB};
+
+    use std::sync::Arc;
+
+    use crate::map::Map;
+
+    use super::{create_backup, list_backups, restore_backup};
+
+    #[test]
+    fn test_backup_restore() {
+        let path = "test-backup-db1";
+        let backup_path = "test-backup-db1-backups";
+        let restore_path = "test-backup-db1-restored";
+        let (first, third) = {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let map = Map::new("abc".to_string(), db.clone());
+
+            map.set("a", "1").unwrap();
+            let first = create_backup(&db, backup_path, 0).unwrap();
+            map.set("a", "2").unwrap();
+            let second = create_backup(&db, backup_path, 0).unwrap();
+            assert!(second.backup_id > first.backup_id);
+
+            let ids: Vec<u32> = list_backups(backup_path)
+                .unwrap()
+                .iter()
+                .map(|info| info.backup_id)
+                .collect();
+            assert_eq!(ids, vec![first.backup_id, second.backup_id]);
+
+            // Only the latest backup is kept
+            let third = create_backup(&db, backup_path, 1).unwrap();
+            let ids: Vec<u32> = list_backups(backup_path)
+                .unwrap()
+                .iter()
+                .map(|info| info.backup_id)
+                .collect();
+            assert_eq!(ids, vec![third.backup_id]);
+            (first, third)
+        };
+
+        restore_backup(backup_path, restore_path, Some(third.backup_id)).unwrap();
+        {
+            let db = Arc::new(DB::open_default(restore_path).unwrap());
+            let map = Map::new("abc".to_string(), db.clone());
+            assert_eq!(&*map.get("a").unwrap().unwrap(), b"2");
+        }
+
+        // A purged backup can not be restored
+        assert!(restore_backup(backup_path, restore_path, Some(first.backup_id)).is_err());
+
+        let opts = Options::default();
+        DB::destroy(&opts, path).unwrap();
+        DB::destroy(&opts, restore_path).unwrap();
+        std::fs::remove_dir_all(backup_path).unwrap();
+    }
+}
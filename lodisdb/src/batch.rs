@@ -0,0 +1,537 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use rocksdb::{DBIterator, Direction, IteratorMode, Snapshot, WriteBatch, DB};
+
+use crate::error::Result;
+
+/// Batch collects the writes of one or more lodisdb data and commits them to rocksdb at once.
+///
+/// Reads through a batch see the writes staged before them, so a sequence of operations
+/// on the same data behaves the same as if each operation was written directly.
+/// Nothing is written to rocksdb until `commit` is called.
+///
+/// A batch created with a snapshot reads the db at the time of the snapshot.
+pub struct Batch<'a> {
+    db: Arc<DB>,
+    snapshot: Option<&'a Snapshot<'a>>,
+    // dbkey -> Some(value) for a put, None for a delete
+    staged: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // Deleted ranges of keys, [start, end), which are written before the staged writes.
+    // A staged write in a range is made after the range is deleted.
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    // Number of puts and deletes, a key written twice is counted twice
+    writes: usize,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(db: Arc<DB>) -> Batch<'a> {
+        Batch {
+            db,
+            snapshot: None,
+            staged: BTreeMap::new(),
+            ranges: Vec::new(),
+            writes: 0,
+        }
+    }
+
+    pub fn with_snapshot(db: Arc<DB>, snapshot: &'a Snapshot<'a>) -> Batch<'a> {
+        Batch {
+            db,
+            snapshot: Some(snapshot),
+            staged: BTreeMap::new(),
+            ranges: Vec::new(),
+            writes: 0,
+        }
+    }
+
+    /// Call `f` with a batch reading a snapshot of the db, so that all reads of `f`
+    /// see the same point in time
+    pub fn read<T, F>(db: &Arc<DB>, f: F) -> T
+    where
+        F: FnOnce(&Batch) -> T,
+    {
+        let snapshot = db.snapshot();
+        let batch = Batch::with_snapshot(db.clone(), &snapshot);
+        f(&batch)
+    }
+
+    pub fn db(&self) -> &Arc<DB> {
+        &self.db
+    }
+
+    pub fn get<K>(&self, key: K) -> Result<Option<Vec<u8>>>
+    where
+        K: AsRef<[u8]>,
+    {
+        if let Some(value) = self.staged.get(key.as_ref()) {
+            return Ok(value.clone());
+        }
+        if self.deleted_until(key.as_ref()).is_some() {
+            return Ok(None);
+        }
+        if let Some(snapshot) = self.snapshot {
+            Ok(snapshot.get(key)?)
+        } else {
+            Ok(self.db.get(key)?)
+        }
+    }
+
+    // The end of the deleted range which has the key, None if the key is not in a deleted range
+    fn deleted_until(&self, key: &[u8]) -> Option<&[u8]> {
+        self.ranges
+            .iter()
+            .filter(|(start, end)| key >= &start[..] && key < &end[..])
+            .map(|(_, end)| &end[..])
+            .max()
+    }
+
+    // The pairs of the db from `start` which start with the prefix and are not in deleted ranges,
+    // until `take` returns false
+    fn db_pairs<F>(&self, prefix: &[u8], start: &[u8], mut take: F)
+    where
+        F: FnMut(Box<[u8]>, Box<[u8]>) -> bool,
+    {
+        let mut iter = self.iterator(IteratorMode::From(start, Direction::Forward));
+        while let Some((key, value)) = iter.next() {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            // Skip the deleted range
+            if let Some(end) = self.deleted_until(&key) {
+                let end = end.to_vec();
+                iter.set_mode(IteratorMode::From(&end, Direction::Forward));
+                continue;
+            }
+            if !take(key, value) {
+                break;
+            }
+        }
+    }
+
+    fn iterator(&self, mode: IteratorMode) -> DBIterator {
+        if let Some(snapshot) = self.snapshot {
+            snapshot.iterator(mode)
+        } else {
+            self.db.iterator(mode)
+        }
+    }
+
+    pub fn put<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.staged
+            .insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+        self.writes += 1;
+    }
+
+    pub fn delete<K>(&mut self, key: K)
+    where
+        K: AsRef<[u8]>,
+    {
+        self.staged.insert(key.as_ref().to_vec(), None);
+        self.writes += 1;
+    }
+
+    /// Get all key, value pairs whose key starts with the prefix, ordered by key
+    pub fn prefix_pairs<P>(&self, prefix: P) -> Result<Vec<(Box<[u8]>, Box<[u8]>)>>
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix = prefix.as_ref();
+
+        let mut pairs = BTreeMap::new();
+        self.db_pairs(prefix, prefix, |key, value| {
+            pairs.insert(key, value);
+            true
+        });
+
+        for (key, value) in self.staged.range(prefix.to_vec()..) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let key = key.clone().into_boxed_slice();
+            if let Some(value) = value {
+                pairs.insert(key, value.clone().into_boxed_slice());
+            } else {
+                pairs.remove(&key);
+            }
+        }
+
+        Ok(pairs.into_iter().collect())
+    }
+
+    /// Get at most `limit` key, value pairs whose key starts with the prefix and is
+    /// greater than `after`, ordered by key
+    pub fn prefix_pairs_after<P, A>(
+        &self,
+        prefix: P,
+        after: A,
+        limit: usize,
+    ) -> Result<Vec<(Box<[u8]>, Box<[u8]>)>>
+    where
+        P: AsRef<[u8]>,
+        A: AsRef<[u8]>,
+    {
+        let prefix = prefix.as_ref();
+        let after = after.as_ref();
+        let start = if after < prefix { prefix } else { after };
+        let in_range = |key: &[u8]| key.starts_with(prefix) && key > after;
+
+        let staged: Vec<_> = self
+            .staged
+            .range(start.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(key, _)| in_range(key))
+            .collect();
+
+        // Staged deletes may remove up to `staged.len()` of the pairs in the db
+        let mut pairs = BTreeMap::new();
+        self.db_pairs(prefix, start, |key, value| {
+            if pairs.len() >= limit + staged.len() {
+                return false;
+            }
+            if in_range(&key) {
+                pairs.insert(key, value);
+            }
+            true
+        });
+        // A staged put after the last pair of the db may come before a pair which is
+        // not read, so it is only taken if the db has no more pairs before it
+        let last = if pairs.len() >= limit + staged.len() {
+            pairs.keys().next_back().cloned()
+        } else {
+            None
+        };
+
+        for (key, value) in staged {
+            let key = key.clone().into_boxed_slice();
+            if let Some(last) = &last {
+                if key > *last {
+                    break;
+                }
+            }
+            if let Some(value) = value {
+                pairs.insert(key, value.clone().into_boxed_slice());
+            } else {
+                pairs.remove(&key);
+            }
+        }
+
+        Ok(pairs.into_iter().take(limit).collect())
+    }
+
+    /// Delete all keys which start with the prefix
+    ///
+    /// The keys are deleted as one range when the batch is committed, without reading them.
+    pub fn delete_prefix<P>(&mut self, prefix: P) -> Result<()>
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix = prefix.as_ref();
+        let end = match prefix_end(prefix) {
+            Some(end) => end,
+            // No key is after the prefix, so the keys are deleted one by one
+            None => {
+                for (key, _) in self.prefix_pairs(prefix)? {
+                    self.delete(key);
+                }
+                return Ok(());
+            }
+        };
+
+        let mut exists = false;
+        self.db_pairs(prefix, prefix, |_, _| {
+            exists = true;
+            false
+        });
+        let staged: Vec<Vec<u8>> = self
+            .staged
+            .range(prefix.to_vec()..end.clone())
+            .map(|(key, _)| key.clone())
+            .collect();
+        // Nothing to delete
+        if !exists && staged.is_empty() {
+            return Ok(());
+        }
+
+        for key in staged {
+            self.staged.remove(&key);
+        }
+        self.ranges.push((prefix.to_vec(), end));
+        self.writes += 1;
+        Ok(())
+    }
+
+    /// The number of staged writes, a deleted range is one write
+    pub fn len(&self) -> usize {
+        self.staged.len() + self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.staged.is_empty() && self.ranges.is_empty()
+    }
+
+    /// The number of puts and deletes made through the batch, which increases even if
+    /// a write replaces a staged write of the same key
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    /// Write all staged writes to rocksdb atomically
+    pub fn commit(self) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::default();
+        for (start, end) in self.ranges.iter() {
+            batch.delete_range(start, end);
+        }
+        for (key, value) in self.staged.iter() {
+            if let Some(value) = value {
+                batch.put(key, value);
+            } else {
+                batch.delete(key);
+            }
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+}
+
+// The least key which is greater than all keys with the prefix, None if there is not one
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test_batch {
+    use rocksdb::{Options, DB};
+
+    use std::sync::Arc;
+
+    use crate::{arraymap::ArrayMap, data::LodisData, list::List, map::Map};
+
+    #[test]
+    fn test_batch_commit() {
+        let path = "test-batch-db1";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let list = List::new("abc".to_string(), db.clone());
+            let map = Map::new("abc".to_string(), db.clone());
+
+            let mut batch = super::Batch::new(db.clone());
+            list.push_with_batch(&["a", "b"], &mut batch).unwrap();
+            map.set_with_batch("a", "1", &mut batch).unwrap();
+
+            // Staged writes are seen through the batch, but not written to db
+            assert_eq!(list.length_with_batch(&batch).unwrap(), 2);
+            assert_eq!(&*map.get_with_batch("a", &batch).unwrap().unwrap(), b"1");
+            assert_eq!(list.length().unwrap(), 0);
+            assert!(map.get("a").unwrap().is_none());
+
+            batch.commit().unwrap();
+            assert_eq!(list.all().unwrap().len(), 2);
+            assert_eq!(&*map.get("a").unwrap().unwrap(), b"1");
+        }
+
+        let opts = Options::default();
+        assert!(DB::destroy(&opts, path).is_ok());
+    }
+
+    #[test]
+    fn test_batch_drop() {
+        let path = "test-batch-db2";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let map = Map::new("abc".to_string(), db.clone());
+            map.set("a", "1").unwrap();
+
+            let mut batch = super::Batch::new(db.clone());
+            map.delete_with_batch("a", &mut batch).unwrap();
+            map.set_with_batch("b", "2", &mut batch).unwrap();
+            assert_eq!(map.keys_with_batch(&batch).unwrap().len(), 1);
+            drop(batch);
+
+            assert_eq!(map.length().unwrap(), 1);
+            assert_eq!(&*map.get("a").unwrap().unwrap(), b"1");
+        }
+
+        let opts = Options::default();
+        assert!(DB::destroy(&opts, path).is_ok());
+    }
+
+    #[test]
+    fn test_batch_remove() {
+        let path = "test-batch-db3";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let list = List::new("abc".to_string(), db.clone());
+            let arraymap = ArrayMap::new("abc".to_string(), db.clone());
+            list.push(&["a", "b"]).unwrap();
+            arraymap.push(&[("a", "1")]).unwrap();
+
+            let mut batch = super::Batch::new(db.clone());
+            list.push_with_batch(&["c"], &mut batch).unwrap();
+            list.remove_with_batch(&mut batch).unwrap();
+            arraymap.remove_with_batch(&mut batch).unwrap();
+            assert_eq!(list.length_with_batch(&batch).unwrap(), 0);
+            assert_eq!(list.length().unwrap(), 2);
+
+            batch.commit().unwrap();
+            assert_eq!(list.length().unwrap(), 0);
+            assert!(list.all().unwrap().is_empty());
+            assert_eq!(arraymap.length().unwrap(), 0);
+            assert!(arraymap.get("a").unwrap().is_none());
+        }
+
+        let opts = Options::default();
+        assert!(DB::destroy(&opts, path).is_ok());
+    }
+
+    #[test]
+    fn test_batch_prefix_pairs_after() {
+        let path = "test-batch-db5";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let map = Map::new("abc".to_string(), db.clone());
+            map.mset(&[("a", "1"), ("c", "3"), ("e", "5"), ("g", "7")])
+                .unwrap();
+
+            let mut batch = super::Batch::new(db.clone());
+            map.delete_with_batch("c", &mut batch).unwrap();
+            map.set_with_batch("b", "2", &mut batch).unwrap();
+            map.set_with_batch("f", "6", &mut batch).unwrap();
+
+            // Page through the fields with staged writes
+            let mut fields = Vec::new();
+            let mut after: Option<Vec<u8>> = None;
+            loop {
+                let keys = map
+                    .keys_after_with_batch(after.as_deref(), 2, &batch)
+                    .unwrap();
+                if keys.is_empty() {
+                    break;
+                }
+                assert!(keys.len() <= 2);
+                after = Some(keys.last().unwrap().to_vec());
+                fields.extend(keys.iter().map(|key| key.to_vec()));
+            }
+            assert_eq!(
+                fields,
+                vec![
+                    b"a".to_vec(),
+                    b"b".to_vec(),
+                    b"e".to_vec(),
+                    b"f".to_vec(),
+                    b"g".to_vec()
+                ]
+            );
+
+            let keys = map.keys_after(Some(b"e"), 10).unwrap();
+            let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+            assert_eq!(&keys[..], &[b"g"]);
+        }
+
+        let opts = Options::default();
+        assert!(DB::destroy(&opts, path).is_ok());
+    }
+
+    #[test]
+    fn test_batch_snapshot() {
+        let path = "test-batch-db4";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let arraymap = ArrayMap::new("abc".to_string(), db.clone());
+            arraymap.push(&[("a", "1"), ("b", "2")]).unwrap();
+
+            let snapshot = db.snapshot();
+            let batch = super::Batch::with_snapshot(db.clone(), &snapshot);
+            arraymap.pop_left().unwrap();
+            arraymap.push(&[("c", "3")]).unwrap();
+
+            // The batch still reads the db at the time of the snapshot
+            let pairs = arraymap.all_with_batch(&batch).unwrap();
+            let mut keys: Vec<&[u8]> = pairs.iter().map(|(k, _)| k.as_ref()).collect();
+            keys.sort();
+            assert_eq!(&keys[..], &[b"a", b"b"]);
+            assert_eq!(arraymap.length_with_batch(&batch).unwrap(), 2);
+
+            let pairs = arraymap.all().unwrap();
+            let mut keys: Vec<&[u8]> = pairs.iter().map(|(k, _)| k.as_ref()).collect();
+            keys.sort();
+            assert_eq!(&keys[..], &[b"b", b"c"]);
+        }
+
+        let opts = Options::default();
+        assert!(DB::destroy(&opts, path).is_ok());
+    }
+
+    #[test]
+    fn test_batch_delete_range() {
+        let path = "test-batch-db5";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let list = List::new("abc".to_string(), db.clone());
+            let other = List::new("abd".to_string(), db.clone());
+            let map = Map::new("abc".to_string(), db.clone());
+            let elements: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+            list.push(&elements).unwrap();
+            other.push(&["x"]).unwrap();
+            map.set("a", "1").unwrap();
+
+            // The whole list is one staged write
+            let mut batch = super::Batch::new(db.clone());
+            list.push_with_batch(&["y"], &mut batch).unwrap();
+            list.remove_with_batch(&mut batch).unwrap();
+            assert_eq!(batch.len(), 1);
+            assert_eq!(list.length_with_batch(&batch).unwrap(), 0);
+            assert!(list.all_with_batch(&batch).unwrap().is_empty());
+            assert!(list.index_with_batch(0, &batch).unwrap().is_none());
+
+            // Writes after the removal are kept
+            list.push_with_batch(&["a", "b"], &mut batch).unwrap();
+            assert_eq!(list.length_with_batch(&batch).unwrap(), 2);
+            let values = list.all_with_batch(&batch).unwrap();
+            assert_eq!(values.len(), 2);
+            assert_eq!(&*values[0], b"a");
+            assert_eq!(other.length_with_batch(&batch).unwrap(), 1);
+            assert_eq!(list.length().unwrap(), 1000);
+
+            batch.commit().unwrap();
+            let values = list.all().unwrap();
+            assert_eq!(values.len(), 2);
+            assert_eq!(&*values[1], b"b");
+            assert_eq!(other.all().unwrap().len(), 1);
+            assert_eq!(&*map.get("a").unwrap().unwrap(), b"1");
+
+            // Removing data which does not exist writes nothing
+            let mut batch = super::Batch::new(db.clone());
+            List::new("nothing".to_string(), db.clone())
+                .remove_with_batch(&mut batch)
+                .unwrap();
+            assert!(batch.is_empty());
+            assert_eq!(batch.writes(), 0);
+        }
+
+        let opts = Options::default();
+        assert!(DB::destroy(&opts, path).is_ok());
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(super::prefix_end(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(super::prefix_end(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(super::prefix_end(b"\xff\xff"), None);
+        assert_eq!(super::prefix_end(b""), None);
+    }
+}
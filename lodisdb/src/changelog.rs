@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use rocksdb::{Direction, IteratorMode, DB};
+
+use crate::{
+    batch::Batch,
+    error::Result,
+    utils::{u64_to_u8x8, u8x8_to_u64},
+};
+
+/// The prefix of the entries of the change log, followed by the sequence number of an entry.
+///
+/// All lodisdb data keys start with a data type flag which is not 0, so the keys
+/// can not be used by them.
+const CHANGE_LOG_PREFIX: &[u8] = b"\x00@CHANGE_LOG";
+
+fn change_key(sequence: u64) -> Vec<u8> {
+    [CHANGE_LOG_PREFIX, &u64_to_u8x8(sequence)[..]].concat()
+}
+
+fn change_sequence(key: &[u8]) -> u64 {
+    let mut buf: [u8; 8] = [0; 8];
+    buf.clone_from_slice(&key[CHANGE_LOG_PREFIX.len()..]);
+    u8x8_to_u64(&buf)
+}
+
+/// Return the sequence numbers of the first and the last entries of the change log,
+/// None if the log is empty
+pub fn change_log_range(db: &Arc<DB>) -> Result<Option<(u64, u64)>> {
+    let mut iter = db.iterator(IteratorMode::From(CHANGE_LOG_PREFIX, Direction::Forward));
+    let first = match iter.next() {
+        Some((key, _)) if key.starts_with(CHANGE_LOG_PREFIX) => change_sequence(&key),
+        _ => return Ok(None),
+    };
+    let end = change_key(u64::MAX);
+    let mut iter = db.iterator(IteratorMode::From(&end, Direction::Reverse));
+    let last = match iter.next() {
+        Some((key, _)) if key.starts_with(CHANGE_LOG_PREFIX) => change_sequence(&key),
+        _ => first,
+    };
+    Ok(Some((first, last)))
+}
+
+/// Stage the entry `sequence` of the change log, and remove the entry `sequence - keep`
+/// so the latest `keep` entries are kept. `keep` 0 keeps all entries.
+///
+/// The caller must append entries in order of their sequence numbers.
+pub fn append_change_with_batch(sequence: u64, value: &[u8], keep: u64, batch: &mut Batch) {
+    batch.put(change_key(sequence), value);
+    if keep > 0 && sequence > keep {
+        batch.delete(change_key(sequence - keep));
+    }
+}
+
+/// Return at most `limit` entries of the change log after `sequence`, in order
+pub fn changes_since(db: &Arc<DB>, sequence: u64, limit: usize) -> Result<Vec<(u64, Vec<u8>)>> {
+    let mut changes = Vec::new();
+    if sequence == u64::MAX {
+        return Ok(changes);
+    }
+    let start = change_key(sequence + 1);
+    for (key, value) in db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+        if !key.starts_with(CHANGE_LOG_PREFIX) || changes.len() >= limit {
+            break;
+        }
+        changes.push((change_sequence(&key), value.into_vec()));
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod test_changelog {
+    use rocksdb::{Options, DB};
+
+    use std::sync::Arc;
+
+    use crate::batch::Batch;
+
+    use super::{append_change_with_batch, change_log_range, changes_since};
+
+    #[test]
+    fn test_changelog() {
+        let path = "test-changelog-db1";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            assert_eq!(change_log_range(&db).unwrap(), None);
+
+            for sequence in 1..=5u64 {
+                let mut batch = Batch::new(db.clone());
+                append_change_with_batch(sequence, &[sequence as u8], 3, &mut batch);
+                batch.commit().unwrap();
+            }
+
+            // Only the latest 3 entries are kept
+            assert_eq!(change_log_range(&db).unwrap(), Some((3, 5)));
+            let changes = changes_since(&db, 0, 10).unwrap();
+            assert_eq!(changes, vec![(3, vec![3]), (4, vec![4]), (5, vec![5])]);
+            assert_eq!(changes_since(&db, 3, 1).unwrap(), vec![(4, vec![4])]);
+            assert!(changes_since(&db, 5, 10).unwrap().is_empty());
+        }
+
+        let opts = Options::default();
+        DB::destroy(&opts, path).unwrap();
+    }
+}
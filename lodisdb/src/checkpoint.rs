@@ -0,0 +1,113 @@
+use std::{fs, path::Path, time::SystemTime};
+
+use rocksdb::{checkpoint::Checkpoint, DB};
+
+use crate::error::{DBError, Result};
+
+const CHECKPOINT_PREFIX: &str = "checkpoint-";
+
+/// Create a checkpoint of the live `db` in the directory `path`, then remove old checkpoints
+/// and keep the latest `keep` of them. `keep` 0 keeps all checkpoints.
+///
+/// A checkpoint is an openable db directory whose sst files are hard links to the files of `db`,
+/// so it is cheap if `path` is on the same filesystem as `db`.
+///
+/// Checkpoints are named `checkpoint-{unix timestamp in milliseconds}`. Return the name of the
+/// new checkpoint.
+pub fn create_checkpoint<P: AsRef<Path>>(db: &DB, path: P, keep: usize) -> Result<String> {
+    let path = path.as_ref();
+    fs::create_dir_all(path).map_err(|err| DBError::CheckpointError(err.to_string()))?;
+
+    let mut millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    // Names must be increasing, even if the clock goes back
+    if let Some(last) = list_checkpoints(path)?.last() {
+        let last_millis: u64 = last[CHECKPOINT_PREFIX.len()..].parse().unwrap_or(0);
+        if millis <= last_millis {
+            millis = last_millis + 1;
+        }
+    }
+    let name = format!("{}{:020}", CHECKPOINT_PREFIX, millis);
+
+    Checkpoint::new(db)?.create_checkpoint(path.join(&name))?;
+
+    if keep > 0 {
+        let names = list_checkpoints(path)?;
+        if names.len() > keep {
+            for old in &names[..names.len() - keep] {
+                fs::remove_dir_all(path.join(old))
+                    .map_err(|err| DBError::CheckpointError(err.to_string()))?;
+            }
+        }
+    }
+    Ok(name)
+}
+
+/// Return the names of all checkpoints in the directory `path`, from old to new.
+pub fn list_checkpoints<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path).map_err(|err| DBError::CheckpointError(err.to_string()))? {
+        let entry = entry.map_err(|err| DBError::CheckpointError(err.to_string()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(CHECKPOINT_PREFIX) && entry.path().is_dir() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test_checkpoint {
+    use rocksdb::{Options, DB};
+
+    use std::sync::Arc;
+
+    use crate::map::Map;
+
+    use super::{create_checkpoint, list_checkpoints};
+
+    #[test]
+    fn test_checkpoint() {
+        let path = "test-checkpoint-db1";
+        let checkpoint_path = "test-checkpoint-db1-checkpoints";
+        let names = {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let map = Map::new("abc".to_string(), db.clone());
+
+            map.set("a", "1").unwrap();
+            let first = create_checkpoint(&db, checkpoint_path, 2).unwrap();
+            map.set("a", "2").unwrap();
+            let second = create_checkpoint(&db, checkpoint_path, 2).unwrap();
+            assert!(second > first);
+            assert_eq!(
+                list_checkpoints(checkpoint_path).unwrap(),
+                vec![first.clone(), second.clone()]
+            );
+
+            // Only the latest 2 checkpoints are kept
+            let third = create_checkpoint(&db, checkpoint_path, 2).unwrap();
+            let names = list_checkpoints(checkpoint_path).unwrap();
+            assert_eq!(names, vec![second, third]);
+            names
+        };
+
+        {
+            let db =
+                Arc::new(DB::open_default(format!("{}/{}", checkpoint_path, &names[0])).unwrap());
+            let map = Map::new("abc".to_string(), db.clone());
+            assert_eq!(&*map.get("a").unwrap().unwrap(), b"2");
+        }
+
+        let opts = Options::default();
+        DB::destroy(&opts, path).unwrap();
+        std::fs::remove_dir_all(checkpoint_path).unwrap();
+    }
+}
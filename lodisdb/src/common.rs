@@ -114,4 +114,16 @@ impl DataType {
             String => [5],
         }
     }
+
+    pub fn from_flag(flag: u8) -> Option<DataType> {
+        use DataType::*;
+        match flag {
+            1 => Some(List),
+            2 => Some(Map),
+            3 => Some(ArrayMap),
+            4 => Some(Set),
+            5 => Some(String),
+            _ => None,
+        }
+    }
 }
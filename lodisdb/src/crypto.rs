@@ -8,3 +8,15 @@ pub fn siphash<H: Hash>(key: &H) -> u64 {
     key.hash(&mut hasher);
     hasher.finish()
 }
+
+/// The hash of a name in a namespace, which is the hash of the name in the default namespace ""
+///
+/// A namespace and a name are hashed as a tuple, which ends each string with a 0xFF byte. It
+/// is not in any utf8 string, so no name in the default namespace has the same hash input.
+pub fn name_hash(namespace: &str, name: &str) -> u64 {
+    if namespace.is_empty() {
+        siphash(&name)
+    } else {
+        siphash(&(namespace, name))
+    }
+}
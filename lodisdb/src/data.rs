@@ -1,8 +1,8 @@
 use std::sync::Arc;
 
-use rocksdb::{Direction, IteratorMode, ReadOptions, WriteBatch, DB};
+use rocksdb::DB;
 
-use crate::{crypto::siphash, error::Result, utils::u64_to_u8x8};
+use crate::{batch::Batch, crypto::siphash, error::Result};
 
 pub trait LodisData {
     fn db(&self) -> &Arc<DB>;
@@ -16,68 +16,73 @@ pub trait LodisData {
     }
 
     fn remove(&self) -> Result<()> {
-        let db = self.db();
-
-        let mut readopts = ReadOptions::default();
-        readopts.set_prefix_same_as_start(true);
-
-        let mut iter = db.iterator_opt(
-            IteratorMode::From(&self.prefix(), Direction::Forward),
-            readopts,
-        );
-
-        let item = iter.next();
-
-        // This data does not exist
-        if item.is_none() {
-            return Ok(());
-        }
-
-        let (start_key, _) = item.unwrap();
-
-        // This start_key does not belong to the data
-        if &&start_key[0..9] != &self.prefix() {
-            return Ok(());
-        }
-
-        let next_key_hash = u64_to_u8x8(siphash(&self.name()) + 1);
-        let mut prefix: [u8; 9] = [0; 9];
-        // type flag
-        prefix[0] = self.prefix()[0];
-        // hash
-        prefix[1..9].clone_from_slice(&next_key_hash[..]);
-
-        let mut readopts = ReadOptions::default();
-        readopts.set_prefix_same_as_start(true);
-
-        let mut iter = db.iterator_opt(
-            IteratorMode::From(&prefix[..], Direction::Reverse),
-            readopts,
-        );
-
-        let item = iter.next();
+        let mut batch = Batch::new(self.db().clone());
+        self.remove_with_batch(&mut batch)?;
+        batch.commit()
+    }
 
-        // This end key does not exist, so we delete the start key
-        if item.is_none() {
-            db.delete(&start_key)?;
-            return Ok(());
-        }
+    fn remove_with_batch(&self, batch: &mut Batch) -> Result<()> {
+        batch.delete_prefix(self.prefix())
+    }
 
-        let (end_key, _) = item.unwrap();
+    /// The prefixes of all records of the data, including metadata records such as `@L`
+    fn prefixes(&self) -> Vec<&[u8]> {
+        vec![self.prefix()]
+    }
 
-        // This end key does not belong to the data. We delete the start key
-        if &&end_key[0..9] != &self.prefix() {
-            db.delete(&start_key)?;
-            return Ok(());
+    /// Copy at most `limit` records of the data to `target`, starting after the record
+    /// `after`, or from the first record without it.
+    ///
+    /// Return the last copied record, which continues the copy at the next call, or None if
+    /// all records are copied. Records of `target` which are not copied over are kept, so
+    /// `target` should be removed before the copy.
+    fn copy_to_with_batch(
+        &self,
+        target: &Self,
+        after: Option<&[u8]>,
+        limit: usize,
+        batch: &mut Batch,
+    ) -> Result<Option<Vec<u8>>>
+    where
+        Self: Sized,
+    {
+        let sources = self.prefixes();
+        let targets = target.prefixes();
+        let current = after
+            .and_then(|after| sources.iter().position(|prefix| after.starts_with(prefix)))
+            .unwrap_or(0);
+
+        for i in current..sources.len() {
+            let after = match after {
+                Some(after) if i == current => after,
+                _ => &[],
+            };
+            let pairs = batch.prefix_pairs_after(sources[i], after, limit)?;
+            let last = match pairs.last() {
+                Some((key, _)) => key.to_vec(),
+                None => continue,
+            };
+            for (key, value) in pairs {
+                batch.put([targets[i], &key[sources[i].len()..]].concat(), value);
+            }
+            return Ok(Some(last));
         }
+        Ok(None)
+    }
 
-        // If start_key == end_key, we only need to delete end key
-        let mut batch = WriteBatch::default();
-        if &&start_key != &&end_key {
-            batch.delete_range(&start_key, &end_key);
+    /// Remove at most `limit` records of the data, return the number of removed records,
+    /// which is 0 if the data is removed
+    fn remove_part_with_batch(&self, limit: usize, batch: &mut Batch) -> Result<usize> {
+        let mut removed = 0;
+        for prefix in self.prefixes() {
+            for (key, _) in batch.prefix_pairs_after(prefix, &[], limit - removed)? {
+                batch.delete(key);
+                removed += 1;
+            }
+            if removed == limit {
+                break;
+            }
         }
-        batch.delete(&end_key);
-        db.write(batch)?;
-        Ok(())
+        Ok(removed)
     }
 }
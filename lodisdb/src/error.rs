@@ -17,6 +17,15 @@ pub enum DBError {
 
     #[error("The Value is numerical")]
     IsNotNumeric,
+
+    #[error("BackupError: {0}")]
+    BackupError(String),
+
+    #[error("CheckpointError: {0}")]
+    CheckpointError(String),
+
+    #[error("Write-ahead log is truncated after sequence number {0}")]
+    WalTruncated(u64),
 }
 
 impl From<RocksDBError> for DBError {
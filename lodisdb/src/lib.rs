@@ -2,6 +2,8 @@ use std::path::Path;
 
 pub use rocksdb::DB;
 
+use rocksdb::Options;
+
 mod crypto;
 mod utils;
 
@@ -9,23 +11,49 @@ pub mod common;
 pub mod error;
 
 mod arraymap;
+mod backup;
+mod batch;
+mod changelog;
+mod checkpoint;
 mod data;
 mod list;
 mod map;
+mod replication;
+mod stats;
 // mod store;
 
 pub use arraymap::ArrayMap;
+pub use backup::{create_backup, list_backups, restore_backup, BackupInfo};
+pub use batch::Batch;
+pub use changelog::{append_change_with_batch, change_log_range, changes_since};
+pub use checkpoint::{create_checkpoint, list_checkpoints};
 pub use crypto::siphash;
 pub use data::LodisData;
 pub use error::DBError;
 pub use list::List;
 pub use map::Map;
-pub use utils::{u32_to_u8x4, u64_to_u8x8, u8_to_u8x1, u8x4_to_u32, u8x8_to_i64};
+pub use replication::{
+    apply_updates, clear_replica, finish_resync, replica_sequence, updates_since, write_pairs,
+    ReplicaSnapshot, WalUpdate, REPLICA_SEQUENCE_KEY,
+};
+pub use stats::{db_properties, DB_PROPERTIES};
+pub use utils::{
+    i64_to_u8x8, u32_to_u8x4, u64_to_u8x8, u8_to_u8x1, u8x4_to_u32, u8x8_to_i64, u8x8_to_u64,
+};
 
 pub fn make_db<P: AsRef<Path>>(path: P) -> DB {
     DB::open_default(path).unwrap()
 }
 
+/// Make a db which keeps its write-ahead log files for `wal_ttl` seconds after they are
+/// obsolete, so replicas can tail them. `wal_ttl` 0 removes them as soon as possible.
+pub fn make_db_with_wal_ttl<P: AsRef<Path>>(path: P, wal_ttl: u64) -> DB {
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    opts.set_wal_ttl_seconds(wal_ttl);
+    DB::open(&opts, path).unwrap()
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
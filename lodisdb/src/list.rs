@@ -1,12 +1,13 @@
 use std::sync::Arc;
 
-use rocksdb::{WriteBatch, DB};
+use rocksdb::DB;
 
 use rand::{self, Rng};
 
 use crate::{
+    batch::Batch,
     common::{DBValue, DataType, Direction, MAX_U32},
-    crypto::siphash,
+    crypto::name_hash,
     data::LodisData,
     error::{DBError, Result},
     utils::{u32_to_u8x4, u64_to_u8x8, u8x4_to_u32},
@@ -50,9 +51,14 @@ impl LodisData for List {
 
 impl List {
     pub fn new(name: String, db: Arc<DB>) -> List {
+        List::new_in("", name, db)
+    }
+
+    /// The List named `name` in the namespace, which does not share data with the other namespaces
+    pub fn new_in(namespace: &str, name: String, db: Arc<DB>) -> List {
         let mut prefix: [u8; 9] = [0; 9];
         prefix[0..1].clone_from_slice(&TYPE.flag()[..]);
-        prefix[1..9].clone_from_slice(&u64_to_u8x8(siphash(&name)));
+        prefix[1..9].clone_from_slice(&u64_to_u8x8(name_hash(namespace, &name)));
         List { name, prefix, db }
     }
 
@@ -64,12 +70,20 @@ impl List {
         &self.prefix[..]
     }
 
+    fn batch(&self) -> Batch {
+        Batch::new(self.db.clone())
+    }
+
     pub fn length(&self) -> Result<u32> {
+        Batch::read(&self.db, |batch| self.length_with_batch(batch))
+    }
+
+    pub fn length_with_batch(&self, batch: &Batch) -> Result<u32> {
         let mut dbkey: [u8; 11] = [0; 11];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..11].clone_from_slice(b"@L");
 
-        let raw_value = self.db.get(&dbkey)?;
+        let raw_value = batch.get(&dbkey)?;
         if let Some(raw_v) = raw_value {
             let mut v: [u8; 4] = [0; 4];
             v.clone_from_slice(&raw_v);
@@ -79,12 +93,16 @@ impl List {
         }
     }
 
-    fn head(&self) -> Result<u32> {
+    pub fn head(&self) -> Result<u32> {
+        Batch::read(&self.db, |batch| self.head_with_batch(batch))
+    }
+
+    pub fn head_with_batch(&self, batch: &Batch) -> Result<u32> {
         let mut dbkey: [u8; 11] = [0; 11];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..11].clone_from_slice(b"@H");
 
-        let raw_value = self.db.get(&dbkey)?;
+        let raw_value = batch.get(&dbkey)?;
         if let Some(raw_v) = raw_value {
             let mut v: [u8; 4] = [0; 4];
             v.clone_from_slice(&raw_v);
@@ -94,12 +112,16 @@ impl List {
         }
     }
 
-    fn tail(&self) -> Result<u32> {
+    pub fn tail(&self) -> Result<u32> {
+        Batch::read(&self.db, |batch| self.tail_with_batch(batch))
+    }
+
+    pub fn tail_with_batch(&self, batch: &Batch) -> Result<u32> {
         let mut dbkey: [u8; 11] = [0; 11];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..11].clone_from_slice(b"@T");
 
-        let raw_value = self.db.get(&dbkey)?;
+        let raw_value = batch.get(&dbkey)?;
         if let Some(raw_v) = raw_value {
             let mut v: [u8; 4] = [0; 4];
             v.clone_from_slice(&raw_v);
@@ -109,7 +131,7 @@ impl List {
         }
     }
 
-    fn set_head(&self, value: u32, batch: &mut WriteBatch) -> Result<()> {
+    fn set_head(&self, value: u32, batch: &mut Batch) -> Result<()> {
         let mut dbkey: [u8; 11] = [0; 11];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..11].clone_from_slice(b"@H");
@@ -119,7 +141,7 @@ impl List {
         Ok(())
     }
 
-    fn set_tail(&self, value: u32, batch: &mut WriteBatch) -> Result<()> {
+    fn set_tail(&self, value: u32, batch: &mut Batch) -> Result<()> {
         let mut dbkey: [u8; 11] = [0; 11];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..11].clone_from_slice(b"@T");
@@ -129,8 +151,8 @@ impl List {
         Ok(())
     }
 
-    fn incr_length(&self, incr: i64, batch: &mut WriteBatch) -> Result<()> {
-        let length = self.length()?;
+    fn incr_length(&self, incr: i64, batch: &mut Batch) -> Result<()> {
+        let length = self.length_with_batch(batch)?;
         if length == 0 && incr < 0 {
             return Ok(());
         }
@@ -150,9 +172,9 @@ impl List {
     }
 
     // Get the absolute index at db
-    fn abs_index(&self, index: i64) -> Result<u32> {
+    fn abs_index(&self, index: i64, batch: &Batch) -> Result<u32> {
         if index >= 0 {
-            let mut head = self.head()?;
+            let mut head = self.head_with_batch(batch)?;
             if head == MAX_U32 {
                 head = 0;
             } else {
@@ -160,7 +182,7 @@ impl List {
             }
             Ok(((head as i64 + index) % (MAX_U32 as i64 + 1)) as u32)
         } else {
-            let mut tail = self.tail()?;
+            let mut tail = self.tail_with_batch(batch)?;
             if tail == 0 {
                 tail = MAX_U32;
             } else {
@@ -171,8 +193,8 @@ impl List {
     }
 
     // Return a random relative index
-    fn random_index(&self) -> Result<i64> {
-        let mut length = self.length().unwrap() as f64;
+    fn random_index(&self, batch: &Batch) -> Result<i64> {
+        let mut length = self.length_with_batch(batch)? as f64;
         if length != 0f64 {
             length -= 1f64; // index starts from 0
         }
@@ -187,32 +209,43 @@ impl List {
     /// index can be positive or negative
     /// index is an i64 which can be from -infinit to +infinit
     pub fn index(&self, index: i64) -> Result<Option<DBValue>> {
-        let abs_index = self.abs_index(index)?;
-        let mut dbkey: [u8; 14] = [0; 14];
-        dbkey[0..9].clone_from_slice(&self.prefix);
-        dbkey[9..10].clone_from_slice(b"$");
-        dbkey[10..14].clone_from_slice(&u32_to_u8x4(abs_index));
-
-        let value = self.db.get(&dbkey)?;
+        Batch::read(&self.db, |batch| self.index_with_batch(index, batch))
+    }
 
-        Ok(value.map(|v| DBValue::Direct(v)))
+    pub fn index_with_batch(&self, index: i64, batch: &Batch) -> Result<Option<DBValue>> {
+        let abs_index = self.abs_index(index, batch)?;
+        self.index_with_abs_with_batch(abs_index, batch)
     }
 
     pub fn index_with_abs(&self, abs_index: u32) -> Result<Option<DBValue>> {
+        Batch::read(&self.db, |batch| {
+            self.index_with_abs_with_batch(abs_index, batch)
+        })
+    }
+
+    pub fn index_with_abs_with_batch(
+        &self,
+        abs_index: u32,
+        batch: &Batch,
+    ) -> Result<Option<DBValue>> {
         let mut dbkey: [u8; 14] = [0; 14];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..10].clone_from_slice(b"$");
         dbkey[10..14].clone_from_slice(&u32_to_u8x4(abs_index));
 
-        let value = self.db.get(&dbkey)?;
+        let value = batch.get(&dbkey)?;
 
         Ok(value.map(|v| DBValue::Direct(v)))
     }
 
     // Randomly returning a item
     pub fn random(&self) -> Result<Option<DBValue>> {
-        let rand_index = self.random_index()?;
-        self.index(rand_index)
+        Batch::read(&self.db, |batch| self.random_with_batch(batch))
+    }
+
+    pub fn random_with_batch(&self, batch: &Batch) -> Result<Option<DBValue>> {
+        let rand_index = self.random_index(batch)?;
+        self.index_with_batch(rand_index, batch)
     }
 
     /// Get a range of element
@@ -230,28 +263,40 @@ impl List {
     ///        |             |
     ///        end           start
     pub fn range(&self, start: u32, end: u32, direction: Direction) -> Result<Vec<DBValue>> {
+        Batch::read(&self.db, |batch| {
+            self.range_with_batch(start, end, direction, batch)
+        })
+    }
+
+    pub fn range_with_batch(
+        &self,
+        start: u32,
+        end: u32,
+        direction: Direction,
+        batch: &Batch,
+    ) -> Result<Vec<DBValue>> {
         let mut range = Vec::new();
         let mut dbkey: [u8; 14] = [0; 14];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..10].clone_from_slice(b"$");
         match direction {
             Direction::Forward => {
-                let head = self.head()? as u64;
+                let head = self.head_with_batch(batch)? as u64;
                 for i in start..end {
                     let index = (1 + i as u64 + head) as u32;
                     dbkey[10..14].clone_from_slice(&u32_to_u8x4(index));
-                    let value = self.db.get(&dbkey)?;
+                    let value = batch.get(&dbkey)?;
                     if let Some(value) = value {
                         range.push(DBValue::Direct(value));
                     }
                 }
             }
             Direction::Reverse => {
-                let tail = self.tail()? as i64;
+                let tail = self.tail_with_batch(batch)? as i64;
                 for i in start..end {
                     let index = (tail - i as i64 - 1) as u32;
                     dbkey[10..14].clone_from_slice(&u32_to_u8x4(index));
-                    let value = self.db.get(&dbkey)?;
+                    let value = batch.get(&dbkey)?;
                     if let Some(value) = value {
                         range.push(DBValue::Direct(value));
                     }
@@ -262,8 +307,12 @@ impl List {
     }
 
     pub fn all(&self) -> Result<Vec<DBValue>> {
-        let head = self.head()? as u64 + 1;
-        let length = self.length()?;
+        Batch::read(&self.db, |batch| self.all_with_batch(batch))
+    }
+
+    pub fn all_with_batch(&self, batch: &Batch) -> Result<Vec<DBValue>> {
+        let head = self.head_with_batch(batch)? as u64 + 1;
+        let length = self.length_with_batch(batch)?;
         let mut all = Vec::new();
         let mut dbkey: [u8; 14] = [0; 14];
         dbkey[0..9].clone_from_slice(&self.prefix);
@@ -271,7 +320,7 @@ impl List {
         for i in 0..length {
             let index = (i as u64 + head) as u32;
             dbkey[10..14].clone_from_slice(&u32_to_u8x4(index));
-            let value = self.db.get(&dbkey)?;
+            let value = batch.get(&dbkey)?;
             if let Some(value) = value {
                 all.push(DBValue::Direct(value));
             }
@@ -284,14 +333,23 @@ impl List {
     where
         V: AsRef<[u8]>,
     {
-        let mut index = self.tail()?;
+        let mut batch = self.batch();
+        let indexes = self.push_with_batch(values, &mut batch)?;
+        batch.commit()?;
+        Ok(indexes)
+    }
+
+    pub fn push_with_batch<V>(&self, values: &[V], batch: &mut Batch) -> Result<Vec<u32>>
+    where
+        V: AsRef<[u8]>,
+    {
+        let mut index = self.tail_with_batch(batch)?;
 
         let mut dbkey: [u8; 14] = [0; 14];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..10].clone_from_slice(b"$");
 
         let mut indexes = Vec::new();
-        let mut batch = WriteBatch::default();
         for value in values {
             indexes.push(index);
             dbkey[10..14].clone_from_slice(&u32_to_u8x4(index));
@@ -303,10 +361,8 @@ impl List {
             }
         }
 
-        self.set_tail(index, &mut batch)?;
-        self.incr_length(values.len() as i64, &mut batch)?;
-
-        self.db.write(batch)?;
+        self.set_tail(index, batch)?;
+        self.incr_length(values.len() as i64, batch)?;
 
         Ok(indexes)
     }
@@ -315,14 +371,23 @@ impl List {
     where
         V: AsRef<[u8]>,
     {
-        let mut index = self.head()?;
+        let mut batch = self.batch();
+        let indexes = self.push_left_with_batch(values, &mut batch)?;
+        batch.commit()?;
+        Ok(indexes)
+    }
+
+    pub fn push_left_with_batch<V>(&self, values: &[V], batch: &mut Batch) -> Result<Vec<u32>>
+    where
+        V: AsRef<[u8]>,
+    {
+        let mut index = self.head_with_batch(batch)?;
 
         let mut dbkey: [u8; 14] = [0; 14];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..10].clone_from_slice(b"$");
 
         let mut indexes = Vec::new();
-        let mut batch = WriteBatch::default();
         for value in values {
             indexes.push(index);
             dbkey[10..14].clone_from_slice(&u32_to_u8x4(index));
@@ -334,10 +399,8 @@ impl List {
             };
         }
 
-        self.set_head(index, &mut batch)?;
-        self.incr_length(values.len() as i64, &mut batch)?;
-
-        self.db.write(batch)?;
+        self.set_head(index, batch)?;
+        self.incr_length(values.len() as i64, batch)?;
 
         Ok(indexes)
     }
@@ -346,8 +409,22 @@ impl List {
     where
         V: AsRef<[u8]>,
     {
-        let head = self.head()?;
-        let tail = self.tail()?;
+        let mut batch = self.batch();
+        self.set_by_absindex_with_batch(abs_index, value, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn set_by_absindex_with_batch<V>(
+        &self,
+        abs_index: u32,
+        value: V,
+        batch: &mut Batch,
+    ) -> Result<()>
+    where
+        V: AsRef<[u8]>,
+    {
+        let head = self.head_with_batch(batch)?;
+        let tail = self.tail_with_batch(batch)?;
         //  -----+++++++++++-----
         //      |           |
         //      head        tail
@@ -369,18 +446,25 @@ impl List {
         dbkey[9..10].clone_from_slice(b"$");
         dbkey[10..14].clone_from_slice(&u32_to_u8x4(abs_index));
 
-        self.db.put(&dbkey, value.as_ref())?;
+        batch.put(&dbkey, value.as_ref());
 
         Ok(())
     }
 
     pub fn pop(&self) -> Result<Option<DBValue>> {
-        let length = self.length()?;
+        let mut batch = self.batch();
+        let value = self.pop_with_batch(&mut batch)?;
+        batch.commit()?;
+        Ok(value)
+    }
+
+    pub fn pop_with_batch(&self, batch: &mut Batch) -> Result<Option<DBValue>> {
+        let length = self.length_with_batch(batch)?;
         if length == 0 {
             return Ok(None);
         }
 
-        let tail = self.tail()?;
+        let tail = self.tail_with_batch(batch)?;
         let index = if tail == 0 { MAX_U32 } else { tail - 1 };
 
         let mut dbkey: [u8; 14] = [0; 14];
@@ -388,26 +472,29 @@ impl List {
         dbkey[9..10].clone_from_slice(b"$");
         dbkey[10..14].clone_from_slice(&u32_to_u8x4(index));
 
-        let value = self.db.get(&dbkey)?;
-
-        let mut batch = WriteBatch::default();
+        let value = batch.get(&dbkey)?;
 
         batch.delete(&dbkey);
-        self.set_tail(index, &mut batch)?;
-        self.incr_length(-1, &mut batch)?;
-
-        self.db.write(batch)?;
+        self.set_tail(index, batch)?;
+        self.incr_length(-1, batch)?;
 
         return Ok(value.map(|v| DBValue::Direct(v)));
     }
 
     pub fn pop_left(&self) -> Result<Option<DBValue>> {
-        let length = self.length()?;
+        let mut batch = self.batch();
+        let value = self.pop_left_with_batch(&mut batch)?;
+        batch.commit()?;
+        Ok(value)
+    }
+
+    pub fn pop_left_with_batch(&self, batch: &mut Batch) -> Result<Option<DBValue>> {
+        let length = self.length_with_batch(batch)?;
         if length == 0 {
             return Ok(None);
         }
 
-        let head = self.head()?;
+        let head = self.head_with_batch(batch)?;
         let index = if head == MAX_U32 { 0 } else { head + 1 };
 
         let mut dbkey: [u8; 14] = [0; 14];
@@ -415,32 +502,41 @@ impl List {
         dbkey[9..10].clone_from_slice(b"$");
         dbkey[10..14].clone_from_slice(&u32_to_u8x4(index));
 
-        let value = self.db.get(&dbkey)?;
-
-        let mut batch = WriteBatch::default();
+        let value = batch.get(&dbkey)?;
 
         batch.delete(&dbkey);
-        self.set_head(index, &mut batch)?;
-        self.incr_length(-1, &mut batch)?;
-
-        self.db.write(batch)?;
+        self.set_head(index, batch)?;
+        self.incr_length(-1, batch)?;
 
         return Ok(value.map(|v| DBValue::Direct(v)));
     }
 
     // Pop out a random item
     pub fn pop_random(&self) -> Result<Option<DBValue>> {
-        let rand_index = self.random_index()?;
-        let value = self.index(rand_index)?;
+        let mut batch = self.batch();
+        let value = self.pop_random_with_batch(&mut batch)?;
+        batch.commit()?;
+        Ok(value)
+    }
+
+    pub fn pop_random_with_batch(&self, batch: &mut Batch) -> Result<Option<DBValue>> {
+        let rand_index = self.random_index(batch)?;
+        let value = self.index_with_batch(rand_index, batch)?;
         if value.is_some() {
-            self.delete(rand_index)?;
+            self.delete_with_batch(rand_index, batch)?;
         }
         Ok(value)
     }
 
     pub fn delete(&self, index: i64) -> Result<()> {
-        let abs_index = self.abs_index(index)?;
-        self.delete_with_abs_index(abs_index)
+        let mut batch = self.batch();
+        self.delete_with_batch(index, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn delete_with_batch(&self, index: i64, batch: &mut Batch) -> Result<()> {
+        let abs_index = self.abs_index(index, batch)?;
+        self.delete_with_abs_index_with_batch(abs_index, batch)
     }
 
     /// Delete an element by its index
@@ -451,52 +547,52 @@ impl List {
     /// We ignore the gap by moving the head element to the index.
     /// In the way, the order of list will be changed.
     pub fn delete_with_abs_index(&self, abs_index: u32) -> Result<()> {
+        let mut batch = self.batch();
+        self.delete_with_abs_index_with_batch(abs_index, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn delete_with_abs_index_with_batch(
+        &self,
+        abs_index: u32,
+        batch: &mut Batch,
+    ) -> Result<()> {
         let mut dbkey: [u8; 14] = [0; 14];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..10].clone_from_slice(b"$");
         dbkey[10..14].clone_from_slice(&u32_to_u8x4(abs_index));
 
-        let value = self.db.get(&dbkey)?;
+        let value = batch.get(&dbkey)?;
         if value.is_none() {
             return Ok(());
         }
 
-        let head = self.head()?;
+        let head = self.head_with_batch(batch)?;
         let first_index = if head == MAX_U32 { 0 } else { head + 1 };
-        let tail = self.tail()?;
+        let tail = self.tail_with_batch(batch)?;
         let last_index = if tail == 0 { MAX_U32 } else { tail - 1 };
 
         if abs_index == first_index {
-            let mut batch = WriteBatch::default();
             batch.delete(&dbkey);
-            self.incr_length(-1, &mut batch)?;
-            self.set_head(abs_index, &mut batch)?;
-
-            self.db.write(batch)?;
+            self.incr_length(-1, batch)?;
+            self.set_head(abs_index, batch)?;
         } else if abs_index == last_index {
-            let mut batch = WriteBatch::default();
             batch.delete(&dbkey);
-            self.incr_length(-1, &mut batch)?;
-            self.set_tail(abs_index, &mut batch)?;
-
-            self.db.write(batch)?;
+            self.incr_length(-1, batch)?;
+            self.set_tail(abs_index, batch)?;
         } else {
             dbkey[10..14].clone_from_slice(&u32_to_u8x4(first_index));
-            let first_value = self.db.get(&dbkey)?;
-
-            let mut batch = WriteBatch::default();
+            let first_value = batch.get(&dbkey)?;
 
             // delete first element
             batch.delete(&dbkey);
-            self.incr_length(-1, &mut batch)?;
+            self.incr_length(-1, batch)?;
             // set head to first_index
-            self.set_head(first_index, &mut batch)?;
+            self.set_head(first_index, batch)?;
 
             // set first element to abs_index
             dbkey[10..14].clone_from_slice(&u32_to_u8x4(abs_index));
             batch.put(&dbkey, &*first_value.unwrap());
-
-            self.db.write(batch)?;
         }
 
         Ok(())
@@ -1,10 +1,11 @@
 use std::sync::Arc;
 
-use rocksdb::{WriteBatch, DB};
+use rocksdb::DB;
 
 use crate::{
+    batch::Batch,
     common::{DBValue, DataType},
-    crypto::siphash,
+    crypto::name_hash,
     data::LodisData,
     error::{DBError, Result},
     utils::{u32_to_u8x4, u64_to_u8x8, u8x4_to_u32},
@@ -34,18 +35,31 @@ impl LodisData for Map {
 
 impl Map {
     pub fn new(name: String, db: Arc<DB>) -> Map {
+        Map::new_in("", name, db)
+    }
+
+    /// The Map named `name` in the namespace, which does not share data with the other namespaces
+    pub fn new_in(namespace: &str, name: String, db: Arc<DB>) -> Map {
         let mut prefix: [u8; 9] = [0; 9];
         prefix[0..1].clone_from_slice(&TYPE.flag()[..]);
-        prefix[1..9].clone_from_slice(&u64_to_u8x8(siphash(&name)));
+        prefix[1..9].clone_from_slice(&u64_to_u8x8(name_hash(namespace, &name)));
         Map { name, prefix, db }
     }
 
+    fn batch(&self) -> Batch {
+        Batch::new(self.db.clone())
+    }
+
     pub fn length(&self) -> Result<u32> {
+        Batch::read(&self.db, |batch| self.length_with_batch(batch))
+    }
+
+    pub fn length_with_batch(&self, batch: &Batch) -> Result<u32> {
         let mut dbkey: [u8; 11] = [0; 11];
         dbkey[0..9].clone_from_slice(&self.prefix);
         dbkey[9..11].clone_from_slice(b"@L");
 
-        let raw_value = self.db.get(&dbkey)?;
+        let raw_value = batch.get(&dbkey)?;
         if let Some(raw_v) = raw_value {
             let mut v: [u8; 4] = [0; 4];
             v.clone_from_slice(&raw_v);
@@ -55,8 +69,8 @@ impl Map {
         }
     }
 
-    fn incr_length(&self, incr: i32, batch: &mut WriteBatch) -> Result<()> {
-        let length = self.length()?;
+    fn incr_length(&self, incr: i32, batch: &mut Batch) -> Result<()> {
+        let length = self.length_with_batch(batch)?;
         if length == 0 && incr < 0 {
             return Ok(());
         }
@@ -73,6 +87,13 @@ impl Map {
     }
 
     pub fn exists<K>(&self, key: K) -> Result<bool>
+    where
+        K: AsRef<[u8]>,
+    {
+        Batch::read(&self.db, |batch| self.exists_with_batch(key, batch))
+    }
+
+    pub fn exists_with_batch<K>(&self, key: K, batch: &Batch) -> Result<bool>
     where
         K: AsRef<[u8]>,
     {
@@ -81,7 +102,7 @@ impl Map {
         dbkey[9..10].clone_from_slice(b":");
         let dbkey = [&dbkey[..], key.as_ref()].concat();
 
-        if self.db.get(dbkey)?.is_some() {
+        if batch.get(dbkey)?.is_some() {
             Ok(true)
         } else {
             Ok(false)
@@ -89,6 +110,13 @@ impl Map {
     }
 
     pub fn get<K>(&self, key: K) -> Result<Option<DBValue>>
+    where
+        K: AsRef<[u8]>,
+    {
+        Batch::read(&self.db, |batch| self.get_with_batch(key, batch))
+    }
+
+    pub fn get_with_batch<K>(&self, key: K, batch: &Batch) -> Result<Option<DBValue>>
     where
         K: AsRef<[u8]>,
     {
@@ -97,12 +125,22 @@ impl Map {
         dbkey[9..10].clone_from_slice(b":");
         let dbkey = [&dbkey[..], key.as_ref()].concat();
 
-        let value = self.db.get(&dbkey)?;
+        let value = batch.get(&dbkey)?;
 
         Ok(value.map(|v| DBValue::Direct(v)))
     }
 
     pub fn set<K, V>(&self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.set_with_batch(key, value, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn set_with_batch<K, V>(&self, key: K, value: V, batch: &mut Batch) -> Result<()>
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>,
@@ -112,23 +150,29 @@ impl Map {
         dbkey[9..10].clone_from_slice(b":");
         let dbkey = [&dbkey[..], key.as_ref()].concat();
 
-        let pre_value = self.db.get(&dbkey)?;
-
-        let mut batch = WriteBatch::default();
+        let pre_value = batch.get(&dbkey)?;
 
         batch.put(&dbkey, &value);
 
         if pre_value.is_none() {
-            self.incr_length(1, &mut batch)?;
+            self.incr_length(1, batch)?;
         }
 
-        self.db.write(batch)?;
-
         Ok(())
     }
 
     // Set the value of a field, only if the field does not exist.
     pub fn setnx<K, V>(&self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.setnx_with_batch(key, value, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn setnx_with_batch<K, V>(&self, key: K, value: V, batch: &mut Batch) -> Result<()>
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>,
@@ -138,22 +182,71 @@ impl Map {
         dbkey[9..10].clone_from_slice(b":");
         let dbkey = [&dbkey[..], key.as_ref()].concat();
 
-        let pre_value = self.db.get(&dbkey)?;
+        let pre_value = batch.get(&dbkey)?;
         if pre_value.is_some() {
             return Ok(());
         }
 
-        let mut batch = WriteBatch::default();
-
         batch.put(&dbkey, &value);
-        self.incr_length(1, &mut batch)?;
-        self.db.write(batch)?;
+        self.incr_length(1, batch)?;
 
         Ok(())
     }
 
+    // Set the value of a field, only if its value equals `expected`,
+    // or the field does not exist if `expected` is None.
+    // Return whether the value is set.
+    pub fn cas<K, V>(&self, key: K, expected: Option<&[u8]>, value: V) -> Result<bool>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        let swapped = self.cas_with_batch(key, expected, value, &mut batch)?;
+        batch.commit()?;
+        Ok(swapped)
+    }
+
+    pub fn cas_with_batch<K, V>(
+        &self,
+        key: K,
+        expected: Option<&[u8]>,
+        value: V,
+        batch: &mut Batch,
+    ) -> Result<bool>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut dbkey: [u8; 10] = [0; 10];
+        dbkey[0..9].clone_from_slice(&self.prefix);
+        dbkey[9..10].clone_from_slice(b":");
+        let dbkey = [&dbkey[..], key.as_ref()].concat();
+
+        let pre_value = batch.get(&dbkey)?;
+        if pre_value.as_deref() != expected {
+            return Ok(false);
+        }
+
+        batch.put(&dbkey, &value);
+        if pre_value.is_none() {
+            self.incr_length(1, batch)?;
+        }
+
+        Ok(true)
+    }
+
     // Increase the value only if the value is an integer string
     pub fn increase<K>(&self, key: K, incr: i64) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.increase_with_batch(key, incr, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn increase_with_batch<K>(&self, key: K, incr: i64, batch: &mut Batch) -> Result<()>
     where
         K: AsRef<[u8]>,
     {
@@ -162,9 +255,7 @@ impl Map {
         dbkey[9..10].clone_from_slice(b":");
         let dbkey = [&dbkey[..], key.as_ref()].concat();
 
-        let mut batch = WriteBatch::default();
-
-        if let Some(value) = self.db.get(&dbkey)? {
+        if let Some(value) = batch.get(&dbkey)? {
             if let Ok(val_str) = std::str::from_utf8(&value) {
                 if let Ok(val_int) = val_str.parse::<i64>() {
                     let new_val = (val_int + incr).to_string();
@@ -178,15 +269,22 @@ impl Map {
         } else {
             let new_val = incr.to_string();
             batch.put(&dbkey, &new_val);
-            self.incr_length(1, &mut batch)?;
+            self.incr_length(1, batch)?;
         }
 
-        self.db.write(batch)?;
-
         Ok(())
     }
 
     pub fn delete<K>(&self, key: K) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.delete_with_batch(key, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn delete_with_batch<K>(&self, key: K, batch: &mut Batch) -> Result<()>
     where
         K: AsRef<[u8]>,
     {
@@ -195,32 +293,58 @@ impl Map {
         dbkey[9..10].clone_from_slice(b":");
         let dbkey = [&dbkey[..], key.as_ref()].concat();
 
-        let value = self.db.get(&dbkey)?;
+        let value = batch.get(&dbkey)?;
         if value.is_none() {
             return Ok(());
         }
 
-        let mut batch = WriteBatch::default();
-
         batch.delete(&dbkey);
-        self.incr_length(-1, &mut batch)?;
-
-        self.db.write(batch)?;
+        self.incr_length(-1, batch)?;
 
         Ok(())
     }
 
     // Get all field names in the map
     pub fn keys(&self) -> Result<Vec<DBValue>> {
+        Batch::read(&self.db, |batch| self.keys_with_batch(batch))
+    }
+
+    pub fn keys_with_batch(&self, batch: &Batch) -> Result<Vec<DBValue>> {
         let mut prefix: [u8; 10] = [0; 10];
         prefix[0..9].clone_from_slice(&self.prefix);
         prefix[9..10].clone_from_slice(b":");
 
-        let length = self.length()?;
+        let mut keys = Vec::new();
+        for (key, _) in batch.prefix_pairs(&prefix)? {
+            keys.push(DBValue::PrefixKeyB(key));
+        }
+        Ok(keys)
+    }
+
+    // Get at most `limit` field names which are after `after` in order, from the first
+    // field without `after`
+    pub fn keys_after(&self, after: Option<&[u8]>, limit: usize) -> Result<Vec<DBValue>> {
+        Batch::read(&self.db, |batch| {
+            self.keys_after_with_batch(after, limit, batch)
+        })
+    }
+
+    pub fn keys_after_with_batch(
+        &self,
+        after: Option<&[u8]>,
+        limit: usize,
+        batch: &Batch,
+    ) -> Result<Vec<DBValue>> {
+        let mut prefix: [u8; 10] = [0; 10];
+        prefix[0..9].clone_from_slice(&self.prefix);
+        prefix[9..10].clone_from_slice(b":");
+        let after = match after {
+            Some(after) => [&prefix[..], after].concat(),
+            None => Vec::new(),
+        };
 
         let mut keys = Vec::new();
-        let iter = self.db.prefix_iterator(&prefix);
-        for (key, _) in iter.take(length as usize) {
+        for (key, _) in batch.prefix_pairs_after(&prefix, &after, limit)? {
             keys.push(DBValue::PrefixKeyB(key));
         }
         Ok(keys)
@@ -228,15 +352,16 @@ impl Map {
 
     // Get all values in the map
     pub fn values(&self) -> Result<Vec<DBValue>> {
+        Batch::read(&self.db, |batch| self.values_with_batch(batch))
+    }
+
+    pub fn values_with_batch(&self, batch: &Batch) -> Result<Vec<DBValue>> {
         let mut prefix: [u8; 10] = [0; 10];
         prefix[0..9].clone_from_slice(&self.prefix);
         prefix[9..10].clone_from_slice(b":");
 
-        let length = self.length()?;
-
         let mut values = Vec::new();
-        let iter = self.db.prefix_iterator(&prefix);
-        for (_, value) in iter.take(length as usize) {
+        for (_, value) in batch.prefix_pairs(&prefix)? {
             values.push(DBValue::DirectB(value));
         }
         Ok(values)
@@ -244,15 +369,16 @@ impl Map {
 
     // Get all key, value pairs in the map
     pub fn all(&self) -> Result<Vec<(DBValue, DBValue)>> {
+        Batch::read(&self.db, |batch| self.all_with_batch(batch))
+    }
+
+    pub fn all_with_batch(&self, batch: &Batch) -> Result<Vec<(DBValue, DBValue)>> {
         let mut prefix: [u8; 10] = [0; 10];
         prefix[0..9].clone_from_slice(&self.prefix);
         prefix[9..10].clone_from_slice(b":");
 
-        let length = self.length()?;
-
         let mut all = Vec::new();
-        let iter = self.db.prefix_iterator(&prefix);
-        for (key, value) in iter.take(length as usize) {
+        for (key, value) in batch.prefix_pairs(&prefix)? {
             all.push((DBValue::PrefixKeyB(key), DBValue::DirectB(value)));
         }
         Ok(all)
@@ -260,6 +386,16 @@ impl Map {
 
     // Set multiple fields to multiple values
     pub fn mset<K, V>(&self, kvs: &[(K, V)]) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut batch = self.batch();
+        self.mset_with_batch(kvs, &mut batch)?;
+        batch.commit()
+    }
+
+    pub fn mset_with_batch<K, V>(&self, kvs: &[(K, V)], batch: &mut Batch) -> Result<()>
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>,
@@ -269,22 +405,27 @@ impl Map {
         dbkey[9..10].clone_from_slice(b":");
 
         let mut incr = 0;
-        let mut batch = WriteBatch::default();
         for (key, value) in &kvs[..] {
             let tdbkey = [&dbkey[..], key.as_ref()].concat();
-            if self.db.get(&tdbkey)?.is_none() {
+            if batch.get(&tdbkey)?.is_none() {
                 incr += 1;
             }
             batch.put(tdbkey, value);
         }
-        self.incr_length(incr, &mut batch)?;
-        self.db.write(batch)?;
+        self.incr_length(incr, batch)?;
 
         Ok(())
     }
 
     // Get all values by the given fields
     pub fn mget<K>(&self, keys: &[K]) -> Result<Vec<Option<DBValue>>>
+    where
+        K: AsRef<[u8]>,
+    {
+        Batch::read(&self.db, |batch| self.mget_with_batch(keys, batch))
+    }
+
+    pub fn mget_with_batch<K>(&self, keys: &[K], batch: &Batch) -> Result<Vec<Option<DBValue>>>
     where
         K: AsRef<[u8]>,
     {
@@ -295,7 +436,7 @@ impl Map {
         let mut values = Vec::new();
         for key in &keys[..] {
             let tdbkey = [&dbkey[..], key.as_ref()].concat();
-            let value = self.db.get(&tdbkey)?;
+            let value = batch.get(&tdbkey)?;
             values.push(value.map(|v| DBValue::Direct(v)));
         }
         Ok(values)
@@ -329,6 +470,30 @@ mod test_map {
         assert!(DB::destroy(&opts, path).is_ok());
     }
 
+    #[test]
+    fn test_map_namespace() {
+        let path = "test-map-db4";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let map = super::Map::new("abc".to_string(), db.clone());
+            let default = super::Map::new_in("", "abc".to_string(), db.clone());
+            let namespaced = super::Map::new_in("ns", "abc".to_string(), db.clone());
+            let other = super::Map::new_in("ns2", "abc".to_string(), db.clone());
+
+            map.set("a", "1").unwrap();
+            namespaced.set("a", "2").unwrap();
+            assert_eq!(&*default.get("a").unwrap().unwrap(), b"1");
+            assert_eq!(&*namespaced.get("a").unwrap().unwrap(), b"2");
+            assert!(other.get("a").unwrap().is_none());
+
+            namespaced.remove().unwrap();
+            assert_eq!(&*map.get("a").unwrap().unwrap(), b"1");
+        }
+
+        let opts = Options::default();
+        assert!(DB::destroy(&opts, path).is_ok());
+    }
+
     #[test]
     fn test_map_funcs() {
         let path = "test-map-db2";
@@ -370,6 +535,14 @@ mod test_map {
             assert_eq!(&*item.unwrap(), b"0");
             map.delete(b"incr").unwrap();
 
+            assert_eq!(map.cas(b"cas", Some(b"x"), b"1").unwrap(), false);
+            assert_eq!(map.cas(b"cas", None, b"1").unwrap(), true);
+            assert_eq!(map.cas(b"cas", None, b"2").unwrap(), false);
+            assert_eq!(map.cas(b"cas", Some(b"1"), b"2").unwrap(), true);
+            let item = map.get(b"cas").unwrap();
+            assert_eq!(&*item.unwrap(), b"2");
+            map.delete(b"cas").unwrap();
+
             map.delete(b"b1").unwrap();
             let item = map.get(b"b1").unwrap();
             assert_eq!(item.is_none(), true);
@@ -0,0 +1,294 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rocksdb::{checkpoint::Checkpoint, IteratorMode, Options, WriteBatch, WriteBatchIterator, DB};
+
+use crate::{
+    error::{DBError, Result},
+    utils::{u64_to_u8x8, u8x8_to_u64},
+};
+
+/// The key of the sequence number of the primary which is applied to a replica.
+///
+/// All lodisdb data keys start with a data type flag which is not 0, so the key
+/// can not be used by them.
+pub const REPLICA_SEQUENCE_KEY: &[u8] = b"\x00@REPLICA_SEQUENCE";
+
+/// A write batch at the write-ahead log of a primary
+pub struct WalUpdate {
+    /// The sequence number of the first write of the batch
+    pub sequence: u64,
+    /// dbkey -> Some(value) for a put, None for a delete, in the order of the writes
+    pub writes: Vec<(Box<[u8]>, Option<Box<[u8]>>)>,
+}
+
+impl WalUpdate {
+    /// The sequence number of the last write of the batch
+    pub fn last_sequence(&self) -> u64 {
+        self.sequence + self.writes.len() as u64 - 1
+    }
+}
+
+impl WriteBatchIterator for WalUpdate {
+    fn put(&mut self, key: Box<[u8]>, value: Box<[u8]>) {
+        self.writes.push((key, Some(value)));
+    }
+
+    fn delete(&mut self, key: Box<[u8]>) {
+        self.writes.push((key, None));
+    }
+}
+
+/// Return the write batches after the sequence number `sequence` at the write-ahead log,
+/// until their size is over `max_size` bytes.
+///
+/// If the write-ahead log does not have all write batches after `sequence`, which happens
+/// when the log is truncated, return `DBError::WalTruncated`.
+pub fn updates_since(db: &DB, sequence: u64, max_size: usize) -> Result<Vec<WalUpdate>> {
+    let latest = db.latest_sequence_number();
+    if sequence == latest {
+        return Ok(Vec::new());
+    }
+    if sequence > latest {
+        return Err(DBError::WalTruncated(sequence));
+    }
+
+    // The iterator starts at the write batch containing `sequence`, which is skipped by
+    // its first `next`, so the first returned batch must start at `sequence + 1`.
+    let mut iter = db
+        .get_updates_since(sequence)
+        .map_err(|_| DBError::WalTruncated(sequence))?;
+    let mut updates = Vec::new();
+    let mut next_sequence = sequence + 1;
+    let mut size = 0;
+    while let Some((batch_sequence, batch)) = iter.next() {
+        if batch_sequence != next_sequence {
+            return Err(DBError::WalTruncated(sequence));
+        }
+        let mut update = WalUpdate {
+            sequence: batch_sequence,
+            writes: Vec::new(),
+        };
+        batch.iterate(&mut update);
+        if update.writes.len() != batch.len() {
+            return Err(DBError::DBValueNotMatch(format!(
+                "write batch at {} has unsupported writes",
+                batch_sequence
+            )));
+        }
+
+        next_sequence = batch_sequence + batch.len() as u64;
+        size += batch.size_in_bytes();
+        updates.push(update);
+        if size >= max_size {
+            break;
+        }
+    }
+    iter.status().map_err(|_| DBError::WalTruncated(sequence))?;
+    if updates.is_empty() {
+        return Err(DBError::WalTruncated(sequence));
+    }
+    Ok(updates)
+}
+
+/// Apply the write batches of a primary to a replica, each one atomically together with
+/// its sequence number at `REPLICA_SEQUENCE_KEY`.
+pub fn apply_updates(db: &Arc<DB>, updates: &[WalUpdate]) -> Result<()> {
+    for update in updates {
+        let mut batch = WriteBatch::default();
+        for (key, value) in update.writes.iter() {
+            if let Some(value) = value {
+                batch.put(key, value);
+            } else {
+                batch.delete(key);
+            }
+        }
+        batch.put(REPLICA_SEQUENCE_KEY, u64_to_u8x8(update.last_sequence()));
+        db.write(batch)?;
+    }
+    Ok(())
+}
+
+/// Return the sequence number of the primary applied to the replica, None if the
+/// replica is not synced.
+pub fn replica_sequence(db: &Arc<DB>) -> Result<Option<u64>> {
+    match db.get(REPLICA_SEQUENCE_KEY)? {
+        Some(value) if value.len() == 8 => {
+            let mut buf: [u8; 8] = [0; 8];
+            buf.clone_from_slice(&value);
+            Ok(Some(u8x8_to_u64(&buf)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Delete all keys of the replica, including its sequence number, before a resync
+pub fn clear_replica(db: &Arc<DB>) -> Result<()> {
+    db.delete(REPLICA_SEQUENCE_KEY)?;
+    let mut batch = WriteBatch::default();
+    for (key, _) in db.iterator(IteratorMode::Start) {
+        batch.delete(key);
+        if batch.len() >= 1000 {
+            db.write(batch)?;
+            batch = WriteBatch::default();
+        }
+    }
+    db.write(batch)?;
+    Ok(())
+}
+
+/// Write pairs of a `ReplicaSnapshot` to the replica. After all pairs are written,
+/// `finish_resync` records the sequence number of the snapshot.
+pub fn write_pairs(db: &Arc<DB>, pairs: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    let mut batch = WriteBatch::default();
+    for (key, value) in pairs.iter() {
+        batch.put(key, value);
+    }
+    db.write(batch)?;
+    Ok(())
+}
+
+/// Record the sequence number of the snapshot which the replica is resynced from
+pub fn finish_resync(db: &Arc<DB>, sequence: u64) -> Result<()> {
+    db.put(REPLICA_SEQUENCE_KEY, u64_to_u8x8(sequence))?;
+    Ok(())
+}
+
+/// A checkpoint of a primary to resync a replica from.
+///
+/// The checkpoint is removed when it is dropped.
+pub struct ReplicaSnapshot {
+    path: PathBuf,
+    db: Option<DB>,
+    sequence: u64,
+}
+
+impl ReplicaSnapshot {
+    /// Create a checkpoint of `db` at `path`, which must not exist
+    pub fn create<P: AsRef<Path>>(db: &DB, path: P) -> Result<ReplicaSnapshot> {
+        let path = path.as_ref().to_path_buf();
+        Checkpoint::new(db)?.create_checkpoint(&path)?;
+        let db = DB::open_for_read_only(&Options::default(), &path, false)?;
+        let sequence = db.latest_sequence_number();
+        Ok(ReplicaSnapshot {
+            path,
+            db: Some(db),
+            sequence,
+        })
+    }
+
+    /// The sequence number of the primary at the checkpoint
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Call `f` with every pair of the checkpoint in order, stop at the first error of `f`
+    pub fn for_each<F, E>(&self, mut f: F) -> std::result::Result<(), E>
+    where
+        F: FnMut(&[u8], &[u8]) -> std::result::Result<(), E>,
+    {
+        if let Some(db) = &self.db {
+            for (key, value) in db.iterator(IteratorMode::Start) {
+                f(&key, &value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ReplicaSnapshot {
+    fn drop(&mut self) {
+        drop(self.db.take());
+        let _ = DB::destroy(&Options::default(), &self.path);
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test_replication {
+    use rocksdb::{Options, DB};
+
+    use std::sync::Arc;
+
+    use crate::{list::List, make_db_with_wal_ttl, map::Map};
+
+    use super::{
+        apply_updates, clear_replica, finish_resync, replica_sequence, updates_since, write_pairs,
+        ReplicaSnapshot,
+    };
+
+    #[test]
+    fn test_replication() {
+        let path = "test-replication-db1";
+        let replica_path = "test-replication-db1-replica";
+        let snapshot_path = "test-replication-db1-snapshot";
+        {
+            // Write-ahead log files are removed after the checkpoint flushes the memtable
+            // unless they are kept
+            let db = Arc::new(make_db_with_wal_ttl(path, 60));
+            let replica = Arc::new(DB::open_default(replica_path).unwrap());
+            let list = List::new("abc".to_string(), db.clone());
+            let map = Map::new("abc".to_string(), db.clone());
+
+            list.push(&["a", "b"]).unwrap();
+            map.set("a", "1").unwrap();
+
+            // Resync the replica from a snapshot
+            assert_eq!(replica_sequence(&replica).unwrap(), None);
+            {
+                let snapshot = ReplicaSnapshot::create(&db, snapshot_path).unwrap();
+                assert_eq!(snapshot.sequence(), db.latest_sequence_number());
+                let mut pairs = Vec::new();
+                snapshot
+                    .for_each(|key, value| -> Result<(), ()> {
+                        pairs.push((key.to_vec(), value.to_vec()));
+                        Ok(())
+                    })
+                    .unwrap();
+                clear_replica(&replica).unwrap();
+                write_pairs(&replica, &pairs).unwrap();
+                finish_resync(&replica, snapshot.sequence()).unwrap();
+            }
+            assert!(!std::path::Path::new(snapshot_path).exists());
+            let sequence = replica_sequence(&replica).unwrap().unwrap();
+            assert_eq!(sequence, db.latest_sequence_number());
+            assert_eq!(
+                List::new("abc".to_string(), replica.clone())
+                    .length()
+                    .unwrap(),
+                2
+            );
+
+            // Nothing is new
+            assert!(updates_since(&db, sequence, 1024).unwrap().is_empty());
+
+            // Tail the write-ahead log
+            list.pop().unwrap();
+            map.set("b", "2").unwrap();
+            map.delete("a").unwrap();
+            let updates = updates_since(&db, sequence, 1024 * 1024).unwrap();
+            assert_eq!(updates.len(), 3);
+            assert_eq!(updates[0].sequence, sequence + 1);
+            apply_updates(&replica, &updates).unwrap();
+            assert_eq!(
+                replica_sequence(&replica).unwrap(),
+                Some(db.latest_sequence_number())
+            );
+
+            let replica_list = List::new("abc".to_string(), replica.clone());
+            let replica_map = Map::new("abc".to_string(), replica.clone());
+            assert_eq!(replica_list.length().unwrap(), 1);
+            assert!(replica_map.get("a").unwrap().is_none());
+            assert_eq!(&*replica_map.get("b").unwrap().unwrap(), b"2");
+
+            // A sequence number after the latest one is not at the log
+            assert!(updates_since(&db, db.latest_sequence_number() + 10, 1024).is_err());
+        }
+
+        let opts = Options::default();
+        DB::destroy(&opts, path).unwrap();
+        DB::destroy(&opts, replica_path).unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+use rocksdb::DB;
+
+use crate::error::Result;
+
+/// Integer properties of RocksDB which describe the size and the load of a db.
+pub const DB_PROPERTIES: [&str; 10] = [
+    "rocksdb.estimate-num-keys",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.live-sst-files-size",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.num-immutable-mem-table",
+    "rocksdb.estimate-pending-compaction-bytes",
+    "rocksdb.num-running-compactions",
+    "rocksdb.num-running-flushes",
+    "rocksdb.block-cache-usage",
+];
+
+/// Return the values of `DB_PROPERTIES` of the `db`, without the prefix `rocksdb.`.
+///
+/// A property which the db does not have, e.g. `rocksdb.block-cache-usage` of a db without
+/// block cache, is skipped.
+pub fn db_properties(db: &DB) -> Result<Vec<(&'static str, u64)>> {
+    let mut properties = Vec::new();
+    for name in DB_PROPERTIES.iter() {
+        if let Some(value) = db.property_int_value(name)? {
+            properties.push((&name["rocksdb.".len()..], value));
+        }
+    }
+    Ok(properties)
+}
+
+#[cfg(test)]
+mod test_stats {
+    use rocksdb::{Options, DB};
+
+    use std::sync::Arc;
+
+    use crate::map::Map;
+
+    use super::db_properties;
+
+    #[test]
+    fn test_db_properties() {
+        let path = "test-stats-db1";
+        {
+            let db = Arc::new(DB::open_default(path).unwrap());
+            let map = Map::new("abc".to_string(), db.clone());
+            map.set("a", "1").unwrap();
+
+            let properties = db_properties(&db).unwrap();
+            let get = |name: &str| {
+                properties
+                    .iter()
+                    .find(|(property, _)| *property == name)
+                    .map(|(_, value)| *value)
+            };
+            assert!(get("estimate-num-keys").unwrap() > 0);
+            assert!(get("cur-size-all-mem-tables").unwrap() > 0);
+            assert_eq!(get("num-running-compactions"), Some(0));
+        }
+
+        let opts = Options::default();
+        DB::destroy(&opts, path).unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+use std::{collections::HashSet, fmt, fs, sync::Arc};
+
+use actix_web::{dev::ServiceRequest, http::HeaderMap, HttpMessage, HttpRequest};
+use serde::Deserialize;
+
+use crate::{
+    common::Command,
+    error::{LodisError, Result},
+    stats::command_name,
+    utils::glob_match,
+};
+
+// The header which carries the token of a user, as `Bearer {token}`
+const AUTHORIZATION_HEADER: &str = "Authorization";
+
+// ACL file format (TOML)
+//
+// [[users]]
+// name = "app"
+// token = "secret"
+// categories = ["read", "write"]
+// keys = ["user:*", "session:*"]
+// namespaces = ["*"]
+//
+// `keys` and `namespaces` are glob-style patterns, they are `["*"]` if they are not given.
+#[derive(Deserialize)]
+struct AclFile {
+    #[serde(default)]
+    users: Vec<User>,
+}
+
+// Categories of commands
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    // Commands which read data or subscribe channels
+    Read,
+    // Commands which write data, run scripts or publish messages
+    Write,
+    // Commands on the server, e.g. FLUSHDB, INFO, BACKUP and CHANGES
+    Admin,
+}
+
+#[derive(Deserialize)]
+pub struct User {
+    pub name: String,
+    token: String,
+    categories: Vec<Category>,
+    #[serde(default = "match_all")]
+    keys: Vec<String>,
+    #[serde(default = "match_all")]
+    namespaces: Vec<String>,
+}
+
+fn match_all() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+impl User {
+    // Fail if the user can not run the command, which is the name of a route, e.g. `LPUSH`
+    pub fn check_command(&self, command: &str) -> Result<()> {
+        let category = command_category(command)
+            .ok_or_else(|| LodisError::UnknownCommand(command.to_string()))?;
+        if self.categories.contains(&category) {
+            Ok(())
+        } else {
+            Err(LodisError::NoPermission(format!(
+                "user: {}, command: {}",
+                self.name, command
+            )))
+        }
+    }
+
+    pub fn check_key(&self, key: &str) -> Result<()> {
+        if self.can_access_key(key) {
+            Ok(())
+        } else {
+            Err(LodisError::NoPermission(format!(
+                "user: {}, key: {}",
+                self.name, key
+            )))
+        }
+    }
+
+    pub fn check_namespace(&self, namespace: &str) -> Result<()> {
+        if matches_any(&self.namespaces, namespace) {
+            Ok(())
+        } else {
+            Err(LodisError::NoPermission(format!(
+                "user: {}, namespace: {}",
+                self.name, namespace
+            )))
+        }
+    }
+
+    pub fn can_access_key(&self, key: &str) -> bool {
+        matches_any(&self.keys, key)
+    }
+}
+
+fn matches_any(patterns: &[String], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes()))
+}
+
+// The users which can access the server, every request must carry the token of one of them
+#[derive(Clone)]
+pub struct Acl {
+    users: Vec<Arc<User>>,
+}
+
+// Only the names of the users, not their tokens
+impl fmt::Debug for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.users.iter().map(|user| &user.name))
+            .finish()
+    }
+}
+
+impl Acl {
+    // Load the users from an ACL file
+    pub fn load(path: &str) -> Result<Acl> {
+        fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|content| Acl::parse(&content))
+            .map_err(|err| LodisError::Error(format!("ACL file {}: {}", path, err)))
+    }
+
+    // Parse the users of an ACL file, tokens must be unique and not empty
+    pub(crate) fn parse(content: &str) -> std::result::Result<Acl, String> {
+        let file: AclFile = toml::from_str(content).map_err(|err| err.to_string())?;
+        let mut tokens = HashSet::new();
+        for user in file.users.iter() {
+            if user.token.is_empty() {
+                return Err(format!("the token of user {} is empty", user.name));
+            }
+            if !tokens.insert(&user.token) {
+                return Err(format!("the token of user {} is not unique", user.name));
+            }
+        }
+        Ok(Acl {
+            users: file.users.into_iter().map(Arc::new).collect(),
+        })
+    }
+
+    // Authenticate the request and check the category of its command, the user of the request is
+    // put to the extensions of the request
+    //
+    // Namespaces are checked by `Namespace`, and keys by the handlers with `check_key`.
+    pub fn authorize(&self, request: &ServiceRequest) -> Result<()> {
+        let user = self.authenticate(request.headers())?;
+        // Requests which match no route are not found
+        if let Some(pattern) = request.match_pattern() {
+            let command = command_name(&pattern);
+            // The commands of a transaction or a pipeline are checked by MULTI or PIPELINE
+            if command != "MULTI" && command != "PIPELINE" {
+                user.check_command(&command)?;
+            }
+        }
+        request.extensions_mut().insert(user);
+        Ok(())
+    }
+
+    fn authenticate(&self, headers: &HeaderMap) -> Result<Arc<User>> {
+        let token = headers
+            .get(AUTHORIZATION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(LodisError::Unauthenticated)?;
+        self.user(token.trim())
+    }
+
+    // The user of the token
+    pub fn user(&self, token: &str) -> Result<Arc<User>> {
+        self.users
+            .iter()
+            .find(|user| constant_time_eq(user.token.as_bytes(), token.as_bytes()))
+            .cloned()
+            .ok_or(LodisError::Unauthenticated)
+    }
+}
+
+// Compare tokens in a time which does not depend on the common prefix of them
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+// The user of the request, None if ACLs are not enabled
+pub fn request_user(request: &HttpRequest) -> Option<Arc<User>> {
+    request.extensions().get::<Arc<User>>().cloned()
+}
+
+// Fail if the user of the request can not access the key
+pub fn check_key(request: &HttpRequest, key: &str) -> Result<()> {
+    match request_user(request) {
+        Some(user) => user.check_key(key),
+        None => Ok(()),
+    }
+}
+
+// The category of a command, None if the command is unknown
+fn command_category(command: &str) -> Option<Category> {
+    let category = match command {
+        "SCAN" | "KEYS" | "TYPE" | "EXISTS" | "DBSIZE" | "NAMESPACES" | "SUBSCRIBE"
+        | "PSUBSCRIBE" | "WS" => Category::Read,
+        "DEL" | "RENAME" | "RENAMENX" | "COPY" | "EVAL" | "EVALSHA" | "SCRIPTLOAD" | "PUBLISH" => {
+            Category::Write
+        }
+        "FLUSHDB" | "INFO" | "METRICS" | "SLOWLOG" | "BACKUP" | "LISTBACKUPS" | "CHECKPOINT"
+        | "REPLUPDATES" | "REPLSNAPSHOT" | "CHANGES" => Category::Admin,
+        _ => data_command_category(command.parse::<Command>().ok()?),
+    };
+    Some(category)
+}
+
+fn data_command_category(command: Command) -> Category {
+    use Command::*;
+    match command {
+        LRANGE | RRANGE | LINDEX | LRAND | LLEN | LWATCH | HGET | HGETALL | HMGET | HKEYS
+        | HVALS | HEXISTS | HLEN | HWATCH | AGET | ARAND | ALRANGE | ARRANGE | AKEYS | AVALS
+        | AALL | AEXISTS | ALEN | AWATCH => Category::Read,
+        LPUSH | RPUSH | LPOP | RPOP | RANDPOP | LDEL | LRM | HSET | HSETNX | HCAS | HMSET
+        | HINCRBY | HDEL | HRM | ALPUSH | ALPUSHNX | ARPUSH | ARPUSHNX | AINCRBY | ACAS | ALPOP
+        | ARPOP | ARANDPOP | ADEL | ARM => Category::Write,
+    }
+}
+
+#[cfg(test)]
+mod test_acl {
+    use std::sync::Arc;
+
+    use actix_web::{
+        dev::Service, rt::System, test, web, App, HttpMessage, HttpResponse, ResponseError,
+    };
+    use futures::future::ready;
+
+    use super::{command_category, Acl, Category, User};
+    use crate::{error::LodisError, routes::make_route, stats::command_name};
+
+    const ACL: &str = r#"
+        [[users]]
+        name = "admin"
+        token = "admin-token"
+        categories = ["read", "write", "admin"]
+
+        [[users]]
+        name = "app"
+        token = "app-token"
+        categories = ["read", "write"]
+        keys = ["user:*", "session:?", "[ab]*"]
+        namespaces = ["", "app*"]
+
+        [[users]]
+        name = "reader"
+        token = "reader-token"
+        categories = ["read"]
+    "#;
+
+    fn user(acl: &Acl, token: &str) -> Arc<User> {
+        acl.user(token).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let acl = Acl::parse(ACL).unwrap();
+        assert_eq!(format!("{:?}", acl), r#"["admin", "app", "reader"]"#);
+        assert_eq!(user(&acl, "app-token").name, "app");
+        assert!(matches!(
+            acl.user("app-toke"),
+            Err(LodisError::Unauthenticated)
+        ));
+        assert!(matches!(acl.user(""), Err(LodisError::Unauthenticated)));
+
+        assert_eq!(Acl::parse("").unwrap().users.len(), 0);
+        for content in [
+            "[[users]]\nname = \"a\"\ntoken = \"t\"\ncategories = [\"root\"]",
+            "[[users]]\nname = \"a\"\ntoken = \"t\"",
+            "[[users]]\nname = \"a\"\ntoken = \"\"\ncategories = []",
+            "[[users]]\nname = \"a\"\ntoken = \"t\"\ncategories = []\n\
+             [[users]]\nname = \"b\"\ntoken = \"t\"\ncategories = []",
+            "users = 1",
+        ]
+        .iter()
+        {
+            assert!(Acl::parse(content).is_err(), "{}", content);
+        }
+        assert!(Acl::load("no-such-acl-file.toml").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let acl = Acl::parse(ACL).unwrap();
+        let admin = user(&acl, "admin-token");
+        let app = user(&acl, "app-token");
+        let reader = user(&acl, "reader-token");
+
+        for command in ["LRANGE", "HGETALL", "KEYS", "WS"].iter() {
+            assert!(reader.check_command(command).is_ok(), "{}", command);
+        }
+        for command in ["LPUSH", "HSET", "DEL", "EVAL", "PUBLISH"].iter() {
+            assert!(app.check_command(command).is_ok(), "{}", command);
+            assert!(matches!(
+                reader.check_command(command),
+                Err(LodisError::NoPermission(_))
+            ));
+        }
+        for command in ["FLUSHDB", "INFO", "METRICS", "REPLUPDATES"].iter() {
+            assert!(admin.check_command(command).is_ok(), "{}", command);
+            assert!(app.check_command(command).is_err(), "{}", command);
+        }
+        // Unknown commands are not run by any user
+        assert!(matches!(
+            admin.check_command("SHUTDOWN"),
+            Err(LodisError::UnknownCommand(_))
+        ));
+
+        for key in ["user:1", "user:", "session:a", "a", "bcd"].iter() {
+            assert!(app.check_key(key).is_ok(), "{}", key);
+        }
+        for key in ["users:1", "session:ab", "session:", "c", ""].iter() {
+            assert!(
+                matches!(app.check_key(key), Err(LodisError::NoPermission(_))),
+                "{}",
+                key
+            );
+        }
+        assert!(reader.check_key("").is_ok());
+
+        for namespace in ["", "app", "app1"].iter() {
+            assert!(app.check_namespace(namespace).is_ok(), "{}", namespace);
+        }
+        for namespace in ["other", "ap"].iter() {
+            assert!(app.check_namespace(namespace).is_err(), "{}", namespace);
+        }
+        assert!(reader.check_namespace("other").is_ok());
+    }
+
+    #[test]
+    fn test_command_category() {
+        assert_eq!(command_category("LRANGE"), Some(Category::Read));
+        assert_eq!(command_category("ARM"), Some(Category::Write));
+        assert_eq!(command_category("CHANGES"), Some(Category::Admin));
+        assert_eq!(command_category("SHUTDOWN"), None);
+        assert_eq!(command_category(""), None);
+
+        // Every route has a category, except MULTI and PIPELINE whose commands are checked
+        let routes = include_str!("routes.rs");
+        let mut count = 0;
+        for route in routes.split(".route(\"").skip(1) {
+            let pattern = &route[..route.find('"').unwrap()];
+            let command = command_name(pattern);
+            if command != "MULTI" && command != "PIPELINE" {
+                assert!(command_category(&command).is_some(), "{}", pattern);
+            }
+            count += 1;
+        }
+        assert!(count > 70);
+    }
+
+    #[test]
+    fn test_authorize() {
+        System::new("test-acl").block_on(async {
+            let acl = Acl::parse(ACL).unwrap();
+            // Respond with the name of the authorized user instead of running the command
+            let mut app = test::init_service(
+                App::new()
+                    .wrap_fn(move |request, _| {
+                        let response = match acl.authorize(&request) {
+                            Ok(()) => {
+                                let name = request
+                                    .extensions()
+                                    .get::<Arc<User>>()
+                                    .unwrap()
+                                    .name
+                                    .clone();
+                                HttpResponse::Ok().body(name)
+                            }
+                            Err(err) => err.error_response(),
+                        };
+                        ready(Ok(request.into_response(response)))
+                    })
+                    .service(make_route())
+                    .default_service(web::to(HttpResponse::NotFound)),
+            )
+            .await;
+
+            // The name of the user, or the status code of the error
+            for (path, token, expected) in [
+                ("/lpush/a", Some("app-token"), "app"),
+                ("/ns/app/lpush/a", Some("app-token"), "app"),
+                ("/lrange/a", Some("reader-token"), "reader"),
+                ("/ns/x/lpush/a", Some("reader-token"), "F"),
+                ("/flushdb", Some("app-token"), "F"),
+                ("/flushdb", Some("admin-token"), "admin"),
+                ("/changes/sse", Some("admin-token"), "admin"),
+                ("/multi", Some("reader-token"), "reader"),
+                ("/pipeline", Some("reader-token"), "reader"),
+                ("/lpush/a", Some("bad-token"), "E"),
+                ("/lpush/a", None, "E"),
+                // Requests which match no route are authenticated, and not found later
+                ("/nothing", Some("reader-token"), "reader"),
+            ]
+            .iter()
+            {
+                let mut request = test::TestRequest::post().uri(path);
+                if let Some(token) = token {
+                    request = request.header("Authorization", format!("Bearer {}", token));
+                }
+                let response = app.call(request.to_request()).await.unwrap();
+                let body = test::read_body(response).await;
+                assert!(
+                    body.starts_with(expected.as_bytes()),
+                    "{} {:?} {:?}",
+                    path,
+                    token,
+                    body
+                );
+            }
+        });
+    }
+}
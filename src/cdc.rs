@@ -0,0 +1,532 @@
+use std::{
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use actix_web::{
+    rt::{spawn, time::timeout},
+    web,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    SinkExt,
+};
+
+use lodisdb::{
+    append_change_with_batch, change_log_range, changes_since, common::DataType, u32_to_u8x4,
+    u64_to_u8x8, u8x4_to_u32, u8x8_to_u64, Batch, DB,
+};
+
+use crate::{
+    common::{Command, SUCCESS},
+    error::{LodisError, Result},
+    state::GlobalState,
+    utils::json_string,
+};
+
+// Change format
+//
+// All integers are big-endian unsign.
+//
+// Change:  8bytes sequence + 8bytes unix timestamp in milliseconds + 1byte data type
+//          + 4bytes length of key + key + 4bytes length of operation + operation
+//          + 4bytes length of params + params + 4bytes length of result + result
+//          + 4bytes length of namespace + namespace
+//
+// Data type is the flag of `DataType`: 1 is List, 2 is HashMap, 3 is ArrayMap.
+// Params are the `CONTENT` of the command. Result is the response content of the command
+// without its status code. Namespace is empty for the default namespace, it is the last field so
+// that consumers which do not know it can ignore it.
+//
+// The change log stores changes without their sequences, which are in the keys of the log.
+
+// Number of changes read from the change log at once
+const CHANGES_BATCH: usize = 1000;
+// How long a stream waits for new changes before it looks at the log again
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// A stream without changes sends a heartbeat at this interval, so that a closed
+// connection is noticed
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+// A write of one key by a command or a script
+pub struct Change {
+    pub namespace: String,
+    pub data_type: DataType,
+    pub key: String,
+    pub operation: String,
+    pub params: Vec<u8>,
+    pub result: Vec<u8>,
+}
+
+impl Change {
+    // The change of a command, `value` is its response content
+    pub fn from_command(
+        command: Command,
+        namespace: &str,
+        key: &str,
+        params: &[web::BytesMut],
+        value: &[u8],
+    ) -> Change {
+        Change {
+            namespace: namespace.to_string(),
+            data_type: command.data_type(),
+            key: key.to_string(),
+            operation: format!("{:?}", command),
+            params: encode_params(params.iter().map(|param| &param[..])),
+            result: value[SUCCESS.len()..].to_vec(),
+        }
+    }
+
+    fn encode(&self, timestamp: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&u64_to_u8x8(timestamp));
+        buf.extend_from_slice(&self.data_type.flag());
+        write_bytes(&mut buf, self.key.as_bytes());
+        write_bytes(&mut buf, self.operation.as_bytes());
+        write_bytes(&mut buf, &self.params);
+        write_bytes(&mut buf, &self.result);
+        write_bytes(&mut buf, self.namespace.as_bytes());
+        buf
+    }
+}
+
+// Encode params as the `CONTENT` of a request
+pub fn encode_params<'a, I>(params: I) -> Vec<u8>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    let mut buf = Vec::new();
+    for param in params {
+        write_bytes(&mut buf, param);
+    }
+    buf
+}
+
+// The change data capture of the db
+//
+// Changes are written to the change log in the same batch as their writes, and get
+// increasing sequence numbers in the order of the writes.
+pub struct Cdc {
+    // Number of the latest changes to keep
+    keep: u64,
+    // The sequence number of the last change, commits hold it so that changes are
+    // in the same order as their writes
+    sequence: Mutex<u64>,
+    // Streams which wait for the changes after the last change
+    waiters: Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+impl Cdc {
+    pub fn new(db: &Arc<DB>, keep: u64) -> Result<Cdc> {
+        let sequence = change_log_range(db)?.map(|(_, last)| last).unwrap_or(0);
+        Ok(Cdc {
+            keep,
+            sequence: Mutex::new(sequence),
+            waiters: Mutex::new(Vec::new()),
+        })
+    }
+
+    // Commit the batch together with its changes
+    pub fn commit(&self, mut batch: Batch, changes: &[Change]) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let mut sequence = self
+            .sequence
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut next = *sequence;
+        for change in changes {
+            next += 1;
+            append_change_with_batch(next, &change.encode(now), self.keep, &mut batch);
+        }
+        batch.commit()?;
+        *sequence = next;
+        drop(sequence);
+        let waiters = std::mem::take(
+            &mut *self
+                .waiters
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+        for waiter in waiters {
+            let _ = waiter.send(());
+        }
+        Ok(())
+    }
+
+    // Wait until there are changes after `sequence`, or `duration` passes
+    async fn wait(&self, sequence: u64, duration: Duration) {
+        let receiver = {
+            let last = self
+                .sequence
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if *last > sequence {
+                return;
+            }
+            // The waiter is added before the lock is released, so a commit after the check
+            // always wakes it up
+            let (sender, receiver) = oneshot::channel();
+            let mut waiters = self
+                .waiters
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            // Streams which timed out or are closed do not wait any more
+            waiters.retain(|waiter| !waiter.is_canceled());
+            waiters.push(sender);
+            receiver
+        };
+        let _ = timeout(duration, receiver).await;
+    }
+}
+
+// Return the sequence number which a stream starts after. `since` must not be before the
+// changes which are trimmed from the change log, None starts after the last change.
+pub fn stream_start(db: &Arc<DB>, since: Option<u64>) -> Result<u64> {
+    let range = change_log_range(db)?;
+    match (since, range) {
+        (None, Some((_, last))) => Ok(last),
+        (None, None) => Ok(0),
+        (Some(since), Some((first, _))) if since + 1 < first => {
+            Err(LodisError::ChangesTrimmed(first))
+        }
+        (Some(since), _) => Ok(since),
+    }
+}
+
+pub enum StreamFormat {
+    // `SUCCESS`, then frames of changes, each of them is prefixed with its length.
+    // An empty frame is a heartbeat.
+    Binary,
+    // Server-Sent Events, see `encode_event`
+    Sse,
+}
+
+// Stream the changes after `since` from a task of the current worker, until the client is
+// disconnected or the changes after the stream are trimmed
+//
+// Reads of the change log are at most `CHANGES_BATCH` changes, so that they do not block the
+// worker for long.
+pub fn stream_changes(
+    global_state: web::Data<GlobalState>,
+    since: u64,
+    format: StreamFormat,
+) -> mpsc::Receiver<Result<web::Bytes>> {
+    let (mut sender, receiver) = mpsc::channel(4);
+    spawn(async move {
+        let cdc = match global_state.cdc.as_ref() {
+            Some(cdc) => cdc,
+            None => return,
+        };
+        if let StreamFormat::Binary = format {
+            if sender
+                .send(Ok(web::Bytes::from_static(SUCCESS)))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        let mut sequence = since;
+        let mut idle = Instant::now();
+        loop {
+            let changes = match changes_since(&global_state.db, sequence, CHANGES_BATCH) {
+                Ok(changes) => changes,
+                Err(err) => {
+                    let _ = sender.send(Err(err.into())).await;
+                    return;
+                }
+            };
+
+            if !changes.is_empty() {
+                // The consumer is too slow, it gets `ChangesTrimmed` when it resumes
+                if changes[0].0 != sequence + 1 {
+                    return;
+                }
+                let mut buf = Vec::new();
+                for (change_sequence, value) in changes.iter() {
+                    match format {
+                        StreamFormat::Binary => {
+                            buf.extend_from_slice(&u32_to_u8x4(8 + value.len() as u32));
+                            buf.extend_from_slice(&u64_to_u8x8(*change_sequence));
+                            buf.extend_from_slice(value);
+                        }
+                        StreamFormat::Sse => encode_event(&mut buf, *change_sequence, value),
+                    }
+                    sequence = *change_sequence;
+                }
+                if sender.send(Ok(web::Bytes::from(buf))).await.is_err() {
+                    return;
+                }
+                idle = Instant::now();
+                continue;
+            }
+
+            if idle.elapsed() >= HEARTBEAT_INTERVAL {
+                let heartbeat: &'static [u8] = match format {
+                    StreamFormat::Binary => &[0, 0, 0, 0],
+                    StreamFormat::Sse => b":\n\n",
+                };
+                if sender
+                    .send(Ok(web::Bytes::from_static(heartbeat)))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                idle = Instant::now();
+            }
+            // Changes replicated from a primary do not wake up the stream, they are
+            // found by polling
+            cdc.wait(sequence, POLL_INTERVAL).await;
+        }
+    });
+    receiver
+}
+
+// Encode a change of the change log as a Server-Sent Event
+//
+// id: sequence
+// event: change
+// data: {"sequence":1,"timestamp":1632268800000,"namespace":"","type":"List","key":"abc",
+//        "operation":"RPUSH","params":["61"],"result":""}
+//
+// Params and result are hex strings.
+fn encode_event(buf: &mut Vec<u8>, sequence: u64, value: &[u8]) {
+    let mut reader = Reader { buf: value };
+    let timestamp = u8x8_to_u64(&to_u8x8(reader.take(8)));
+    let data_type = match reader.take(1)[0] {
+        1 => "List",
+        2 => "HashMap",
+        3 => "ArrayMap",
+        4 => "Set",
+        _ => "String",
+    };
+    let key = String::from_utf8_lossy(reader.take_bytes()).into_owned();
+    let operation = String::from_utf8_lossy(reader.take_bytes()).into_owned();
+    let mut params_reader = Reader {
+        buf: reader.take_bytes(),
+    };
+    let mut params = Vec::new();
+    while !params_reader.buf.is_empty() {
+        params.push(format!("\"{}\"", to_hex(params_reader.take_bytes())));
+    }
+    let result = to_hex(reader.take_bytes());
+    let namespace = String::from_utf8_lossy(reader.take_bytes()).into_owned();
+
+    let mut event = String::new();
+    let _ = write!(
+        event,
+        "id: {}\nevent: change\ndata: {{\"sequence\":{},\"timestamp\":{},\"namespace\":{},\"type\":\"{}\",\
+         \"key\":{},\"operation\":\"{}\",\"params\":[{}],\"result\":\"{}\"}}\n\n",
+        sequence,
+        sequence,
+        timestamp,
+        json_string(&namespace),
+        data_type,
+        json_string(&key),
+        operation,
+        params.join(","),
+        result
+    );
+    buf.extend_from_slice(event.as_bytes());
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&u32_to_u8x4(bytes.len() as u32));
+    buf.extend_from_slice(bytes);
+}
+
+fn to_u8x8(bytes: &[u8]) -> [u8; 8] {
+    let mut buf: [u8; 8] = [0; 8];
+    buf.clone_from_slice(bytes);
+    buf
+}
+
+// Reads changes written by `Change::encode`
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> &'a [u8] {
+        let (bytes, rest) = self.buf.split_at(n.min(self.buf.len()));
+        self.buf = rest;
+        bytes
+    }
+
+    fn take_bytes(&mut self) -> &'a [u8] {
+        let mut len: [u8; 4] = [0; 4];
+        let bytes = self.take(4);
+        len[..bytes.len()].clone_from_slice(bytes);
+        self.take(u8x4_to_u32(&len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test_cdc {
+    use std::fs;
+
+    use actix_web::{rt::System, web};
+    use futures::StreamExt;
+    use log::LevelFilter;
+
+    use lodisdb::{common::DataType, make_db, u64_to_u8x8, Batch};
+
+    use super::*;
+    use crate::{logging::LogFormat, utils::LodisConfig};
+
+    fn config(db_path: &str, cdc_keep: u64) -> LodisConfig {
+        LodisConfig {
+            db_path: db_path.to_string(),
+            ip_port: "127.0.0.1:0".to_string(),
+            workers: 1,
+            backup_path: None,
+            backup_keep: 0,
+            checkpoint_path: None,
+            checkpoint_keep: 0,
+            checkpoint_interval: 0,
+            wal_ttl: 0,
+            replica_of: None,
+            replica_token: None,
+            replica_tls: None,
+            cdc_keep,
+            mixed_types: false,
+            log_level: LevelFilter::Off,
+            log_format: LogFormat::Text,
+            slowlog_threshold: 0,
+            slowlog_max_len: 0,
+            acl: None,
+            tls: None,
+            resp_ip_port: None,
+            resp_max_connections: 0,
+            pipeline_max_size: 1024,
+        }
+    }
+
+    fn change(key: &str, param: &[u8]) -> Change {
+        Change {
+            namespace: String::new(),
+            data_type: DataType::List,
+            key: key.to_string(),
+            operation: "RPUSH".to_string(),
+            params: encode_params(std::iter::once(param)),
+            result: Vec::new(),
+        }
+    }
+
+    fn commit(db: &Arc<DB>, cdc: &Cdc, key: &str, param: &[u8]) {
+        cdc.commit(Batch::new(db.clone()), &[change(key, param)])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stream_start() {
+        let path = "test-cdc-db1";
+        {
+            let db = Arc::new(make_db(path));
+            assert_eq!(stream_start(&db, None).unwrap(), 0);
+            assert_eq!(stream_start(&db, Some(7)).unwrap(), 7);
+
+            // Keeps the changes 3, 4 and 5
+            let cdc = Cdc::new(&db, 3).unwrap();
+            for i in 0..5 {
+                commit(&db, &cdc, "l", &[i]);
+            }
+            assert_eq!(change_log_range(&db).unwrap(), Some((3, 5)));
+            assert_eq!(stream_start(&db, None).unwrap(), 5);
+            assert_eq!(stream_start(&db, Some(2)).unwrap(), 2);
+            assert_eq!(stream_start(&db, Some(5)).unwrap(), 5);
+            assert!(matches!(
+                stream_start(&db, Some(1)),
+                Err(LodisError::ChangesTrimmed(3))
+            ));
+            assert!(matches!(
+                stream_start(&db, Some(0)),
+                Err(LodisError::ChangesTrimmed(3))
+            ));
+
+            // The sequence goes on after a restart
+            drop(cdc);
+            let cdc = Cdc::new(&db, 3).unwrap();
+            commit(&db, &cdc, "l", &[5]);
+            assert_eq!(change_log_range(&db).unwrap(), Some((4, 6)));
+        }
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_stream_changes() {
+        let path = "test-cdc-db2";
+        System::new("test_stream_changes").block_on(async move {
+            let global_state = web::Data::new(GlobalState::new(&config(path, 100)).unwrap());
+            let db = global_state.db.clone();
+            let cdc = global_state.cdc.as_ref().unwrap();
+            for key in ["a", "b", "c"].iter() {
+                commit(&db, cdc, key, b"x");
+            }
+
+            // Resume after the first change
+            let mut stream = stream_changes(global_state.clone(), 1, StreamFormat::Binary);
+            assert_eq!(&stream.next().await.unwrap().unwrap()[..], SUCCESS);
+            let frames = stream.next().await.unwrap().unwrap();
+            let mut expected = Vec::new();
+            for (sequence, value) in changes_since(&db, 1, 10).unwrap() {
+                expected.extend_from_slice(&u32_to_u8x4(8 + value.len() as u32));
+                expected.extend_from_slice(&u64_to_u8x8(sequence));
+                expected.extend_from_slice(&value);
+            }
+            assert_eq!(&frames[..], &expected[..]);
+
+            // A new change wakes up the stream
+            commit(&db, cdc, "d", b"y");
+            let frames = stream.next().await.unwrap().unwrap();
+            assert_eq!(&frames[4..12], &u64_to_u8x8(4)[..]);
+            assert_eq!(&frames[12..], &changes_since(&db, 3, 1).unwrap()[0].1[..]);
+            drop(stream);
+
+            // Server-Sent Events
+            let mut stream = stream_changes(global_state.clone(), 3, StreamFormat::Sse);
+            let event = stream.next().await.unwrap().unwrap();
+            let event = String::from_utf8_lossy(&event);
+            assert!(event.starts_with("id: 4\nevent: change\ndata: {\"sequence\":4,"));
+            assert!(event.ends_with(
+                "\"namespace\":\"\",\"type\":\"List\",\"key\":\"d\",\"operation\":\"RPUSH\",\
+                 \"params\":[\"79\"],\"result\":\"\"}\n\n"
+            ));
+        });
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_encode_event() {
+        let change = Change {
+            namespace: "ns".to_string(),
+            data_type: DataType::Map,
+            key: "k\"\n".to_string(),
+            operation: "HSET".to_string(),
+            params: encode_params([&b"f"[..], b"\x00\xff"].iter().copied()),
+            result: vec![1],
+        };
+        let mut buf = Vec::new();
+        encode_event(&mut buf, 7, &change.encode(1632268800000));
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "id: 7\nevent: change\ndata: {\"sequence\":7,\"timestamp\":1632268800000,\
+             \"namespace\":\"ns\",\"type\":\"HashMap\",\"key\":\"k\\\"\\u000a\",\"operation\":\"HSET\",\
+             \"params\":[\"66\",\"00ff\"],\"result\":\"01\"}\n\n"
+        );
+    }
+}
@@ -1,9 +1,20 @@
-use std::ops::Deref;
+use std::{ops::Deref, str::FromStr};
 
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
 use serde::Deserialize;
 
+use lodisdb::common::DataType;
+
+use crate::{acl::request_user, error::LodisError};
+
 pub const LODIS_KEY_MAP: &'static str = "@@@LODIS_KEY_MAP@@@";
 pub const LODIS_STRING_MAP: &'static str = "@@@LODIS_STRING_MAP@@@";
+// Namespaces which have keys, a namespace -> timestamp
+pub const LODIS_NAMESPACE_MAP: &'static str = "@@@LODIS_NAMESPACE_MAP@@@";
+
+// The header which selects the namespace of a request without the `/ns/{namespace}` route
+pub const NAMESPACE_HEADER: &str = "Lodis-Namespace";
 
 pub const SUCCESS: &'static [u8] = &[0];
 
@@ -22,7 +33,57 @@ impl Deref for KeyName {
     }
 }
 
-#[derive(Debug)]
+// The namespace of a request, from the `/ns/{namespace}` route or the `Lodis-Namespace` header
+//
+// The default namespace is "".
+#[derive(Debug, Clone, Default)]
+pub struct Namespace(pub String);
+
+impl Namespace {
+    pub fn from_request_head(request: &HttpRequest) -> Result<Namespace, LodisError> {
+        if let Some(namespace) = request.match_info().get("namespace") {
+            return Ok(Namespace(namespace.to_string()));
+        }
+        match request.headers().get(NAMESPACE_HEADER) {
+            Some(namespace) => namespace
+                .to_str()
+                .map(|namespace| Namespace(namespace.to_string()))
+                .map_err(|_| LodisError::ParamTypeError(format!("namespace: {:?}", namespace))),
+            None => Ok(Namespace::default()),
+        }
+    }
+}
+
+impl Deref for Namespace {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromRequest for Namespace {
+    type Error = LodisError;
+    type Future = Ready<Result<Namespace, LodisError>>;
+    type Config = ();
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Namespace::from_request_head(request).and_then(|namespace| {
+            if let Some(user) = request_user(request) {
+                user.check_namespace(&namespace)?;
+            }
+            Ok(namespace)
+        }))
+    }
+}
+
+// Query of `GET /changes/sse`
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    pub since: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Command {
     // List
     LPUSH,
@@ -37,11 +98,13 @@ pub enum Command {
     LLEN,
     LDEL,
     LRM,
+    LWATCH,
 
     // Map
     HGET,
     HSET,
     HSETNX,
+    HCAS,
     HGETALL,
     HMGET,
     HMSET,
@@ -52,6 +115,7 @@ pub enum Command {
     HDEL,
     HLEN,
     HRM,
+    HWATCH,
 
     // ArrayMap
     ALPUSH,
@@ -59,6 +123,7 @@ pub enum Command {
     ARPUSH,
     ARPUSHNX,
     AINCRBY,
+    ACAS,
     ALPOP,
     ARPOP,
     ARANDPOP,
@@ -73,4 +138,82 @@ pub enum Command {
     ALEN,
     ADEL,
     ARM,
+    AWATCH,
+}
+
+impl Command {
+    // The data type which the command operates on
+    pub fn data_type(&self) -> DataType {
+        use Command::*;
+        match self {
+            LPUSH | RPUSH | LPOP | RPOP | RANDPOP | LRANGE | RRANGE | LINDEX | LRAND | LLEN
+            | LDEL | LRM | LWATCH => DataType::List,
+            HGET | HSET | HSETNX | HCAS | HGETALL | HMGET | HMSET | HINCRBY | HKEYS | HVALS
+            | HEXISTS | HDEL | HLEN | HRM | HWATCH => DataType::Map,
+            ALPUSH | ALPUSHNX | ARPUSH | ARPUSHNX | AINCRBY | ACAS | ALPOP | ARPOP | ARANDPOP
+            | AGET | ARAND | ALRANGE | ARRANGE | AKEYS | AVALS | AALL | AEXISTS | ALEN | ADEL
+            | ARM | AWATCH => DataType::ArrayMap,
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = LodisError;
+
+    fn from_str(name: &str) -> Result<Command, LodisError> {
+        use Command::*;
+        let command = match name.to_ascii_uppercase().as_str() {
+            "LPUSH" => LPUSH,
+            "RPUSH" => RPUSH,
+            "LPOP" => LPOP,
+            "RPOP" => RPOP,
+            "RANDPOP" => RANDPOP,
+            "LRANGE" => LRANGE,
+            "RRANGE" => RRANGE,
+            "LINDEX" => LINDEX,
+            "LRAND" => LRAND,
+            "LLEN" => LLEN,
+            "LDEL" => LDEL,
+            "LRM" => LRM,
+            "LWATCH" => LWATCH,
+            "HGET" => HGET,
+            "HSET" => HSET,
+            "HSETNX" => HSETNX,
+            "HCAS" => HCAS,
+            "HGETALL" => HGETALL,
+            "HMGET" => HMGET,
+            "HMSET" => HMSET,
+            "HINCRBY" => HINCRBY,
+            "HKEYS" => HKEYS,
+            "HVALS" => HVALS,
+            "HEXISTS" => HEXISTS,
+            "HDEL" => HDEL,
+            "HLEN" => HLEN,
+            "HRM" => HRM,
+            "HWATCH" => HWATCH,
+            "ALPUSH" => ALPUSH,
+            "ALPUSHNX" => ALPUSHNX,
+            "ARPUSH" => ARPUSH,
+            "ARPUSHNX" => ARPUSHNX,
+            "AINCRBY" => AINCRBY,
+            "ACAS" => ACAS,
+            "ALPOP" => ALPOP,
+            "ARPOP" => ARPOP,
+            "ARANDPOP" => ARANDPOP,
+            "AGET" => AGET,
+            "ARAND" => ARAND,
+            "ALRANGE" => ALRANGE,
+            "ARRANGE" => ARRANGE,
+            "AKEYS" => AKEYS,
+            "AVALS" => AVALS,
+            "AALL" => AALL,
+            "AEXISTS" => AEXISTS,
+            "ALEN" => ALEN,
+            "ADEL" => ADEL,
+            "ARM" => ARM,
+            "AWATCH" => AWATCH,
+            _ => return Err(LodisError::UnknownCommand(name.to_string())),
+        };
+        Ok(command)
+    }
 }
@@ -1,4 +1,4 @@
-use std::env::VarError;
+use std::{env::VarError, io};
 
 use thiserror::Error as ThisError;
 
@@ -6,6 +6,8 @@ use actix_web::{error::PayloadError, ResponseError};
 
 use lodisdb::DBError;
 
+// The first byte of an error is its status code, which is `b'0'` plus the number of the error,
+// so every error has a distinct one-byte status code, e.g. `b'1'` for 1 and `b':'` for 10
 #[derive(Debug, ThisError)]
 pub enum LodisError {
     #[error("1{0}")]
@@ -20,11 +22,87 @@ pub enum LodisError {
     ParamNoMatch(String),
     #[error("6Type of parameter is not right, {0}")]
     ParamTypeError(String),
+    #[error("7Unknown command, {0}")]
+    UnknownCommand(String),
+    #[error("8Transaction is aborted, {0}")]
+    TransactionAborted(String),
+    #[error("9Watched data is changed, {0}")]
+    WatchedChanged(String),
+    #[error(":Script is not found, {0}")]
+    ScriptNotFound(String),
+    #[error(";Script error, {0}")]
+    ScriptError(String),
+    #[error("<Backup is not configured, set LODIS_BACKUP_PATH")]
+    BackupNotConfigured,
+    #[error("=Checkpoint is not configured, set LODIS_CHECKPOINT_PATH")]
+    CheckpointNotConfigured,
+    #[error(">Write-ahead log is truncated after sequence {0}")]
+    WalTruncated(u64),
+    #[error("?Replica is read-only")]
+    ReadOnly,
+    #[error("@Replication is not configured, set LODIS_WAL_TTL")]
+    ReplicationNotConfigured,
+    #[error("AChange data capture is not configured, set LODIS_CDC_KEEP")]
+    CdcNotConfigured,
+    #[error("BChanges are trimmed, the first change is {0}")]
+    ChangesTrimmed(u64),
+    #[error("CNo such key, {0}")]
+    NoSuchKey(String),
+    #[error("DKey holds another data type, {0}")]
+    WrongType(String),
+    #[error("EAuthentication is required, give the token of a user at the Authorization header")]
+    Unauthenticated,
+    #[error("FNo permission, {0}")]
+    NoPermission(String),
+}
+
+impl LodisError {
+    // The status code of the error, which is the first byte of its response content
+    pub fn status(&self) -> u8 {
+        self.to_string().as_bytes()[0]
+    }
+
+    // The number of the error, e.g. 20 of `WrongType`
+    pub fn code(&self) -> u8 {
+        self.status() - b'0'
+    }
+
+    // The name of the variant, e.g. `WrongType`
+    pub fn name(&self) -> &'static str {
+        match self {
+            LodisError::Error(_) => "Error",
+            LodisError::LodisdbError(_) => "LodisdbError",
+            LodisError::ConfigError => "ConfigError",
+            LodisError::ParseParamError => "ParseParamError",
+            LodisError::ParamNoMatch(_) => "ParamNoMatch",
+            LodisError::ParamTypeError(_) => "ParamTypeError",
+            LodisError::UnknownCommand(_) => "UnknownCommand",
+            LodisError::TransactionAborted(_) => "TransactionAborted",
+            LodisError::WatchedChanged(_) => "WatchedChanged",
+            LodisError::ScriptNotFound(_) => "ScriptNotFound",
+            LodisError::ScriptError(_) => "ScriptError",
+            LodisError::BackupNotConfigured => "BackupNotConfigured",
+            LodisError::CheckpointNotConfigured => "CheckpointNotConfigured",
+            LodisError::WalTruncated(_) => "WalTruncated",
+            LodisError::ReadOnly => "ReadOnly",
+            LodisError::ReplicationNotConfigured => "ReplicationNotConfigured",
+            LodisError::CdcNotConfigured => "CdcNotConfigured",
+            LodisError::ChangesTrimmed(_) => "ChangesTrimmed",
+            LodisError::NoSuchKey(_) => "NoSuchKey",
+            LodisError::WrongType(_) => "WrongType",
+            LodisError::Unauthenticated => "Unauthenticated",
+            LodisError::NoPermission(_) => "NoPermission",
+        }
+    }
 }
 
 impl From<DBError> for LodisError {
     fn from(err: DBError) -> LodisError {
-        LodisError::LodisdbError(err.to_string())
+        match err {
+            // Replicas resync when they see this error
+            DBError::WalTruncated(sequence) => LodisError::WalTruncated(sequence),
+            _ => LodisError::LodisdbError(err.to_string()),
+        }
     }
 }
 
@@ -34,6 +112,15 @@ impl From<VarError> for LodisError {
     }
 }
 
+impl From<io::Error> for LodisError {
+    fn from(err: io::Error) -> LodisError {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => LodisError::Error("Unexpected end of file".to_string()),
+            _ => LodisError::Error(err.to_string()),
+        }
+    }
+}
+
 impl From<PayloadError> for LodisError {
     fn from(err: PayloadError) -> LodisError {
         LodisError::Error(format!("{:?}", err))
@@ -43,3 +130,44 @@ impl From<PayloadError> for LodisError {
 impl ResponseError for LodisError {}
 
 pub type Result<T, E = LodisError> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod test_error {
+    use super::LodisError;
+
+    #[test]
+    fn test_status() {
+        let s = || "s".to_string();
+        let errors = vec![
+            LodisError::Error(s()),
+            LodisError::LodisdbError(s()),
+            LodisError::ConfigError,
+            LodisError::ParseParamError,
+            LodisError::ParamNoMatch(s()),
+            LodisError::ParamTypeError(s()),
+            LodisError::UnknownCommand(s()),
+            LodisError::TransactionAborted(s()),
+            LodisError::WatchedChanged(s()),
+            LodisError::ScriptNotFound(s()),
+            LodisError::ScriptError(s()),
+            LodisError::BackupNotConfigured,
+            LodisError::CheckpointNotConfigured,
+            LodisError::WalTruncated(1),
+            LodisError::ReadOnly,
+            LodisError::ReplicationNotConfigured,
+            LodisError::CdcNotConfigured,
+            LodisError::ChangesTrimmed(1),
+            LodisError::NoSuchKey(s()),
+            LodisError::WrongType(s()),
+            LodisError::Unauthenticated,
+            LodisError::NoPermission(s()),
+        ];
+        // Every error has its own status code, which is not the byte of success
+        for (i, err) in errors.iter().enumerate() {
+            assert_eq!(err.code() as usize, i + 1, "{}", err.name());
+            assert_ne!(err.status(), 0);
+        }
+        assert_eq!(LodisError::WrongType(s()).status(), b'D');
+        assert_eq!(LodisError::LodisdbError(s()).status(), b'2');
+    }
+}
@@ -0,0 +1,558 @@
+use std::{
+    io::{self, Read, Write},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use lodisdb::{
+    common::{DataType, Direction},
+    u32_to_u8x4, u64_to_u8x8, u8x4_to_u32, ArrayMap, Batch, List, LodisData, Map, DB,
+};
+
+use crate::{
+    common::{LODIS_KEY_MAP, LODIS_NAMESPACE_MAP, LODIS_STRING_MAP},
+    error::{LodisError, Result},
+};
+
+// Export format
+//
+// All integers are big-endian unsign.
+//
+// Header:  8bytes magic `LODISEXP` + 4bytes version
+// Record:  1byte data type + 4bytes length of name + name + 4bytes number of elements + elements
+//          List, String element:       4bytes length of value + value
+//          HashMap, ArrayMap element:  4bytes length of field + field + 4bytes length of value + value
+// Namespace: 1byte 5 + 4bytes length of namespace + namespace
+// End:     1byte 0
+//
+// Data type: 1 is List, 2 is HashMap, 3 is ArrayMap, 4 is String.
+// Elements of List and ArrayMap are in order from left to right. String has one element.
+// Records are in the namespace of the last namespace record before them, or in the default
+// namespace without it. Version 1 has no namespace records.
+pub const EXPORT_MAGIC: &[u8; 8] = b"LODISEXP";
+pub const EXPORT_VERSION: u32 = 2;
+
+const RECORD_END: u8 = 0;
+const RECORD_LIST: u8 = 1;
+const RECORD_MAP: u8 = 2;
+const RECORD_ARRAYMAP: u8 = 3;
+const RECORD_STRING: u8 = 4;
+const RECORD_NAMESPACE: u8 = 5;
+
+// Most elements allocated for a record before they are read, so a broken number of elements
+// does not allocate more than the export
+const PREALLOCATED_ELEMENTS: usize = 1024;
+
+// All elements of one data
+pub enum Record {
+    List(Vec<Vec<u8>>),
+    Map(Vec<(Vec<u8>, Vec<u8>)>),
+    ArrayMap(Vec<(Vec<u8>, Vec<u8>)>),
+    String(Vec<u8>),
+}
+
+impl Record {
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Record::List(_) => DataType::List,
+            Record::Map(_) => DataType::Map,
+            Record::ArrayMap(_) => DataType::ArrayMap,
+            Record::String(_) => DataType::String,
+        }
+    }
+
+    fn record_type(&self) -> u8 {
+        match self {
+            Record::List(_) => RECORD_LIST,
+            Record::Map(_) => RECORD_MAP,
+            Record::ArrayMap(_) => RECORD_ARRAYMAP,
+            Record::String(_) => RECORD_STRING,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Record::List(values) => values.is_empty(),
+            Record::Map(pairs) | Record::ArrayMap(pairs) => pairs.is_empty(),
+            Record::String(_) => false,
+        }
+    }
+
+    // Read the data from the db, return None if the string is not at the string map
+    //
+    // Strings are not in namespaces, all of them are at the string map.
+    fn read(
+        db: &Arc<DB>,
+        namespace: &str,
+        data_type: DataType,
+        name: &str,
+        batch: &Batch,
+    ) -> Result<Option<Record>> {
+        let record = match data_type {
+            DataType::List => {
+                let list = List::new_in(namespace, name.to_string(), db.clone());
+                let values = list.all_with_batch(batch)?;
+                Record::List(values.iter().map(|value| value.to_vec()).collect())
+            }
+            DataType::Map => {
+                let map = Map::new_in(namespace, name.to_string(), db.clone());
+                let pairs = map.all_with_batch(batch)?;
+                Record::Map(
+                    pairs
+                        .iter()
+                        .map(|(field, value)| (field.to_vec(), value.to_vec()))
+                        .collect(),
+                )
+            }
+            DataType::ArrayMap => {
+                let arraymap = ArrayMap::new_in(namespace, name.to_string(), db.clone());
+                let length = arraymap.length_with_batch(batch)?;
+                let pairs = arraymap.range_with_batch(0, length, Direction::Forward, batch)?;
+                Record::ArrayMap(
+                    pairs
+                        .iter()
+                        .map(|(field, value)| (field.to_vec(), value.to_vec()))
+                        .collect(),
+                )
+            }
+            DataType::String => {
+                let string_map = Map::new(LODIS_STRING_MAP.to_string(), db.clone());
+                match string_map.get_with_batch(name, batch)? {
+                    Some(value) => Record::String(value.to_vec()),
+                    None => return Ok(None),
+                }
+            }
+            DataType::Set => {
+                return Err(LodisError::Error(format!(
+                    "Set data is not supported, {}",
+                    name
+                )))
+            }
+        };
+        Ok(Some(record))
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, name: &str) -> Result<()> {
+        writer.write_all(&[self.record_type()])?;
+        write_bytes(writer, name.as_bytes())?;
+        match self {
+            Record::List(values) => {
+                writer.write_all(&u32_to_u8x4(values.len() as u32))?;
+                for value in values.iter() {
+                    write_bytes(writer, value)?;
+                }
+            }
+            Record::Map(pairs) | Record::ArrayMap(pairs) => {
+                writer.write_all(&u32_to_u8x4(pairs.len() as u32))?;
+                for (field, value) in pairs.iter() {
+                    write_bytes(writer, field)?;
+                    write_bytes(writer, value)?;
+                }
+            }
+            Record::String(value) => {
+                writer.write_all(&u32_to_u8x4(1))?;
+                write_bytes(writer, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Read a record after its record type, return its name and the record
+    fn parse<R: Read>(reader: &mut R, record_type: u8) -> Result<(String, Record)> {
+        let name = String::from_utf8(read_bytes(reader)?)
+            .map_err(|_| LodisError::Error("Name is not utf8".to_string()))?;
+        let num = read_u32(reader)? as usize;
+        let record = match record_type {
+            RECORD_LIST => {
+                let mut values = Vec::with_capacity(num.min(PREALLOCATED_ELEMENTS));
+                for _ in 0..num {
+                    values.push(read_bytes(reader)?);
+                }
+                Record::List(values)
+            }
+            RECORD_MAP | RECORD_ARRAYMAP => {
+                let mut pairs = Vec::with_capacity(num.min(PREALLOCATED_ELEMENTS));
+                for _ in 0..num {
+                    let field = read_bytes(reader)?;
+                    let value = read_bytes(reader)?;
+                    pairs.push((field, value));
+                }
+                if record_type == RECORD_MAP {
+                    Record::Map(pairs)
+                } else {
+                    Record::ArrayMap(pairs)
+                }
+            }
+            RECORD_STRING => {
+                if num != 1 {
+                    return Err(LodisError::Error(format!(
+                        "String {} has {} elements",
+                        name, num
+                    )));
+                }
+                Record::String(read_bytes(reader)?)
+            }
+            other => {
+                return Err(LodisError::Error(format!("Unknown record type {}", other)));
+            }
+        };
+        Ok((name, record))
+    }
+}
+
+// Write the record to the namespace, the existing data with the same name and data type is
+// replaced
+pub fn write_record(db: &Arc<DB>, namespace: &str, name: &str, record: &Record) -> Result<()> {
+    let mut batch = Batch::new(db.clone());
+    match record {
+        Record::List(values) => {
+            let list = List::new_in(namespace, name.to_string(), db.clone());
+            list.remove_with_batch(&mut batch)?;
+            list.push_with_batch(values, &mut batch)?;
+        }
+        Record::Map(pairs) => {
+            let map = Map::new_in(namespace, name.to_string(), db.clone());
+            map.remove_with_batch(&mut batch)?;
+            for (field, value) in pairs.iter() {
+                map.set_with_batch(field, value, &mut batch)?;
+            }
+        }
+        Record::ArrayMap(pairs) => {
+            let arraymap = ArrayMap::new_in(namespace, name.to_string(), db.clone());
+            arraymap.remove_with_batch(&mut batch)?;
+            arraymap.push_with_batch(pairs, &mut batch)?;
+        }
+        Record::String(value) => {
+            let string_map = Map::new(LODIS_STRING_MAP.to_string(), db.clone());
+            string_map.set_with_batch(name, value, &mut batch)?;
+        }
+    }
+
+    // Same structure as `GlobalState::add_key_with_batch`
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let key_map = Map::new_in(namespace, LODIS_KEY_MAP.to_string(), db.clone());
+    key_map.setnx_with_batch(
+        [&record.data_type().flag()[..], name.as_bytes()].concat(),
+        &u64_to_u8x8(now)[..],
+        &mut batch,
+    )?;
+    if !namespace.is_empty() {
+        let namespace_map = Map::new(LODIS_NAMESPACE_MAP.to_string(), db.clone());
+        namespace_map.setnx_with_batch(namespace, &u64_to_u8x8(now)[..], &mut batch)?;
+    }
+    batch.commit()?;
+    Ok(())
+}
+
+// The data type of a data type flag at `LODIS_KEY_MAP`
+fn data_type(flag: u8) -> Option<DataType> {
+    [
+        DataType::List,
+        DataType::Map,
+        DataType::ArrayMap,
+        DataType::Set,
+        DataType::String,
+    ]
+    .iter()
+    .find(|data_type| data_type.flag()[0] == flag)
+    .copied()
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    writer.write_all(&u32_to_u8x4(bytes.len() as u32))?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf: [u8; 4] = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u8x4_to_u32(&buf))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    // The buffer grows as it is read, a broken length does not allocate more than the export
+    let mut buf = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buf)
+}
+
+// Write all data recorded at `LODIS_KEY_MAP` of all namespaces to `writer`, return the number
+// of records
+//
+// All data are read from one snapshot of the db.
+pub fn export<W: Write>(db: Arc<DB>, writer: &mut W) -> Result<usize> {
+    writer.write_all(EXPORT_MAGIC)?;
+    writer.write_all(&u32_to_u8x4(EXPORT_VERSION))?;
+
+    let namespace_map = Map::new(LODIS_NAMESPACE_MAP.to_string(), db.clone());
+    let count = Batch::read(&db, |batch| -> Result<usize> {
+        let mut namespaces = vec![String::new()];
+        for namespace in namespace_map.keys_with_batch(batch)? {
+            namespaces.push(String::from_utf8_lossy(&namespace).into_owned());
+        }
+        let mut count = 0;
+        for namespace in namespaces.iter() {
+            writer.write_all(&[RECORD_NAMESPACE])?;
+            write_bytes(writer, namespace.as_bytes())?;
+            count += export_namespace(&db, namespace, writer, batch)?;
+        }
+        Ok(count)
+    })?;
+
+    writer.write_all(&[RECORD_END])?;
+    writer.flush()?;
+    Ok(count)
+}
+
+fn export_namespace<W: Write>(
+    db: &Arc<DB>,
+    namespace: &str,
+    writer: &mut W,
+    batch: &Batch,
+) -> Result<usize> {
+    let key_map = Map::new_in(namespace, LODIS_KEY_MAP.to_string(), db.clone());
+    let mut count = 0;
+    for key in key_map.keys_with_batch(batch)? {
+        if key.is_empty() {
+            continue;
+        }
+        let data_type = data_type(key[0]).ok_or_else(|| {
+            LodisError::Error(format!(
+                "Unknown data type {} of key {:?}",
+                key[0],
+                &key[1..]
+            ))
+        })?;
+        let name = String::from_utf8(key[1..].to_vec())
+            .map_err(|_| LodisError::Error(format!("Name is not utf8: {:?}", &key[1..])))?;
+
+        // Removed data are still recorded at `LODIS_KEY_MAP`
+        let record = match Record::read(db, namespace, data_type, &name, batch)? {
+            Some(record) if !record.is_empty() => record,
+            _ => continue,
+        };
+        record.write(writer, &name)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Read records from `reader` and write them to the db, return the number of records
+//
+// Existing data with the same name and data type are replaced.
+pub fn import<R: Read>(db: Arc<DB>, reader: &mut R) -> Result<usize> {
+    let mut magic: [u8; 8] = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != EXPORT_MAGIC {
+        return Err(LodisError::Error("Not a lodis export".to_string()));
+    }
+    let version = read_u32(reader)?;
+    if version == 0 || version > EXPORT_VERSION {
+        return Err(LodisError::Error(format!(
+            "Export version {} is not supported, supported versions are 1 to {}",
+            version, EXPORT_VERSION
+        )));
+    }
+
+    let mut namespace = String::new();
+    let mut count = 0;
+    loop {
+        let mut record_type: [u8; 1] = [0; 1];
+        reader.read_exact(&mut record_type)?;
+        if record_type[0] == RECORD_END {
+            break;
+        }
+        if record_type[0] == RECORD_NAMESPACE {
+            namespace = String::from_utf8(read_bytes(reader)?)
+                .map_err(|_| LodisError::Error("Namespace is not utf8".to_string()))?;
+            continue;
+        }
+        let (name, record) = Record::parse(reader, record_type[0])?;
+        write_record(&db, &namespace, &name, &record)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test_export {
+    use std::{fs, sync::Arc};
+
+    use lodisdb::{make_db, Batch, Map, DB};
+
+    use super::*;
+
+    fn records() -> Vec<(&'static str, &'static str, Record)> {
+        let bytes = |values: &[&str]| -> Vec<Vec<u8>> {
+            values.iter().map(|v| v.as_bytes().to_vec()).collect()
+        };
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(Vec<u8>, Vec<u8>)> {
+            pairs
+                .iter()
+                .map(|(f, v)| (f.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect()
+        };
+        vec![
+            ("", "list", Record::List(bytes(&["c", "a", "b", ""]))),
+            ("", "map", Record::Map(pairs(&[("f1", "v1"), ("f2", "")]))),
+            (
+                "",
+                "arraymap",
+                Record::ArrayMap(pairs(&[("z", "1"), ("a", "2"), ("m", "3")])),
+            ),
+            ("", "string", Record::String(b"\x00\xffvalue".to_vec())),
+            // The same name of several data types
+            ("", "list", Record::Map(pairs(&[("f", "v")]))),
+            ("ns1", "list", Record::List(bytes(&["x"]))),
+            ("ns1", "arraymap", Record::ArrayMap(pairs(&[("b", "1")]))),
+            ("ns2", "map", Record::Map(pairs(&[("f", "v")]))),
+        ]
+    }
+
+    // Encode the record in the export format, for comparisons
+    fn encode(record: &Record) -> Vec<u8> {
+        let mut buf = Vec::new();
+        record.write(&mut buf, "").unwrap();
+        buf
+    }
+
+    fn key_map(db: &Arc<DB>, namespace: &str) -> Vec<Vec<u8>> {
+        let key_map = Map::new_in(namespace, LODIS_KEY_MAP.to_string(), db.clone());
+        let mut keys: Vec<Vec<u8>> = key_map.keys().unwrap().iter().map(|k| k.to_vec()).collect();
+        keys.sort();
+        keys
+    }
+
+    fn namespaces(db: &Arc<DB>) -> Vec<Vec<u8>> {
+        let namespace_map = Map::new(LODIS_NAMESPACE_MAP.to_string(), db.clone());
+        let mut namespaces: Vec<Vec<u8>> = namespace_map
+            .keys()
+            .unwrap()
+            .iter()
+            .map(|k| k.to_vec())
+            .collect();
+        namespaces.sort();
+        namespaces
+    }
+
+    fn export_records() -> Vec<u8> {
+        let path = "test-export-db0";
+        let mut buf = Vec::new();
+        {
+            let db = Arc::new(make_db(path));
+            for (namespace, name, record) in records().iter() {
+                write_record(&db, namespace, name, record).unwrap();
+            }
+            export(db, &mut buf).unwrap();
+        }
+        let _ = fs::remove_dir_all(path);
+        buf
+    }
+
+    #[test]
+    fn test_export_import() {
+        let src = "test-export-db1";
+        let dst = "test-export-db2";
+        {
+            let db = Arc::new(make_db(src));
+            for (namespace, name, record) in records().iter() {
+                write_record(&db, namespace, name, record).unwrap();
+            }
+            // Removed data are still recorded at the key map, but are not exported
+            write_record(&db, "", "removed", &Record::List(vec![b"a".to_vec()])).unwrap();
+            let mut batch = Batch::new(db.clone());
+            List::new("removed".to_string(), db.clone())
+                .remove_with_batch(&mut batch)
+                .unwrap();
+            batch.commit().unwrap();
+
+            let mut buf = Vec::new();
+            assert_eq!(export(db.clone(), &mut buf).unwrap(), records().len());
+            assert_eq!(&buf[..8], EXPORT_MAGIC);
+
+            let imported = Arc::new(make_db(dst));
+            assert_eq!(
+                import(imported.clone(), &mut &buf[..]).unwrap(),
+                records().len()
+            );
+
+            for (namespace, name, record) in records().iter() {
+                let read = Batch::read(&imported, |batch| {
+                    Record::read(&imported, namespace, record.data_type(), name, batch)
+                })
+                .unwrap()
+                .unwrap();
+                assert_eq!(encode(&read), encode(record), "{} {}", namespace, name);
+            }
+            for namespace in ["", "ns1", "ns2"].iter() {
+                let mut keys = key_map(&db, namespace);
+                if namespace.is_empty() {
+                    keys.retain(|key| &key[1..] != b"removed");
+                }
+                assert_eq!(key_map(&imported, namespace), keys, "{}", namespace);
+            }
+            let mut keys = vec![
+                [&DataType::List.flag()[..], b"list"].concat(),
+                [&DataType::ArrayMap.flag()[..], b"arraymap"].concat(),
+            ];
+            keys.sort();
+            assert_eq!(key_map(&imported, "ns1"), keys);
+            assert_eq!(
+                namespaces(&imported),
+                vec![b"ns1".to_vec(), b"ns2".to_vec()]
+            );
+
+            // The import of an export is the same export
+            let mut exported = Vec::new();
+            export(imported, &mut exported).unwrap();
+            assert_eq!(exported, buf);
+        }
+        let _ = fs::remove_dir_all(src);
+        let _ = fs::remove_dir_all(dst);
+    }
+
+    #[test]
+    fn test_import_truncated() {
+        let buf = export_records();
+        let path = "test-export-db3";
+        {
+            let db = Arc::new(make_db(path));
+            for len in 0..buf.len() {
+                assert!(
+                    import(db.clone(), &mut &buf[..len]).is_err(),
+                    "length: {}",
+                    len
+                );
+            }
+            assert!(import(db.clone(), &mut &buf[..]).is_ok());
+
+            // Broken lengths fail without allocating them
+            let mut broken = buf[..12].to_vec();
+            broken.push(RECORD_LIST);
+            broken.extend_from_slice(&u32_to_u8x4(u32::MAX));
+            assert!(import(db.clone(), &mut &broken[..]).is_err());
+            let mut broken = buf[..12].to_vec();
+            broken.push(RECORD_LIST);
+            broken.extend_from_slice(&u32_to_u8x4(1));
+            broken.push(b'l');
+            broken.extend_from_slice(&u32_to_u8x4(u32::MAX));
+            assert!(import(db.clone(), &mut &broken[..]).is_err());
+
+            let mut broken = buf.clone();
+            broken[0] = b'X';
+            assert!(import(db.clone(), &mut &broken[..]).is_err());
+            let mut broken = buf.clone();
+            broken[8..12].copy_from_slice(&u32_to_u8x4(EXPORT_VERSION + 1));
+            assert!(import(db.clone(), &mut &broken[..]).is_err());
+        }
+        let _ = fs::remove_dir_all(path);
+    }
+}
@@ -1,14 +1,31 @@
+use std::{
+    cell::Cell,
+    sync::{Arc, MutexGuard},
+};
+
 use lodisdb::{
     common::{DataType, Direction},
-    siphash, u32_to_u8x4, u8x4_to_u32, u8x8_to_i64, ArrayMap, List, LodisData, Map,
+    create_backup, db_properties, list_backups, u32_to_u8x4, u64_to_u8x8, u8x4_to_u32, u8x8_to_i64,
+    u8x8_to_u64, updates_since, ArrayMap, BackupInfo, Batch, List, LodisData, Map,
 };
 
-use actix_web::{web, HttpResponse};
+use actix_http::ws::handshake;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::{future, stream, StreamExt};
+use rhai::AST;
 
 use crate::{
-    common::{Command, KeyName, PRIME, SUCCESS},
+    acl::{check_key, request_user, User},
+    cdc::{encode_params, stream_changes, stream_start, Change, StreamFormat},
+    common::{ChangesQuery, Command, KeyName, Namespace, PRIME, SUCCESS},
     error::{LodisError, Result},
+    logging::ParamSizes,
+    pubsub::{serve_ws, Subscription, Target},
+    replication::{encode_updates, stream_snapshot, MAX_UPDATES_SIZE},
+    script::{self, data_type_name, parse_data_type},
+    slowlog::{RequestBody, SlowEntry},
     state::GlobalState,
+    utils::glob_match,
 };
 
 pub async fn parse_params(body: web::Bytes) -> Result<Vec<web::BytesMut>> {
@@ -18,36 +35,1808 @@ pub async fn parse_params(body: web::Bytes) -> Result<Vec<web::BytesMut>> {
     let mut buf: [u8; 4] = [0; 4];
     let mut index = 0;
 
-    loop {
-        if index == n {
-            break;
-        }
+    loop {
+        if index == n {
+            break;
+        }
+
+        if index + 4 > n {
+            return Err(LodisError::ParseParamError);
+        }
+        buf.clone_from_slice(&body[index..index + 4]);
+
+        let end = u8x4_to_u32(&buf) as usize;
+        if index + 4 + end > n {
+            return Err(LodisError::ParseParamError);
+        }
+        params.push(web::BytesMut::from(&body[index + 4..index + 4 + end]));
+        index += 4 + end;
+    }
+    Ok(params)
+}
+
+pub async fn handle(
+    body: web::Bytes,
+    key: web::Path<KeyName>,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+    command: Command,
+) -> Result<HttpResponse> {
+    let key: &str = &key;
+    check_key(&request, key)?;
+    request.extensions_mut().insert(RequestBody(body.clone()));
+    let params = parse_params(body).await?;
+    request
+        .extensions_mut()
+        .insert(ParamSizes(params.iter().map(|param| param.len()).collect()));
+    let namespace: &str = &namespace;
+
+    global_state.stats.record_commands(std::iter::once(command));
+    let mut values = run_commands(
+        &global_state,
+        namespace,
+        &[(command, key.to_string(), params)],
+        |_, _, err| err,
+    )?;
+    Ok(HttpResponse::Ok().body(values.pop().unwrap()))
+}
+
+// Execute several commands in one transaction
+//
+// The body is a list of params which every 3 params are one command:
+// command name, key, the params of the command encoded as the body of the command.
+// All commands are written to db atomically. If any of them fails, nothing is written.
+//
+// The response content is the response contents of the commands, each of them is
+// prefixed with its length.
+pub async fn handle_multi(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    request.extensions_mut().insert(RequestBody(body.clone()));
+    let frames = parse_params(body).await?;
+    if frames.len() % 3 != 0 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: MULTI, params: {:?}",
+            &frames
+        )));
+    }
+
+    let user = request_user(&request);
+    let mut commands = Vec::new();
+    for frame in frames.chunks(3) {
+        commands.push(parse_frame(frame, &user).await?);
+    }
+    global_state
+        .stats
+        .record_commands(commands.iter().map(|(command, _, _)| *command));
+
+    let values = run_commands(&global_state, namespace, &commands, |i, command, err| {
+        match err {
+            // Clients retry the transaction when watched data is changed
+            LodisError::WatchedChanged(_) => err,
+            _ => LodisError::TransactionAborted(format!("command {}, {:?}: {}", i, command, err)),
+        }
+    })?;
+    let mut content = SUCCESS.to_vec();
+    for value in values {
+        content.extend_from_slice(&u32_to_u8x4(value.len() as u32));
+        content.extend_from_slice(&value);
+    }
+    Ok(HttpResponse::Ok().body(content))
+}
+
+// Execute several commands in order, each of them succeeds or fails on its own
+//
+// The body is the same as the body of MULTI, and is at most `LODIS_PIPELINE_MAX_SIZE` bytes.
+// Unlike MULTI, the commands are not atomic, a failed command does not stop the commands after
+// it, and the writes of the commands before it are kept. Consecutive commands are written to db
+// in batches, see `run_pipeline`.
+//
+// The response content is the response contents of the commands, each of them is
+// prefixed with its length. The response content of a failed command is its error.
+pub async fn handle_pipeline(
+    payload: web::Payload,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let body = read_body(payload, global_state.pipeline_max_size).await?;
+    request.extensions_mut().insert(RequestBody(body.clone()));
+    let frames = parse_params(body).await?;
+    if frames.len() % 3 != 0 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: PIPELINE, params: {:?}",
+            &frames
+        )));
+    }
+
+    let user = request_user(&request);
+    let mut values = Vec::with_capacity(frames.len() / 3);
+    let mut commands = Vec::new();
+    for frame in frames.chunks(3) {
+        match parse_frame(frame, &user).await {
+            Ok(command) => {
+                global_state
+                    .stats
+                    .record_commands(std::iter::once(command.0));
+                commands.push(command);
+            }
+            Err(err) => {
+                values.extend(run_pipeline(&global_state, namespace, &commands));
+                commands.clear();
+                values.push(Err(err));
+            }
+        }
+    }
+    values.extend(run_pipeline(&global_state, namespace, &commands));
+
+    let mut content = SUCCESS.to_vec();
+    for value in values {
+        let value = value.unwrap_or_else(|err| err.to_string().into_bytes());
+        content.extend_from_slice(&u32_to_u8x4(value.len() as u32));
+        content.extend_from_slice(&value);
+    }
+    Ok(HttpResponse::Ok().body(content))
+}
+
+// Read the body of a request, which is at most `limit` bytes
+async fn read_body(mut payload: web::Payload, limit: usize) -> Result<web::Bytes> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(LodisError::Error(format!(
+                "Body is larger than {} bytes",
+                limit
+            )));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+// Most commands of a pipeline written to db in one batch
+const PIPELINE_BATCH: usize = 1024;
+
+// Execute the commands of a pipeline and return the response content or the error of each of them
+//
+// Consecutive commands are written to db in batches of at most `PIPELINE_BATCH` commands. If a
+// command fails, the commands before it are executed again in one batch without it, then it is
+// executed on its own and its error is kept. After a failure, batches start from one command and
+// grow twice as large with every batch written, so consecutive failures do not execute the
+// commands after them again. If a batch fails but not any command of it, e.g. writes on a
+// replica, its commands are executed one by one.
+fn run_pipeline(
+    global_state: &GlobalState,
+    namespace: &str,
+    commands: &[(Command, String, Vec<web::BytesMut>)],
+) -> Vec<Result<Vec<u8>>> {
+    let mut values = Vec::with_capacity(commands.len());
+    let mut start = 0;
+    let mut size = PIPELINE_BATCH;
+    while start < commands.len() {
+        let end = commands.len().min(start + size);
+        let failed = Cell::new(None);
+        match run_commands(
+            global_state,
+            namespace,
+            &commands[start..end],
+            |i, _, err| {
+                failed.set(Some(i));
+                err
+            },
+        ) {
+            Ok(batch_values) => {
+                values.extend(batch_values.into_iter().map(Ok));
+                start = end;
+                size = PIPELINE_BATCH.min(size * 2);
+            }
+            Err(err) => match failed.get() {
+                Some(0) => {
+                    values.push(Err(err));
+                    start += 1;
+                    size = 1;
+                }
+                Some(i) => size = i,
+                None if size == 1 => {
+                    values.push(Err(err));
+                    start += 1;
+                }
+                None => size = 1,
+            },
+        }
+    }
+    values
+}
+
+// Parse a frame of MULTI or PIPELINE, which is command name, key and the params of the command,
+// and check it against the user of the request
+async fn parse_frame(
+    frame: &[web::BytesMut],
+    user: &Option<Arc<User>>,
+) -> Result<(Command, String, Vec<web::BytesMut>)> {
+    let command = std::str::from_utf8(&frame[0])
+        .map_err(|_| LodisError::UnknownCommand(format!("{:?}", &frame[0])))?
+        .parse::<Command>()?;
+    let key = String::from_utf8(frame[1].to_vec())
+        .map_err(|_| LodisError::ParamNoMatch(format!("key: {:?}", &frame[1])))?;
+    if let Some(user) = user {
+        user.check_command(&format!("{:?}", command))?;
+        user.check_key(&key)?;
+    }
+    let params = parse_params(web::Bytes::copy_from_slice(&frame[2])).await?;
+    Ok((command, key, params))
+}
+
+// Execute the commands on keys in one transaction and return the response content of each of them
+//
+// All commands are written to db atomically. If any of them fails, nothing is written and the
+// error is made by `map_err` from the index of the command, the command and its error.
+//
+// The commands are counted by the callers, so a command executed again by PIPELINE is counted once.
+pub fn run_commands<F>(
+    global_state: &GlobalState,
+    namespace: &str,
+    commands: &[(Command, String, Vec<web::BytesMut>)],
+    map_err: F,
+) -> Result<Vec<Vec<u8>>>
+where
+    F: Fn(usize, Command, LodisError) -> LodisError,
+{
+    let mut indexes: Vec<usize> = commands
+        .iter()
+        .map(|(command, key, _)| lock_index(global_state, namespace, command.data_type(), key))
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    let lock_indexes: Vec<usize> = commands
+        .iter()
+        .flat_map(|(command, key, _)| {
+            type_lock_indexes(global_state, namespace, command.data_type(), key)
+        })
+        .collect();
+
+    let mut batch = Batch::new(global_state.db.clone());
+    let mut values = Vec::with_capacity(commands.len());
+    let mut changes = Vec::new();
+    let mut written = Vec::new();
+    {
+        let locks = global_state.lock_all(&lock_indexes);
+        for (i, (command, key, params)) in commands.iter().enumerate() {
+            let writes = batch.writes();
+            let value = execute(global_state, namespace, &mut batch, *command, key, params)
+                .map_err(|err| map_err(i, *command, err))?;
+            if batch.writes() > writes {
+                written.push((command.data_type(), key.as_str()));
+                if global_state.cdc.is_some() {
+                    changes.push(Change::from_command(
+                        *command, namespace, key, params, &value,
+                    ));
+                }
+            }
+            values.push(value);
+        }
+        let changed = !batch.is_empty();
+        if changed && global_state.read_only {
+            return Err(LodisError::ReadOnly);
+        }
+        let key_map_lock = stage_key_records(global_state, namespace, &mut batch, &written)?;
+        global_state.commit(batch, &changes)?;
+        if changed {
+            global_state.increase_versions(&indexes);
+        }
+        drop(key_map_lock);
+        drop(locks);
+    }
+    Ok(values)
+}
+
+// Run a script and cache it
+//
+// Params: script, the number of keys, data type and name of each key, arguments...
+pub async fn handle_eval(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    request.extensions_mut().insert(RequestBody(body.clone()));
+    let params = parse_params(body).await?;
+    if params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: EVAL, params: {:?}",
+            &params
+        )));
+    }
+
+    let (_, ast) = global_state.scripts.load(&params[0])?;
+    run_script(&global_state, namespace, &request, &ast, &params[1..])
+}
+
+// Run a cached script by its hash
+//
+// Params: script hash, the number of keys, data type and name of each key, arguments...
+pub async fn handle_evalsha(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    request.extensions_mut().insert(RequestBody(body.clone()));
+    let params = parse_params(body).await?;
+    if params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: EVALSHA, params: {:?}",
+            &params
+        )));
+    }
+
+    let hash = String::from_utf8_lossy(&params[0]);
+    let ast = global_state
+        .scripts
+        .get(&hash)
+        .ok_or_else(|| LodisError::ScriptNotFound(hash.to_string()))?;
+    run_script(&global_state, namespace, &request, &ast, &params[1..])
+}
+
+// Cache a script and return its hash
+pub async fn handle_scriptload(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    if params.len() != 1 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: SCRIPTLOAD, params: {:?}",
+            &params
+        )));
+    }
+
+    let (hash, _) = global_state.scripts.load(&params[0])?;
+    Ok(HttpResponse::Ok().body([SUCCESS, hash.as_bytes()].concat()))
+}
+
+fn run_script(
+    global_state: &GlobalState,
+    namespace: &str,
+    request: &HttpRequest,
+    ast: &AST,
+    params: &[web::BytesMut],
+) -> Result<HttpResponse> {
+    if params.is_empty() || params[0].len() != 4 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "script params: {:?}",
+            params
+        )));
+    }
+    let mut buf: [u8; 4] = [0; 4];
+    buf.clone_from_slice(&params[0]);
+    let key_num = u8x4_to_u32(&buf) as usize;
+    if params.len() < 1 + key_num * 2 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "script params: {:?}",
+            params
+        )));
+    }
+
+    let mut keys = Vec::new();
+    for pair in params[1..1 + key_num * 2].chunks(2) {
+        let data_type = parse_data_type(&pair[0])?;
+        let key = String::from_utf8(pair[1].to_vec())
+            .map_err(|_| LodisError::ParamNoMatch(format!("key: {:?}", &pair[1])))?;
+        check_key(request, &key)?;
+        keys.push((data_type, key));
+    }
+    let args: Vec<&[u8]> = params[1 + key_num * 2..].iter().map(|p| &p[..]).collect();
+
+    let mut indexes: Vec<usize> = keys
+        .iter()
+        .map(|(data_type, key)| lock_index(global_state, namespace, *data_type, key))
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    let lock_indexes: Vec<usize> = keys
+        .iter()
+        .flat_map(|(data_type, key)| type_lock_indexes(global_state, namespace, *data_type, key))
+        .collect();
+
+    let content = {
+        let locks = global_state.lock_all(&lock_indexes);
+        Batch::read(&global_state.db, |batch| -> Result<()> {
+            for (data_type, key) in keys.iter() {
+                check_type(global_state, namespace, batch, *data_type, key)?;
+            }
+            Ok(())
+        })?;
+        let (content, mut batch, written) =
+            script::run(global_state.db.clone(), namespace, ast, &keys, &args)?;
+        let changed = !batch.is_empty();
+        if changed && global_state.read_only {
+            return Err(LodisError::ReadOnly);
+        }
+        let written: Vec<(DataType, &str)> = written
+            .iter()
+            .map(|(key, data_type)| (*data_type, key.as_str()))
+            .collect();
+        let mut changes = Vec::new();
+        if changed && global_state.cdc.is_some() {
+            // Every key written by the script is a change, keys which are only read are not
+            let params = encode_params(args.iter().copied());
+            for (data_type, key) in written.iter() {
+                changes.push(Change {
+                    namespace: namespace.to_string(),
+                    data_type: *data_type,
+                    key: key.to_string(),
+                    operation: "SCRIPT".to_string(),
+                    params: params.clone(),
+                    result: content.clone(),
+                });
+            }
+        }
+        let key_map_lock = stage_key_records(global_state, namespace, &mut batch, &written)?;
+        global_state.commit(batch, &changes)?;
+        if changed {
+            global_state.increase_versions(&indexes);
+        }
+        drop(key_map_lock);
+        drop(locks);
+        content
+    };
+    Ok(HttpResponse::Ok().body([SUCCESS, &content].concat()))
+}
+
+// Take an incremental backup of the database into the configured directory
+//
+// Params: None
+pub async fn handle_backup(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    if !params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: BACKUP, params: {:?}",
+            &params
+        )));
+    }
+    let backup_path = global_state
+        .backup_path
+        .as_ref()
+        .ok_or(LodisError::BackupNotConfigured)?;
+
+    let lock = global_state.backup_lock.lock();
+    let info = create_backup(&global_state.db, backup_path, global_state.backup_keep)?;
+    drop(lock);
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(SUCCESS);
+    encode_backup(&mut buf, &info);
+    Ok(HttpResponse::Ok().body(buf))
+}
+
+// List all backups in the configured directory, from old to new
+//
+// Params: None
+pub async fn handle_listbackups(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    if !params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: LISTBACKUPS, params: {:?}",
+            &params
+        )));
+    }
+    let backup_path = global_state
+        .backup_path
+        .as_ref()
+        .ok_or(LodisError::BackupNotConfigured)?;
+
+    let infos = list_backups(backup_path)?;
+    let mut buf = Vec::new();
+    buf.extend_from_slice(SUCCESS);
+    for info in infos.iter() {
+        encode_backup(&mut buf, info);
+    }
+    Ok(HttpResponse::Ok().body(buf))
+}
+
+// Create a checkpoint of the database in the configured directory and return its name
+//
+// Params: None
+pub async fn handle_checkpoint(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    if !params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: CHECKPOINT, params: {:?}",
+            &params
+        )));
+    }
+
+    let name = global_state.checkpoint()?;
+    Ok(HttpResponse::Ok().body([SUCCESS, name.as_bytes()].concat()))
+}
+
+// Sections of INFO
+const INFO_SECTIONS: [&str; 5] = ["server", "clients", "commands", "keyspace", "rocksdb"];
+// Number of entries returned by SLOWLOG GET without count
+const DEFAULT_SLOWLOG_COUNT: usize = 10;
+
+// Return the statistics of the server as text, one `name:value` line for each of them under
+// the `# Section` line of its section
+//
+// Params: [section]
+//
+// Without `section`, all sections are returned. Keyspace reads all keys of all namespaces.
+pub async fn handle_info(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    let sections = match params.as_slice() {
+        [] => INFO_SECTIONS.to_vec(),
+        [section] => {
+            let section = String::from_utf8_lossy(section).to_ascii_lowercase();
+            match INFO_SECTIONS.iter().find(|name| **name == section) {
+                Some(name) => vec![*name],
+                None => {
+                    return Err(LodisError::ParamNoMatch(format!(
+                        "command: INFO, section: {}",
+                        section
+                    )))
+                }
+            }
+        }
+        _ => {
+            return Err(LodisError::ParamNoMatch(format!(
+                "command: INFO, params: {:?}",
+                &params
+            )))
+        }
+    };
+
+    let mut info = String::new();
+    for section in sections {
+        let lines = match section {
+            "server" => vec![
+                ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+                (
+                    "role".to_string(),
+                    if global_state.read_only {
+                        "replica".to_string()
+                    } else {
+                        "primary".to_string()
+                    },
+                ),
+                (
+                    "uptime_in_seconds".to_string(),
+                    global_state.stats.uptime().to_string(),
+                ),
+                ("workers".to_string(), global_state.workers.to_string()),
+            ],
+            "clients" => vec![(
+                "connected_clients".to_string(),
+                global_state.stats.clients().to_string(),
+            )],
+            "commands" => global_state
+                .stats
+                .commands()
+                .into_iter()
+                .map(|(command, count)| (command.to_ascii_lowercase(), count.to_string()))
+                .collect(),
+            "keyspace" => key_counts(&global_state)?
+                .into_iter()
+                .map(|(name, count)| (name, count.to_string()))
+                .collect(),
+            _ => db_properties(&global_state.db)?
+                .into_iter()
+                .map(|(name, value)| (name.replace('-', "_"), value.to_string()))
+                .collect(),
+        };
+        if !info.is_empty() {
+            info.push('\n');
+        }
+        info.push_str(&format!("# {}\n", section));
+        for (name, value) in lines {
+            info.push_str(&format!("{}:{}\n", name, value));
+        }
+    }
+    Ok(HttpResponse::Ok().body([SUCCESS, info.as_bytes()].concat()))
+}
+
+// Return the statistics of the server in the Prometheus text format, without a status code
+pub async fn handle_metrics(global_state: web::Data<GlobalState>) -> Result<HttpResponse> {
+    let properties = db_properties(&global_state.db)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(global_state.stats.metrics(&properties)))
+}
+
+// Read or reset the slow log
+//
+// Params: GET [count] | LEN | RESET
+//
+// `GET` returns the latest `count` entries, 10 by default, from the newest one. `LEN` returns the
+// number of entries.
+pub async fn handle_slowlog(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    let subcommand = params
+        .first()
+        .map(|subcommand| subcommand.to_ascii_uppercase())
+        .unwrap_or_default();
+    let mut buf = SUCCESS.to_vec();
+    match (subcommand.as_slice(), &params[1.min(params.len())..]) {
+        (b"GET", []) => {
+            encode_slow_entries(&mut buf, &global_state.slowlog.get(DEFAULT_SLOWLOG_COUNT))
+        }
+        (b"GET", [count]) if count.len() == 4 => {
+            let mut count_buf: [u8; 4] = [0; 4];
+            count_buf.clone_from_slice(count);
+            let count = u8x4_to_u32(&count_buf) as usize;
+            encode_slow_entries(&mut buf, &global_state.slowlog.get(count));
+        }
+        (b"LEN", []) => buf.extend_from_slice(&u32_to_u8x4(global_state.slowlog.len() as u32)),
+        (b"RESET", []) => global_state.slowlog.reset(),
+        _ => {
+            return Err(LodisError::ParamNoMatch(format!(
+                "command: SLOWLOG, params: {:?}",
+                &params
+            )))
+        }
+    }
+    Ok(HttpResponse::Ok().body(buf))
+}
+
+fn encode_slow_entries(buf: &mut Vec<u8>, entries: &[SlowEntry]) {
+    for entry in entries {
+        buf.extend_from_slice(&u64_to_u8x8(entry.id));
+        buf.extend_from_slice(&u64_to_u8x8(entry.timestamp));
+        buf.extend_from_slice(&u64_to_u8x8(entry.duration.as_micros() as u64));
+        for field in [&entry.command, &entry.namespace, &entry.key].iter() {
+            buf.extend_from_slice(&u32_to_u8x4(field.len() as u32));
+            buf.extend_from_slice(field.as_bytes());
+        }
+        buf.extend_from_slice(&u32_to_u8x4(entry.args.len() as u32));
+        for arg in entry.args.iter() {
+            buf.extend_from_slice(&u32_to_u8x4(arg.len() as u32));
+            buf.extend_from_slice(arg);
+        }
+    }
+}
+
+// The number of namespaces, and the number of keys of each data type in all namespaces
+fn key_counts(global_state: &GlobalState) -> Result<Vec<(String, usize)>> {
+    let namespaces = global_state.namespaces()?;
+    let mut counts = vec![0; KEY_TYPES.len()];
+    for namespace in namespaces.iter() {
+        let keys = Batch::read(&global_state.db, |batch| {
+            all_keys(global_state, namespace, batch, None)
+        })?;
+        for (data_type, _) in keys {
+            if let Some(i) = KEY_TYPES
+                .iter()
+                .position(|key_type| key_type.flag() == data_type.flag())
+            {
+                counts[i] += 1;
+            }
+        }
+    }
+
+    let mut lines = vec![("namespaces".to_string(), namespaces.len())];
+    for (data_type, count) in KEY_TYPES.iter().zip(counts) {
+        lines.push((format!("{}_keys", data_type_name(*data_type)), count));
+    }
+    Ok(lines)
+}
+
+// Return the write batches after a sequence number at the write-ahead log, for replicas
+//
+// Params: sequence number (8 bytes)
+pub async fn handle_replupdates(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    if params.len() != 1 || params[0].len() != 8 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: REPLUPDATES, params: {:?}",
+            &params
+        )));
+    }
+    if global_state.wal_ttl == 0 {
+        return Err(LodisError::ReplicationNotConfigured);
+    }
+    let mut buf: [u8; 8] = [0; 8];
+    buf.clone_from_slice(&params[0]);
+    let sequence = u8x8_to_u64(&buf);
+
+    let updates = updates_since(&global_state.db, sequence, MAX_UPDATES_SIZE)?;
+    let mut content = SUCCESS.to_vec();
+    encode_updates(&mut content, &updates);
+    Ok(HttpResponse::Ok().body(content))
+}
+
+// Stream a snapshot of all data, for replicas to resync from
+//
+// Params: None
+pub async fn handle_replsnapshot(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    if !params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: REPLSNAPSHOT, params: {:?}",
+            &params
+        )));
+    }
+    let snapshot = global_state.replica_snapshot()?;
+    Ok(HttpResponse::Ok().streaming(stream_snapshot(snapshot)))
+}
+
+// Stream the changes after a sequence number, and the following changes when they are made
+//
+// Params: sequence number (8 bytes), optional, the stream starts after the last change
+// without it
+pub async fn handle_changes(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    let since = match params.as_slice() {
+        [] => None,
+        [sequence] if sequence.len() == 8 => {
+            let mut buf: [u8; 8] = [0; 8];
+            buf.clone_from_slice(sequence);
+            Some(u8x8_to_u64(&buf))
+        }
+        _ => {
+            return Err(LodisError::ParamNoMatch(format!(
+                "command: CHANGES, params: {:?}",
+                &params
+            )))
+        }
+    };
+    if global_state.cdc.is_none() {
+        return Err(LodisError::CdcNotConfigured);
+    }
+    let since = stream_start(&global_state.db, since)?;
+    Ok(HttpResponse::Ok().streaming(stream_changes(global_state, since, StreamFormat::Binary)))
+}
+
+// Stream changes as Server-Sent Events
+//
+// The stream starts after the `Last-Event-ID` header or the `since` query, or after the
+// last change without them.
+pub async fn handle_changes_sse(
+    request: HttpRequest,
+    query: web::Query<ChangesQuery>,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    if global_state.cdc.is_none() {
+        return Err(LodisError::CdcNotConfigured);
+    }
+    let since = match request.headers().get("Last-Event-ID") {
+        Some(id) => Some(
+            id.to_str()
+                .ok()
+                .and_then(|id| id.parse::<u64>().ok())
+                .ok_or_else(|| LodisError::ParamTypeError(format!("Last-Event-ID: {:?}", id)))?,
+        ),
+        None => query.since,
+    };
+    let since = stream_start(&global_state.db, since)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(stream_changes(global_state, since, StreamFormat::Sse)))
+}
+
+// Publish the message to a channel, return the number of subscribers which receive it
+pub async fn handle_publish(
+    body: web::Bytes,
+    channel: web::Path<KeyName>,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    if params.len() != 1 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: PUBLISH, params: {:?}",
+            &params
+        )));
+    }
+    let count = global_state
+        .pubsub
+        .publish(channel.key.as_bytes(), &params[0]);
+    Ok(HttpResponse::Ok().body([SUCCESS, &u32_to_u8x4(count)[..]].concat()))
+}
+
+pub async fn handle_subscribe(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    subscribe(body, global_state, Target::Channel).await
+}
+
+pub async fn handle_psubscribe(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    subscribe(body, global_state, Target::Pattern).await
+}
+
+// Stream `SUCCESS`, then the messages of the channels or patterns until the client is
+// disconnected
+async fn subscribe(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    target: Target,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    if params.is_empty() {
+        let command = match target {
+            Target::Channel => "SUBSCRIBE",
+            Target::Pattern => "PSUBSCRIBE",
+        };
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: {}, params: {:?}",
+            command, &params
+        )));
+    }
+    let subscription = Subscription::new(global_state.clone());
+    let names = params.iter().map(|param| param.to_vec()).collect();
+    global_state
+        .pubsub
+        .subscribe(subscription.id(), target, names);
+
+    let messages = subscription.map(|message| {
+        let mut buf = Vec::new();
+        message.encode(&mut buf);
+        Ok::<_, LodisError>(web::Bytes::from(buf))
+    });
+    Ok(HttpResponse::Ok()
+        .streaming(stream::once(future::ok(web::Bytes::from_static(SUCCESS))).chain(messages)))
+}
+
+// Subscribe over a websocket
+pub async fn handle_ws(
+    request: HttpRequest,
+    payload: web::Payload,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let mut response =
+        handshake(request.head()).map_err(|err| LodisError::Error(err.to_string()))?;
+    Ok(response.streaming(serve_ws(payload, global_state)))
+}
+
+// Data types of the keys at `LODIS_KEY_MAP`
+const KEY_TYPES: [DataType; 3] = [DataType::List, DataType::Map, DataType::ArrayMap];
+// Number of records of `LODIS_KEY_MAP` examined by SCAN without COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+// Number of records of `LODIS_KEY_MAP` read at once by KEYS and DBSIZE
+const KEYS_BATCH: usize = 1000;
+// Number of records written at once by RENAME and COPY
+const REWRITE_BATCH: usize = 1000;
+
+// Data type and name of a key
+pub type Key = (DataType, String);
+
+// Scan the keys incrementally
+//
+// Params: cursor, [MATCH pattern], [COUNT count], [TYPE type]
+//
+// The cursor is empty at the first call, and is the cursor returned by the last call after that.
+// The response content is the next cursor, which is empty when the scan is finished, then the
+// data type and name of each key.
+pub async fn handle_scan(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let params = parse_params(body).await?;
+    if params.is_empty() || params.len() % 2 != 1 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: SCAN, params: {:?}",
+            &params
+        )));
+    }
+
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut data_type = None;
+    for option in params[1..].chunks(2) {
+        match option[0].to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = Some(&option[1][..]),
+            b"COUNT" if option[1].len() == 4 => {
+                let mut buf: [u8; 4] = [0; 4];
+                buf.clone_from_slice(&option[1]);
+                count = (u8x4_to_u32(&buf) as usize).max(1);
+            }
+            b"TYPE" => data_type = Some(parse_data_type(&option[1])?),
+            _ => {
+                return Err(LodisError::ParamNoMatch(format!(
+                    "command: SCAN, params: {:?}",
+                    &params
+                )))
+            }
+        }
+    }
+
+    let (cursor, mut keys) = Batch::read(&global_state.db, |batch| {
+        scan_keys(
+            &global_state,
+            namespace,
+            batch,
+            &params[0],
+            count,
+            pattern,
+            data_type,
+        )
+    })?;
+    if let Some(user) = request_user(&request) {
+        keys.retain(|(_, key)| user.can_access_key(key));
+    }
+    let mut buf = SUCCESS.to_vec();
+    buf.extend_from_slice(&u32_to_u8x4(cursor.len() as u32));
+    buf.extend_from_slice(&cursor);
+    encode_keys(&mut buf, &keys);
+    Ok(HttpResponse::Ok().body(buf))
+}
+
+// Return the data type and name of all keys which match the pattern
+//
+// Params: pattern
+pub async fn handle_keys(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let params = parse_params(body).await?;
+    if params.len() != 1 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: KEYS, params: {:?}",
+            &params
+        )));
+    }
+
+    let mut keys = Batch::read(&global_state.db, |batch| {
+        all_keys(&global_state, namespace, batch, Some(&params[0]))
+    })?;
+    if let Some(user) = request_user(&request) {
+        keys.retain(|(_, key)| user.can_access_key(key));
+    }
+    let mut buf = SUCCESS.to_vec();
+    encode_keys(&mut buf, &keys);
+    Ok(HttpResponse::Ok().body(buf))
+}
+
+// Return the number of keys
+//
+// Params: None
+pub async fn handle_dbsize(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let params = parse_params(body).await?;
+    if !params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: DBSIZE, params: {:?}",
+            &params
+        )));
+    }
+
+    let keys = Batch::read(&global_state.db, |batch| {
+        all_keys(&global_state, namespace, batch, None)
+    })?;
+    Ok(HttpResponse::Ok().body([SUCCESS, &u32_to_u8x4(keys.len() as u32)[..]].concat()))
+}
+
+// Return the names of the data types of a key
+//
+// Params: key
+pub async fn handle_type(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let params = parse_params(body).await?;
+    if params.len() != 1 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: TYPE, params: {:?}",
+            &params
+        )));
+    }
+    let key = parse_key(&params[0])?;
+    check_key(&request, &key)?;
+
+    let data_types = Batch::read(&global_state.db, |batch| {
+        key_types(&global_state, namespace, batch, &key)
+    })?;
+    let mut buf = SUCCESS.to_vec();
+    for data_type in data_types {
+        let name = data_type_name(data_type);
+        buf.extend_from_slice(&u32_to_u8x4(name.len() as u32));
+        buf.extend_from_slice(name.as_bytes());
+    }
+    Ok(HttpResponse::Ok().body(buf))
+}
+
+// Return the number of the keys which exist, a key is counted once for each time it is given
+//
+// Params: key, [key ...]
+pub async fn handle_exists(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let params = parse_params(body).await?;
+    if params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: EXISTS, params: {:?}",
+            &params
+        )));
+    }
+    let keys = params
+        .iter()
+        .map(|param| parse_key(param))
+        .collect::<Result<Vec<_>>>()?;
+    for key in keys.iter() {
+        check_key(&request, key)?;
+    }
+
+    let count = Batch::read(&global_state.db, |batch| -> Result<u32> {
+        let mut count = 0;
+        for key in keys.iter() {
+            if !key_types(&global_state, namespace, batch, key)?.is_empty() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    })?;
+    Ok(HttpResponse::Ok().body([SUCCESS, &u32_to_u8x4(count)[..]].concat()))
+}
+
+// Remove the data of all data types of the keys atomically, return the number of the removed
+// data
+//
+// Params: key, [key ...]
+pub async fn handle_del(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let params = parse_params(body).await?;
+    if params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: DEL, params: {:?}",
+            &params
+        )));
+    }
+    let mut keys = params
+        .iter()
+        .map(|param| parse_key(param))
+        .collect::<Result<Vec<_>>>()?;
+    for key in keys.iter() {
+        check_key(&request, key)?;
+    }
+    keys.sort_unstable();
+    keys.dedup();
+
+    let removed = delete_keys(&global_state, namespace, &keys)?;
+    Ok(HttpResponse::Ok().body([SUCCESS, &u32_to_u8x4(removed as u32)[..]].concat()))
+}
+
+// Remove the data of all data types of the keys atomically, return the number of the removed
+// data
+//
+// The keys must be sorted and deduplicated.
+pub fn delete_keys(global_state: &GlobalState, namespace: &str, keys: &[String]) -> Result<usize> {
+    let mut indexes = Vec::new();
+    for key in keys.iter() {
+        for data_type in KEY_TYPES.iter() {
+            indexes.push(lock_index(global_state, namespace, *data_type, key));
+        }
+    }
+
+    let mut batch = Batch::new(global_state.db.clone());
+    let mut removed = Vec::new();
+    {
+        let locks = global_state.lock_all(&indexes);
+        for key in keys.iter() {
+            for data_type in key_types(global_state, namespace, &batch, key)? {
+                remove_data(global_state, namespace, &mut batch, data_type, key)?;
+                removed.push((data_type, key.as_str()));
+            }
+        }
+        let changed = !batch.is_empty();
+        if changed && global_state.read_only {
+            return Err(LodisError::ReadOnly);
+        }
+        let mut changes = Vec::new();
+        if changed && global_state.cdc.is_some() {
+            for (data_type, key) in removed.iter() {
+                changes.push(Change {
+                    namespace: namespace.to_string(),
+                    data_type: *data_type,
+                    key: key.to_string(),
+                    operation: "DEL".to_string(),
+                    params: Vec::new(),
+                    result: Vec::new(),
+                });
+            }
+        }
+        let key_map_lock = stage_key_records(global_state, namespace, &mut batch, &removed)?;
+        global_state.commit(batch, &changes)?;
+        if changed {
+            global_state.increase_versions(&indexes);
+        }
+        drop(key_map_lock);
+        drop(locks);
+    }
+    Ok(removed.len())
+}
+
+// Remove all keys of the namespace, return the number of the removed data
+//
+// Params: None
+//
+// Keys are removed one by one, in writes of at most `REWRITE_BATCH` records, so keys written
+// during the flush may be kept.
+pub async fn handle_flushdb(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let params = parse_params(body).await?;
+    if !params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: FLUSHDB, params: {:?}",
+            &params
+        )));
+    }
+    if global_state.read_only {
+        return Err(LodisError::ReadOnly);
+    }
+
+    let key_map = global_state.key_map(namespace);
+    let mut removed: u32 = 0;
+    let mut cursor = None;
+    loop {
+        let records = key_map.keys_after(cursor.as_deref(), KEYS_BATCH)?;
+        for record in records.iter() {
+            let data_type = match record.first().and_then(|flag| DataType::from_flag(*flag)) {
+                Some(data_type) => data_type,
+                None => continue,
+            };
+            let key = String::from_utf8_lossy(&record[1..]).into_owned();
+            if flush_key(&global_state, namespace, data_type, &key)? {
+                removed += 1;
+            }
+        }
+        match records.last() {
+            Some(record) if records.len() == KEYS_BATCH => cursor = Some(record.to_vec()),
+            _ => break,
+        }
+    }
+    global_state.remove_namespace(namespace)?;
+    Ok(HttpResponse::Ok().body([SUCCESS, &u32_to_u8x4(removed)[..]].concat()))
+}
+
+// Return the name and the number of keys of every namespace which has keys, the default
+// namespace is the first, whose name is empty
+//
+// Params: None
+pub async fn handle_namespaces(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+) -> Result<HttpResponse> {
+    let params = parse_params(body).await?;
+    if !params.is_empty() {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: NAMESPACES, params: {:?}",
+            &params
+        )));
+    }
+
+    let mut buf = SUCCESS.to_vec();
+    for namespace in global_state.namespaces()? {
+        let keys = Batch::read(&global_state.db, |batch| {
+            all_keys(&global_state, &namespace, batch, None)
+        })?;
+        buf.extend_from_slice(&u32_to_u8x4(namespace.len() as u32));
+        buf.extend_from_slice(namespace.as_bytes());
+        buf.extend_from_slice(&u32_to_u8x4(keys.len() as u32));
+    }
+    Ok(HttpResponse::Ok().body(buf))
+}
+
+// Rename all keys of a name, which replace the keys of the new name
+//
+// Params: name, new name
+pub async fn handle_rename(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let (source, target, _) = parse_rewrite(body, "RENAME").await?;
+    check_key(&request, &source)?;
+    check_key(&request, &target)?;
+    rewrite_keys(&global_state, namespace, &source, &target, true, true)?;
+    Ok(HttpResponse::Ok().body(SUCCESS))
+}
+
+// Rename all keys of a name if the new name is not a key, return whether they are renamed
+//
+// Params: name, new name
+pub async fn handle_renamenx(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let (source, target, _) = parse_rewrite(body, "RENAMENX").await?;
+    check_key(&request, &source)?;
+    check_key(&request, &target)?;
+    let renamed = rewrite_keys(&global_state, namespace, &source, &target, true, false)?;
+    Ok(HttpResponse::Ok().body([SUCCESS, &[renamed as u8]].concat()))
+}
+
+// Copy all keys of a name to a new name, return whether they are copied
+//
+// Params: name, new name, [REPLACE]
+pub async fn handle_copy(
+    body: web::Bytes,
+    global_state: web::Data<GlobalState>,
+    namespace: Namespace,
+    request: HttpRequest,
+) -> Result<HttpResponse> {
+    let namespace: &str = &namespace;
+    let (source, target, replace) = parse_rewrite(body, "COPY").await?;
+    check_key(&request, &source)?;
+    check_key(&request, &target)?;
+    let copied = rewrite_keys(&global_state, namespace, &source, &target, false, replace)?;
+    Ok(HttpResponse::Ok().body([SUCCESS, &[copied as u8]].concat()))
+}
+
+// Parse the params of RENAME, RENAMENX and COPY, which are a name, a new name and REPLACE of
+// COPY
+async fn parse_rewrite(body: web::Bytes, command: &str) -> Result<(String, String, bool)> {
+    let params = parse_params(body).await?;
+    let replace = match params.get(2) {
+        Some(option) if command == "COPY" && option.eq_ignore_ascii_case(b"REPLACE") => true,
+        None => false,
+        _ => {
+            return Err(LodisError::ParamNoMatch(format!(
+                "command: {}, params: {:?}",
+                command, &params
+            )))
+        }
+    };
+    if params.len() < 2 || params.len() > 3 {
+        return Err(LodisError::ParamNoMatch(format!(
+            "command: {}, params: {:?}",
+            command, &params
+        )));
+    }
+    Ok((parse_key(&params[0])?, parse_key(&params[1])?, replace))
+}
+
+// Rewrite the records of all keys of `source` under the name `target`, return whether they are
+// rewritten, which is false if `target` is a key and `replace` is false
+//
+// The keys of `target` are removed first. The records are copied, and removed from `source`
+// if `remove_source`, in writes of at most `REWRITE_BATCH` records, so a large key does not
+// make a large write. The locks of both names are held until the end, but replicas, the change
+// log and checkpoints may see a part of the records under `target`.
+fn rewrite_keys(
+    global_state: &GlobalState,
+    namespace: &str,
+    source: &str,
+    target: &str,
+    remove_source: bool,
+    replace: bool,
+) -> Result<bool> {
+    if global_state.read_only {
+        return Err(LodisError::ReadOnly);
+    }
+    let mut indexes = Vec::new();
+    for name in [source, target].iter() {
+        for data_type in KEY_TYPES.iter() {
+            indexes.push(lock_index(global_state, namespace, *data_type, name));
+        }
+    }
+
+    let locks = global_state.lock_all(&indexes);
+    let (data_types, target_types) = Batch::read(&global_state.db, |batch| -> Result<_> {
+        Ok((
+            key_types(global_state, namespace, batch, source)?,
+            key_types(global_state, namespace, batch, target)?,
+        ))
+    })?;
+    if data_types.is_empty() {
+        return Err(LodisError::NoSuchKey(source.to_string()));
+    }
+    if source == target {
+        if remove_source {
+            return Ok(true);
+        }
+        return Err(LodisError::ParamNoMatch(format!(
+            "source and target are the same, {}",
+            source
+        )));
+    }
+    if !target_types.is_empty() && !replace {
+        return Ok(false);
+    }
+
+    for data_type in target_types.iter() {
+        rewrite_data(global_state, namespace, *data_type, target, None)?;
+    }
+    for data_type in data_types.iter() {
+        rewrite_data(global_state, namespace, *data_type, source, Some(target))?;
+        if remove_source {
+            rewrite_data(global_state, namespace, *data_type, source, None)?;
+        }
+    }
+
+    let mut changes = Vec::new();
+    if global_state.cdc.is_some() {
+        for data_type in data_types.iter() {
+            let (key, operation, param) = if remove_source {
+                (source, "RENAME", target)
+            } else {
+                (target, "COPY", source)
+            };
+            changes.push(Change {
+                namespace: namespace.to_string(),
+                data_type: *data_type,
+                key: key.to_string(),
+                operation: operation.to_string(),
+                params: encode_params(std::iter::once(param.as_bytes())),
+                result: Vec::new(),
+            });
+        }
+    }
+    let mut keys = Vec::new();
+    for data_type in target_types.iter().chain(data_types.iter()) {
+        keys.push((*data_type, target));
+    }
+    if remove_source {
+        for data_type in data_types.iter() {
+            keys.push((*data_type, source));
+        }
+    }
+    let mut batch = Batch::new(global_state.db.clone());
+    let key_map_lock = stage_key_records(global_state, namespace, &mut batch, &keys)?;
+    global_state.commit(batch, &changes)?;
+    global_state.increase_versions(&indexes);
+    drop(key_map_lock);
+    drop(locks);
+    Ok(true)
+}
+
+// Copy the records of the data to `target`, or remove them without `target`, in writes of
+// at most `REWRITE_BATCH` records
+//
+// The caller must hold the locks of the data and `target`.
+fn rewrite_data(
+    global_state: &GlobalState,
+    namespace: &str,
+    data_type: DataType,
+    key: &str,
+    target: Option<&str>,
+) -> Result<()> {
+    let db = global_state.db.clone();
+    match data_type {
+        DataType::List => rewrite_records(
+            global_state,
+            &List::new_in(namespace, key.to_string(), db.clone()),
+            target.map(|target| List::new_in(namespace, target.to_string(), db)),
+        ),
+        DataType::Map => rewrite_records(
+            global_state,
+            &Map::new_in(namespace, key.to_string(), db.clone()),
+            target.map(|target| Map::new_in(namespace, target.to_string(), db)),
+        ),
+        DataType::ArrayMap => rewrite_records(
+            global_state,
+            &ArrayMap::new_in(namespace, key.to_string(), db.clone()),
+            target.map(|target| ArrayMap::new_in(namespace, target.to_string(), db)),
+        ),
+        _ => unreachable!(),
+    }
+}
+
+fn rewrite_records<D: LodisData>(
+    global_state: &GlobalState,
+    data: &D,
+    target: Option<D>,
+) -> Result<()> {
+    let mut after = None;
+    loop {
+        let mut batch = Batch::new(global_state.db.clone());
+        let done = match &target {
+            Some(target) => {
+                after =
+                    data.copy_to_with_batch(target, after.as_deref(), REWRITE_BATCH, &mut batch)?;
+                after.is_none()
+            }
+            None => data.remove_part_with_batch(REWRITE_BATCH, &mut batch)? == 0,
+        };
+        global_state.commit(batch, &[])?;
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+// Remove the data of the key in writes of at most `REWRITE_BATCH` records, return whether
+// it has data
+fn flush_key(
+    global_state: &GlobalState,
+    namespace: &str,
+    data_type: DataType,
+    key: &str,
+) -> Result<bool> {
+    let index = lock_index(global_state, namespace, data_type, key);
+    let locks = global_state.lock_all(&[index]);
+    let length = Batch::read(&global_state.db, |batch| {
+        key_length(global_state, namespace, batch, data_type, key)
+    })?;
+    let mut batch = Batch::new(global_state.db.clone());
+    if length == 0 {
+        // Remove the record of a key without data
+        let key_map_lock =
+            stage_key_records(global_state, namespace, &mut batch, &[(data_type, key)])?;
+        global_state.commit(batch, &[])?;
+        drop(key_map_lock);
+        return Ok(false);
+    }
+    rewrite_data(global_state, namespace, data_type, key, None)?;
+
+    let mut changes = Vec::new();
+    if global_state.cdc.is_some() {
+        changes.push(Change {
+            namespace: namespace.to_string(),
+            data_type,
+            key: key.to_string(),
+            operation: "DEL".to_string(),
+            params: Vec::new(),
+            result: Vec::new(),
+        });
+    }
+    let key_map_lock = stage_key_records(global_state, namespace, &mut batch, &[(data_type, key)])?;
+    global_state.commit(batch, &changes)?;
+    global_state.increase_versions(&[index]);
+    drop(key_map_lock);
+    drop(locks);
+    Ok(true)
+}
+
+fn parse_key(param: &[u8]) -> Result<String> {
+    String::from_utf8(param.to_vec())
+        .map_err(|_| LodisError::ParamNoMatch(format!("key: {:?}", param)))
+}
+
+// Scan at most `count` records of `LODIS_KEY_MAP` after the cursor, return the next cursor
+// and the keys which match the pattern and the data type
+//
+// A record is a field of `LODIS_KEY_MAP`, which is the data type flag and the name of a key.
+// The next cursor is the last examined record, or empty if there are no more records.
+// Keys whose data is empty are skipped, e.g. a list whose elements are all popped.
+fn scan_keys(
+    global_state: &GlobalState,
+    namespace: &str,
+    batch: &Batch,
+    cursor: &[u8],
+    count: usize,
+    pattern: Option<&[u8]>,
+    data_type: Option<DataType>,
+) -> Result<(Vec<u8>, Vec<Key>)> {
+    let after = if cursor.is_empty() {
+        None
+    } else {
+        Some(cursor)
+    };
+    let records = global_state
+        .key_map(namespace)
+        .keys_after_with_batch(after, count, batch)?;
+    let cursor = match records.last() {
+        Some(record) if records.len() == count => record.to_vec(),
+        _ => Vec::new(),
+    };
+
+    let mut keys = Vec::new();
+    for record in records.iter() {
+        let record_type = match record.first().and_then(|flag| DataType::from_flag(*flag)) {
+            Some(record_type) => record_type,
+            None => continue,
+        };
+        if let Some(data_type) = data_type {
+            if data_type.flag() != record_type.flag() {
+                continue;
+            }
+        }
+        let key = String::from_utf8_lossy(&record[1..]).into_owned();
+        if let Some(pattern) = pattern {
+            if !glob_match(pattern, key.as_bytes()) {
+                continue;
+            }
+        }
+        if key_length(global_state, namespace, batch, record_type, &key)? > 0 {
+            keys.push((record_type, key));
+        }
+    }
+    Ok((cursor, keys))
+}
+
+pub fn all_keys(
+    global_state: &GlobalState,
+    namespace: &str,
+    batch: &Batch,
+    pattern: Option<&[u8]>,
+) -> Result<Vec<Key>> {
+    let mut keys = Vec::new();
+    let mut cursor = Vec::new();
+    loop {
+        let (next, mut scanned) = scan_keys(
+            global_state,
+            namespace,
+            batch,
+            &cursor,
+            KEYS_BATCH,
+            pattern,
+            None,
+        )?;
+        keys.append(&mut scanned);
+        if next.is_empty() {
+            return Ok(keys);
+        }
+        cursor = next;
+    }
+}
+
+// The data types which the key has data of
+pub fn key_types(
+    global_state: &GlobalState,
+    namespace: &str,
+    batch: &Batch,
+    key: &str,
+) -> Result<Vec<DataType>> {
+    let mut data_types = Vec::new();
+    for data_type in KEY_TYPES.iter() {
+        if key_length(global_state, namespace, batch, *data_type, key)? > 0 {
+            data_types.push(*data_type);
+        }
+    }
+    Ok(data_types)
+}
+
+// The number of elements of the data, 0 if the data does not exist
+fn key_length(
+    global_state: &GlobalState,
+    namespace: &str,
+    batch: &Batch,
+    data_type: DataType,
+    key: &str,
+) -> Result<u32> {
+    let db = global_state.db.clone();
+    let length = match data_type {
+        DataType::List => List::new_in(namespace, key.to_string(), db).length_with_batch(batch)?,
+        DataType::Map => Map::new_in(namespace, key.to_string(), db).length_with_batch(batch)?,
+        DataType::ArrayMap => {
+            ArrayMap::new_in(namespace, key.to_string(), db).length_with_batch(batch)?
+        }
+        _ => 0,
+    };
+    Ok(length)
+}
+
+// Stage the records of the keys at `LODIS_KEY_MAP` to match whether they have data after the
+// writes of the batch, return the lock of the key maps if any record is staged
+//
+// The caller must hold the locks of the keys, and commit the batch before it drops the
+// returned lock, so the records are written atomically with the data.
+fn stage_key_records<'a>(
+    global_state: &'a GlobalState,
+    namespace: &str,
+    batch: &mut Batch,
+    keys: &[(DataType, &str)],
+) -> Result<Option<MutexGuard<'a, ()>>> {
+    let mut key_map_lock = None;
+    for (data_type, key) in keys.iter() {
+        let has_data = key_length(global_state, namespace, batch, *data_type, key)? > 0;
+        if has_data == global_state.has_key_with_batch(namespace, key, *data_type, batch)? {
+            continue;
+        }
+        if key_map_lock.is_none() {
+            key_map_lock = Some(global_state.lock_key_map());
+        }
+        if has_data {
+            global_state.add_key_with_batch(namespace, key, *data_type, batch)?;
+        } else {
+            global_state.remove_key_with_batch(namespace, key, *data_type, batch)?;
+        }
+    }
+    Ok(key_map_lock)
+}
+
+fn remove_data(
+    global_state: &GlobalState,
+    namespace: &str,
+    batch: &mut Batch,
+    data_type: DataType,
+    key: &str,
+) -> Result<()> {
+    let db = global_state.db.clone();
+    match data_type {
+        DataType::List => List::new_in(namespace, key.to_string(), db).remove_with_batch(batch)?,
+        DataType::Map => Map::new_in(namespace, key.to_string(), db).remove_with_batch(batch)?,
+        DataType::ArrayMap => {
+            ArrayMap::new_in(namespace, key.to_string(), db).remove_with_batch(batch)?
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+// Encode keys as pairs of the name of the data type and the name of the key
+fn encode_keys(buf: &mut Vec<u8>, keys: &[Key]) {
+    for (data_type, key) in keys {
+        let name = data_type_name(*data_type);
+        buf.extend_from_slice(&u32_to_u8x4(name.len() as u32));
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(&u32_to_u8x4(key.len() as u32));
+        buf.extend_from_slice(key.as_bytes());
+    }
+}
+
+// Return data struct
+//
+// 4bytes backup id + 8bytes timestamp + 8bytes size + 4bytes number of files
+fn encode_backup(buf: &mut Vec<u8>, info: &BackupInfo) {
+    buf.extend_from_slice(&u32_to_u8x4(info.backup_id)[..]);
+    buf.extend_from_slice(&u64_to_u8x8(info.timestamp as u64)[..]);
+    buf.extend_from_slice(&u64_to_u8x8(info.size)[..]);
+    buf.extend_from_slice(&u32_to_u8x4(info.num_files)[..]);
+}
+
+// Get the index of the lock which guards the data of the key
+fn lock_index(
+    global_state: &GlobalState,
+    namespace: &str,
+    data_type: DataType,
+    key: &str,
+) -> usize {
+    let db = global_state.db.clone();
+    let prefix_hash = match data_type {
+        DataType::List => List::new_in(namespace, key.to_string(), db).prefix_hash(),
+        DataType::Map => Map::new_in(namespace, key.to_string(), db).prefix_hash(),
+        DataType::ArrayMap => ArrayMap::new_in(namespace, key.to_string(), db).prefix_hash(),
+        _ => unreachable!(),
+    };
+    (prefix_hash % PRIME) as usize
+}
+
+// The indexes of the locks which a command on the key holds, which are the locks of all data
+// types of the key if data types are checked, so that no other data type of the key is created
+// while the command runs
+fn type_lock_indexes(
+    global_state: &GlobalState,
+    namespace: &str,
+    data_type: DataType,
+    key: &str,
+) -> Vec<usize> {
+    if global_state.mixed_types {
+        return vec![lock_index(global_state, namespace, data_type, key)];
+    }
+    KEY_TYPES
+        .iter()
+        .map(|data_type| lock_index(global_state, namespace, *data_type, key))
+        .collect()
+}
 
-        if index + 4 > n {
-            return Err(LodisError::ParseParamError);
+// Fail if the key has data of another data type, unless `LODIS_MIXED_TYPES` allows it
+//
+// The caller must hold the locks of `type_lock_indexes`.
+fn check_type(
+    global_state: &GlobalState,
+    namespace: &str,
+    batch: &Batch,
+    data_type: DataType,
+    key: &str,
+) -> Result<()> {
+    if global_state.mixed_types {
+        return Ok(());
+    }
+    for other in KEY_TYPES.iter() {
+        if other.flag() == data_type.flag() {
+            continue;
         }
-        buf.clone_from_slice(&body[index..index + 4]);
-
-        let end = u8x4_to_u32(&buf) as usize;
-        if index + 4 + end > n {
-            return Err(LodisError::ParseParamError);
+        if key_length(global_state, namespace, batch, *other, key)? > 0 {
+            return Err(LodisError::WrongType(format!(
+                "key: {}, type: {}",
+                key,
+                data_type_name(*other)
+            )));
         }
-        params.push(web::BytesMut::from(&body[index + 4..index + 4 + end]));
-        index += 4 + end;
     }
-    Ok(params)
+    Ok(())
 }
 
-pub async fn handle(
-    body: web::Bytes,
-    key: web::Path<KeyName>,
-    global_state: web::Data<GlobalState>,
+// Without parameter, return the watch token of the key.
+// With a watch token, check whether the key is changed since the token is returned.
+fn watch(
+    global_state: &GlobalState,
+    namespace: &str,
     command: Command,
-) -> Result<HttpResponse> {
-    let params = parse_params(body).await?;
-    let key: &str = &key;
+    key: &str,
+    params: &[web::BytesMut],
+) -> Result<Vec<u8>> {
+    let index = lock_index(global_state, namespace, command.data_type(), key);
+    let token = global_state.watch_token(index);
+    match params.len() {
+        0 => Ok([SUCCESS, &token[..]].concat()),
+        1 => {
+            if params[0] != token[..] {
+                return Err(LodisError::WatchedChanged(format!(
+                    "command: {:?}, key: {}",
+                    command, key
+                )));
+            }
+            Ok(SUCCESS.to_vec())
+        }
+        _ => Err(LodisError::ParamNoMatch(format!(
+            "command: {:?}, params: {:?}",
+            command, params
+        ))),
+    }
+}
 
+// Execute the command on the batch and return the response content
+//
+// The caller must hold the locks of `type_lock_indexes` of the key.
+fn execute(
+    global_state: &GlobalState,
+    namespace: &str,
+    batch: &mut Batch,
+    command: Command,
+    key: &str,
+    params: &[web::BytesMut],
+) -> Result<Vec<u8>> {
     let db = global_state.db.clone();
+    check_type(global_state, namespace, batch, command.data_type(), key)?;
 
     match command {
         // List
@@ -56,79 +1845,52 @@ pub async fn handle(
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::LPUSH,
-                    &params
+                    params
                 )));
             }
 
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                list.push_left(&params)?;
-            }
-            &global_state.add_key(&key, DataType::List);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let list = List::new_in(namespace, key.to_string(), db);
+            list.push_left_with_batch(params, batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::RPUSH => {
             if params.len() < 1 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::RPUSH,
-                    &params
+                    params
                 )));
             }
 
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                list.push(&params)?;
-            }
-            &global_state.add_key(&key, DataType::List);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let list = List::new_in(namespace, key.to_string(), db);
+            list.push_with_batch(params, batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::LPOP => {
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                list.pop_left()?
-            };
+            let list = List::new_in(namespace, key.to_string(), db);
+            let value = list.pop_left_with_batch(batch)?;
             if let Some(value) = value {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &*value].concat()));
+                return Ok([SUCCESS, &*value].concat());
             } else {
-                return Ok(HttpResponse::Ok().body(SUCCESS));
+                return Ok(SUCCESS.to_vec());
             }
         }
         Command::RPOP => {
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                list.pop()?
-            };
+            let list = List::new_in(namespace, key.to_string(), db);
+            let value = list.pop_with_batch(batch)?;
             if let Some(value) = value {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &*value].concat()));
+                return Ok([SUCCESS, &*value].concat());
             } else {
-                return Ok(HttpResponse::Ok().body(SUCCESS));
+                return Ok(SUCCESS.to_vec());
             }
         }
         Command::RANDPOP => {
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                list.pop_random()?
-            };
+            let list = List::new_in(namespace, key.to_string(), db);
+            let value = list.pop_random_with_batch(batch)?;
             if let Some(value) = value {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &*value].concat()));
+                return Ok([SUCCESS, &*value].concat());
             } else {
-                return Ok(HttpResponse::Ok().body(SUCCESS));
+                return Ok(SUCCESS.to_vec());
             }
         }
         Command::LRANGE => {
@@ -136,7 +1898,14 @@ pub async fn handle(
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::LRANGE,
-                    &params
+                    params
+                )));
+            }
+            if params[0].len() != 4 || params[1].len() != 4 {
+                return Err(LodisError::ParamTypeError(format!(
+                    "command: {:?}, params: {:?}",
+                    Command::LRANGE,
+                    params
                 )));
             }
 
@@ -146,27 +1915,29 @@ pub async fn handle(
             buf.clone_from_slice(&params[1]);
             let end = u8x4_to_u32(&buf);
 
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let values = {
-                let lock = mutex.lock();
-                list.range(start, end, Direction::Forward)?
-            };
+            let list = List::new_in(namespace, key.to_string(), db);
+            let values = list.range_with_batch(start, end, Direction::Forward, batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for value in values {
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::RRANGE => {
             if params.len() != 2 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::RRANGE,
-                    &params
+                    params
+                )));
+            }
+            if params[0].len() != 4 || params[1].len() != 4 {
+                return Err(LodisError::ParamTypeError(format!(
+                    "command: {:?}, params: {:?}",
+                    Command::RRANGE,
+                    params
                 )));
             }
 
@@ -176,69 +1947,49 @@ pub async fn handle(
             buf.clone_from_slice(&params[1]);
             let end = u8x4_to_u32(&buf);
 
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let values = {
-                let lock = mutex.lock();
-                list.range(start, end, Direction::Reverse)?
-            };
+            let list = List::new_in(namespace, key.to_string(), db);
+            let values = list.range_with_batch(start, end, Direction::Reverse, batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for value in values {
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::LINDEX => {
             if params.len() != 1 || params[0].len() != 8usize {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::LINDEX,
-                    &params
+                    params
                 )));
             }
 
             let mut buf: [u8; 8] = [0; 8];
             buf.clone_from_slice(&params[0]);
 
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                list.index(u8x8_to_i64(&buf))?
-            };
+            let list = List::new_in(namespace, key.to_string(), db);
+            let value = list.index_with_batch(u8x8_to_i64(&buf), batch)?;
             if let Some(value) = value {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &*value].concat()));
+                return Ok([SUCCESS, &*value].concat());
             } else {
-                return Ok(HttpResponse::Ok().body(SUCCESS));
+                return Ok(SUCCESS.to_vec());
             }
         }
         Command::LRAND => {
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                list.random()?
-            };
+            let list = List::new_in(namespace, key.to_string(), db);
+            let value = list.random_with_batch(batch)?;
             if let Some(value) = value {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &*value].concat()));
+                return Ok([SUCCESS, &*value].concat());
             } else {
-                return Ok(HttpResponse::Ok().body(SUCCESS));
+                return Ok(SUCCESS.to_vec());
             }
         }
         Command::LLEN => {
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                list.length()?
-            };
-            return Ok(HttpResponse::Ok().body([SUCCESS, &u32_to_u8x4(value)[..]].concat()));
+            let list = List::new_in(namespace, key.to_string(), db);
+            let value = list.length_with_batch(batch)?;
+            return Ok([SUCCESS, &u32_to_u8x4(value)[..]].concat());
         }
         // Delete one element by its index
         Command::LDEL => {
@@ -246,31 +1997,28 @@ pub async fn handle(
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::LDEL,
-                    &params
+                    params
+                )));
+            }
+            if params[0].len() != 4 {
+                return Err(LodisError::ParamTypeError(format!(
+                    "command: {:?}, params: {:?}",
+                    Command::LDEL,
+                    params
                 )));
             }
 
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
+            let list = List::new_in(namespace, key.to_string(), db);
             let mut buf: [u8; 4] = [0; 4];
             buf.clone_from_slice(&params[0]);
             let index = u8x4_to_u32(&buf);
-            {
-                let lock = mutex.lock();
-                list.delete(index as i64)?
-            }
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            list.delete_with_batch(index as i64, batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::LRM => {
-            let list = List::new(key.to_string(), db);
-            let hash_num = siphash(&list.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                list.remove()?
-            };
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let list = List::new_in(namespace, key.to_string(), db);
+            list.remove_with_batch(batch)?;
+            return Ok(SUCCESS.to_vec());
         }
 
         // Map
@@ -279,21 +2027,16 @@ pub async fn handle(
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::HGET,
-                    &params
+                    params
                 )));
             }
 
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                map.get(&params[0])?
-            };
+            let map = Map::new_in(namespace, key.to_string(), db);
+            let value = map.get_with_batch(&params[0], batch)?;
             if let Some(value) = value {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &*value].concat()));
+                return Ok([SUCCESS, &*value].concat());
             } else {
-                return Ok(HttpResponse::Ok().body(SUCCESS));
+                return Ok(SUCCESS.to_vec());
             }
         }
         Command::HSET => {
@@ -301,47 +2044,50 @@ pub async fn handle(
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::HSET,
-                    &params
+                    params
                 )));
             }
 
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                map.set(&params[0], &params[1])?;
-            }
-            &global_state.add_key(&key, DataType::Map);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let map = Map::new_in(namespace, key.to_string(), db);
+            map.set_with_batch(&params[0], &params[1], batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::HSETNX => {
             if params.len() != 2 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::HSETNX,
-                    &params
+                    params
                 )));
             }
 
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                map.setnx(&params[0], &params[1])?;
+            let map = Map::new_in(namespace, key.to_string(), db);
+            map.setnx_with_batch(&params[0], &params[1], batch)?;
+            return Ok(SUCCESS.to_vec());
+        }
+        // Set the value of the field only if its value equals the expected value,
+        // or the field does not exist if no expected value is given
+        Command::HCAS => {
+            if params.len() != 2 && params.len() != 3 {
+                return Err(LodisError::ParamNoMatch(format!(
+                    "command: {:?}, params: {:?}",
+                    Command::HCAS,
+                    params
+                )));
+            }
+
+            let map = Map::new_in(namespace, key.to_string(), db);
+            let expected = params.get(2).map(|v| &v[..]);
+            let value = map.cas_with_batch(&params[0], expected, &params[1], batch)?;
+            if value {
+                return Ok([SUCCESS, &[1u8]].concat());
+            } else {
+                return Ok([SUCCESS, &[0u8]].concat());
             }
-            &global_state.add_key(&key, DataType::Map);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
         }
         Command::HGETALL => {
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let values = {
-                let lock = mutex.lock();
-                map.all()?
-            };
+            let map = Map::new_in(namespace, key.to_string(), db);
+            let values = map.all_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for (key, value) in values {
@@ -350,7 +2096,7 @@ pub async fn handle(
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         // Return data struct
         //
@@ -363,17 +2109,12 @@ pub async fn handle(
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::HMGET,
-                    &params
+                    params
                 )));
             }
 
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let values = {
-                let lock = mutex.lock();
-                map.mget(&params)?
-            };
+            let map = Map::new_in(namespace, key.to_string(), db);
+            let values = map.mget_with_batch(params, batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for value in values {
@@ -385,14 +2126,14 @@ pub async fn handle(
                     buf.extend_from_slice(b"\x00");
                 }
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::HMSET => {
             if params.len() < 2 || params.len() % 2 != 0 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::HMSET,
-                    &params
+                    params
                 )));
             }
 
@@ -404,22 +2145,16 @@ pub async fn handle(
                 pairs
             };
 
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                map.mset(&pairs)?
-            };
-            &global_state.add_key(&key, DataType::Map);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let map = Map::new_in(namespace, key.to_string(), db);
+            map.mset_with_batch(&pairs, batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::HINCRBY => {
             if params.len() != 2 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::HINCRBY,
-                    &params
+                    params
                 )));
             }
 
@@ -431,79 +2166,58 @@ pub async fn handle(
                     return Err(LodisError::ParamTypeError(format!(
                         "command: {:?}, params: {:?}, incr is not an integer string",
                         Command::HINCRBY,
-                        &params
+                        params
                     )));
                 }
             } else {
                 return Err(LodisError::ParamTypeError(format!(
                     "command: {:?}, params: {:?}, incr is not an integer string",
                     Command::HINCRBY,
-                    &params
+                    params
                 )));
             }
 
-            let map = Map::new(key.to_string(), db);
-            let hash_num = map.prefix_hash() % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                map.increase(&params[0], incr)?;
-            };
-            &global_state.add_key(&key, DataType::Map);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let map = Map::new_in(namespace, key.to_string(), db);
+            map.increase_with_batch(&params[0], incr, batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::HKEYS => {
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let values = {
-                let lock = mutex.lock();
-                map.keys()?
-            };
+            let map = Map::new_in(namespace, key.to_string(), db);
+            let values = map.keys_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for key in values {
                 buf.extend_from_slice(&u32_to_u8x4(key.len() as u32)[..]);
                 buf.extend_from_slice(&key);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::HVALS => {
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let values = {
-                let lock = mutex.lock();
-                map.values()?
-            };
+            let map = Map::new_in(namespace, key.to_string(), db);
+            let values = map.values_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for value in values {
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::HEXISTS => {
             if params.len() != 1 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::HEXISTS,
-                    &params
+                    params
                 )));
             }
 
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                map.exists(&params[0])?
-            };
+            let map = Map::new_in(namespace, key.to_string(), db);
+            let value = map.exists_with_batch(&params[0], batch)?;
             if value {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &[1u8]].concat()));
+                return Ok([SUCCESS, &[1u8]].concat());
             } else {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &[0u8]].concat()));
+                return Ok([SUCCESS, &[0u8]].concat());
             }
         }
         Command::HDEL => {
@@ -511,38 +2225,23 @@ pub async fn handle(
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::HDEL,
-                    &params
+                    params
                 )));
             }
 
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                map.delete(&params[0])?
-            }
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let map = Map::new_in(namespace, key.to_string(), db);
+            map.delete_with_batch(&params[0], batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::HLEN => {
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                map.length()?
-            };
-            return Ok(HttpResponse::Ok().body([SUCCESS, &u32_to_u8x4(value)[..]].concat()));
+            let map = Map::new_in(namespace, key.to_string(), db);
+            let value = map.length_with_batch(batch)?;
+            return Ok([SUCCESS, &u32_to_u8x4(value)[..]].concat());
         }
         Command::HRM => {
-            let map = Map::new(key.to_string(), db);
-            let hash_num = siphash(&map.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                map.remove()?
-            };
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let map = Map::new_in(namespace, key.to_string(), db);
+            map.remove_with_batch(batch)?;
+            return Ok(SUCCESS.to_vec());
         }
 
         // ArrayMap
@@ -551,7 +2250,7 @@ pub async fn handle(
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::ALPUSH,
-                    &params
+                    params
                 )));
             }
 
@@ -563,22 +2262,16 @@ pub async fn handle(
                 pairs
             };
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                arraymap.push_left(&pairs)?
-            }
-            &global_state.add_key(&key, DataType::ArrayMap);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            arraymap.push_left_with_batch(&pairs, batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::ALPUSHNX => {
             if params.len() < 2 || params.len() % 2 != 0 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::ALPUSHNX,
-                    &params
+                    params
                 )));
             }
 
@@ -590,22 +2283,16 @@ pub async fn handle(
                 pairs
             };
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                arraymap.pushnx_left(&pairs)?;
-            }
-            &global_state.add_key(&key, DataType::ArrayMap);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            arraymap.pushnx_left_with_batch(&pairs, batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::ARPUSH => {
             if params.len() < 2 || params.len() % 2 != 0 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::ARPUSH,
-                    &params
+                    params
                 )));
             }
 
@@ -617,22 +2304,16 @@ pub async fn handle(
                 pairs
             };
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                arraymap.push(&pairs)?;
-            }
-            &global_state.add_key(&key, DataType::ArrayMap);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            arraymap.push_with_batch(&pairs, batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::ARPUSHNX => {
             if params.len() < 2 || params.len() % 2 != 0 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::ARPUSHNX,
-                    &params
+                    params
                 )));
             }
 
@@ -644,22 +2325,16 @@ pub async fn handle(
                 pairs
             };
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                arraymap.pushnx(&pairs)?;
-            }
-            &global_state.add_key(&key, DataType::ArrayMap);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            arraymap.pushnx_with_batch(&pairs, batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::AINCRBY => {
             if params.len() != 2 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::AINCRBY,
-                    &params
+                    params
                 )));
             }
 
@@ -671,26 +2346,40 @@ pub async fn handle(
                     return Err(LodisError::ParamTypeError(format!(
                         "command: {:?}, params: {:?}, incr is not an integer string",
                         Command::AINCRBY,
-                        &params
+                        params
                     )));
                 }
             } else {
                 return Err(LodisError::ParamTypeError(format!(
                     "command: {:?}, params: {:?}, incr is not an integer string",
                     Command::AINCRBY,
-                    &params
+                    params
                 )));
             }
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = arraymap.prefix_hash() % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                arraymap.increase(&params[0], incr)?;
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            arraymap.increase_with_batch(&params[0], incr, batch)?;
+            return Ok(SUCCESS.to_vec());
+        }
+        // Set the value of the field only if its value equals the expected value,
+        // or the field does not exist if no expected value is given
+        Command::ACAS => {
+            if params.len() != 2 && params.len() != 3 {
+                return Err(LodisError::ParamNoMatch(format!(
+                    "command: {:?}, params: {:?}",
+                    Command::ACAS,
+                    params
+                )));
+            }
+
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let expected = params.get(2).map(|v| &v[..]);
+            let value = arraymap.cas_with_batch(&params[0], expected, &params[1], batch)?;
+            if value {
+                return Ok([SUCCESS, &[1u8]].concat());
+            } else {
+                return Ok([SUCCESS, &[0u8]].concat());
             }
-            &global_state.add_key(&key, DataType::ArrayMap);
-            return Ok(HttpResponse::Ok().body(SUCCESS));
         }
 
         // Result data structure
@@ -700,13 +2389,8 @@ pub async fn handle(
         // else
         // SUCCESS
         Command::ALPOP => {
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                arraymap.pop_left()?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let value = arraymap.pop_left_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             if let Some((key, value)) = value {
@@ -715,16 +2399,11 @@ pub async fn handle(
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::ARPOP => {
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                arraymap.pop()?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let value = arraymap.pop_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             if let Some((key, value)) = value {
@@ -733,16 +2412,11 @@ pub async fn handle(
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::ARANDPOP => {
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                arraymap.pop_random()?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let value = arraymap.pop_random_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             if let Some((key, value)) = value {
@@ -751,38 +2425,28 @@ pub async fn handle(
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::AGET => {
             if params.len() != 1 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::AGET,
-                    &params
+                    params
                 )));
             }
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                arraymap.get(&params[0])?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let value = arraymap.get_with_batch(&params[0], batch)?;
             if let Some(value) = value {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &*value].concat()));
+                return Ok([SUCCESS, &*value].concat());
             } else {
-                return Ok(HttpResponse::Ok().body(SUCCESS));
+                return Ok(SUCCESS.to_vec());
             }
         }
         Command::ARAND => {
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                arraymap.random()?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let value = arraymap.random_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             if let Some((key, value)) = value {
@@ -791,14 +2455,14 @@ pub async fn handle(
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::ALRANGE => {
             if params.len() != 2 || params[0].len() != 4 || params[1].len() != 4 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::ALRANGE,
-                    &params
+                    params
                 )));
             }
 
@@ -808,14 +2472,8 @@ pub async fn handle(
             buf.clone_from_slice(&params[1]);
             let end = u8x4_to_u32(&buf);
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-
-            let values = {
-                let lock = mutex.lock();
-                arraymap.range(start, end, Direction::Forward)?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let values = arraymap.range_with_batch(start, end, Direction::Forward, batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for (key, value) in values {
@@ -824,31 +2482,25 @@ pub async fn handle(
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::ARRANGE => {
             if params.len() != 2 || params[0].len() != 4 || params[1].len() != 4 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::ARRANGE,
-                    &params
+                    params
                 )));
             }
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
             let mut buf: [u8; 4] = [0; 4];
             buf.clone_from_slice(&params[0]);
             let start = u8x4_to_u32(&buf);
             buf.clone_from_slice(&params[1]);
             let end = u8x4_to_u32(&buf);
 
-            let values = {
-                let lock = mutex.lock();
-                arraymap.range(start, end, Direction::Reverse)?
-            };
+            let values = arraymap.range_with_batch(start, end, Direction::Reverse, batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for (key, value) in values {
@@ -857,48 +2509,33 @@ pub async fn handle(
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::AKEYS => {
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let values = {
-                let lock = mutex.lock();
-                arraymap.keys()?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let values = arraymap.keys_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for key in values {
                 buf.extend_from_slice(&u32_to_u8x4(key.len() as u32)[..]);
                 buf.extend_from_slice(&key);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::AVALS => {
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let values = {
-                let lock = mutex.lock();
-                arraymap.values()?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let values = arraymap.values_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for value in values {
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::AALL => {
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let values = {
-                let lock = mutex.lock();
-                arraymap.all()?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let values = arraymap.all_with_batch(batch)?;
             let mut buf = Vec::new();
             buf.extend_from_slice(SUCCESS);
             for (key, value) in values {
@@ -907,67 +2544,955 @@ pub async fn handle(
                 buf.extend_from_slice(&u32_to_u8x4(value.len() as u32)[..]);
                 buf.extend_from_slice(&value);
             }
-            return Ok(HttpResponse::Ok().body(buf));
+            return Ok(buf);
         }
         Command::AEXISTS => {
             if params.len() != 1 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::AEXISTS,
-                    &params
+                    params
                 )));
             }
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                arraymap.exists(&params[0])?
-            };
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let value = arraymap.exists_with_batch(&params[0], batch)?;
             if value {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &[1u8]].concat()));
+                return Ok([SUCCESS, &[1u8]].concat());
             } else {
-                return Ok(HttpResponse::Ok().body([SUCCESS, &[0u8]].concat()));
+                return Ok([SUCCESS, &[0u8]].concat());
             }
         }
         Command::ALEN => {
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                arraymap.length()?
-            };
-            return Ok(HttpResponse::Ok().body([SUCCESS, &u32_to_u8x4(value)[..]].concat()));
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            let value = arraymap.length_with_batch(batch)?;
+            return Ok([SUCCESS, &u32_to_u8x4(value)[..]].concat());
         }
         Command::ADEL => {
             if params.len() != 1 {
                 return Err(LodisError::ParamNoMatch(format!(
                     "command: {:?}, params: {:?}",
                     Command::ADEL,
-                    &params
+                    params
                 )));
             }
 
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            {
-                let lock = mutex.lock();
-                arraymap.delete(&params[0])?;
-            }
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            arraymap.delete_with_batch(&params[0], batch)?;
+            return Ok(SUCCESS.to_vec());
         }
         Command::ARM => {
-            let arraymap = ArrayMap::new(key.to_string(), db);
-            let hash_num = siphash(&arraymap.prefix()) % PRIME;
-            let mutex = &global_state.locks[hash_num as usize];
-            let value = {
-                let lock = mutex.lock();
-                arraymap.remove()?
+            let arraymap = ArrayMap::new_in(namespace, key.to_string(), db);
+            arraymap.remove_with_batch(batch)?;
+            return Ok(SUCCESS.to_vec());
+        }
+
+        // Watch
+        Command::LWATCH | Command::HWATCH | Command::AWATCH => {
+            watch(global_state, namespace, command, key, params)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_handler {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    use actix_web::{
+        dev::ServiceResponse,
+        rt::{time::delay_for, System},
+        test, web, App,
+    };
+    use lodisdb::{changes_since, common::DataType, u32_to_u8x4, u8x4_to_u32, u8x8_to_u64, List};
+    use log::LevelFilter;
+
+    use super::{parse_frame, run_pipeline};
+    use crate::{
+        acl::Acl,
+        common::Command,
+        error::LodisError,
+        logging::{LogFormat, REQUEST_ID_HEADER},
+        observe,
+        replication::start_replica,
+        resp,
+        routes::make_route,
+        state::GlobalState,
+        utils::LodisConfig,
+    };
+
+    fn config(db_path: &str) -> LodisConfig {
+        LodisConfig {
+            db_path: db_path.to_string(),
+            ip_port: "127.0.0.1:0".to_string(),
+            workers: 1,
+            backup_path: None,
+            backup_keep: 0,
+            checkpoint_path: None,
+            checkpoint_keep: 0,
+            checkpoint_interval: 0,
+            wal_ttl: 0,
+            replica_of: None,
+            replica_token: None,
+            replica_tls: None,
+            cdc_keep: 0,
+            mixed_types: false,
+            log_level: LevelFilter::Off,
+            log_format: LogFormat::Text,
+            slowlog_threshold: 0,
+            slowlog_max_len: 0,
+            acl: None,
+            tls: None,
+            resp_ip_port: None,
+            resp_max_connections: 0,
+            pipeline_max_size: 1024,
+        }
+    }
+
+    fn encode(params: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for param in params {
+            body.extend_from_slice(&u32_to_u8x4(param.len() as u32));
+            body.extend_from_slice(param);
+        }
+        body
+    }
+
+    fn frame(command: &str, key: &[u8], params: &[&[u8]]) -> Vec<web::BytesMut> {
+        vec![
+            web::BytesMut::from(command.as_bytes()),
+            web::BytesMut::from(key),
+            web::BytesMut::from(&encode(params)[..]),
+        ]
+    }
+
+    // The body of MULTI or PIPELINE
+    fn frames(frames: &[Vec<web::BytesMut>]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for frame in frames {
+            body.extend_from_slice(&encode(
+                &frame.iter().map(|param| &param[..]).collect::<Vec<_>>(),
+            ));
+        }
+        body
+    }
+
+    fn decode(mut content: &[u8]) -> Vec<Vec<u8>> {
+        let mut values = Vec::new();
+        while !content.is_empty() {
+            let mut len = [0; 4];
+            len.copy_from_slice(&content[..4]);
+            let len = u8x4_to_u32(&len) as usize;
+            values.push(content[4..4 + len].to_vec());
+            content = &content[4 + len..];
+        }
+        values
+    }
+
+    fn list(global_state: &GlobalState, key: &str) -> Vec<Vec<u8>> {
+        List::new_in("", key.to_string(), global_state.db.clone())
+            .all()
+            .unwrap()
+            .iter()
+            .map(|value| value.to_vec())
+            .collect()
+    }
+
+    // (id, [command, namespace, key], args) of the entries of SLOWLOG GET
+    fn slow_entries(mut content: &[u8]) -> Vec<(u64, Vec<String>, Vec<Vec<u8>>)> {
+        fn take<'a>(content: &mut &'a [u8], n: usize) -> &'a [u8] {
+            let (bytes, rest) = content.split_at(n);
+            *content = rest;
+            bytes
+        }
+        fn take_len(content: &mut &[u8]) -> usize {
+            let mut len = [0; 4];
+            len.copy_from_slice(take(content, 4));
+            u8x4_to_u32(&len) as usize
+        }
+
+        let mut entries = Vec::new();
+        while !content.is_empty() {
+            let mut id = [0; 8];
+            id.copy_from_slice(take(&mut content, 8));
+            // Timestamp and duration
+            take(&mut content, 16);
+            let mut fields = Vec::new();
+            for _ in 0..3 {
+                let len = take_len(&mut content);
+                fields.push(String::from_utf8(take(&mut content, len).to_vec()).unwrap());
+            }
+            let mut args = Vec::new();
+            for _ in 0..take_len(&mut content) {
+                let len = take_len(&mut content);
+                args.push(take(&mut content, len).to_vec());
+            }
+            entries.push((u8x8_to_u64(&id), fields, args));
+        }
+        entries
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        System::new("test_parse_frame").block_on(async {
+            let (command, key, params) = parse_frame(&frame("rpush", b"l", &[b"a", b""]), &None)
+                .await
+                .unwrap();
+            assert_eq!(command, Command::RPUSH);
+            assert_eq!(key, "l");
+            assert_eq!(params, vec![web::BytesMut::from("a"), web::BytesMut::new()]);
+
+            let err = |frame: Vec<web::BytesMut>| async move {
+                parse_frame(&frame, &None).await.unwrap_err()
+            };
+            assert!(matches!(
+                err(frame("SHUTDOWN", b"l", &[])).await,
+                LodisError::UnknownCommand(_)
+            ));
+            assert!(matches!(
+                err(frame("\u{ff}", b"l", &[])).await,
+                LodisError::UnknownCommand(_)
+            ));
+            let mut broken = frame("RPUSH", b"\xffl", &[b"a"]);
+            assert!(matches!(
+                err(broken.clone()).await,
+                LodisError::ParamNoMatch(_)
+            ));
+            broken[1] = web::BytesMut::from("l");
+            broken[2].truncate(3);
+            assert!(matches!(err(broken).await, LodisError::ParseParamError));
+
+            let acl = Acl::parse(
+                r#"
+                [[users]]
+                name = "reader"
+                token = "reader-token"
+                categories = ["read"]
+                keys = ["user:*"]
+                "#,
+            )
+            .unwrap();
+            let user = Some(acl.user("reader-token").unwrap());
+            assert!(parse_frame(&frame("LRANGE", b"user:1", &[]), &user)
+                .await
+                .is_ok());
+            assert!(matches!(
+                parse_frame(&frame("RPUSH", b"user:1", &[b"a"]), &user).await,
+                Err(LodisError::NoPermission(_))
+            ));
+            assert!(matches!(
+                parse_frame(&frame("LRANGE", b"other", &[]), &user).await,
+                Err(LodisError::NoPermission(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_run_pipeline() {
+        let path = "test-handler-db1";
+        {
+            let global_state = GlobalState::new(&config(path)).unwrap();
+            let command = |command: Command, key: &str, params: &[&[u8]]| {
+                (
+                    command,
+                    key.to_string(),
+                    params
+                        .iter()
+                        .map(|param| web::BytesMut::from(*param))
+                        .collect(),
+                )
             };
-            return Ok(HttpResponse::Ok().body(SUCCESS));
+            assert!(run_pipeline(&global_state, "", &[]).is_empty());
+
+            let values = run_pipeline(
+                &global_state,
+                "",
+                &[
+                    command(Command::RPUSH, "l", &[b"a"]),
+                    command(Command::RPUSH, "l", &[b"b"]),
+                    // `l` is a list pushed by the commands before, in the same batch
+                    command(Command::HSET, "l", &[b"f", b"v"]),
+                    command(Command::RPUSH, "l", &[b"c"]),
+                    command(Command::RPUSH, "l", &[]),
+                    command(Command::HSET, "m", &[b"f", b"v"]),
+                ],
+            );
+            assert_eq!(values.len(), 6);
+            assert!(values[0].is_ok() && values[1].is_ok() && values[3].is_ok());
+            assert!(matches!(values[2], Err(LodisError::WrongType(_))));
+            assert!(matches!(values[4], Err(LodisError::ParamNoMatch(_))));
+            assert!(values[5].is_ok());
+            assert_eq!(list(&global_state, "l"), vec![b"a", b"b", b"c"]);
+            let values = run_pipeline(
+                &global_state,
+                "",
+                &[
+                    command(Command::LLEN, "l", &[]),
+                    command(Command::HGET, "m", &[b"f"]),
+                ],
+            );
+            assert!(values.iter().all(|value| value.is_ok()), "{:?}", values);
+
+            // Many consecutive failures, between runs of commands which succeed
+            let mut commands = Vec::new();
+            for i in 0..200_000 {
+                if i % 50_000 < 100 {
+                    commands.push(command(Command::RPUSH, "many", &[b"x"]));
+                } else {
+                    commands.push(command(Command::RPUSH, "many", &[]));
+                }
+            }
+            let values = run_pipeline(&global_state, "", &commands);
+            assert_eq!(values.len(), commands.len());
+            for (i, value) in values.iter().enumerate() {
+                if i % 50_000 < 100 {
+                    assert!(value.is_ok(), "{}", i);
+                } else {
+                    assert!(matches!(value, Err(LodisError::ParamNoMatch(_))), "{}", i);
+                }
+            }
+            assert_eq!(list(&global_state, "many").len(), 400);
         }
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_handle_pipeline() {
+        let path = "test-handler-db2";
+        System::new("test_handle_pipeline").block_on(async move {
+            let global_state = web::Data::new(GlobalState::new(&config(path)).unwrap());
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(global_state.clone())
+                    .service(make_route()),
+            )
+            .await;
+            let pipeline = |body: Vec<u8>| {
+                test::TestRequest::post()
+                    .uri("/pipeline")
+                    .set_payload(body)
+                    .to_request()
+            };
+
+            let body = frames(&[
+                frame("RPUSH", b"p", &[b"a"]),
+                frame("NOSUCHCOMMAND", b"p", &[]),
+                frame("RPUSH", b"p", &[b"b", b"c"]),
+                frame("LLEN", b"p", &[]),
+            ]);
+            let response = test::call_service(&mut app, pipeline(body)).await;
+            assert!(response.status().is_success());
+            let content = test::read_body(response).await;
+            assert_eq!(content[0], 0);
+            let values = decode(&content[1..]);
+            assert_eq!(values.len(), 4);
+            assert_eq!(values[0], vec![0]);
+            assert!(values[1].starts_with(b"7Unknown command"));
+            assert_eq!(values[2], vec![0]);
+            assert_eq!(values[3][0], 0);
+            assert_eq!(list(&global_state, "p"), vec![b"a", b"b", b"c"]);
+            let metrics = global_state.stats.metrics(&[]);
+            assert!(metrics.contains("lodis_commands_total{command=\"RPUSH\"} 2\n"));
+            assert!(metrics.contains("lodis_commands_total{command=\"LLEN\"} 1\n"));
+
+            // Not a multiple of 3 params
+            let response = test::call_service(&mut app, pipeline(encode(&[b"RPUSH", b"p"]))).await;
+            assert!(test::read_body(response).await.starts_with(b"5"));
+
+            // Larger than LODIS_PIPELINE_MAX_SIZE
+            let response = test::call_service(
+                &mut app,
+                pipeline(encode(&[b"RPUSH", b"p", &encode(&[&[b'x'; 1024]])])),
+            )
+            .await;
+            assert!(test::read_body(response)
+                .await
+                .starts_with(b"1Body is larger"));
+            assert_eq!(list(&global_state, "p").len(), 3);
+        });
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_script_changes() {
+        let path = "test-handler-db3";
+        System::new("test_script_changes").block_on(async move {
+            let mut config = config(path);
+            config.cdc_keep = 100;
+            let global_state = web::Data::new(GlobalState::new(&config).unwrap());
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(global_state.clone())
+                    .service(make_route()),
+            )
+            .await;
+
+            let body = encode(&[
+                b"rpush(keys[0], args[0]); hget(keys[1], \"f\"); \"ok\"",
+                &u32_to_u8x4(2),
+                b"list",
+                b"l",
+                b"hashmap",
+                b"m",
+                b"a",
+            ]);
+            let request = test::TestRequest::post()
+                .uri("/eval")
+                .set_payload(body)
+                .to_request();
+            let response = test::call_service(&mut app, request).await;
+            assert_eq!(&test::read_body(response).await[..], b"\x00ok");
+
+            // Only the written key is a change, the key which is only read is not
+            let changes = changes_since(&global_state.db, 0, 10).unwrap();
+            assert_eq!(changes.len(), 1);
+            // Timestamp, data type, then the key
+            let change = &changes[0].1;
+            assert_eq!(change[8..9], DataType::List.flag()[..]);
+            assert_eq!(change[9..13], u32_to_u8x4(1)[..]);
+            assert_eq!(&change[13..14], b"l");
+        });
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_scripts() {
+        let path = "test-handler-db4";
+        System::new("test_scripts").block_on(async move {
+            let global_state = web::Data::new(GlobalState::new(&config(path)).unwrap());
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(global_state.clone())
+                    .service(make_route()),
+            )
+            .await;
+            let post = |uri: &str, body: Vec<u8>| {
+                test::TestRequest::post()
+                    .uri(uri)
+                    .set_payload(body)
+                    .to_request()
+            };
+            let script: &[u8] =
+                b"let item = lpop(keys[0]); if item != () { rpush(keys[1], item) } item";
+
+            let body = encode(&[
+                b"rpush(keys[0], args[0]); rpush(keys[0], args[1])",
+                &u32_to_u8x4(1),
+                b"list",
+                b"from",
+                b"a",
+                b"b",
+            ]);
+            let response = test::call_service(&mut app, post("/eval", body)).await;
+            assert_eq!(test::read_body(response).await[0], 0);
+            assert_eq!(list(&global_state, "from"), vec![b"a", b"b"]);
+
+            // EVAL
+            let body = encode(&[script, &u32_to_u8x4(2), b"list", b"from", b"list", b"to"]);
+            let response = test::call_service(&mut app, post("/eval", body)).await;
+            assert_eq!(&test::read_body(response).await[..], b"\x00a");
+            assert_eq!(list(&global_state, "from"), vec![b"b"]);
+            assert_eq!(list(&global_state, "to"), vec![b"a"]);
+
+            // SCRIPTLOAD, then EVALSHA by the returned hash
+            let response =
+                test::call_service(&mut app, post("/scriptload", encode(&[script]))).await;
+            let content = test::read_body(response).await;
+            assert_eq!(content[0], 0);
+            let hash = content[1..].to_vec();
+            let body = encode(&[&hash, &u32_to_u8x4(2), b"list", b"from", b"list", b"to"]);
+            let response = test::call_service(&mut app, post("/evalsha", body)).await;
+            assert_eq!(&test::read_body(response).await[..], b"\x00b");
+            assert!(list(&global_state, "from").is_empty());
+            assert_eq!(list(&global_state, "to"), vec![b"a", b"b"]);
+
+            // EVALSHA of a script which is not cached
+            let body = encode(&[b"0000", &u32_to_u8x4(0)]);
+            let response = test::call_service(&mut app, post("/evalsha", body)).await;
+            assert_eq!(
+                test::read_body(response).await[0],
+                LodisError::ScriptNotFound(String::new()).status()
+            );
+
+            // A script which fails after a write applies none of its writes
+            let body = encode(&[
+                b"lpop(keys[0]); throw \"fail\"",
+                &u32_to_u8x4(1),
+                b"list",
+                b"to",
+            ]);
+            let response = test::call_service(&mut app, post("/eval", body)).await;
+            assert_eq!(test::read_body(response).await[0], b';');
+            assert_eq!(list(&global_state, "to"), vec![b"a", b"b"]);
+
+            // A key which is not declared is rejected
+            let body = encode(&[
+                b"lpop(keys[0]); rpush(\"other\", \"x\")",
+                &u32_to_u8x4(1),
+                b"list",
+                b"to",
+            ]);
+            let response = test::call_service(&mut app, post("/eval", body)).await;
+            let content = test::read_body(response).await;
+            assert_eq!(content[0], b';');
+            assert!(String::from_utf8_lossy(&content).contains("key `other` is not declared"));
+            assert_eq!(list(&global_state, "to"), vec![b"a", b"b"]);
+            assert!(list(&global_state, "other").is_empty());
+
+            // Strings and arrays which are too large fail the script
+            for script in [
+                &b"let s = \"x\"; loop { s += s; }"[..],
+                b"let a = [0]; loop { a += a; }",
+            ]
+            .iter()
+            {
+                let body = encode(&[script, &u32_to_u8x4(0)]);
+                let response = test::call_service(&mut app, post("/eval", body)).await;
+                let content = test::read_body(response).await;
+                assert_eq!(content[0], b';');
+                assert!(String::from_utf8_lossy(&content).contains("too large"));
+            }
+        });
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_watch() {
+        let path = "test-handler-db5";
+        System::new("test_watch").block_on(async move {
+            let global_state = web::Data::new(GlobalState::new(&config(path)).unwrap());
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(global_state.clone())
+                    .service(make_route()),
+            )
+            .await;
+            let post = |uri: &str, body: Vec<u8>| {
+                test::TestRequest::post()
+                    .uri(uri)
+                    .set_payload(body)
+                    .to_request()
+            };
+            let watched_changed = LodisError::WatchedChanged(String::new()).status();
+
+            for (watch, write_uri, write) in [
+                ("/lwatch/wl", "/rpush/wl", encode(&[b"a"])),
+                ("/hwatch/wm", "/hset/wm", encode(&[b"f", b"v"])),
+                ("/awatch/wa", "/arpush/wa", encode(&[b"f", b"v"])),
+            ]
+            .iter()
+            {
+                let response = test::call_service(&mut app, post(watch, vec![])).await;
+                let content = test::read_body(response).await;
+                assert_eq!(content[0], 0);
+                let token = content[1..].to_vec();
+                assert_eq!(token.len(), 16);
+
+                // Unchanged
+                let response = test::call_service(&mut app, post(watch, encode(&[&token]))).await;
+                assert_eq!(&test::read_body(response).await[..], &[0]);
+
+                // A write changes the token
+                let response = test::call_service(&mut app, post(write_uri, write.clone())).await;
+                assert_eq!(test::read_body(response).await[0], 0);
+                let response = test::call_service(&mut app, post(watch, vec![])).await;
+                assert_ne!(test::read_body(response).await[1..], token[..]);
+                let response = test::call_service(&mut app, post(watch, encode(&[&token]))).await;
+                assert_eq!(test::read_body(response).await[0], watched_changed);
+            }
+
+            // MULTI with a stale token fails with WatchedChanged and writes nothing
+            let response = test::call_service(&mut app, post("/lwatch/l", vec![])).await;
+            let token = test::read_body(response).await[1..].to_vec();
+            let response = test::call_service(&mut app, post("/rpush/l", encode(&[b"a"]))).await;
+            assert_eq!(test::read_body(response).await[0], 0);
+            let body = frames(&[
+                frame("LWATCH", b"l", &[&token]),
+                frame("RPUSH", b"l", &[b"b"]),
+                frame("RPUSH", b"other", &[b"b"]),
+            ]);
+            let response = test::call_service(&mut app, post("/multi", body)).await;
+            assert_eq!(test::read_body(response).await[0], watched_changed);
+            assert_eq!(list(&global_state, "l"), vec![b"a"]);
+            assert!(list(&global_state, "other").is_empty());
+
+            // MULTI with the current token writes
+            let response = test::call_service(&mut app, post("/lwatch/l", vec![])).await;
+            let token = test::read_body(response).await[1..].to_vec();
+            let body = frames(&[
+                frame("LWATCH", b"l", &[&token]),
+                frame("RPUSH", b"l", &[b"b"]),
+            ]);
+            let response = test::call_service(&mut app, post("/multi", body)).await;
+            assert_eq!(test::read_body(response).await[0], 0);
+            assert_eq!(list(&global_state, "l"), vec![b"a", b"b"]);
+        });
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_handle_multi() {
+        let path = "test-handler-db6";
+        System::new("test_handle_multi").block_on(async move {
+            let global_state = web::Data::new(GlobalState::new(&config(path)).unwrap());
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(global_state.clone())
+                    .service(make_route()),
+            )
+            .await;
+            let multi = |body: Vec<u8>| {
+                test::TestRequest::post()
+                    .uri("/multi")
+                    .set_payload(body)
+                    .to_request()
+            };
+
+            let body = frames(&[
+                frame("RPUSH", b"l", &[b"a", b"b"]),
+                frame("HSET", b"m", &[b"f", b"v"]),
+                frame("LLEN", b"l", &[]),
+            ]);
+            let response = test::call_service(&mut app, multi(body)).await;
+            let content = test::read_body(response).await;
+            assert_eq!(content[0], 0);
+            let values = decode(&content[1..]);
+            assert_eq!(values.len(), 3);
+            assert_eq!(values[0], vec![0]);
+            assert_eq!(values[1], vec![0]);
+            assert_eq!(values[2], [&[0][..], &u32_to_u8x4(2)[..]].concat());
+            assert_eq!(list(&global_state, "l"), vec![b"a", b"b"]);
+
+            // The last command fails, so the writes of the commands before it are not kept
+            let body = frames(&[
+                frame("RPUSH", b"l", &[b"c"]),
+                frame("LPOP", b"l", &[]),
+                frame("RPUSH", b"new", &[b"x"]),
+                frame("HSET", b"l", &[b"f", b"v"]),
+            ]);
+            let response = test::call_service(&mut app, multi(body)).await;
+            let content = test::read_body(response).await;
+            assert_eq!(
+                content[0],
+                LodisError::TransactionAborted(String::new()).status()
+            );
+            assert!(String::from_utf8_lossy(&content).contains("command 3, HSET"));
+            assert_eq!(list(&global_state, "l"), vec![b"a", b"b"]);
+            assert!(list(&global_state, "new").is_empty());
+        });
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_replication() {
+        let primary_path = "test-handler-db7";
+        let replica_path = "test-handler-db8";
+        System::new("test_replication").block_on(async move {
+            let mut primary_config = config(primary_path);
+            primary_config.wal_ttl = 3600;
+            let primary_state = web::Data::new(GlobalState::new(&primary_config).unwrap());
+            let server_state = primary_state.clone();
+            let primary = test::start(move || {
+                App::new()
+                    .app_data(server_state.clone())
+                    .service(make_route())
+            });
+            let write = |uri: &str, params: &[&[u8]]| primary.post(uri).send_body(encode(params));
+            assert!(write("/rpush/l", &[b"a", b"b"])
+                .await
+                .unwrap()
+                .status()
+                .is_success());
+
+            let mut replica_config = config(replica_path);
+            replica_config.replica_of = Some(primary.addr().to_string());
+            let replica_state = web::Data::new(GlobalState::new(&replica_config).unwrap());
+            start_replica(
+                replica_state.clone(),
+                primary.addr().to_string(),
+                None,
+                None,
+            );
+            // Wait until the replica has the data of the primary
+            let synced = |expected: &'static [&'static [u8]]| {
+                let replica_state = replica_state.clone();
+                async move {
+                    for _ in 0..200 {
+                        if list(&replica_state, "l") == expected {
+                            return true;
+                        }
+                        delay_for(Duration::from_millis(50)).await;
+                    }
+                    false
+                }
+            };
+
+            // The snapshot of the primary, then the writes after it
+            assert!(synced(&[b"a", b"b"]).await);
+            assert!(write("/rpush/l", &[b"c"])
+                .await
+                .unwrap()
+                .status()
+                .is_success());
+            assert!(write("/lpop/l", &[]).await.unwrap().status().is_success());
+            assert!(synced(&[b"b", b"c"]).await);
+
+            // The replica serves reads, and rejects writes
+            let mut replica = test::init_service(
+                App::new()
+                    .app_data(replica_state.clone())
+                    .service(make_route()),
+            )
+            .await;
+            let request = test::TestRequest::post().uri("/llen/l").to_request();
+            let response = test::call_service(&mut replica, request).await;
+            assert_eq!(
+                &test::read_body(response).await[..],
+                &[&[0][..], &u32_to_u8x4(2)[..]].concat()[..]
+            );
+            for (uri, body) in [
+                ("/rpush/l", encode(&[b"x"])),
+                ("/del", encode(&[b"l"])),
+                ("/flushdb", vec![]),
+            ]
+            .iter()
+            {
+                let request = test::TestRequest::post()
+                    .uri(uri)
+                    .set_payload(body.clone())
+                    .to_request();
+                let response = test::call_service(&mut replica, request).await;
+                assert_eq!(
+                    test::read_body(response).await[0],
+                    LodisError::ReadOnly.status()
+                );
+            }
+            assert_eq!(list(&replica_state, "l"), vec![b"b", b"c"]);
+            assert_eq!(list(&primary_state, "l"), vec![b"b", b"c"]);
+        });
+        let _ = fs::remove_dir_all(primary_path);
+        let _ = fs::remove_dir_all(replica_path);
+    }
+
+    #[test]
+    fn test_command_metrics() {
+        let path = "test-handler-db9";
+        System::new("test_command_metrics").block_on(async move {
+            let global_state = web::Data::new(GlobalState::new(&config(path)).unwrap());
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(global_state.clone())
+                    .service(make_route()),
+            )
+            .await;
+            let count = |command: &str| {
+                let metrics = global_state.stats.metrics(&[]);
+                let line = format!("lodis_commands_total{{command=\"{}\"}} ", command);
+                metrics
+                    .lines()
+                    .find(|metric| metric.starts_with(&line))
+                    .map(|metric| metric[line.len()..].parse::<u64>().unwrap())
+                    .unwrap_or(0)
+            };
+
+            // Each command of MULTI is counted, also when the transaction fails
+            let body = frames(&[
+                frame("RPUSH", b"l", &[b"a"]),
+                frame("RPUSH", b"l", &[b"b"]),
+                frame("LLEN", b"l", &[]),
+            ]);
+            let request = test::TestRequest::post()
+                .uri("/multi")
+                .set_payload(body)
+                .to_request();
+            let response = test::call_service(&mut app, request).await;
+            assert_eq!(test::read_body(response).await[0], 0);
+            let body = frames(&[frame("RPUSH", b"l", &[b"c"]), frame("HSET", b"l", &[])]);
+            let request = test::TestRequest::post()
+                .uri("/multi")
+                .set_payload(body)
+                .to_request();
+            let response = test::call_service(&mut app, request).await;
+            assert_ne!(test::read_body(response).await[0], 0);
+            assert_eq!(count("RPUSH"), 3);
+            assert_eq!(count("LLEN"), 1);
+            assert_eq!(count("HSET"), 1);
+            assert_eq!(count("MULTI"), 0);
+
+            // Commands over the Redis protocol
+            let ip_port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .to_string();
+            resp::start(global_state.clone(), &ip_port, 4).unwrap();
+            let mut stream = TcpStream::connect(&ip_port).unwrap();
+            stream
+                .write_all(b"RPUSH l d\r\nLLEN l\r\nLLEN l\r\n")
+                .unwrap();
+            let mut replies = [0; 12];
+            stream.read_exact(&mut replies).unwrap();
+            assert_eq!(&replies, b":3\r\n:3\r\n:3\r\n");
+            assert_eq!(count("RPUSH"), 4);
+            assert_eq!(count("LLEN"), 3);
+        });
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_request_id() {
+        let path = "test-handler-db10";
+        System::new("test_request_id").block_on(async move {
+            let global_state = web::Data::new(GlobalState::new(&config(path)).unwrap());
+            let observe_state = global_state.clone();
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(global_state.clone())
+                    .wrap_fn(move |request, service| {
+                        observe(observe_state.clone(), request, service)
+                    })
+                    .service(make_route()),
+            )
+            .await;
+            let request_id = |response: &ServiceResponse| {
+                response
+                    .headers()
+                    .get(REQUEST_ID_HEADER)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            };
+
+            // The id of the client is returned
+            let request = test::TestRequest::post()
+                .uri("/rpush/l")
+                .header(REQUEST_ID_HEADER, "client-1")
+                .set_payload(encode(&[b"a"]))
+                .to_request();
+            let response = test::call_service(&mut app, request).await;
+            assert!(response.status().is_success());
+            assert_eq!(request_id(&response), "client-1");
+
+            // Requests without an id, or with an id which is too long, get new ids
+            let mut ids = Vec::new();
+            for id in ["", &"x".repeat(129)].iter() {
+                let mut request = test::TestRequest::post().uri("/llen/l");
+                if !id.is_empty() {
+                    request = request.header(REQUEST_ID_HEADER, *id);
+                }
+                let response = test::call_service(&mut app, request.to_request()).await;
+                ids.push(request_id(&response));
+            }
+            let prefix = format!("{:x}-", global_state.epoch);
+            assert!(ids.iter().all(|id| id.starts_with(&prefix)));
+            assert_ne!(ids[0], ids[1]);
+
+            // Failed requests get ids too, and are recorded
+            let request = test::TestRequest::post()
+                .uri("/hget/l")
+                .header(REQUEST_ID_HEADER, "client-2")
+                .set_payload(encode(&[b"f"]))
+                .to_request();
+            let response = test::call_service(&mut app, request).await;
+            assert!(!response.status().is_success());
+            assert_eq!(request_id(&response), "client-2");
+            let metrics = global_state.stats.metrics(&[]);
+            assert!(metrics.contains("lodis_requests_total{command=\"RPUSH\"} 1\n"));
+            assert!(metrics.contains("lodis_requests_total{command=\"LLEN\"} 2\n"));
+            assert!(metrics.contains("lodis_errors_total{error=\"WrongType\"} 1\n"));
+        });
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_slowlog() {
+        let path = "test-handler-db11";
+        System::new("test_slowlog").block_on(async move {
+            let mut config = config(path);
+            config.slowlog_max_len = 3;
+            let global_state = web::Data::new(GlobalState::new(&config).unwrap());
+            let observe_state = global_state.clone();
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(global_state.clone())
+                    .wrap_fn(move |request, service| {
+                        observe(observe_state.clone(), request, service)
+                    })
+                    .service(make_route()),
+            )
+            .await;
+            let post = |uri: &str, body: Vec<u8>| {
+                test::TestRequest::post()
+                    .uri(uri)
+                    .set_payload(body)
+                    .to_request()
+            };
+            let long = vec![b'b'; 200];
+            let response =
+                test::call_service(&mut app, post("/ns/app/rpush/l", encode(&[b"a", &long]))).await;
+            assert!(response.status().is_success());
+            let many: Vec<Vec<u8>> = (0..40).map(|i| i.to_string().into_bytes()).collect();
+            let many: Vec<&[u8]> = many.iter().map(|param| &param[..]).collect();
+            let response = test::call_service(&mut app, post("/rpush/l", encode(&many))).await;
+            assert!(response.status().is_success());
+
+            // The latest 2 entries, from the newest one
+            let body = encode(&[b"GET", &u32_to_u8x4(2)]);
+            let response = test::call_service(&mut app, post("/slowlog", body)).await;
+            let content = test::read_body(response).await;
+            assert_eq!(content[0], 0);
+            let got = slow_entries(&content[1..]);
+            assert_eq!(got.len(), 2);
+            let (id, fields, args) = &got[0];
+            assert_eq!(*id, 1);
+            assert_eq!(fields, &["RPUSH", "", "l"]);
+            assert_eq!(args.len(), 32);
+            assert_eq!(&args[30][..], b"30");
+            assert_eq!(&args[31][..], b"... (9 more params)");
+            let (id, fields, args) = &got[1];
+            assert_eq!(*id, 0);
+            assert_eq!(fields, &["RPUSH", "app", "l"]);
+            assert_eq!(args.len(), 2);
+            assert_eq!(&args[0][..], b"a");
+            assert_eq!(
+                &args[1][..],
+                &[&long[..128], b"... (72 more bytes)"].concat()[..]
+            );
+
+            // SLOWLOG itself is recorded, and only the latest 3 entries are kept
+            let response = test::call_service(&mut app, post("/slowlog", encode(&[b"len"]))).await;
+            assert_eq!(&test::read_body(response).await[1..], &u32_to_u8x4(3)[..]);
+            let response = test::call_service(&mut app, post("/slowlog", encode(&[b"GET"]))).await;
+            let got = slow_entries(&test::read_body(response).await[1..]);
+            let ids: Vec<u64> = got.iter().map(|(id, _, _)| *id).collect();
+            assert_eq!(ids, vec![3, 2, 1]);
+            assert_eq!(got[0].1, &["SLOWLOG", "", ""]);
+            // Only the params of commands on keys and commands of several commands are kept
+            assert!(got[0].2.is_empty());
+
+            // RESET removes the entries, but ids go on
+            let response =
+                test::call_service(&mut app, post("/slowlog", encode(&[b"RESET"]))).await;
+            assert_eq!(&test::read_body(response).await[..], &[0]);
+            let response = test::call_service(&mut app, post("/slowlog", encode(&[b"GET"]))).await;
+            let got = slow_entries(&test::read_body(response).await[1..]);
+            assert_eq!(got.len(), 1);
+            assert_eq!(got[0].0, 5);
+            assert_eq!(got[0].1, &["SLOWLOG", "", ""]);
+
+            // An unknown subcommand
+            let response =
+                test::call_service(&mut app, post("/slowlog", encode(&[b"GET", b"1"]))).await;
+            assert_eq!(
+                test::read_body(response).await[0],
+                LodisError::ParamNoMatch(String::new()).status()
+            );
+        });
+        let _ = fs::remove_dir_all(path);
     }
 }
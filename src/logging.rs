@@ -0,0 +1,261 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write as _},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    dev::ServiceResponse,
+    http::{HeaderMap, HeaderName, HeaderValue},
+};
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    Level, LevelFilter, Log, Metadata, Record,
+};
+
+use crate::{common::Namespace, error::LodisError, stats::command_name, utils::json_string};
+
+// The header which carries the id of a request, from the client or generated by the server
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// Longest request id which is accepted from a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Number of request ids generated by the server
+static GENERATED_IDS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub enum LogFormat {
+    // `timestamp LEVEL target: message key=value ...`
+    Text,
+    // `{"timestamp":...,"level":"INFO","target":"...","message":"...","key":value,...}`
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = LodisError;
+
+    fn from_str(s: &str) -> Result<LogFormat, LodisError> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(LodisError::ConfigError),
+        }
+    }
+}
+
+// The sizes of the params of a request, which are put to the extensions of the request by
+// the handler which parses them
+pub struct ParamSizes(pub Vec<usize>);
+
+// Write every log record as a line to stdout
+struct Logger {
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        // Levels are filtered by `log::max_level`
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let line = self.line(record, timestamp);
+        let stdout = io::stdout();
+        let _ = writeln!(stdout.lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+impl Logger {
+    // The line of the record without the line break
+    fn line(&self, record: &Record, timestamp: u128) -> String {
+        let mut line = String::new();
+        match self.format {
+            LogFormat::Text => {
+                let _ = write!(
+                    line,
+                    "{} {:<5} {}: {}",
+                    timestamp,
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+                let _ = record.key_values().visit(&mut TextFields(&mut line));
+            }
+            LogFormat::Json => {
+                let _ = write!(
+                    line,
+                    "{{\"timestamp\":{},\"level\":\"{}\",\"target\":{},\"message\":{}",
+                    timestamp,
+                    record.level(),
+                    json_string(record.target()),
+                    json_string(&record.args().to_string())
+                );
+                let _ = record.key_values().visit(&mut JsonFields(&mut line));
+                line.push('}');
+            }
+        }
+        line
+    }
+}
+
+struct TextFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for TextFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_string();
+        if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+            let _ = write!(self.0, " {}={:?}", key, value);
+        } else {
+            let _ = write!(self.0, " {}={}", key, value);
+        }
+        Ok(())
+    }
+}
+
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let _ = write!(self.0, ",{}:", json_string(key.as_str()));
+        if value.to_u64().is_some() || value.to_i64().is_some() || value.to_bool().is_some() {
+            let _ = write!(self.0, "{}", value);
+        } else {
+            self.0.push_str(&json_string(&value.to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Log records of `level` and above in `format`
+pub fn init(level: LevelFilter, format: LogFormat) {
+    if log::set_boxed_logger(Box::new(Logger { format })).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+// The id of a request, which is the `X-Request-Id` header, or a new id which is unique in all
+// runs of the server
+pub fn request_id(headers: &HeaderMap, epoch: u64) -> String {
+    if let Some(id) = headers.get(REQUEST_ID_HEADER) {
+        if let Ok(id) = id.to_str() {
+            if !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN {
+                return id.to_string();
+            }
+        }
+    }
+    let n = GENERATED_IDS.fetch_add(1, Ordering::SeqCst);
+    format!("{:x}-{:x}", epoch, n)
+}
+
+// Return the request id to the client
+pub fn set_request_id(response: &mut ServiceResponse, request_id: &str) {
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+}
+
+// Log the request of the response, which is handled in `latency`
+//
+// A successful request is logged at the info level, a failed request at the warn level.
+pub fn log_request(response: &ServiceResponse, request_id: &str, latency: Duration) {
+    let failed = !response.status().is_success();
+    let level = if failed { Level::Warn } else { Level::Info };
+    if level > log::max_level() {
+        return;
+    }
+
+    let request = response.request();
+    let command = match request.match_pattern() {
+        Some(pattern) => command_name(&pattern),
+        None => String::new(),
+    };
+    let namespace = Namespace::from_request_head(request).unwrap_or_default();
+    let key = request.match_info().get("key").unwrap_or_default();
+    let body_size: u64 = request
+        .headers()
+        .get("content-length")
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let param_sizes = request
+        .extensions()
+        .get::<ParamSizes>()
+        .map(|sizes| {
+            sizes
+                .0
+                .iter()
+                .map(|size| size.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    let error = response
+        .response()
+        .error()
+        .map(|err| match err.as_error::<LodisError>() {
+            Some(err) => err.name().to_string(),
+            None => err.to_string(),
+        })
+        .unwrap_or_default();
+
+    log::log!(
+        target: "lodis::request",
+        level,
+        request_id = request_id,
+        method = request.method().as_str(),
+        path = request.path(),
+        command = command.as_str(),
+        namespace = &*namespace,
+        key = key,
+        body_size = body_size,
+        param_sizes = param_sizes.as_str(),
+        status = response.status().as_u16(),
+        error = error.as_str(),
+        latency_us = latency.as_micros() as u64;
+        "request"
+    );
+}
+
+#[cfg(test)]
+mod test_logging {
+    use log::{Level, Record};
+
+    use super::{LogFormat, Logger};
+
+    #[test]
+    fn test_line() {
+        let fields: &[(&str, &str)] = &[("request_id", "a-1"), ("path", "/rpush/a b")];
+        let record = |format: LogFormat| {
+            Logger { format }.line(
+                &Record::builder()
+                    .args(format_args!("request \"x\""))
+                    .level(Level::Warn)
+                    .target("lodis::request")
+                    .key_values(&fields)
+                    .build(),
+                1632268800000,
+            )
+        };
+        assert_eq!(
+            record(LogFormat::Text),
+            "1632268800000 WARN  lodis::request: request \"x\" request_id=a-1 path=\"/rpush/a b\""
+        );
+        assert_eq!(
+            record(LogFormat::Json),
+            "{\"timestamp\":1632268800000,\"level\":\"WARN\",\"target\":\"lodis::request\",\
+             \"message\":\"request \\\"x\\\"\",\"request_id\":\"a-1\",\"path\":\"/rpush/a b\"}"
+        );
+    }
+}
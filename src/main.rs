@@ -1,38 +1,313 @@
-use actix_web::{web, App, HttpServer};
+use std::{
+    fs,
+    io::{self, BufReader, BufWriter},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    web, App, HttpServer,
+};
+use clap::{crate_version, App as ClapApp, Arg, SubCommand};
+use futures::{
+    future::{ready, Either},
+    Future,
+};
+
+mod acl;
+mod cdc;
 mod common;
 mod error;
+mod export;
 #[allow(unused_variables)]
 mod handler;
+mod logging;
+mod pubsub;
+mod rdb;
+mod replication;
+mod resp;
 mod routes;
+mod script;
+mod slowlog;
 #[allow(unused_variables)]
 mod state;
+mod stats;
+mod tls;
 mod utils;
 
 use routes::make_route;
 use state::GlobalState;
-use utils::get_config;
+use utils::{get_config, get_db_path, get_restore_config};
+
+// Restore a backup into `LODIS_DB_PATH`, which must be empty
+fn restore(backup_id: Option<&str>) -> error::Result<()> {
+    let config = get_restore_config()?;
+    let backup_id = match backup_id {
+        Some(id) => Some(
+            id.parse::<u32>()
+                .map_err(|_| error::LodisError::ParamTypeError(format!("backup id: {}", id)))?,
+        ),
+        None => None,
+    };
+
+    let db_path = Path::new(&config.db_path);
+    if db_path.exists() && fs::read_dir(db_path)?.next().is_some() {
+        return Err(error::LodisError::Error(format!(
+            "{} is not empty, restore a backup into a fresh path",
+            &config.db_path
+        )));
+    }
+
+    lodisdb::restore_backup(&config.backup_path, db_path, backup_id)?;
+    Ok(())
+}
+
+// Export all data of `LODIS_DB_PATH` to the file, or stdout if the file is `-`
+fn export_data(file: &str) -> error::Result<usize> {
+    let db = Arc::new(lodisdb::make_db(get_db_path()?));
+    if file == "-" {
+        let stdout = io::stdout();
+        export::export(db, &mut BufWriter::new(stdout.lock()))
+    } else {
+        export::export(db, &mut BufWriter::new(fs::File::create(file)?))
+    }
+}
+
+// Import data to `LODIS_DB_PATH` from the file, or stdin if the file is `-`
+fn import_data(file: &str) -> error::Result<usize> {
+    let db = Arc::new(lodisdb::make_db(get_db_path()?));
+    if file == "-" {
+        let stdin = io::stdin();
+        export::import(db, &mut BufReader::new(stdin.lock()))
+    } else {
+        export::import(db, &mut BufReader::new(fs::File::open(file)?))
+    }
+}
+
+// Import a Redis database from a RDB file to `LODIS_DB_PATH`
+fn import_rdb(file: &str, redis_db: Option<&str>) -> error::Result<rdb::RdbReport> {
+    let redis_db = match redis_db {
+        Some(n) => n
+            .parse::<u64>()
+            .map_err(|_| error::LodisError::ParamTypeError(format!("redis db: {}", n)))?,
+        None => 0,
+    };
+    let db = Arc::new(lodisdb::make_db(get_db_path()?));
+    rdb::import_rdb(db, BufReader::new(fs::File::open(file)?), redis_db)
+}
+
+// Create a checkpoint every `interval` seconds in a background thread
+fn schedule_checkpoints(global_state: web::Data<GlobalState>, interval: u64) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(interval));
+        if let Err(err) = global_state.checkpoint() {
+            log::error!("Checkpoint Error: {}", err);
+        }
+    });
+}
+
+// Authorize the request, then record it at the stats and the slow log, log it, and return its
+// id to the client
+fn observe<S>(
+    global_state: web::Data<GlobalState>,
+    request: ServiceRequest,
+    service: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let start = Instant::now();
+    let request_id = logging::request_id(request.headers(), global_state.epoch);
+    let authorized = match &global_state.acl {
+        Some(acl) => acl.authorize(&request),
+        None => Ok(()),
+    };
+    let response = match authorized {
+        Ok(()) => Either::Left(service.call(request)),
+        Err(err) => Either::Right(ready(Ok(request.error_response(err)))),
+    };
+    async move {
+        let mut response = response.await?;
+        let latency = start.elapsed();
+        global_state.stats.record(&response, latency);
+        global_state.slowlog.record(&response, latency);
+        logging::log_request(&response, &request_id, latency);
+        logging::set_request_id(&mut response, &request_id);
+        Ok(response)
+    }
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let config = get_config();
-    if config.is_err() {
-        println!("!!! Environment Error: {:?}", config);
+    let matches = ClapApp::new("lodis")
+        .version(crate_version!())
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restore a backup from LODIS_BACKUP_PATH into an empty LODIS_DB_PATH")
+                .arg(
+                    Arg::with_name("BACKUP_ID")
+                        .help("The id of the backup, the latest backup is restored if not given")
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export all data of LODIS_DB_PATH to a file")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("The file to write, `-` is stdout")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Import data from a file exported by `lodis export` to LODIS_DB_PATH")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("The file to read, `-` is stdin")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-rdb")
+                .about("Import a Redis database from a RDB file to LODIS_DB_PATH")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("The RDB file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("DB")
+                        .long("db")
+                        .takes_value(true)
+                        .help("The Redis database to import, default is 0"),
+                ),
+        )
+        .get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("restore") {
+        if let Err(err) = restore(matches.value_of("BACKUP_ID")) {
+            eprintln!("!!! Restore Error: {}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("export") {
+        match export_data(matches.value_of("FILE").unwrap()) {
+            Ok(count) => eprintln!("Exported {} keys", count),
+            Err(err) => {
+                eprintln!("!!! Export Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import") {
+        match import_data(matches.value_of("FILE").unwrap()) {
+            Ok(count) => println!("Imported {} keys", count),
+            Err(err) => {
+                eprintln!("!!! Import Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import-rdb") {
+        match import_rdb(matches.value_of("FILE").unwrap(), matches.value_of("DB")) {
+            Ok(report) => {
+                println!(
+                    "Imported {} strings, {} lists, {} hashes, {} sets, {} sorted sets",
+                    report.strings, report.lists, report.hashes, report.sets, report.zsets
+                );
+                if report.expired > 0 {
+                    println!("Skipped {} expired keys", report.expired);
+                }
+                if report.other_db > 0 {
+                    println!("Skipped {} keys of other Redis databases", report.other_db);
+                }
+                for unsupported in report.unsupported.iter() {
+                    println!("Unsupported: {}", unsupported);
+                }
+            }
+            Err(err) => {
+                eprintln!("!!! Import Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    let config = get_config().unwrap_or_else(|err| {
+        eprintln!("!!! Environment Error: {}", err);
+        std::process::exit(1);
+    });
+    logging::init(config.log_level, config.log_format);
+    let global_state = web::Data::new(GlobalState::new(&config).unwrap_or_else(|err| {
+        log::error!("State Error: {}", err);
         std::process::exit(1);
+    }));
+
+    if config.checkpoint_interval > 0 {
+        schedule_checkpoints(global_state.clone(), config.checkpoint_interval);
+    }
+
+    if let Some(primary) = &config.replica_of {
+        let tls_config = config.replica_tls.as_ref().map(|tls| {
+            tls.client_config().unwrap_or_else(|err| {
+                log::error!("TLS Error: {}", err);
+                std::process::exit(1);
+            })
+        });
+        replication::start_replica(
+            global_state.clone(),
+            primary.clone(),
+            config.replica_token.clone(),
+            tls_config,
+        );
     }
 
-    let config = config.unwrap();
-    let global_state = web::Data::new(GlobalState::new(&config.db_path));
+    if let Some(ip_port) = &config.resp_ip_port {
+        resp::start(global_state.clone(), ip_port, config.resp_max_connections).unwrap_or_else(
+            |err| {
+                log::error!("RESP Error: can't bind {}: {}", ip_port, err);
+                std::process::exit(1);
+            },
+        );
+    }
 
-    HttpServer::new(move || {
+    let connect_state = global_state.clone();
+    let server = HttpServer::new(move || {
+        let stats_state = global_state.clone();
         App::new()
             .app_data(global_state.clone())
+            .wrap_fn(move |request, service| observe(stats_state.clone(), request, service))
             .service(make_route())
     })
-    .keep_alive(1 * 60 * 60) // 1 hour
-    .bind(&config.ip_port)
-    .expect(&format!("Can't bind {}", &config.ip_port))
-    .workers(config.workers)
-    .run()
-    .await
+    // The client is dropped with the connection
+    .on_connect(move |_, extensions| extensions.insert(connect_state.stats.connect()))
+    .keep_alive(1 * 60 * 60); // 1 hour
+    let server = match &config.tls {
+        Some(tls) => {
+            let tls_config = tls.server_config().unwrap_or_else(|err| {
+                log::error!("TLS Error: {}", err);
+                std::process::exit(1);
+            });
+            server.bind_rustls(&config.ip_port, tls_config)
+        }
+        None => server.bind(&config.ip_port),
+    };
+    server
+        .expect(&format!("Can't bind {}", &config.ip_port))
+        .workers(config.workers)
+        .run()
+        .await
 }
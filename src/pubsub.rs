@@ -0,0 +1,565 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use actix_http::ws::{CloseCode, CloseReason, OpCode, Parser};
+use actix_web::web;
+use futures::{channel::mpsc, future, stream, Stream, StreamExt};
+
+use lodisdb::u32_to_u8x4;
+
+use crate::{
+    error::{LodisError, Result},
+    handler::parse_params,
+    state::GlobalState,
+    utils::glob_match,
+};
+
+// Message format
+//
+// [4bytes length of channel][channel][4bytes length of pattern][pattern][4bytes length of message][message]
+//
+// The pattern is empty if the message is received by subscribing the channel.
+
+// Number of messages which a subscriber can fall behind, it is disconnected after that
+const SUBSCRIBER_BUFFER: usize = 1024;
+// Max size of a websocket frame from a client, and of a message of several frames
+const MAX_WS_FRAME_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+pub enum Target {
+    Channel,
+    Pattern,
+}
+
+#[derive(Clone)]
+pub struct Message {
+    pub channel: web::Bytes,
+    pub pattern: Option<web::Bytes>,
+    pub payload: web::Bytes,
+}
+
+impl Message {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let pattern: &[u8] = match &self.pattern {
+            Some(pattern) => pattern,
+            None => &[],
+        };
+        for bytes in [&self.channel[..], pattern, &self.payload[..]].iter() {
+            buf.extend_from_slice(&u32_to_u8x4(bytes.len() as u32));
+            buf.extend_from_slice(bytes);
+        }
+    }
+}
+
+struct Subscriber {
+    channels: BTreeSet<Vec<u8>>,
+    patterns: BTreeSet<Vec<u8>>,
+    sender: mpsc::Sender<Message>,
+}
+
+impl Subscriber {
+    // The pattern which `channel` matches, Some(None) if `channel` is subscribed,
+    // None if `channel` is not subscribed
+    fn matches(&self, channel: &[u8]) -> Option<Option<&[u8]>> {
+        if self.channels.contains(channel) {
+            return Some(None);
+        }
+        self.patterns
+            .iter()
+            .find(|pattern| glob_match(pattern, channel))
+            .map(|pattern| Some(&pattern[..]))
+    }
+}
+
+// Channels for messages between clients, without persistence
+pub struct PubSub {
+    next_id: AtomicU64,
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            next_id: AtomicU64::new(0),
+            subscribers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Register a subscriber without channels, return its id and the receiver of its messages
+    pub fn register(&self) -> (u64, mpsc::Receiver<Message>) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        self.lock().insert(
+            id,
+            Subscriber {
+                channels: BTreeSet::new(),
+                patterns: BTreeSet::new(),
+                sender,
+            },
+        );
+        (id, receiver)
+    }
+
+    // Remove the subscriber, its receiver ends
+    pub fn unregister(&self, id: u64) {
+        self.lock().remove(&id);
+    }
+
+    pub fn subscribe(&self, id: u64, target: Target, names: Vec<Vec<u8>>) {
+        if let Some(subscriber) = self.lock().get_mut(&id) {
+            match target {
+                Target::Channel => subscriber.channels.extend(names),
+                Target::Pattern => subscriber.patterns.extend(names),
+            }
+        }
+    }
+
+    // Unsubscribe the names, or all channels or patterns if `names` is empty
+    pub fn unsubscribe(&self, id: u64, target: Target, names: &[Vec<u8>]) {
+        if let Some(subscriber) = self.lock().get_mut(&id) {
+            let set = match target {
+                Target::Channel => &mut subscriber.channels,
+                Target::Pattern => &mut subscriber.patterns,
+            };
+            if names.is_empty() {
+                set.clear();
+            }
+            for name in names {
+                set.remove(name);
+            }
+        }
+    }
+
+    // Send the message to every subscriber of the channel, return the number of them
+    //
+    // A subscriber receives a message once, even if it subscribes the channel and
+    // patterns matching the channel. A subscriber which is disconnected or falls
+    // behind is removed.
+    pub fn publish(&self, channel: &[u8], payload: &[u8]) -> u32 {
+        let channel = web::Bytes::copy_from_slice(channel);
+        let payload = web::Bytes::copy_from_slice(payload);
+        let mut subscribers = self.lock();
+        let mut count = 0;
+        let mut removed = Vec::new();
+        for (id, subscriber) in subscribers.iter_mut() {
+            let pattern = match subscriber.matches(&channel) {
+                Some(pattern) => pattern.map(web::Bytes::copy_from_slice),
+                None => continue,
+            };
+            let message = Message {
+                channel: channel.clone(),
+                pattern,
+                payload: payload.clone(),
+            };
+            if subscriber.sender.try_send(message).is_ok() {
+                count += 1;
+            } else {
+                removed.push(*id);
+            }
+        }
+        for id in removed {
+            subscribers.remove(&id);
+        }
+        count
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Subscriber>> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// The messages of a subscriber, which is unregistered when the stream is dropped
+pub struct Subscription {
+    id: u64,
+    global_state: web::Data<GlobalState>,
+    receiver: mpsc::Receiver<Message>,
+}
+
+impl Subscription {
+    pub fn new(global_state: web::Data<GlobalState>) -> Subscription {
+        let (id, receiver) = global_state.pubsub.register();
+        Subscription {
+            id,
+            global_state,
+            receiver,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.global_state.pubsub.unregister(self.id);
+    }
+}
+
+// The frames to a websocket client which subscribes with commands in `payload`
+//
+// A binary frame is the `CONTENT` of a command and its params, a text frame is a command
+// and its params separated by spaces. Commands are SUBSCRIBE, UNSUBSCRIBE, PSUBSCRIBE and
+// PUNSUBSCRIBE. Messages are sent as binary frames.
+pub fn serve_ws(
+    payload: web::Payload,
+    global_state: web::Data<GlobalState>,
+) -> impl Stream<Item = Result<web::Bytes>> {
+    let subscription = Subscription::new(global_state.clone());
+    let id = subscription.id();
+    // Whether a close frame is sent, only one is sent by the reader or the subscription
+    let closed = Arc::new(AtomicBool::new(false));
+    let (control, control_receiver) = mpsc::unbounded();
+    actix_web::rt::spawn(read_ws(payload, global_state, id, control, closed.clone()));
+
+    // The subscription ends when the reader ends or the subscriber falls behind
+    let messages = subscription.map(|message| ws_frame(&message)).chain(
+        stream::once(future::lazy(move |_| {
+            if closed.swap(true, Ordering::SeqCst) {
+                None
+            } else {
+                Some(close_frame(
+                    CloseCode::Policy,
+                    Some("subscriber falls behind"),
+                ))
+            }
+        }))
+        .filter_map(future::ready),
+    );
+    stream::select(messages, control_receiver).map(Ok)
+}
+
+// Read commands until the client closes the connection, control frames to the client are
+// sent to `control`
+async fn read_ws(
+    mut payload: web::Payload,
+    global_state: web::Data<GlobalState>,
+    id: u64,
+    control: mpsc::UnboundedSender<web::Bytes>,
+    closed: Arc<AtomicBool>,
+) {
+    let mut buf = web::BytesMut::new();
+    // The opcode and the data of the frames of a fragmented message which are read
+    let mut fragments = None;
+    let close = 'read: loop {
+        let chunk = match payload.next().await {
+            Some(Ok(chunk)) => chunk,
+            _ => break None,
+        };
+        buf.extend_from_slice(&chunk);
+        loop {
+            let (fin, op, data) = match Parser::parse(&mut buf, true, MAX_WS_FRAME_SIZE) {
+                Ok(Some((fin, op, data))) => (fin, op, data.unwrap_or_default()),
+                Ok(None) => break,
+                Err(err) => break 'read Some((CloseCode::Protocol, Some(err.to_string()))),
+            };
+            let (op, data) = match assemble(&mut fragments, fin, op, data) {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                Err((code, description)) => break 'read Some((code, Some(description))),
+            };
+            match op {
+                OpCode::Binary | OpCode::Text => {
+                    let params = if let OpCode::Text = op {
+                        data[..]
+                            .split(|c| c.is_ascii_whitespace())
+                            .filter(|param| !param.is_empty())
+                            .map(|param| param.to_vec())
+                            .collect()
+                    } else {
+                        match parse_params(data.freeze()).await {
+                            Ok(params) => params.iter().map(|param| param.to_vec()).collect(),
+                            Err(err) => {
+                                break 'read Some((CloseCode::Invalid, Some(err.to_string())))
+                            }
+                        }
+                    };
+                    if let Err(err) = execute_ws(&global_state, id, params) {
+                        break 'read Some((CloseCode::Invalid, Some(err.to_string())));
+                    }
+                }
+                OpCode::Ping => {
+                    let mut frame = web::BytesMut::new();
+                    Parser::write_message(&mut frame, &data, OpCode::Pong, true, false);
+                    let _ = control.unbounded_send(frame.freeze());
+                }
+                OpCode::Close => break 'read Some((CloseCode::Normal, None)),
+                _ => {}
+            }
+        }
+    };
+
+    if let Some((code, description)) = close {
+        if !closed.swap(true, Ordering::SeqCst) {
+            let _ = control.unbounded_send(close_frame(code, description.as_deref()));
+        }
+    }
+    // Both streams of the response end
+    global_state.pubsub.unregister(id);
+}
+
+// Join the frames of a fragmented message, return the message when its last frame is read
+//
+// Control frames can be between the frames of a message, they are returned at once.
+fn assemble(
+    fragments: &mut Option<(OpCode, web::BytesMut)>,
+    fin: bool,
+    op: OpCode,
+    data: web::BytesMut,
+) -> std::result::Result<Option<(OpCode, web::BytesMut)>, (CloseCode, String)> {
+    match op {
+        OpCode::Binary | OpCode::Text if fragments.is_some() => Err((
+            CloseCode::Protocol,
+            "a new message before the last frame of a message".to_string(),
+        )),
+        OpCode::Binary | OpCode::Text if !fin => {
+            *fragments = Some((op, data));
+            Ok(None)
+        }
+        OpCode::Continue => {
+            let (op, mut message) = fragments.take().ok_or_else(|| {
+                (
+                    CloseCode::Protocol,
+                    "a continuation frame without a message".to_string(),
+                )
+            })?;
+            if message.len() + data.len() > MAX_WS_FRAME_SIZE {
+                return Err((
+                    CloseCode::Size,
+                    format!("a message is larger than {} bytes", MAX_WS_FRAME_SIZE),
+                ));
+            }
+            message.extend_from_slice(&data);
+            if fin {
+                Ok(Some((op, message)))
+            } else {
+                *fragments = Some((op, message));
+                Ok(None)
+            }
+        }
+        _ => Ok(Some((op, data))),
+    }
+}
+
+fn execute_ws(global_state: &GlobalState, id: u64, mut params: Vec<Vec<u8>>) -> Result<()> {
+    if params.is_empty() {
+        return Err(LodisError::ParamNoMatch("command is not given".to_string()));
+    }
+    let command = String::from_utf8_lossy(&params.remove(0)).to_ascii_uppercase();
+    let pubsub = &global_state.pubsub;
+    match command.as_str() {
+        "SUBSCRIBE" | "PSUBSCRIBE" if params.is_empty() => Err(LodisError::ParamNoMatch(format!(
+            "command: {}, params: {:?}",
+            command, &params
+        ))),
+        "SUBSCRIBE" => {
+            pubsub.subscribe(id, Target::Channel, params);
+            Ok(())
+        }
+        "PSUBSCRIBE" => {
+            pubsub.subscribe(id, Target::Pattern, params);
+            Ok(())
+        }
+        "UNSUBSCRIBE" => {
+            pubsub.unsubscribe(id, Target::Channel, &params);
+            Ok(())
+        }
+        "PUNSUBSCRIBE" => {
+            pubsub.unsubscribe(id, Target::Pattern, &params);
+            Ok(())
+        }
+        _ => Err(LodisError::UnknownCommand(command)),
+    }
+}
+
+// Encode a message as a binary websocket frame
+fn ws_frame(message: &Message) -> web::Bytes {
+    let mut payload = Vec::new();
+    message.encode(&mut payload);
+    let mut frame = web::BytesMut::new();
+    Parser::write_message(&mut frame, payload, OpCode::Binary, true, false);
+    frame.freeze()
+}
+
+fn close_frame(code: CloseCode, description: Option<&str>) -> web::Bytes {
+    let mut frame = web::BytesMut::new();
+    let reason = CloseReason {
+        code,
+        description: description.map(|description| description.to_string()),
+    };
+    Parser::write_close(&mut frame, Some(reason), false);
+    frame.freeze()
+}
+
+#[cfg(test)]
+mod test_pubsub {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<Vec<u8>> {
+        names.iter().map(|name| name.as_bytes().to_vec()).collect()
+    }
+
+    // Messages which the receiver has got, as (channel, pattern, payload)
+    fn received(receiver: &mut mpsc::Receiver<Message>) -> Vec<(String, Option<String>, String)> {
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        let mut messages = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            messages.push((
+                text(&message.channel),
+                message.pattern.as_deref().map(text),
+                text(&message.payload),
+            ));
+        }
+        messages
+    }
+
+    #[test]
+    fn test_publish() {
+        let pubsub = PubSub::new();
+        let (a, mut receiver_a) = pubsub.register();
+        let (b, mut receiver_b) = pubsub.register();
+        let (_, mut receiver_c) = pubsub.register();
+        assert_eq!(pubsub.publish(b"news", b"0"), 0);
+
+        pubsub.subscribe(a, Target::Channel, names(&["news", "sports"]));
+        pubsub.subscribe(b, Target::Pattern, names(&["n*"]));
+        assert_eq!(pubsub.publish(b"news", b"1"), 2);
+        assert_eq!(pubsub.publish(b"sports", b"2"), 1);
+        assert_eq!(pubsub.publish(b"weather", b"3"), 0);
+        assert_eq!(
+            received(&mut receiver_a),
+            vec![
+                ("news".to_string(), None, "1".to_string()),
+                ("sports".to_string(), None, "2".to_string())
+            ]
+        );
+        assert_eq!(
+            received(&mut receiver_b),
+            vec![("news".to_string(), Some("n*".to_string()), "1".to_string())]
+        );
+        assert!(received(&mut receiver_c).is_empty());
+
+        pubsub.unsubscribe(a, Target::Channel, &names(&["news"]));
+        pubsub.unsubscribe(b, Target::Pattern, &[]);
+        assert_eq!(pubsub.publish(b"news", b"4"), 0);
+        assert_eq!(pubsub.publish(b"sports", b"5"), 1);
+
+        // An unregistered subscriber gets nothing, and its receiver ends
+        pubsub.unregister(a);
+        assert_eq!(pubsub.publish(b"sports", b"6"), 0);
+        received(&mut receiver_a);
+        assert!(matches!(
+            receiver_a.try_recv(),
+            Err(mpsc::TryRecvError::Closed)
+        ));
+    }
+
+    #[test]
+    fn test_publish_once() {
+        let pubsub = PubSub::new();
+        let (id, mut receiver) = pubsub.register();
+        pubsub.subscribe(id, Target::Channel, names(&["news"]));
+        pubsub.subscribe(id, Target::Pattern, names(&["n*", "*s"]));
+
+        // The channel and both patterns match, the message is received once
+        assert_eq!(pubsub.publish(b"news", b"1"), 1);
+        assert_eq!(
+            received(&mut receiver),
+            vec![("news".to_string(), None, "1".to_string())]
+        );
+        // Both patterns match
+        assert_eq!(pubsub.publish(b"nets", b"2"), 1);
+        assert_eq!(received(&mut receiver).len(), 1);
+    }
+
+    #[test]
+    fn test_subscriber_falls_behind() {
+        let pubsub = PubSub::new();
+        let (slow, mut slow_receiver) = pubsub.register();
+        let (fast, mut fast_receiver) = pubsub.register();
+        pubsub.subscribe(slow, Target::Channel, names(&["news"]));
+        pubsub.subscribe(fast, Target::Channel, names(&["news"]));
+
+        // The buffer of a receiver has one more place for each sender
+        for i in 0..=SUBSCRIBER_BUFFER {
+            assert_eq!(pubsub.publish(b"news", i.to_string().as_bytes()), 2);
+            assert_eq!(received(&mut fast_receiver).len(), 1);
+        }
+        // The slow subscriber is removed, the other is not
+        assert_eq!(pubsub.publish(b"news", b"last"), 1);
+        assert_eq!(received(&mut fast_receiver).len(), 1);
+        assert_eq!(received(&mut slow_receiver).len(), SUBSCRIBER_BUFFER + 1);
+        assert!(matches!(
+            slow_receiver.try_recv(),
+            Err(mpsc::TryRecvError::Closed)
+        ));
+        assert_eq!(pubsub.publish(b"news", b"after"), 1);
+    }
+
+    #[test]
+    fn test_assemble() {
+        let data = |data: &str| web::BytesMut::from(data);
+        let mut fragments = None;
+
+        // A message of one frame
+        let (op, message) = assemble(&mut fragments, true, OpCode::Text, data("PING"))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(op, OpCode::Text));
+        assert_eq!(&message[..], b"PING");
+
+        // A message of three frames, with a control frame between them
+        assert!(assemble(&mut fragments, false, OpCode::Binary, data("SUB"))
+            .unwrap()
+            .is_none());
+        let (op, message) = assemble(&mut fragments, true, OpCode::Ping, data(""))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(op, OpCode::Ping));
+        assert!(message.is_empty());
+        assert!(
+            assemble(&mut fragments, false, OpCode::Continue, data("SCR"))
+                .unwrap()
+                .is_none()
+        );
+        let (op, message) = assemble(&mut fragments, true, OpCode::Continue, data("IBE"))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(op, OpCode::Binary));
+        assert_eq!(&message[..], b"SUBSCRIBE");
+        assert!(fragments.is_none());
+
+        // A continuation frame without a message
+        let err = assemble(&mut fragments, true, OpCode::Continue, data("a")).unwrap_err();
+        assert!(matches!(err.0, CloseCode::Protocol));
+
+        // A new message before the last frame of a message
+        assemble(&mut fragments, false, OpCode::Text, data("a")).unwrap();
+        let err = assemble(&mut fragments, true, OpCode::Text, data("b")).unwrap_err();
+        assert!(matches!(err.0, CloseCode::Protocol));
+
+        // A message which is too large
+        let mut fragments = None;
+        let frame = web::BytesMut::from(&vec![b'a'; MAX_WS_FRAME_SIZE / 2 + 1][..]);
+        assemble(&mut fragments, false, OpCode::Text, frame.clone()).unwrap();
+        let err = assemble(&mut fragments, true, OpCode::Continue, frame).unwrap_err();
+        assert!(matches!(err.0, CloseCode::Size));
+    }
+}
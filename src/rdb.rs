@@ -0,0 +1,1187 @@
+use std::{cmp::Ordering, io::Read, sync::Arc, time::SystemTime};
+
+use lodisdb::DB;
+
+use crate::{
+    error::{LodisError, Result},
+    export::{write_record, Record},
+};
+
+// Redis RDB opcodes
+const RDB_OPCODE_SLOT_INFO: u8 = 0xF4;
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+const RDB_OPCODE_FUNCTION: u8 = 0xF6;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+// Redis RDB value types
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_MODULE: u8 = 6;
+const RDB_TYPE_MODULE_2: u8 = 7;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// Special string encodings
+const RDB_ENC_INT8: u64 = 0;
+const RDB_ENC_INT16: u64 = 1;
+const RDB_ENC_INT32: u64 = 2;
+const RDB_ENC_LZF: u64 = 3;
+
+// Module value opcodes
+const RDB_MODULE_OPCODE_EOF: u64 = 0;
+const RDB_MODULE_OPCODE_SINT: u64 = 1;
+const RDB_MODULE_OPCODE_UINT: u64 = 2;
+const RDB_MODULE_OPCODE_FLOAT: u64 = 3;
+const RDB_MODULE_OPCODE_DOUBLE: u64 = 4;
+const RDB_MODULE_OPCODE_STRING: u64 = 5;
+
+// Quicklist node containers
+const QUICKLIST_NODE_CONTAINER_PLAIN: u64 = 1;
+
+// A Redis value read from a RDB file
+enum RdbValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    ZSet(Vec<(Vec<u8>, f64)>),
+    Hash(Vec<(Vec<u8>, Vec<u8>)>),
+    // The value is skipped, with the name of its type
+    Unsupported(&'static str),
+}
+
+// The result of importing a RDB file
+#[derive(Debug, Default)]
+pub struct RdbReport {
+    pub strings: usize,
+    pub lists: usize,
+    pub hashes: usize,
+    pub sets: usize,
+    pub zsets: usize,
+    // Keys which are expired
+    pub expired: usize,
+    // Keys which are not at the imported Redis database
+    pub other_db: usize,
+    // Keys which can not be imported, with the reason
+    pub unsupported: Vec<String>,
+}
+
+fn rdb_error(msg: String) -> LodisError {
+    LodisError::Error(format!("RDB: {}", msg))
+}
+
+fn int_bytes(value: i64) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+// Redis prints scores in the shortest form, e.g. `1`, `1.5`, `inf`
+fn score_bytes(score: f64) -> Vec<u8> {
+    if score.is_infinite() {
+        if score > 0.0 {
+            b"inf".to_vec()
+        } else {
+            b"-inf".to_vec()
+        }
+    } else {
+        score.to_string().into_bytes()
+    }
+}
+
+fn parse_score(buf: &[u8]) -> Result<f64> {
+    let score = String::from_utf8_lossy(buf);
+    match score.as_ref() {
+        "inf" | "+inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        _ => score
+            .parse()
+            .map_err(|_| rdb_error(format!("score is not a number, {}", score))),
+    }
+}
+
+// Decompress a LZF compressed string
+fn lzf_decompress(input: &[u8], length: usize) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(length);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let len = ctrl + 1;
+            if i + len > input.len() {
+                return Err(rdb_error("LZF literal is out of range".to_string()));
+            }
+            output.extend_from_slice(&input[i..i + len]);
+            i += len;
+        } else {
+            // Back reference
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                if i >= input.len() {
+                    return Err(rdb_error("LZF length is out of range".to_string()));
+                }
+                len += input[i] as usize;
+                i += 1;
+            }
+            if i >= input.len() {
+                return Err(rdb_error("LZF offset is out of range".to_string()));
+            }
+            let back = ((ctrl & 0x1f) << 8) + input[i] as usize + 1;
+            i += 1;
+            if back > output.len() {
+                return Err(rdb_error("LZF reference is out of range".to_string()));
+            }
+            let start = output.len() - back;
+            for k in 0..len + 2 {
+                let byte = output[start + k];
+                output.push(byte);
+            }
+        }
+    }
+    if output.len() != length {
+        return Err(rdb_error(format!(
+            "LZF length is {}, expected {}",
+            output.len(),
+            length
+        )));
+    }
+    Ok(output)
+}
+
+// Take `n` bytes from `buf` at `pos` and move `pos` forward
+fn take<'a>(buf: &'a [u8], pos: &mut usize, n: usize) -> Result<&'a [u8]> {
+    if *pos + n > buf.len() {
+        return Err(rdb_error("encoded value is out of range".to_string()));
+    }
+    let bytes = &buf[*pos..*pos + n];
+    *pos += n;
+    Ok(bytes)
+}
+
+fn le_int(bytes: &[u8]) -> i64 {
+    // Sign-extend a little-endian integer of 1 to 8 bytes
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let shift = 64 - 8 * bytes.len() as u32;
+    (i64::from_le_bytes(buf) << shift) >> shift
+}
+
+// Elements of a ziplist
+fn parse_ziplist(buf: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut elements = Vec::new();
+    // zlbytes, zltail, zllen
+    let mut pos = 10;
+    loop {
+        let flag = take(buf, &mut pos, 1)?[0];
+        if flag == 0xFF {
+            break;
+        }
+        // Skip the length of the previous entry
+        if flag == 0xFE {
+            take(buf, &mut pos, 4)?;
+        }
+
+        let enc = take(buf, &mut pos, 1)?[0];
+        let element = match enc >> 6 {
+            0 => take(buf, &mut pos, (enc & 0x3f) as usize)?.to_vec(),
+            1 => {
+                let len = (((enc & 0x3f) as usize) << 8) | take(buf, &mut pos, 1)?[0] as usize;
+                take(buf, &mut pos, len)?.to_vec()
+            }
+            2 => {
+                let bytes = take(buf, &mut pos, 4)?;
+                let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+                take(buf, &mut pos, len)?.to_vec()
+            }
+            _ => match enc {
+                0xC0 => int_bytes(le_int(take(buf, &mut pos, 2)?)),
+                0xD0 => int_bytes(le_int(take(buf, &mut pos, 4)?)),
+                0xE0 => int_bytes(le_int(take(buf, &mut pos, 8)?)),
+                0xF0 => int_bytes(le_int(take(buf, &mut pos, 3)?)),
+                0xFE => int_bytes(le_int(take(buf, &mut pos, 1)?)),
+                0xF1..=0xFD => int_bytes((enc & 0x0f) as i64 - 1),
+                _ => return Err(rdb_error(format!("unknown ziplist encoding {}", enc))),
+            },
+        };
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+// Elements of a listpack
+fn parse_listpack(buf: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut elements = Vec::new();
+    // total bytes, number of elements
+    let mut pos = 6;
+    loop {
+        let start = pos;
+        let enc = take(buf, &mut pos, 1)?[0];
+        if enc == 0xFF {
+            break;
+        }
+
+        let element = if enc & 0x80 == 0 {
+            int_bytes((enc & 0x7f) as i64)
+        } else if enc & 0xC0 == 0x80 {
+            take(buf, &mut pos, (enc & 0x3f) as usize)?.to_vec()
+        } else if enc & 0xE0 == 0xC0 {
+            let value = (((enc & 0x1f) as i64) << 8) | take(buf, &mut pos, 1)?[0] as i64;
+            // 13 bits signed integer
+            int_bytes((value << 51) >> 51)
+        } else if enc & 0xF0 == 0xE0 {
+            let len = (((enc & 0x0f) as usize) << 8) | take(buf, &mut pos, 1)?[0] as usize;
+            take(buf, &mut pos, len)?.to_vec()
+        } else {
+            match enc {
+                0xF0 => {
+                    let bytes = take(buf, &mut pos, 4)?;
+                    let len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+                    take(buf, &mut pos, len)?.to_vec()
+                }
+                0xF1 => int_bytes(le_int(take(buf, &mut pos, 2)?)),
+                0xF2 => int_bytes(le_int(take(buf, &mut pos, 3)?)),
+                0xF3 => int_bytes(le_int(take(buf, &mut pos, 4)?)),
+                0xF4 => int_bytes(le_int(take(buf, &mut pos, 8)?)),
+                _ => return Err(rdb_error(format!("unknown listpack encoding {}", enc))),
+            }
+        };
+        elements.push(element);
+
+        // Skip the length of the entry at its end
+        let len = pos - start;
+        let backlen = match len {
+            0..=127 => 1,
+            128..=16383 => 2,
+            16384..=2097151 => 3,
+            2097152..=268435455 => 4,
+            _ => 5,
+        };
+        take(buf, &mut pos, backlen)?;
+    }
+    Ok(elements)
+}
+
+// Members of an intset
+fn parse_intset(buf: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut pos = 0;
+    let header = take(buf, &mut pos, 8)?;
+    let enc = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if enc != 2 && enc != 4 && enc != 8 {
+        return Err(rdb_error(format!("unknown intset encoding {}", enc)));
+    }
+    let mut members = Vec::with_capacity(len);
+    for _ in 0..len {
+        members.push(int_bytes(le_int(take(buf, &mut pos, enc)?)));
+    }
+    Ok(members)
+}
+
+// Pairs of a zipmap
+fn parse_zipmap(buf: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    fn zipmap_len(buf: &[u8], pos: &mut usize) -> Result<Option<usize>> {
+        match take(buf, pos, 1)?[0] {
+            255 => Ok(None),
+            254 => {
+                let bytes = take(buf, pos, 4)?;
+                Ok(Some(
+                    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize,
+                ))
+            }
+            len => Ok(Some(len as usize)),
+        }
+    }
+
+    let mut pairs = Vec::new();
+    // zmlen
+    let mut pos = 1;
+    while let Some(len) = zipmap_len(buf, &mut pos)? {
+        let field = take(buf, &mut pos, len)?.to_vec();
+        let len = zipmap_len(buf, &mut pos)?
+            .ok_or_else(|| rdb_error("zipmap value is missing".to_string()))?;
+        let free = take(buf, &mut pos, 1)?[0] as usize;
+        let value = take(buf, &mut pos, len)?.to_vec();
+        take(buf, &mut pos, free)?;
+        pairs.push((field, value));
+    }
+    Ok(pairs)
+}
+
+fn into_pairs(elements: Vec<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if elements.len() % 2 != 0 {
+        return Err(rdb_error("pairs are not matched".to_string()));
+    }
+    let mut pairs = Vec::with_capacity(elements.len() / 2);
+    let mut iter = elements.into_iter();
+    while let (Some(field), Some(value)) = (iter.next(), iter.next()) {
+        pairs.push((field, value));
+    }
+    Ok(pairs)
+}
+
+fn into_scores(elements: Vec<Vec<u8>>) -> Result<Vec<(Vec<u8>, f64)>> {
+    let mut members = Vec::new();
+    for (member, score) in into_pairs(elements)? {
+        members.push((member, parse_score(&score)?));
+    }
+    Ok(members)
+}
+
+struct RdbReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> RdbReader<R> {
+    fn read_exact(&mut self, n: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; n];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_exact(1)?[0])
+    }
+
+    // Return the length and whether it is a special encoding
+    fn read_length_encoding(&mut self) -> Result<(u64, bool)> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3f) as u64, false)),
+            1 => {
+                let second = self.read_u8()?;
+                Ok(((((first & 0x3f) as u64) << 8) | second as u64, false))
+            }
+            2 => match first {
+                0x80 => {
+                    let buf = self.read_exact(4)?;
+                    Ok((
+                        u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as u64,
+                        false,
+                    ))
+                }
+                0x81 => {
+                    let mut buf: [u8; 8] = [0; 8];
+                    self.reader.read_exact(&mut buf)?;
+                    Ok((u64::from_be_bytes(buf), false))
+                }
+                _ => Err(rdb_error(format!("unknown length encoding {}", first))),
+            },
+            _ => Ok(((first & 0x3f) as u64, true)),
+        }
+    }
+
+    fn read_length(&mut self) -> Result<u64> {
+        match self.read_length_encoding()? {
+            (len, false) => Ok(len),
+            (enc, true) => Err(rdb_error(format!(
+                "expected a length, found encoding {}",
+                enc
+            ))),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>> {
+        match self.read_length_encoding()? {
+            (len, false) => self.read_exact(len as usize),
+            (RDB_ENC_INT8, true) => Ok(int_bytes(le_int(&self.read_exact(1)?))),
+            (RDB_ENC_INT16, true) => Ok(int_bytes(le_int(&self.read_exact(2)?))),
+            (RDB_ENC_INT32, true) => Ok(int_bytes(le_int(&self.read_exact(4)?))),
+            (RDB_ENC_LZF, true) => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_exact(compressed_len)?;
+                lzf_decompress(&compressed, len)
+            }
+            (enc, true) => Err(rdb_error(format!("unknown string encoding {}", enc))),
+        }
+    }
+
+    // Score of RDB_TYPE_ZSET, which is a string
+    fn read_string_score(&mut self) -> Result<f64> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_score(&self.read_exact(len as usize)?),
+        }
+    }
+
+    // Score of RDB_TYPE_ZSET_2, which is a binary double
+    fn read_binary_score(&mut self) -> Result<f64> {
+        let mut buf: [u8; 8] = [0; 8];
+        self.reader.read_exact(&mut buf)?;
+        Ok(f64::from_le_bytes(buf))
+    }
+
+    fn read_strings(&mut self) -> Result<Vec<Vec<u8>>> {
+        let len = self.read_length()?;
+        let mut elements = Vec::new();
+        for _ in 0..len {
+            elements.push(self.read_string()?);
+        }
+        Ok(elements)
+    }
+
+    // Skip a module value which is written with module opcodes
+    fn skip_module_value(&mut self) -> Result<()> {
+        loop {
+            match self.read_length()? {
+                RDB_MODULE_OPCODE_EOF => return Ok(()),
+                RDB_MODULE_OPCODE_SINT | RDB_MODULE_OPCODE_UINT => {
+                    self.read_length()?;
+                }
+                RDB_MODULE_OPCODE_FLOAT => {
+                    self.read_exact(4)?;
+                }
+                RDB_MODULE_OPCODE_DOUBLE => {
+                    self.read_exact(8)?;
+                }
+                RDB_MODULE_OPCODE_STRING => {
+                    self.read_string()?;
+                }
+                opcode => return Err(rdb_error(format!("unknown module opcode {}", opcode))),
+            }
+        }
+    }
+
+    fn skip_stream(&mut self, value_type: u8) -> Result<()> {
+        // Listpacks of entries
+        let len = self.read_length()?;
+        for _ in 0..len {
+            self.read_string()?;
+            self.read_string()?;
+        }
+        // Length and last id
+        for _ in 0..3 {
+            self.read_length()?;
+        }
+        // First id, max deleted id and number of added entries
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            for _ in 0..5 {
+                self.read_length()?;
+            }
+        }
+
+        let groups = self.read_length()?;
+        for _ in 0..groups {
+            self.read_string()?;
+            // Last id
+            self.read_length()?;
+            self.read_length()?;
+            // Number of read entries
+            if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                self.read_length()?;
+            }
+            // Pending entries: id, delivery time and delivery count
+            let pending = self.read_length()?;
+            for _ in 0..pending {
+                self.read_exact(16 + 8)?;
+                self.read_length()?;
+            }
+            let consumers = self.read_length()?;
+            for _ in 0..consumers {
+                self.read_string()?;
+                // Seen time, and active time since version 3
+                self.read_exact(8)?;
+                if value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                    self.read_exact(8)?;
+                }
+                // Pending entry ids
+                let pending = self.read_length()?;
+                for _ in 0..pending {
+                    self.read_exact(16)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_value(&mut self, value_type: u8) -> Result<RdbValue> {
+        let value = match value_type {
+            RDB_TYPE_STRING => RdbValue::String(self.read_string()?),
+            RDB_TYPE_LIST => RdbValue::List(self.read_strings()?),
+            RDB_TYPE_SET => RdbValue::Set(self.read_strings()?),
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut members = Vec::new();
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if value_type == RDB_TYPE_ZSET {
+                        self.read_string_score()?
+                    } else {
+                        self.read_binary_score()?
+                    };
+                    members.push((member, score));
+                }
+                RdbValue::ZSet(members)
+            }
+            RDB_TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut pairs = Vec::new();
+                for _ in 0..len {
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    pairs.push((field, value));
+                }
+                RdbValue::Hash(pairs)
+            }
+            RDB_TYPE_HASH_ZIPMAP => RdbValue::Hash(parse_zipmap(&self.read_string()?)?),
+            RDB_TYPE_LIST_ZIPLIST => RdbValue::List(parse_ziplist(&self.read_string()?)?),
+            RDB_TYPE_SET_INTSET => RdbValue::Set(parse_intset(&self.read_string()?)?),
+            RDB_TYPE_ZSET_ZIPLIST => {
+                RdbValue::ZSet(into_scores(parse_ziplist(&self.read_string()?)?)?)
+            }
+            RDB_TYPE_HASH_ZIPLIST => {
+                RdbValue::Hash(into_pairs(parse_ziplist(&self.read_string()?)?)?)
+            }
+            RDB_TYPE_LIST_QUICKLIST => {
+                let len = self.read_length()?;
+                let mut elements = Vec::new();
+                for _ in 0..len {
+                    elements.extend(parse_ziplist(&self.read_string()?)?);
+                }
+                RdbValue::List(elements)
+            }
+            RDB_TYPE_LIST_QUICKLIST_2 => {
+                let len = self.read_length()?;
+                let mut elements = Vec::new();
+                for _ in 0..len {
+                    let container = self.read_length()?;
+                    let node = self.read_string()?;
+                    if container == QUICKLIST_NODE_CONTAINER_PLAIN {
+                        elements.push(node);
+                    } else {
+                        elements.extend(parse_listpack(&node)?);
+                    }
+                }
+                RdbValue::List(elements)
+            }
+            RDB_TYPE_HASH_LISTPACK => {
+                RdbValue::Hash(into_pairs(parse_listpack(&self.read_string()?)?)?)
+            }
+            RDB_TYPE_ZSET_LISTPACK => {
+                RdbValue::ZSet(into_scores(parse_listpack(&self.read_string()?)?)?)
+            }
+            RDB_TYPE_SET_LISTPACK => RdbValue::Set(parse_listpack(&self.read_string()?)?),
+            RDB_TYPE_MODULE_2 => {
+                // Module id
+                self.read_length()?;
+                self.skip_module_value()?;
+                RdbValue::Unsupported("module")
+            }
+            RDB_TYPE_STREAM_LISTPACKS
+            | RDB_TYPE_STREAM_LISTPACKS_2
+            | RDB_TYPE_STREAM_LISTPACKS_3 => {
+                self.skip_stream(value_type)?;
+                RdbValue::Unsupported("stream")
+            }
+            // The values of these types can not be skipped, so the rest of the file can not be read
+            RDB_TYPE_MODULE => {
+                return Err(rdb_error(
+                    "module values of RDB_TYPE_MODULE are not supported".to_string(),
+                ))
+            }
+            _ => {
+                return Err(rdb_error(format!(
+                    "value type {} is not supported",
+                    value_type
+                )))
+            }
+        };
+        Ok(value)
+    }
+}
+
+// Import all keys of the Redis database `redis_db` from a RDB file
+//
+// Redis lists are imported to `List`, hashes to `HashMap`, strings to the string map,
+// sets to `HashMap` with members as fields and empty values, and sorted sets to `ArrayMap`
+// with members as fields and scores as values, ordered by scores.
+// Existing data with the same name and data type are replaced.
+// Expired keys are skipped, and expire times of other keys are dropped.
+pub fn import_rdb<R: Read>(db: Arc<DB>, reader: R, redis_db: u64) -> Result<RdbReport> {
+    let mut reader = RdbReader { reader };
+    let mut report = RdbReport::default();
+
+    let magic = reader.read_exact(9)?;
+    if &magic[..5] != b"REDIS" {
+        return Err(rdb_error("not a RDB file".to_string()));
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    let mut current_db = 0;
+    let mut expire_at: Option<u64> = None;
+    loop {
+        let value_type = reader.read_u8()?;
+        match value_type {
+            RDB_OPCODE_EOF => break,
+            RDB_OPCODE_SELECTDB => {
+                current_db = reader.read_length()?;
+                continue;
+            }
+            RDB_OPCODE_EXPIRETIME => {
+                let buf = reader.read_exact(4)?;
+                let secs = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as u64;
+                expire_at = Some(secs * 1000);
+                continue;
+            }
+            RDB_OPCODE_EXPIRETIME_MS => {
+                let mut buf: [u8; 8] = [0; 8];
+                reader.reader.read_exact(&mut buf)?;
+                expire_at = Some(u64::from_le_bytes(buf));
+                continue;
+            }
+            RDB_OPCODE_RESIZEDB => {
+                reader.read_length()?;
+                reader.read_length()?;
+                continue;
+            }
+            RDB_OPCODE_AUX => {
+                reader.read_string()?;
+                reader.read_string()?;
+                continue;
+            }
+            RDB_OPCODE_FREQ => {
+                reader.read_u8()?;
+                continue;
+            }
+            RDB_OPCODE_IDLE => {
+                reader.read_length()?;
+                continue;
+            }
+            RDB_OPCODE_MODULE_AUX => {
+                // Module id, `when` opcode and `when`
+                reader.read_length()?;
+                reader.read_length()?;
+                reader.read_length()?;
+                reader.skip_module_value()?;
+                continue;
+            }
+            RDB_OPCODE_FUNCTION2 => {
+                reader.read_string()?;
+                report
+                    .unsupported
+                    .push("a library of Redis functions".to_string());
+                continue;
+            }
+            RDB_OPCODE_FUNCTION => {
+                return Err(rdb_error(
+                    "functions of Redis 7.0 release candidates are not supported".to_string(),
+                ));
+            }
+            RDB_OPCODE_SLOT_INFO => {
+                // Slot id, slot size and expires slot size
+                for _ in 0..3 {
+                    reader.read_length()?;
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let key = reader.read_string()?;
+        let value = reader.read_value(value_type)?;
+        let expired = matches!(expire_at.take(), Some(at) if at <= now);
+
+        if current_db != redis_db {
+            report.other_db += 1;
+            continue;
+        }
+        if expired {
+            report.expired += 1;
+            continue;
+        }
+        let name = match String::from_utf8(key) {
+            Ok(name) => name,
+            Err(err) => {
+                report.unsupported.push(format!(
+                    "key {:?} is not utf8",
+                    String::from_utf8_lossy(err.as_bytes())
+                ));
+                continue;
+            }
+        };
+
+        let record = match value {
+            RdbValue::String(value) => {
+                report.strings += 1;
+                Record::String(value)
+            }
+            RdbValue::List(values) => {
+                report.lists += 1;
+                Record::List(values)
+            }
+            RdbValue::Hash(pairs) => {
+                report.hashes += 1;
+                Record::Map(pairs)
+            }
+            RdbValue::Set(members) => {
+                report.sets += 1;
+                Record::Map(members.into_iter().map(|m| (m, Vec::new())).collect())
+            }
+            RdbValue::ZSet(mut members) => {
+                report.zsets += 1;
+                members.sort_by(|a, b| {
+                    a.1.partial_cmp(&b.1)
+                        .unwrap_or(Ordering::Equal)
+                        .then_with(|| a.0.cmp(&b.0))
+                });
+                Record::ArrayMap(
+                    members
+                        .into_iter()
+                        .map(|(member, score)| (member, score_bytes(score)))
+                        .collect(),
+                )
+            }
+            RdbValue::Unsupported(value_type) => {
+                report
+                    .unsupported
+                    .push(format!("key {:?} of type {}", name, value_type));
+                continue;
+            }
+        };
+        write_record(&db, "", &name, &record)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test_rdb {
+    use std::{fs, sync::Arc};
+
+    use lodisdb::{common::Direction, make_db, ArrayMap, List, Map, DB};
+
+    use super::*;
+    use crate::common::LODIS_STRING_MAP;
+
+    // Build a RDB file
+    struct Rdb(Vec<u8>);
+
+    impl Rdb {
+        fn new() -> Rdb {
+            let mut rdb = Rdb(b"REDIS0011".to_vec());
+            rdb.0.push(RDB_OPCODE_AUX);
+            rdb.string(b"redis-ver").string(b"7.2.0");
+            rdb
+        }
+
+        fn byte(&mut self, byte: u8) -> &mut Rdb {
+            self.0.push(byte);
+            self
+        }
+
+        fn bytes(&mut self, bytes: &[u8]) -> &mut Rdb {
+            self.0.extend_from_slice(bytes);
+            self
+        }
+
+        fn length(&mut self, len: usize) -> &mut Rdb {
+            if len < 64 {
+                self.byte(len as u8)
+            } else if len < 16384 {
+                self.bytes(&[0x40 | (len >> 8) as u8, len as u8])
+            } else {
+                self.byte(0x80).bytes(&(len as u32).to_be_bytes())
+            }
+        }
+
+        fn string(&mut self, s: &[u8]) -> &mut Rdb {
+            self.length(s.len()).bytes(s)
+        }
+
+        fn key(&mut self, value_type: u8, key: &str) -> &mut Rdb {
+            self.byte(value_type).string(key.as_bytes())
+        }
+
+        fn select(&mut self, db: usize) -> &mut Rdb {
+            self.byte(RDB_OPCODE_SELECTDB).length(db)
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            self.byte(RDB_OPCODE_EOF);
+            // Checksum
+            self.bytes(&[0; 8]);
+            std::mem::take(&mut self.0)
+        }
+    }
+
+    // Entries of a ziplist, which are encoded strings or integers
+    fn ziplist(entries: &[&[u8]]) -> Vec<u8> {
+        let mut buf = vec![0; 10];
+        for entry in entries {
+            // Length of the previous entry, which is not read
+            buf.push(0);
+            buf.extend_from_slice(entry);
+        }
+        buf.push(0xFF);
+        buf
+    }
+
+    fn ziplist_string(s: &str) -> Vec<u8> {
+        [&[s.len() as u8][..], s.as_bytes()].concat()
+    }
+
+    // Entries of a listpack, which are encoded strings or integers without their lengths
+    fn listpack(entries: &[&[u8]]) -> Vec<u8> {
+        let mut buf = vec![0; 6];
+        for entry in entries {
+            buf.extend_from_slice(entry);
+            buf.push(entry.len() as u8);
+        }
+        buf.push(0xFF);
+        buf
+    }
+
+    fn listpack_string(s: &str) -> Vec<u8> {
+        [&[0x80 | s.len() as u8][..], s.as_bytes()].concat()
+    }
+
+    fn intset(members: &[i16]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(&(members.len() as u32).to_le_bytes());
+        for member in members {
+            buf.extend_from_slice(&member.to_le_bytes());
+        }
+        buf
+    }
+
+    fn zipmap(pairs: &[(&str, &str)]) -> Vec<u8> {
+        let mut buf = vec![pairs.len() as u8];
+        for (field, value) in pairs {
+            buf.push(field.len() as u8);
+            buf.extend_from_slice(field.as_bytes());
+            buf.push(value.len() as u8);
+            // Free bytes after the value
+            buf.push(1);
+            buf.extend_from_slice(value.as_bytes());
+            buf.push(0);
+        }
+        buf.push(255);
+        buf
+    }
+
+    fn list(db: &Arc<DB>, name: &str) -> Vec<Vec<u8>> {
+        let list = List::new_in("", name.to_string(), db.clone());
+        list.all().unwrap().iter().map(|v| v.to_vec()).collect()
+    }
+
+    fn map(db: &Arc<DB>, name: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let map = Map::new_in("", name.to_string(), db.clone());
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = map
+            .all()
+            .unwrap()
+            .iter()
+            .map(|(f, v)| (f.to_vec(), v.to_vec()))
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
+    fn arraymap(db: &Arc<DB>, name: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let arraymap = ArrayMap::new_in("", name.to_string(), db.clone());
+        let length = arraymap.length().unwrap();
+        arraymap
+            .range(0, length, Direction::Forward)
+            .unwrap()
+            .iter()
+            .map(|(f, v)| (f.to_vec(), v.to_vec()))
+            .collect()
+    }
+
+    fn string(db: &Arc<DB>, name: &str) -> Option<Vec<u8>> {
+        let string_map = Map::new(LODIS_STRING_MAP.to_string(), db.clone());
+        string_map.get(name).unwrap().map(|v| v.to_vec())
+    }
+
+    fn values(values: &[&str]) -> Vec<Vec<u8>> {
+        values.iter().map(|v| v.as_bytes().to_vec()).collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        pairs
+            .iter()
+            .map(|(f, v)| (f.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_import_types() {
+        let mut rdb = Rdb::new();
+        rdb.select(0).byte(RDB_OPCODE_RESIZEDB).length(20).length(0);
+
+        // Strings: raw, integer and LZF compressed
+        rdb.key(RDB_TYPE_STRING, "s1").string(b"abc");
+        rdb.key(RDB_TYPE_STRING, "s2").bytes(&[0xC0, 0xF6]);
+        rdb.key(RDB_TYPE_STRING, "s3").bytes(&[0xC1, 0x39, 0x30]);
+        rdb.key(RDB_TYPE_STRING, "s4")
+            .bytes(&[0xC3])
+            .length(6)
+            .length(9)
+            .bytes(&[0x02, b'a', b'b', b'c', 0x80, 0x02]);
+
+        // Lists
+        rdb.key(RDB_TYPE_LIST, "l1")
+            .length(2)
+            .string(b"a")
+            .string(b"b");
+        rdb.key(RDB_TYPE_LIST_ZIPLIST, "l2").string(&ziplist(&[
+            &ziplist_string("a"),
+            &[0xC0, 0x00, 0x01],
+            &[0xF3],
+            &[0xFE, 0xFF],
+        ]));
+        rdb.key(RDB_TYPE_LIST_QUICKLIST, "l3")
+            .length(2)
+            .string(&ziplist(&[&ziplist_string("a")]))
+            .string(&ziplist(&[&ziplist_string("b"), &ziplist_string("c")]));
+        rdb.key(RDB_TYPE_LIST_QUICKLIST_2, "l4")
+            .length(2)
+            .length(2)
+            .string(&listpack(&[
+                &listpack_string("a"),
+                &[5],
+                &[0xDF, 0xFF],
+                &[0xF1, 0x00, 0x80],
+            ]))
+            .length(QUICKLIST_NODE_CONTAINER_PLAIN as usize)
+            .string(b"plain");
+
+        // Hashes
+        rdb.key(RDB_TYPE_HASH, "h1")
+            .length(1)
+            .string(b"f")
+            .string(b"v");
+        rdb.key(RDB_TYPE_HASH_ZIPMAP, "h2")
+            .string(&zipmap(&[("f1", "v1"), ("f2", "v2")]));
+        rdb.key(RDB_TYPE_HASH_ZIPLIST, "h3")
+            .string(&ziplist(&[&ziplist_string("f"), &[0xF2]]));
+        rdb.key(RDB_TYPE_HASH_LISTPACK, "h4")
+            .string(&listpack(&[&listpack_string("f"), &listpack_string("v")]));
+
+        // Sets
+        rdb.key(RDB_TYPE_SET, "set1").length(1).string(b"m");
+        rdb.key(RDB_TYPE_SET_INTSET, "set2")
+            .string(&intset(&[-2, 300]));
+        rdb.key(RDB_TYPE_SET_LISTPACK, "set3")
+            .string(&listpack(&[&listpack_string("m"), &[7]]));
+
+        // Sorted sets
+        rdb.key(RDB_TYPE_ZSET, "z1")
+            .length(3)
+            .string(b"b")
+            .bytes(&[3])
+            .bytes(b"2.5")
+            .string(b"a")
+            .bytes(&[255])
+            .string(b"c")
+            .bytes(&[254]);
+        rdb.key(RDB_TYPE_ZSET_2, "z2")
+            .length(2)
+            .string(b"b")
+            .bytes(&2.0f64.to_le_bytes())
+            .string(b"a")
+            .bytes(&1.5f64.to_le_bytes());
+        rdb.key(RDB_TYPE_ZSET_ZIPLIST, "z3").string(&ziplist(&[
+            &ziplist_string("b"),
+            &[0xF4],
+            &ziplist_string("a"),
+            &[0xF2],
+        ]));
+        rdb.key(RDB_TYPE_ZSET_LISTPACK, "z4")
+            .string(&listpack(&[&listpack_string("a"), &listpack_string("0.5")]));
+        let rdb = rdb.finish();
+
+        let path = "test-rdb-db1";
+        {
+            let db = Arc::new(make_db(path));
+            let report = import_rdb(db.clone(), &rdb[..], 0).unwrap();
+            assert_eq!(report.strings, 4);
+            assert_eq!(report.lists, 4);
+            assert_eq!(report.hashes, 4);
+            assert_eq!(report.sets, 3);
+            assert_eq!(report.zsets, 4);
+            assert!(report.unsupported.is_empty());
+
+            assert_eq!(string(&db, "s1").unwrap(), b"abc");
+            assert_eq!(string(&db, "s2").unwrap(), b"-10");
+            assert_eq!(string(&db, "s3").unwrap(), b"12345");
+            assert_eq!(string(&db, "s4").unwrap(), b"abcabcabc");
+
+            assert_eq!(list(&db, "l1"), values(&["a", "b"]));
+            assert_eq!(list(&db, "l2"), values(&["a", "256", "2", "-1"]));
+            assert_eq!(list(&db, "l3"), values(&["a", "b", "c"]));
+            assert_eq!(
+                list(&db, "l4"),
+                values(&["a", "5", "-1", "-32768", "plain"])
+            );
+
+            assert_eq!(map(&db, "h1"), pairs(&[("f", "v")]));
+            assert_eq!(map(&db, "h2"), pairs(&[("f1", "v1"), ("f2", "v2")]));
+            assert_eq!(map(&db, "h3"), pairs(&[("f", "1")]));
+            assert_eq!(map(&db, "h4"), pairs(&[("f", "v")]));
+
+            assert_eq!(map(&db, "set1"), pairs(&[("m", "")]));
+            assert_eq!(map(&db, "set2"), pairs(&[("-2", ""), ("300", "")]));
+            assert_eq!(map(&db, "set3"), pairs(&[("7", ""), ("m", "")]));
+
+            // Members are ordered by scores
+            assert_eq!(
+                arraymap(&db, "z1"),
+                pairs(&[("a", "-inf"), ("b", "2.5"), ("c", "inf")])
+            );
+            assert_eq!(arraymap(&db, "z2"), pairs(&[("a", "1.5"), ("b", "2")]));
+            assert_eq!(arraymap(&db, "z3"), pairs(&[("a", "1"), ("b", "3")]));
+            assert_eq!(arraymap(&db, "z4"), pairs(&[("a", "0.5")]));
+        }
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_import_skipped() {
+        let mut rdb = Rdb::new();
+        rdb.select(0);
+        // Expired keys, in milliseconds and in seconds
+        rdb.byte(RDB_OPCODE_EXPIRETIME_MS)
+            .bytes(&1000u64.to_le_bytes())
+            .key(RDB_TYPE_STRING, "expired1")
+            .string(b"v");
+        rdb.byte(RDB_OPCODE_EXPIRETIME)
+            .bytes(&1u32.to_le_bytes())
+            .key(RDB_TYPE_LIST, "expired2")
+            .length(1)
+            .string(b"v");
+        // Expire times of other keys are dropped
+        rdb.byte(RDB_OPCODE_EXPIRETIME_MS)
+            .bytes(&u64::MAX.to_le_bytes())
+            .byte(RDB_OPCODE_IDLE)
+            .length(10)
+            .byte(RDB_OPCODE_FREQ)
+            .byte(1)
+            .key(RDB_TYPE_STRING, "later")
+            .string(b"v");
+        // Unsupported values
+        rdb.key(RDB_TYPE_STREAM_LISTPACKS, "stream")
+            .length(0)
+            .length(0)
+            .length(0)
+            .length(0)
+            .length(0);
+        rdb.key(RDB_TYPE_MODULE_2, "module")
+            .length(1)
+            .length(RDB_MODULE_OPCODE_UINT as usize)
+            .length(1)
+            .length(RDB_MODULE_OPCODE_EOF as usize);
+        rdb.byte(RDB_TYPE_STRING).string(&[0xFF, 0xFE]).string(b"v");
+        rdb.byte(RDB_OPCODE_FUNCTION2).string(b"#!lua name=lib");
+        // Keys of another Redis database
+        rdb.select(1)
+            .key(RDB_TYPE_STRING, "db1")
+            .string(b"v")
+            .key(RDB_TYPE_HASH, "db1")
+            .length(1)
+            .string(b"f")
+            .string(b"v");
+        let rdb = rdb.finish();
+
+        let path = "test-rdb-db2";
+        {
+            let db = Arc::new(make_db(path));
+            let report = import_rdb(db.clone(), &rdb[..], 0).unwrap();
+            assert_eq!(report.strings, 1);
+            assert_eq!(report.lists, 0);
+            assert_eq!(report.hashes, 0);
+            assert_eq!(report.expired, 2);
+            assert_eq!(report.other_db, 2);
+            assert_eq!(
+                report.unsupported,
+                vec![
+                    "key \"stream\" of type stream".to_string(),
+                    "key \"module\" of type module".to_string(),
+                    "key \"\u{fffd}\u{fffd}\" is not utf8".to_string(),
+                    "a library of Redis functions".to_string(),
+                ]
+            );
+            assert_eq!(string(&db, "expired1"), None);
+            assert!(list(&db, "expired2").is_empty());
+            assert_eq!(string(&db, "later").unwrap(), b"v");
+            assert_eq!(string(&db, "db1"), None);
+
+            // Import another Redis database
+            let report = import_rdb(db.clone(), &rdb[..], 1).unwrap();
+            assert_eq!(report.strings, 1);
+            assert_eq!(report.hashes, 1);
+            // All keys of the database 0, including expired and unsupported keys
+            assert_eq!(report.other_db, 6);
+            assert_eq!(string(&db, "db1").unwrap(), b"v");
+            assert_eq!(map(&db, "db1"), pairs(&[("f", "v")]));
+        }
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let path = "test-rdb-db3";
+        {
+            let db = Arc::new(make_db(path));
+            assert!(import_rdb(db.clone(), &b"RESP00011"[..], 0).is_err());
+
+            // Module values of RDB_TYPE_MODULE can not be skipped
+            let mut rdb = Rdb::new();
+            rdb.key(RDB_TYPE_MODULE, "module").length(1);
+            assert!(import_rdb(db.clone(), &rdb.finish()[..], 0).is_err());
+
+            // Truncated files
+            let mut rdb = Rdb::new();
+            rdb.key(RDB_TYPE_LIST, "l")
+                .length(2)
+                .string(b"a")
+                .string(b"b");
+            let rdb = rdb.finish();
+            assert!(import_rdb(db.clone(), &rdb[..rdb.len() - 14], 0).is_err());
+
+            // Broken encodings
+            let mut rdb = Rdb::new();
+            rdb.key(RDB_TYPE_LIST_ZIPLIST, "l")
+                .string(&ziplist(&[&[0x20, b'a']]));
+            assert!(import_rdb(db.clone(), &rdb.finish()[..], 0).is_err());
+            let mut rdb = Rdb::new();
+            rdb.key(RDB_TYPE_HASH_LISTPACK, "h")
+                .string(&listpack(&[&listpack_string("f")]));
+            assert!(import_rdb(db.clone(), &rdb.finish()[..], 0).is_err());
+            let mut rdb = Rdb::new();
+            rdb.key(RDB_TYPE_STRING, "s")
+                .bytes(&[0xC3])
+                .length(2)
+                .length(4)
+                .bytes(&[0x20, 0x00]);
+            assert!(import_rdb(db.clone(), &rdb.finish()[..], 0).is_err());
+        }
+        let _ = fs::remove_dir_all(path);
+    }
+}
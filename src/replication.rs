@@ -0,0 +1,367 @@
+use std::{sync::Arc, thread, time::Duration};
+
+use actix_web::{
+    client::{Client, ClientResponse, Connector},
+    error::PayloadError,
+    rt::{time::delay_for, System},
+    web,
+};
+use futures::{channel::mpsc, executor::block_on, SinkExt, Stream, StreamExt};
+use rustls::ClientConfig;
+
+use lodisdb::{
+    apply_updates, clear_replica, finish_resync, replica_sequence, u32_to_u8x4, u64_to_u8x8,
+    u8x4_to_u32, u8x8_to_u64, write_pairs, ReplicaSnapshot, WalUpdate,
+};
+
+use crate::{
+    common::SUCCESS,
+    error::{LodisError, Result},
+    state::GlobalState,
+};
+
+// Replication formats
+//
+// All integers are big-endian unsign. Both responses start with `SUCCESS`.
+//
+// Updates:   write batches, every one is 8bytes sequence + 4bytes number of writes + writes
+//            Write: 1byte type (1 is put, 0 is delete) + 4bytes length of key + key
+//                   + 4bytes length of value + value (only for put)
+// Snapshot:  8bytes sequence + pairs + 4bytes 0xFFFFFFFF
+//            Pair:  4bytes length of key + key + 4bytes length of value + value
+const WRITE_DELETE: u8 = 0;
+const WRITE_PUT: u8 = 1;
+const SNAPSHOT_END: u32 = u32::MAX;
+
+// Size of write batches returned by one REPLUPDATES
+pub const MAX_UPDATES_SIZE: usize = 4 * 1024 * 1024;
+// Size of chunks of a streamed snapshot
+const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+// Limit of a REPLUPDATES response at a replica, one write batch can be larger than
+// `MAX_UPDATES_SIZE`
+const MAX_RESPONSE_SIZE: usize = 256 * 1024 * 1024;
+// Number of pairs of a snapshot written to a replica at once
+const SNAPSHOT_BATCH_PAIRS: usize = 1000;
+
+// How long a replica waits when it catches up with its primary
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long a replica waits after an error, e.g. the primary is down
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn encode_updates(buf: &mut Vec<u8>, updates: &[WalUpdate]) {
+    for update in updates {
+        buf.extend_from_slice(&u64_to_u8x8(update.sequence));
+        buf.extend_from_slice(&u32_to_u8x4(update.writes.len() as u32));
+        for (key, value) in update.writes.iter() {
+            match value {
+                Some(value) => {
+                    buf.push(WRITE_PUT);
+                    write_bytes(buf, key);
+                    write_bytes(buf, value);
+                }
+                None => {
+                    buf.push(WRITE_DELETE);
+                    write_bytes(buf, key);
+                }
+            }
+        }
+    }
+}
+
+fn decode_updates(buf: &[u8]) -> Result<Vec<WalUpdate>> {
+    let mut reader = Reader { buf, index: 0 };
+    let mut updates = Vec::new();
+    while !reader.is_empty() {
+        let sequence = u8x8_to_u64(&to_u8x8(reader.take(8)?));
+        let num = u8x4_to_u32(&to_u8x4(reader.take(4)?));
+        let mut writes = Vec::new();
+        for _ in 0..num {
+            let write_type = reader.take(1)?[0];
+            let key = reader.take_bytes()?.into();
+            let value = match write_type {
+                WRITE_PUT => Some(reader.take_bytes()?.into()),
+                WRITE_DELETE => None,
+                other => {
+                    return Err(LodisError::Error(format!("Unknown write type {}", other)));
+                }
+            };
+            writes.push((key, value));
+        }
+        if writes.is_empty() {
+            return Err(LodisError::Error(format!(
+                "Write batch at {} is empty",
+                sequence
+            )));
+        }
+        updates.push(WalUpdate { sequence, writes });
+    }
+    Ok(updates)
+}
+
+// Stream all pairs of the snapshot from a background thread, the snapshot is removed
+// after it is sent or the replica is disconnected
+pub fn stream_snapshot(snapshot: ReplicaSnapshot) -> mpsc::Receiver<Result<web::Bytes>> {
+    let (mut sender, receiver) = mpsc::channel(4);
+    thread::spawn(move || {
+        let mut buf = SUCCESS.to_vec();
+        buf.extend_from_slice(&u64_to_u8x8(snapshot.sequence()));
+        let result = snapshot.for_each(|key, value| {
+            write_bytes(&mut buf, key);
+            write_bytes(&mut buf, value);
+            if buf.len() >= SNAPSHOT_CHUNK_SIZE {
+                let chunk = web::Bytes::from(std::mem::take(&mut buf));
+                block_on(sender.send(Ok(chunk)))?;
+            }
+            Ok::<(), mpsc::SendError>(())
+        });
+        if result.is_ok() {
+            buf.extend_from_slice(&u32_to_u8x4(SNAPSHOT_END));
+            let _ = block_on(sender.send(Ok(web::Bytes::from(buf))));
+        }
+    });
+    receiver
+}
+
+// Keep the db of the replica in sync with the primary at `primary` (`ip:port`) in a
+// background thread, `token` is the token of a user of the primary which has the admin category
+//
+// The replica connects to the primary over TLS if `tls_config` is given, then the host of
+// `primary` must be a name in the certificate of the primary.
+pub fn start_replica(
+    global_state: web::Data<GlobalState>,
+    primary: String,
+    token: Option<String>,
+    tls_config: Option<ClientConfig>,
+) {
+    thread::spawn(move || {
+        let mut system = System::new("lodis-replica");
+        system.block_on(replicate(global_state, primary, token, tls_config));
+    });
+}
+
+async fn replicate(
+    global_state: web::Data<GlobalState>,
+    primary: String,
+    token: Option<String>,
+    tls_config: Option<ClientConfig>,
+) {
+    let mut builder = Client::builder().timeout(Duration::from_secs(60));
+    if let Some(token) = token {
+        builder = builder.bearer_auth(token);
+    }
+    let primary = match tls_config {
+        Some(tls_config) => {
+            builder = builder.connector(Connector::new().rustls(Arc::new(tls_config)).finish());
+            format!("https://{}", primary)
+        }
+        None => format!("http://{}", primary),
+    };
+    let client = builder.finish();
+    loop {
+        let result = match replica_sequence(&global_state.db) {
+            Ok(Some(sequence)) => match tail(&client, &global_state, &primary, sequence).await {
+                Err(LodisError::WalTruncated(_)) => {
+                    resync(&client, &global_state, &primary).await.map(|_| true)
+                }
+                result => result,
+            },
+            // Not synced yet, or the last resync is not finished
+            Ok(None) => resync(&client, &global_state, &primary).await.map(|_| true),
+            Err(err) => Err(err.into()),
+        };
+        match result {
+            Ok(true) => {}
+            Ok(false) => delay_for(POLL_INTERVAL).await,
+            Err(err) => {
+                log::error!("Replication Error: {}", err);
+                delay_for(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+// Apply write batches of the primary after `sequence`, return whether there are any
+async fn tail(
+    client: &Client,
+    global_state: &GlobalState,
+    primary: &str,
+    sequence: u64,
+) -> Result<bool> {
+    let mut body = u32_to_u8x4(8).to_vec();
+    body.extend_from_slice(&u64_to_u8x8(sequence));
+    let mut response = client
+        .post(format!("{}/replupdates", primary))
+        .send_body(body)
+        .await
+        .map_err(|err| LodisError::Error(format!("Can't connect {}: {}", primary, err)))?;
+    check_response(&mut response, sequence).await?;
+
+    let body = response.body().limit(MAX_RESPONSE_SIZE).await?;
+    if body.is_empty() || body[0] != SUCCESS[0] {
+        return Err(LodisError::Error(
+            "Unknown response of REPLUPDATES".to_string(),
+        ));
+    }
+    let updates = decode_updates(&body[1..])?;
+    match updates.first() {
+        Some(update) if update.sequence != sequence + 1 => {
+            return Err(LodisError::Error(format!(
+                "Write batches of the primary start at {}, not {}",
+                update.sequence,
+                sequence + 1
+            )));
+        }
+        _ => {}
+    }
+    apply_updates(&global_state.db, &updates)?;
+    Ok(!updates.is_empty())
+}
+
+// Replace all data of the replica with a snapshot of the primary
+async fn resync(client: &Client, global_state: &GlobalState, primary: &str) -> Result<()> {
+    let mut response = client
+        .post(format!("{}/replsnapshot", primary))
+        .send()
+        .await
+        .map_err(|err| LodisError::Error(format!("Can't connect {}: {}", primary, err)))?;
+    check_response(&mut response, 0).await?;
+
+    let db = &global_state.db;
+    let mut buf = web::BytesMut::new();
+    let mut sequence = None;
+    let mut pairs = Vec::new();
+    while let Some(chunk) = response.next().await {
+        buf.extend_from_slice(&chunk?);
+        let snapshot_sequence = match sequence {
+            Some(sequence) => sequence,
+            None => {
+                if buf.len() < 9 {
+                    continue;
+                }
+                if buf[0] != SUCCESS[0] {
+                    return Err(LodisError::Error(
+                        "Unknown response of REPLSNAPSHOT".to_string(),
+                    ));
+                }
+                let head = buf.split_to(9);
+                let snapshot_sequence = u8x8_to_u64(&to_u8x8(&head[1..]));
+                // The replica is not synced until `finish_resync`
+                clear_replica(db)?;
+                sequence = Some(snapshot_sequence);
+                snapshot_sequence
+            }
+        };
+
+        while let Some(item) = take_snapshot_item(&mut buf) {
+            match item {
+                Some(pair) => {
+                    pairs.push(pair);
+                    if pairs.len() >= SNAPSHOT_BATCH_PAIRS {
+                        write_pairs(db, &pairs)?;
+                        pairs.clear();
+                    }
+                }
+                None => {
+                    write_pairs(db, &pairs)?;
+                    finish_resync(db, snapshot_sequence)?;
+                    log::info!(
+                        "Resynced from {} at sequence {}",
+                        primary,
+                        snapshot_sequence
+                    );
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Err(LodisError::Error(
+        "Snapshot from the primary is truncated".to_string(),
+    ))
+}
+
+// Turn an error response of the primary into an error
+async fn check_response<S>(response: &mut ClientResponse<S>, sequence: u64) -> Result<()>
+where
+    S: Stream<Item = std::result::Result<web::Bytes, PayloadError>> + Unpin,
+{
+    if response.status().is_success() {
+        return Ok(());
+    }
+    let body = response.body().await?;
+    let err = LodisError::WalTruncated(sequence);
+    if body.first() == Some(&err.status()) {
+        return Err(err);
+    }
+    let message = String::from_utf8_lossy(&body);
+    Err(LodisError::Error(format!("Primary error: {}", message)))
+}
+
+// Take a pair at the head of `buf`, Some(None) is the end of the snapshot.
+// Return None if `buf` does not have the whole item yet.
+fn take_snapshot_item(buf: &mut web::BytesMut) -> Option<Option<(Vec<u8>, Vec<u8>)>> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let key_len = u8x4_to_u32(&to_u8x4(&buf[..4]));
+    if key_len == SNAPSHOT_END {
+        let _ = buf.split_to(4);
+        return Some(None);
+    }
+    let key_len = key_len as usize;
+    if buf.len() < 4 + key_len + 4 {
+        return None;
+    }
+    let value_len = u8x4_to_u32(&to_u8x4(&buf[4 + key_len..4 + key_len + 4])) as usize;
+    if buf.len() < 4 + key_len + 4 + value_len {
+        return None;
+    }
+    let item = buf.split_to(4 + key_len + 4 + value_len);
+    Some(Some((
+        item[4..4 + key_len].to_vec(),
+        item[4 + key_len + 4..].to_vec(),
+    )))
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&u32_to_u8x4(bytes.len() as u32));
+    buf.extend_from_slice(bytes);
+}
+
+fn to_u8x4(bytes: &[u8]) -> [u8; 4] {
+    let mut buf: [u8; 4] = [0; 4];
+    buf.clone_from_slice(bytes);
+    buf
+}
+
+fn to_u8x8(bytes: &[u8]) -> [u8; 8] {
+    let mut buf: [u8; 8] = [0; 8];
+    buf.clone_from_slice(bytes);
+    buf
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.index == self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.index + n > self.buf.len() {
+            return Err(LodisError::Error(
+                "Updates from the primary are truncated".to_string(),
+            ));
+        }
+        let bytes = &self.buf[self.index..self.index + n];
+        self.index += n;
+        Ok(bytes)
+    }
+
+    fn take_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = u8x4_to_u32(&to_u8x4(self.take(4)?)) as usize;
+        self.take(len)
+    }
+}
@@ -0,0 +1,1087 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use actix_web::web;
+use clap::crate_version;
+
+use lodisdb::{common::DataType, i64_to_u8x8, u32_to_u8x4, u8x4_to_u32, Batch};
+
+use crate::{
+    acl::User,
+    common::Command,
+    error::LodisError,
+    handler::{all_keys, delete_keys, key_types, run_commands},
+    script::data_type_name,
+    state::GlobalState,
+};
+
+// Longest line of a command, which is an inline command or the header of an argument
+const MAX_LINE_LEN: u64 = 64 * 1024;
+// Most arguments of a command
+const MAX_ARGS: usize = 1024 * 1024;
+// Longest argument of a command
+const MAX_ARG_LEN: usize = 512 * 1024 * 1024;
+// Most arguments and longest argument of a command before the connection is authenticated
+const UNAUTHENTICATED_MAX_ARGS: usize = 10;
+const UNAUTHENTICATED_MAX_ARG_LEN: usize = 16 * 1024;
+// Most bytes allocated for the arguments of a command before they are read
+const PREALLOCATED_LEN: usize = 64 * 1024;
+
+// Number of connections accepted by the listener, the id of a connection
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+// A reply of the Redis protocol
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    // A missing value, `$-1` in RESP2
+    Null,
+    // A missing array, `*-1` in RESP2
+    NullArray,
+    Array(Vec<Reply>),
+    // An array of pairs in RESP2
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn bulks(values: Vec<Vec<u8>>) -> Reply {
+        Reply::Array(values.into_iter().map(Reply::Bulk).collect())
+    }
+
+    fn write(&self, buf: &mut Vec<u8>, protocol: u8) {
+        match self {
+            Reply::Status(status) => write_line(buf, b'+', status.as_bytes()),
+            Reply::Error(message) => {
+                write_line(buf, b'-', message.replace(['\r', '\n'], " ").as_bytes())
+            }
+            Reply::Integer(n) => write_line(buf, b':', n.to_string().as_bytes()),
+            Reply::Bulk(value) => {
+                write_line(buf, b'$', value.len().to_string().as_bytes());
+                buf.extend_from_slice(value);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if protocol >= 3 => buf.extend_from_slice(b"_\r\n"),
+            Reply::Null => buf.extend_from_slice(b"$-1\r\n"),
+            Reply::NullArray if protocol >= 3 => buf.extend_from_slice(b"_\r\n"),
+            Reply::NullArray => buf.extend_from_slice(b"*-1\r\n"),
+            Reply::Array(items) => {
+                write_line(buf, b'*', items.len().to_string().as_bytes());
+                for item in items {
+                    item.write(buf, protocol);
+                }
+            }
+            Reply::Map(pairs) => {
+                if protocol >= 3 {
+                    write_line(buf, b'%', pairs.len().to_string().as_bytes());
+                } else {
+                    write_line(buf, b'*', (pairs.len() * 2).to_string().as_bytes());
+                }
+                for (key, value) in pairs {
+                    key.write(buf, protocol);
+                    value.write(buf, protocol);
+                }
+            }
+        }
+    }
+}
+
+fn write_line(buf: &mut Vec<u8>, prefix: u8, content: &[u8]) {
+    buf.push(prefix);
+    buf.extend_from_slice(content);
+    buf.extend_from_slice(b"\r\n");
+}
+
+// The error of a command, which is replied as `-{code} {message}`
+enum RespError {
+    // An error of the protocol, e.g. `ERR syntax error`
+    Reply(String),
+    Lodis(LodisError),
+}
+
+impl From<LodisError> for RespError {
+    fn from(err: LodisError) -> RespError {
+        RespError::Lodis(err)
+    }
+}
+
+impl RespError {
+    fn reply(self) -> Reply {
+        match self {
+            RespError::Reply(message) => Reply::Error(message),
+            RespError::Lodis(err) => {
+                let code = match err {
+                    LodisError::WrongType(_) => "WRONGTYPE",
+                    LodisError::ReadOnly => "READONLY",
+                    LodisError::NoPermission(_) => "NOPERM",
+                    LodisError::Unauthenticated => "NOAUTH",
+                    _ => "ERR",
+                };
+                // The message without the status code of the error
+                let message = err.to_string();
+                let message = &message[1..];
+                Reply::Error(format!("{} {}", code, message))
+            }
+        }
+    }
+}
+
+type RespResult = Result<Reply, RespError>;
+
+fn wrong_arity(name: &str) -> RespError {
+    RespError::Reply(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+fn not_integer() -> RespError {
+    RespError::Reply("ERR value is not an integer or out of range".to_string())
+}
+
+fn parse_integer(arg: &[u8]) -> Result<i64, RespError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(not_integer)
+}
+
+fn parse_key(arg: &[u8]) -> Result<String, RespError> {
+    String::from_utf8(arg.to_vec())
+        .map_err(|_| RespError::Reply("ERR keys must be UTF-8 strings".to_string()))
+}
+
+// Split a response content of `len + value` items
+fn decode_values(mut content: &[u8]) -> Vec<Vec<u8>> {
+    let mut values = Vec::new();
+    while content.len() >= 4 {
+        let mut len: [u8; 4] = [0; 4];
+        len.clone_from_slice(&content[..4]);
+        let len = (u8x4_to_u32(&len) as usize).min(content.len() - 4);
+        values.push(content[4..4 + len].to_vec());
+        content = &content[4 + len..];
+    }
+    values
+}
+
+// Split the response content of HMGET, which is `1u8 + len + value` or `0u8` for every field
+fn decode_optional_values(mut content: &[u8]) -> Vec<Option<Vec<u8>>> {
+    let mut values = Vec::new();
+    while let Some((&flag, rest)) = content.split_first() {
+        if flag == 0 {
+            values.push(None);
+            content = rest;
+            continue;
+        }
+        match decode_values(rest).into_iter().next() {
+            Some(value) => {
+                content = &rest[4 + value.len()..];
+                values.push(Some(value));
+            }
+            None => break,
+        }
+    }
+    values
+}
+
+fn decode_u32(content: &[u8]) -> u32 {
+    let mut buf: [u8; 4] = [0; 4];
+    buf.clone_from_slice(&content[..4]);
+    u8x4_to_u32(&buf)
+}
+
+// The fields of the arguments without duplicates, in the order of the arguments
+fn distinct<'a>(args: impl Iterator<Item = &'a Vec<u8>>) -> Vec<&'a [u8]> {
+    let mut seen = HashSet::new();
+    args.filter(|arg| seen.insert(&arg[..]))
+        .map(|arg| &arg[..])
+        .collect()
+}
+
+// Read a command, which is an array of bulk strings or an inline command, None at the end of
+// the stream
+//
+// Empty commands are returned as empty arrays. Commands of a connection which is not
+// authenticated are limited to a few short arguments.
+fn read_command<R: BufRead>(
+    reader: &mut R,
+    authenticated: bool,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(
+            line.split(|c| c.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect(),
+        ));
+    }
+
+    let (max_args, max_arg_len) = if authenticated {
+        (MAX_ARGS, MAX_ARG_LEN)
+    } else {
+        (UNAUTHENTICATED_MAX_ARGS, UNAUTHENTICATED_MAX_ARG_LEN)
+    };
+    let count = parse_len(&line[1..])?;
+    if count > max_args {
+        return Err(protocol_error("invalid multibulk length"));
+    }
+    let mut args = Vec::with_capacity(count.min(PREALLOCATED_LEN));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end"))?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_len(&line[1..])?;
+        if len > max_arg_len {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        // The argument grows as it is read, so a client can not allocate more than it sends
+        let mut arg = Vec::with_capacity((len + 2).min(PREALLOCATED_LEN));
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("expected CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Read a line without `\r\n`
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(protocol_error("too big inline request"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+// The length of an array or a bulk string, a negative length is 0
+fn parse_len(n: &[u8]) -> io::Result<usize> {
+    let n: i64 = std::str::from_utf8(n)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    Ok(n.max(0) as usize)
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// A client of the listener
+struct Connection {
+    global_state: web::Data<GlobalState>,
+    id: u64,
+    // Version of the protocol of the replies, 2 or 3
+    protocol: u8,
+    namespace: String,
+    // The authenticated user, None if ACLs are not enabled or the client is not authenticated
+    user: Option<Arc<User>>,
+    closed: bool,
+}
+
+impl Connection {
+    fn call(&mut self, args: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let result = if self.global_state.acl.is_some()
+            && self.user.is_none()
+            && !matches!(name.as_str(), "AUTH" | "HELLO" | "QUIT")
+        {
+            Err(RespError::Reply(
+                "NOAUTH Authentication required.".to_string(),
+            ))
+        } else {
+            self.dispatch(&name, &args[1..])
+        };
+        result.unwrap_or_else(RespError::reply)
+    }
+
+    fn dispatch(&mut self, name: &str, args: &[Vec<u8>]) -> RespResult {
+        match name {
+            // Connection
+            "PING" => match args {
+                [] => Ok(Reply::Status("PONG")),
+                [message] => Ok(Reply::Bulk(message.clone())),
+                _ => Err(wrong_arity(name)),
+            },
+            "ECHO" => match args {
+                [message] => Ok(Reply::Bulk(message.clone())),
+                _ => Err(wrong_arity(name)),
+            },
+            "QUIT" => {
+                self.closed = true;
+                Ok(Reply::Status("OK"))
+            }
+            "HELLO" => self.hello(args),
+            "AUTH" => match args {
+                [token] => self.auth(None, token),
+                [user, token] => self.auth(Some(user), token),
+                _ => Err(wrong_arity(name)),
+            },
+            "SELECT" => self.select(args),
+            // Clients query commands and set their names when they connect
+            "COMMAND" => Ok(Reply::Array(Vec::new())),
+            "CLIENT" => Ok(Reply::Status("OK")),
+
+            // Keys
+            "DEL" => self.del(args),
+            "EXISTS" => self.exists(args),
+            "TYPE" => self.key_type(args),
+            "KEYS" => self.keys(args),
+            "DBSIZE" => self.dbsize(args),
+
+            // List
+            "LPUSH" => self.push(Command::LPUSH, args),
+            "RPUSH" => self.push(Command::RPUSH, args),
+            "LPOP" => self.pop(Command::LPOP, args),
+            "RPOP" => self.pop(Command::RPOP, args),
+            "LRANGE" => self.lrange(args),
+            "LINDEX" => self.lindex(args),
+            "LLEN" => match args {
+                [key] => {
+                    let values = self.run(Command::LLEN, key, vec![(Command::LLEN, vec![])])?;
+                    Ok(Reply::Integer(decode_u32(&values[0]) as i64))
+                }
+                _ => Err(wrong_arity(name)),
+            },
+
+            // Hash
+            "HGET" => match args {
+                [key, field] => {
+                    let values =
+                        self.run(Command::HGET, key, vec![(Command::HMGET, vec![field])])?;
+                    Ok(match decode_optional_values(&values[0]).pop() {
+                        Some(Some(value)) => Reply::Bulk(value),
+                        _ => Reply::Null,
+                    })
+                }
+                _ => Err(wrong_arity(name)),
+            },
+            "HSET" => self.hset(args),
+            "HSETNX" => match args {
+                [key, field, value] => {
+                    let values = self.run(
+                        Command::HSETNX,
+                        key,
+                        vec![
+                            (Command::HEXISTS, vec![field]),
+                            (Command::HSETNX, vec![field, value]),
+                        ],
+                    )?;
+                    Ok(Reply::Integer((values[0][0] == 0) as i64))
+                }
+                _ => Err(wrong_arity(name)),
+            },
+            "HMSET" => {
+                if args.len() < 3 || args.len() % 2 == 0 {
+                    return Err(wrong_arity(name));
+                }
+                self.run(
+                    Command::HMSET,
+                    &args[0],
+                    vec![(Command::HMSET, args[1..].iter().collect())],
+                )?;
+                Ok(Reply::Status("OK"))
+            }
+            "HGETALL" => match args {
+                [key] => {
+                    let values =
+                        self.run(Command::HGETALL, key, vec![(Command::HGETALL, vec![])])?;
+                    let mut pairs = Vec::new();
+                    let mut items = decode_values(&values[0]).into_iter();
+                    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+                        pairs.push((Reply::Bulk(field), Reply::Bulk(value)));
+                    }
+                    Ok(Reply::Map(pairs))
+                }
+                _ => Err(wrong_arity(name)),
+            },
+            "HMGET" => {
+                if args.len() < 2 {
+                    return Err(wrong_arity(name));
+                }
+                let values = self.run(
+                    Command::HMGET,
+                    &args[0],
+                    vec![(Command::HMGET, args[1..].iter().collect())],
+                )?;
+                Ok(Reply::Array(
+                    decode_optional_values(&values[0])
+                        .into_iter()
+                        .map(|value| value.map(Reply::Bulk).unwrap_or(Reply::Null))
+                        .collect(),
+                ))
+            }
+            "HKEYS" | "HVALS" => match args {
+                [key] => {
+                    let command = if name == "HKEYS" {
+                        Command::HKEYS
+                    } else {
+                        Command::HVALS
+                    };
+                    let values = self.run(command, key, vec![(command, vec![])])?;
+                    Ok(Reply::bulks(decode_values(&values[0])))
+                }
+                _ => Err(wrong_arity(name)),
+            },
+            "HEXISTS" => match args {
+                [key, field] => {
+                    let values =
+                        self.run(Command::HEXISTS, key, vec![(Command::HEXISTS, vec![field])])?;
+                    Ok(Reply::Integer(values[0][0] as i64))
+                }
+                _ => Err(wrong_arity(name)),
+            },
+            "HLEN" => match args {
+                [key] => {
+                    let values = self.run(Command::HLEN, key, vec![(Command::HLEN, vec![])])?;
+                    Ok(Reply::Integer(decode_u32(&values[0]) as i64))
+                }
+                _ => Err(wrong_arity(name)),
+            },
+            "HDEL" => self.hdel(args),
+            "HINCRBY" => match args {
+                [key, field, incr] => {
+                    parse_integer(incr)?;
+                    let values = self.run(
+                        Command::HINCRBY,
+                        key,
+                        vec![
+                            (Command::HINCRBY, vec![field, incr]),
+                            (Command::HMGET, vec![field]),
+                        ],
+                    )?;
+                    match decode_optional_values(&values[1]).pop() {
+                        Some(Some(value)) => Ok(Reply::Integer(parse_integer(&value)?)),
+                        _ => Err(not_integer()),
+                    }
+                }
+                _ => Err(wrong_arity(name)),
+            },
+
+            _ => Err(RespError::Reply(format!(
+                "ERR unknown command '{}'",
+                name.to_ascii_lowercase()
+            ))),
+        }
+    }
+
+    // HELLO [protover [AUTH username password] [SETNAME clientname]]
+    fn hello(&mut self, args: &[Vec<u8>]) -> RespResult {
+        let mut protocol = self.protocol;
+        if let Some(version) = args.first() {
+            protocol = match &version[..] {
+                b"2" => 2,
+                b"3" => 3,
+                _ => {
+                    return Err(RespError::Reply(
+                        "NOPROTO unsupported protocol version".to_string(),
+                    ))
+                }
+            };
+        }
+        let mut i = 1;
+        while i < args.len() {
+            match args[i].to_ascii_uppercase().as_slice() {
+                b"AUTH" if i + 2 < args.len() => {
+                    self.auth(Some(&args[i + 1]), &args[i + 2])?;
+                    i += 3;
+                }
+                b"SETNAME" if i + 1 < args.len() => i += 2,
+                _ => return Err(RespError::Reply("ERR syntax error".to_string())),
+            }
+        }
+        if self.global_state.acl.is_some() && self.user.is_none() {
+            return Err(RespError::Reply(
+                "NOAUTH HELLO must be called with the client already authenticated, otherwise \
+                 the HELLO AUTH <user> <pass> option can be used to authenticate the client and \
+                 select the RESP protocol version at the same time"
+                    .to_string(),
+            ));
+        }
+        self.protocol = protocol;
+
+        let role = if self.global_state.read_only {
+            "replica"
+        } else {
+            "master"
+        };
+        let field = |name: &str| Reply::Bulk(name.as_bytes().to_vec());
+        Ok(Reply::Map(vec![
+            (field("server"), field("lodis")),
+            (field("version"), field(crate_version!())),
+            (field("proto"), Reply::Integer(protocol as i64)),
+            (field("id"), Reply::Integer(self.id as i64)),
+            (field("mode"), field("standalone")),
+            (field("role"), field(role)),
+            (field("modules"), Reply::Array(Vec::new())),
+        ]))
+    }
+
+    // The user is the name of the user of the token, which is not checked if it is `default`
+    fn auth(&mut self, user: Option<&Vec<u8>>, token: &[u8]) -> Result<Reply, RespError> {
+        let acl = match &self.global_state.acl {
+            Some(acl) => acl,
+            None => {
+                return Err(RespError::Reply(
+                    "ERR AUTH called without any users configured, set LODIS_ACL_FILE".to_string(),
+                ))
+            }
+        };
+        let wrong_pass = || {
+            RespError::Reply(
+                "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+            )
+        };
+        let found = acl
+            .user(&String::from_utf8_lossy(token))
+            .map_err(|_| wrong_pass())?;
+        if let Some(user) = user {
+            if &user[..] != b"default" && &user[..] != found.name.as_bytes() {
+                return Err(wrong_pass());
+            }
+        }
+        self.user = Some(found);
+        Ok(Reply::Status("OK"))
+    }
+
+    // SELECT namespace, where `0` is the default namespace
+    fn select(&mut self, args: &[Vec<u8>]) -> RespResult {
+        let namespace = match args {
+            [namespace] if &namespace[..] == b"0" => String::new(),
+            [namespace] => String::from_utf8(namespace.clone())
+                .map_err(|_| RespError::Reply("ERR invalid namespace".to_string()))?,
+            _ => return Err(wrong_arity("SELECT")),
+        };
+        if let Some(user) = &self.user {
+            user.check_namespace(&namespace)?;
+        }
+        self.namespace = namespace;
+        Ok(Reply::Status("OK"))
+    }
+
+    // Fail if the user can not run the command on the keys
+    fn check(&self, command: &str, keys: &[&str]) -> Result<(), RespError> {
+        if let Some(user) = &self.user {
+            user.check_namespace(&self.namespace)?;
+            user.check_command(command)?;
+            for key in keys {
+                user.check_key(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Run the commands on the key in one transaction, and return the response contents of them
+    // without `SUCCESS`
+    //
+    // The ACL of the user is checked against `command`, which is what the client requests.
+    fn run(
+        &self,
+        command: Command,
+        key: &[u8],
+        commands: Vec<(Command, Vec<&Vec<u8>>)>,
+    ) -> Result<Vec<Vec<u8>>, RespError> {
+        let key = parse_key(key)?;
+        self.check(&format!("{:?}", command), &[&key])?;
+        self.global_state
+            .stats
+            .record_commands(std::iter::once(command));
+        let commands: Vec<_> = commands
+            .into_iter()
+            .map(|(command, params)| {
+                let params = params
+                    .into_iter()
+                    .map(|param| web::BytesMut::from(&param[..]))
+                    .collect();
+                (command, key.clone(), params)
+            })
+            .collect();
+        let values = run_commands(
+            &self.global_state,
+            &self.namespace,
+            &commands,
+            |_, _, err| err,
+        )?;
+        Ok(values
+            .into_iter()
+            .map(|value| value[1..].to_vec())
+            .collect())
+    }
+
+    fn parse_keys(&self, command: &str, args: &[Vec<u8>]) -> Result<Vec<String>, RespError> {
+        if args.is_empty() {
+            return Err(wrong_arity(command));
+        }
+        let keys = args
+            .iter()
+            .map(|arg| parse_key(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.check(
+            command,
+            &keys.iter().map(|key| &key[..]).collect::<Vec<_>>(),
+        )?;
+        Ok(keys)
+    }
+
+    fn del(&self, args: &[Vec<u8>]) -> RespResult {
+        let mut keys = self.parse_keys("DEL", args)?;
+        keys.sort_unstable();
+        keys.dedup();
+        let removed = delete_keys(&self.global_state, &self.namespace, &keys)?;
+        Ok(Reply::Integer(removed as i64))
+    }
+
+    fn exists(&self, args: &[Vec<u8>]) -> RespResult {
+        let keys = self.parse_keys("EXISTS", args)?;
+        let count = Batch::read(&self.global_state.db, |batch| -> Result<i64, LodisError> {
+            let mut count = 0;
+            for key in keys.iter() {
+                if !key_types(&self.global_state, &self.namespace, batch, key)?.is_empty() {
+                    count += 1;
+                }
+            }
+            Ok(count)
+        })?;
+        Ok(Reply::Integer(count))
+    }
+
+    // The first data type of the key, which is named as Redis names it
+    fn key_type(&self, args: &[Vec<u8>]) -> RespResult {
+        if args.len() != 1 {
+            return Err(wrong_arity("TYPE"));
+        }
+        let key = self.parse_keys("TYPE", args)?.remove(0);
+        let data_types = Batch::read(&self.global_state.db, |batch| {
+            key_types(&self.global_state, &self.namespace, batch, &key)
+        })?;
+        Ok(Reply::Status(match data_types.first() {
+            Some(DataType::Map) => "hash",
+            Some(data_type) => data_type_name(*data_type),
+            None => "none",
+        }))
+    }
+
+    // The names of the keys which match the pattern, a name of several data types is returned once
+    fn keys(&self, args: &[Vec<u8>]) -> RespResult {
+        let pattern = match args {
+            [pattern] => pattern,
+            _ => return Err(wrong_arity("KEYS")),
+        };
+        self.check("KEYS", &[])?;
+        let keys = Batch::read(&self.global_state.db, |batch| {
+            all_keys(&self.global_state, &self.namespace, batch, Some(pattern))
+        })?;
+        let mut names = HashSet::new();
+        let mut values = Vec::new();
+        for (_, key) in keys {
+            if let Some(user) = &self.user {
+                if !user.can_access_key(&key) {
+                    continue;
+                }
+            }
+            if names.insert(key.clone()) {
+                values.push(key.into_bytes());
+            }
+        }
+        Ok(Reply::bulks(values))
+    }
+
+    fn dbsize(&self, args: &[Vec<u8>]) -> RespResult {
+        if !args.is_empty() {
+            return Err(wrong_arity("DBSIZE"));
+        }
+        self.check("DBSIZE", &[])?;
+        let keys = Batch::read(&self.global_state.db, |batch| {
+            all_keys(&self.global_state, &self.namespace, batch, None)
+        })?;
+        Ok(Reply::Integer(keys.len() as i64))
+    }
+
+    // LPUSH|RPUSH key element [element ...], reply the length of the list
+    fn push(&self, command: Command, args: &[Vec<u8>]) -> RespResult {
+        if args.len() < 2 {
+            return Err(wrong_arity(&format!("{:?}", command)));
+        }
+        let values = self.run(
+            command,
+            &args[0],
+            vec![
+                (command, args[1..].iter().collect()),
+                (Command::LLEN, vec![]),
+            ],
+        )?;
+        Ok(Reply::Integer(decode_u32(&values[1]) as i64))
+    }
+
+    // LPOP|RPOP key [count]
+    //
+    // The length is read before the elements are popped, so an element may be left if it is
+    // pushed between them, as if it is pushed after the pop.
+    fn pop(&self, command: Command, args: &[Vec<u8>]) -> RespResult {
+        let (key, count) = match args {
+            [key] => (key, None),
+            [key, count] => match parse_integer(count)? {
+                count if count >= 0 => (key, Some(count as usize)),
+                _ => {
+                    return Err(RespError::Reply(
+                        "ERR value is out of range, must be positive".to_string(),
+                    ))
+                }
+            },
+            _ => return Err(wrong_arity(&format!("{:?}", command))),
+        };
+        let pops = match count {
+            Some(count) => {
+                let values = self.run(command, key, vec![(Command::LLEN, vec![])])?;
+                count.min(decode_u32(&values[0]) as usize)
+            }
+            None => 1,
+        };
+
+        let mut commands = vec![(Command::LLEN, vec![])];
+        commands.extend((0..pops).map(|_| (command, vec![])));
+        let mut values = self.run(command, key, commands)?;
+        // Elements are popped only from a list which is not empty
+        let len = decode_u32(&values.remove(0)) as usize;
+        values.truncate(len);
+        match count {
+            None => Ok(values.pop().map(Reply::Bulk).unwrap_or(Reply::Null)),
+            Some(_) if len == 0 => Ok(Reply::NullArray),
+            Some(_) => Ok(Reply::bulks(values)),
+        }
+    }
+
+    // LRANGE key start stop, where the indexes are inclusive and negative indexes are from the end
+    //
+    // The length is read before the elements, so elements which are removed between them are not
+    // returned.
+    fn lrange(&self, args: &[Vec<u8>]) -> RespResult {
+        let (key, start, stop) = match args {
+            [key, start, stop] => (key, parse_integer(start)?, parse_integer(stop)?),
+            _ => return Err(wrong_arity("LRANGE")),
+        };
+        let values = self.run(Command::LRANGE, key, vec![(Command::LLEN, vec![])])?;
+        let len = decode_u32(&values[0]) as i64;
+        let start = if start < 0 {
+            (start + len).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            stop + len
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            return Ok(Reply::Array(Vec::new()));
+        }
+
+        let start = u32_to_u8x4(start as u32).to_vec();
+        let end = u32_to_u8x4(stop as u32 + 1).to_vec();
+        let values = self.run(
+            Command::LRANGE,
+            key,
+            vec![(Command::LRANGE, vec![&start, &end])],
+        )?;
+        Ok(Reply::bulks(decode_values(&values[0])))
+    }
+
+    fn lindex(&self, args: &[Vec<u8>]) -> RespResult {
+        let (key, index) = match args {
+            [key, index] => (key, parse_integer(index)?),
+            _ => return Err(wrong_arity("LINDEX")),
+        };
+        let index_param = i64_to_u8x8(index).to_vec();
+        let values = self.run(
+            Command::LINDEX,
+            key,
+            vec![
+                (Command::LLEN, vec![]),
+                (Command::LINDEX, vec![&index_param]),
+            ],
+        )?;
+        let len = decode_u32(&values[0]) as i64;
+        if index >= len || index < -len {
+            return Ok(Reply::Null);
+        }
+        Ok(Reply::Bulk(values[1].clone()))
+    }
+
+    // HSET key field value [field value ...], reply the number of the new fields
+    fn hset(&self, args: &[Vec<u8>]) -> RespResult {
+        if args.len() < 3 || args.len() % 2 == 0 {
+            return Err(wrong_arity("HSET"));
+        }
+        let fields = distinct(args[1..].iter().step_by(2));
+        let fields: Vec<Vec<u8>> = fields.into_iter().map(|field| field.to_vec()).collect();
+        let values = self.run(
+            Command::HMSET,
+            &args[0],
+            vec![
+                (Command::HMGET, fields.iter().collect()),
+                (Command::HMSET, args[1..].iter().collect()),
+            ],
+        )?;
+        let added = decode_optional_values(&values[0])
+            .iter()
+            .filter(|value| value.is_none())
+            .count();
+        Ok(Reply::Integer(added as i64))
+    }
+
+    // HDEL key field [field ...], reply the number of the removed fields
+    fn hdel(&self, args: &[Vec<u8>]) -> RespResult {
+        if args.len() < 2 {
+            return Err(wrong_arity("HDEL"));
+        }
+        let fields: Vec<Vec<u8>> = distinct(args[1..].iter())
+            .into_iter()
+            .map(|field| field.to_vec())
+            .collect();
+        let mut commands = vec![(Command::HMGET, fields.iter().collect())];
+        commands.extend(fields.iter().map(|field| (Command::HDEL, vec![field])));
+        let values = self.run(Command::HDEL, &args[0], commands)?;
+        let removed = decode_optional_values(&values[0])
+            .iter()
+            .filter(|value| value.is_some())
+            .count();
+        Ok(Reply::Integer(removed as i64))
+    }
+}
+
+// Serve the commands of a client until it closes the connection or sends QUIT
+fn serve(global_state: web::Data<GlobalState>, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut connection = Connection {
+        global_state,
+        id: CONNECTIONS.fetch_add(1, Ordering::SeqCst) + 1,
+        protocol: 2,
+        namespace: String::new(),
+        user: None,
+        closed: false,
+    };
+    let _client = connection.global_state.stats.connect();
+
+    let mut buf = Vec::new();
+    while !connection.closed {
+        let authenticated = connection.global_state.acl.is_none() || connection.user.is_some();
+        let args = match read_command(&mut reader, authenticated) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                Reply::Error(format!("ERR Protocol error: {}", err)).write(&mut buf, 2);
+                writer.write_all(&buf)?;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        if !args.is_empty() {
+            connection.call(&args).write(&mut buf, connection.protocol);
+        }
+        // Replies of pipelined commands are written together
+        if reader.buffer().is_empty() {
+            writer.write_all(&buf)?;
+            writer.flush()?;
+            buf.clear();
+        }
+    }
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+// An open connection, which is counted until it is dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Accept connections of the Redis protocol on `ip_port`, every connection is served by a thread
+//
+// Connections over `max_connections` are closed with an error.
+pub fn start(
+    global_state: web::Data<GlobalState>,
+    ip_port: &str,
+    max_connections: usize,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(ip_port)?;
+    let open = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    if open.fetch_add(1, Ordering::SeqCst) >= max_connections {
+                        open.fetch_sub(1, Ordering::SeqCst);
+                        let _ = stream.write_all(b"-ERR max number of clients reached\r\n");
+                        continue;
+                    }
+                    let global_state = global_state.clone();
+                    let slot = Slot(open.clone());
+                    thread::spawn(move || {
+                        if let Err(err) = serve(global_state, stream) {
+                            log::debug!("RESP connection error: {}", err);
+                        }
+                        drop(slot);
+                    });
+                }
+                Err(err) => log::error!("RESP Accept Error: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_resp {
+    use std::io::{self, Cursor};
+
+    use super::{read_command, Reply, UNAUTHENTICATED_MAX_ARG_LEN};
+
+    fn read(input: &[u8], authenticated: bool) -> io::Result<Option<Vec<Vec<u8>>>> {
+        read_command(&mut Cursor::new(input), authenticated)
+    }
+
+    fn args(args: &[&str]) -> Option<Vec<Vec<u8>>> {
+        Some(args.iter().map(|arg| arg.as_bytes().to_vec()).collect())
+    }
+
+    fn write(reply: Reply, protocol: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        reply.write(&mut buf, protocol);
+        buf
+    }
+
+    #[test]
+    fn test_read_command() {
+        assert_eq!(
+            read(b"*2\r\n$4\r\nLLEN\r\n$3\r\nk\r\n\r\n", true).unwrap(),
+            args(&["LLEN", "k\r\n"])
+        );
+        assert_eq!(
+            read(b"*3\r\n$4\r\nHGET\r\n$0\r\n\r\n$1\r\n\x00\r\n", true).unwrap(),
+            Some(vec![b"HGET".to_vec(), Vec::new(), vec![0]])
+        );
+        // Inline commands
+        assert_eq!(
+            read(b"LPUSH  k a\r\n", true).unwrap(),
+            args(&["LPUSH", "k", "a"])
+        );
+        assert_eq!(read(b"PING\n", true).unwrap(), args(&["PING"]));
+        assert_eq!(read(b"\r\n", true).unwrap(), args(&[]));
+        assert_eq!(read(b"*0\r\n", true).unwrap(), args(&[]));
+        assert_eq!(read(b"*-1\r\n", true).unwrap(), args(&[]));
+        // The end of the stream
+        assert_eq!(read(b"", true).unwrap(), None);
+
+        // Pipelined commands
+        let mut input = Cursor::new(&b"*1\r\n$4\r\nPING\r\nECHO a\r\n"[..]);
+        assert_eq!(read_command(&mut input, true).unwrap(), args(&["PING"]));
+        assert_eq!(
+            read_command(&mut input, true).unwrap(),
+            args(&["ECHO", "a"])
+        );
+        assert_eq!(read_command(&mut input, true).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_command_errors() {
+        for input in [
+            &b"*x\r\n"[..],
+            b"*1\r\n+PING\r\n",
+            b"*1\r\n$x\r\n",
+            b"*1\r\n$4\r\nPINGxx",
+            b"*2000000\r\n",
+            b"*1\r\n$1000000000\r\n",
+        ]
+        .iter()
+        {
+            let err = read(input, true).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", input);
+        }
+
+        for input in [&b"*2\r\n$4\r\nPING\r\n"[..], b"*1\r\n$4\r\nPI"].iter() {
+            assert!(read(input, true).is_err(), "{:?}", input);
+        }
+
+        let line = vec![b'a'; 65 * 1024];
+        assert_eq!(
+            read(&line, true).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_read_unauthenticated_command() {
+        assert_eq!(
+            read(b"*2\r\n$4\r\nAUTH\r\n$3\r\ntok\r\n", false).unwrap(),
+            args(&["AUTH", "tok"])
+        );
+
+        let mut input = b"*11\r\n".to_vec();
+        for _ in 0..11 {
+            input.extend_from_slice(b"$1\r\na\r\n");
+        }
+        assert!(read(&input, true).is_ok());
+        assert!(read(&input, false).is_err());
+
+        let arg = vec![b'a'; UNAUTHENTICATED_MAX_ARG_LEN + 1];
+        let mut input = format!("*2\r\n$4\r\nAUTH\r\n${}\r\n", arg.len()).into_bytes();
+        input.extend_from_slice(&arg);
+        input.extend_from_slice(b"\r\n");
+        assert!(read(&input, true).is_ok());
+        assert!(read(&input, false).is_err());
+    }
+
+    #[test]
+    fn test_write_reply() {
+        assert_eq!(write(Reply::Status("OK"), 2), b"+OK\r\n");
+        assert_eq!(
+            write(Reply::Error("ERR a\r\nb".to_string()), 2),
+            b"-ERR a  b\r\n"
+        );
+        assert_eq!(write(Reply::Integer(-3), 2), b":-3\r\n");
+        assert_eq!(write(Reply::Bulk(b"a".to_vec()), 2), b"$1\r\na\r\n");
+        assert_eq!(write(Reply::Null, 2), b"$-1\r\n");
+        assert_eq!(write(Reply::Null, 3), b"_\r\n");
+        assert_eq!(write(Reply::NullArray, 2), b"*-1\r\n");
+        assert_eq!(write(Reply::NullArray, 3), b"_\r\n");
+        assert_eq!(
+            write(Reply::bulks(vec![b"a".to_vec(), Vec::new()]), 2),
+            b"*2\r\n$1\r\na\r\n$0\r\n\r\n"
+        );
+
+        let map = || Reply::Map(vec![(Reply::Bulk(b"f".to_vec()), Reply::Integer(1))]);
+        assert_eq!(write(map(), 2), b"*2\r\n$1\r\nf\r\n:1\r\n");
+        assert_eq!(write(map(), 3), b"%1\r\n$1\r\nf\r\n:1\r\n");
+    }
+}
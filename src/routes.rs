@@ -1,9 +1,16 @@
-use actix_web::{web, HttpResponse, Scope};
+use actix_web::{web, HttpRequest, HttpResponse, Scope};
 
 use crate::{
-    common::{Command, KeyName},
+    common::{Command, KeyName, Namespace},
     error::Result,
-    handler::handle,
+    handler::{
+        handle, handle_backup, handle_changes, handle_changes_sse, handle_checkpoint, handle_copy,
+        handle_dbsize, handle_del, handle_eval, handle_evalsha, handle_exists, handle_flushdb,
+        handle_info, handle_keys, handle_listbackups, handle_metrics, handle_multi,
+        handle_namespaces, handle_pipeline, handle_psubscribe, handle_publish, handle_rename,
+        handle_renamenx, handle_replsnapshot, handle_replupdates, handle_scan, handle_scriptload,
+        handle_slowlog, handle_subscribe, handle_type, handle_ws,
+    },
     state::GlobalState,
 };
 
@@ -18,8 +25,10 @@ macro_rules! handle_fn {
                 body: web::Bytes,
                 key_name: web::Path<KeyName>,
                 global_store: web::Data<GlobalState>,
+                namespace: Namespace,
+                request: HttpRequest,
             ) -> Result<HttpResponse> {
-                handle(body, key_name, global_store, $cmd).await
+                handle(body, key_name, global_store, namespace, request, $cmd).await
             }
         )+
     };
@@ -38,9 +47,11 @@ handle_fn! {
     (handle_llen, Command::LLEN);
     (handle_ldel, Command::LDEL);
     (handle_lrm, Command::LRM);
+    (handle_lwatch, Command::LWATCH);
     (handle_hget, Command::HGET);
     (handle_hset, Command::HSET);
     (handle_hsetnx, Command::HSETNX);
+    (handle_hcas, Command::HCAS);
     (handle_hgetall, Command::HGETALL);
     (handle_hmget, Command::HMGET);
     (handle_hmset, Command::HMSET);
@@ -51,11 +62,13 @@ handle_fn! {
     (handle_hdel, Command::HDEL);
     (handle_hlen, Command::HLEN);
     (handle_hrm, Command::HRM);
+    (handle_hwatch, Command::HWATCH);
     (handle_alpush, Command::ALPUSH);
     (handle_alpushnx, Command::ALPUSHNX);
     (handle_arpush, Command::ARPUSH);
     (handle_arpushnx, Command::ARPUSHNX);
     (handle_aincrby, Command::AINCRBY);
+    (handle_acas, Command::ACAS);
     (handle_alpop, Command::ALPOP);
     (handle_arpop, Command::ARPOP);
     (handle_arandpop, Command::ARANDPOP);
@@ -70,10 +83,34 @@ handle_fn! {
     (handle_alen, Command::ALEN);
     (handle_adel, Command::ADEL);
     (handle_arm, Command::ARM);
+    (handle_awatch, Command::AWATCH);
 }
 
 pub fn make_route() -> Scope {
-    web::scope("/")
+    data_routes(web::scope("/"))
+        .service(data_routes(web::scope("/ns/{namespace}")))
+        .route("/namespaces", web::post().to(handle_namespaces))
+        .route("/info", web::post().to(handle_info))
+        .route("/metrics", web::get().to(handle_metrics))
+        .route("/slowlog", web::post().to(handle_slowlog))
+        .route("/scriptload", web::post().to(handle_scriptload))
+        .route("/backup", web::post().to(handle_backup))
+        .route("/listbackups", web::post().to(handle_listbackups))
+        .route("/checkpoint", web::post().to(handle_checkpoint))
+        .route("/replupdates", web::post().to(handle_replupdates))
+        .route("/replsnapshot", web::post().to(handle_replsnapshot))
+        .route("/changes", web::post().to(handle_changes))
+        .route("/changes/sse", web::get().to(handle_changes_sse))
+        .route("/publish/{key}", web::post().to(handle_publish))
+        .route("/subscribe", web::post().to(handle_subscribe))
+        .route("/psubscribe", web::post().to(handle_psubscribe))
+        .route("/ws", web::get().to(handle_ws))
+}
+
+// Routes of the commands on the data of a namespace, which are served at the default
+// namespace and at `/ns/{namespace}`
+fn data_routes(scope: Scope) -> Scope {
+    scope
         .route("/lpush/{key}", web::post().to(handle_lpush))
         .route("/rpush/{key}", web::post().to(handle_rpush))
         .route("/lpop/{key}", web::post().to(handle_lpop))
@@ -86,9 +123,11 @@ pub fn make_route() -> Scope {
         .route("/llen/{key}", web::post().to(handle_llen))
         .route("/ldel/{key}", web::post().to(handle_ldel))
         .route("/lrm/{key}", web::post().to(handle_lrm))
+        .route("/lwatch/{key}", web::post().to(handle_lwatch))
         .route("/hget/{key}", web::post().to(handle_hget))
         .route("/hset/{key}", web::post().to(handle_hset))
         .route("/hsetnx/{key}", web::post().to(handle_hsetnx))
+        .route("/hcas/{key}", web::post().to(handle_hcas))
         .route("/hgetall/{key}", web::post().to(handle_hgetall))
         .route("/hmget/{key}", web::post().to(handle_hmget))
         .route("/hmset/{key}", web::post().to(handle_hmset))
@@ -99,11 +138,13 @@ pub fn make_route() -> Scope {
         .route("/hdel/{key}", web::post().to(handle_hdel))
         .route("/hlen/{key}", web::post().to(handle_hlen))
         .route("/hrm/{key}", web::post().to(handle_hrm))
+        .route("/hwatch/{key}", web::post().to(handle_hwatch))
         .route("/alpush/{key}", web::post().to(handle_alpush))
         .route("/alpushnx/{key}", web::post().to(handle_alpushnx))
         .route("/arpush/{key}", web::post().to(handle_arpush))
         .route("/arpushnx/{key}", web::post().to(handle_arpushnx))
         .route("/aincrby/{key}", web::post().to(handle_aincrby))
+        .route("/acas/{key}", web::post().to(handle_acas))
         .route("/alpop/{key}", web::post().to(handle_alpop))
         .route("/arpop/{key}", web::post().to(handle_arpop))
         .route("/arandpop/{key}", web::post().to(handle_arandpop))
@@ -118,4 +159,19 @@ pub fn make_route() -> Scope {
         .route("/alen/{key}", web::post().to(handle_alen))
         .route("/adel/{key}", web::post().to(handle_adel))
         .route("/arm/{key}", web::post().to(handle_arm))
+        .route("/awatch/{key}", web::post().to(handle_awatch))
+        .route("/scan", web::post().to(handle_scan))
+        .route("/keys", web::post().to(handle_keys))
+        .route("/type", web::post().to(handle_type))
+        .route("/exists", web::post().to(handle_exists))
+        .route("/del", web::post().to(handle_del))
+        .route("/dbsize", web::post().to(handle_dbsize))
+        .route("/rename", web::post().to(handle_rename))
+        .route("/renamenx", web::post().to(handle_renamenx))
+        .route("/copy", web::post().to(handle_copy))
+        .route("/flushdb", web::post().to(handle_flushdb))
+        .route("/multi", web::post().to(handle_multi))
+        .route("/pipeline", web::post().to(handle_pipeline))
+        .route("/eval", web::post().to(handle_eval))
+        .route("/evalsha", web::post().to(handle_evalsha))
 }
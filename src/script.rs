@@ -0,0 +1,442 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope, AST, INT};
+
+use lodisdb::{
+    common::{DBValue, DataType, Direction},
+    siphash, u32_to_u8x4, ArrayMap, Batch, List, LodisData, Map, DB,
+};
+
+use crate::error::{LodisError, Result};
+
+// The maximum number of operations a script can run, so that a script can not hold
+// the locks of its keys forever
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+// The maximum sizes of the strings, arrays and maps of a script, so that a script which holds
+// the locks of its keys can not use unbounded memory
+const MAX_STRING_SIZE: usize = 16 * 1024 * 1024;
+const MAX_ARRAY_SIZE: usize = 100_000;
+const MAX_MAP_SIZE: usize = 100_000;
+
+// The maximum number of cached scripts, the earliest loaded script is evicted first
+const MAX_SCRIPTS: usize = 10_000;
+
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+// Compiled scripts cached by their hashes
+pub struct Scripts {
+    engine: Engine,
+    cache: Mutex<ScriptCache>,
+}
+
+#[derive(Default)]
+struct ScriptCache {
+    scripts: HashMap<String, AST>,
+    // Hashes in the order of loading
+    hashes: VecDeque<String>,
+}
+
+impl Scripts {
+    pub fn new() -> Scripts {
+        Scripts {
+            engine: new_engine(),
+            cache: Mutex::new(ScriptCache::default()),
+        }
+    }
+
+    // Compile the script and cache it, return the hash of the script and its AST
+    pub fn load(&self, script: &[u8]) -> Result<(String, AST)> {
+        let hash = format!("{:016x}", siphash(&script));
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(ast) = cache.scripts.get(&hash) {
+            return Ok((hash, ast.clone()));
+        }
+
+        let source = std::str::from_utf8(script)
+            .map_err(|_| LodisError::ScriptError("script is not utf8".to_owned()))?;
+        let ast = self
+            .engine
+            .compile(source)
+            .map_err(|err| LodisError::ScriptError(err.to_string()))?;
+        if cache.hashes.len() >= MAX_SCRIPTS {
+            if let Some(evicted) = cache.hashes.pop_front() {
+                cache.scripts.remove(&evicted);
+            }
+        }
+        cache.hashes.push_back(hash.clone());
+        cache.scripts.insert(hash.clone(), ast.clone());
+        Ok((hash, ast))
+    }
+
+    pub fn get(&self, hash: &str) -> Option<AST> {
+        self.cache.lock().unwrap().scripts.get(hash).cloned()
+    }
+}
+
+// Parse the name of a data type declared by a script
+pub fn parse_data_type(name: &[u8]) -> Result<DataType> {
+    match name.to_ascii_lowercase().as_slice() {
+        b"list" => Ok(DataType::List),
+        b"hashmap" => Ok(DataType::Map),
+        b"arraymap" => Ok(DataType::ArrayMap),
+        _ => Err(LodisError::ParamTypeError(format!(
+            "data type: {}",
+            String::from_utf8_lossy(name)
+        ))),
+    }
+}
+
+// The name of a data type, which is parsed by `parse_data_type`
+pub fn data_type_name(data_type: DataType) -> &'static str {
+    match data_type {
+        DataType::List => "list",
+        DataType::Map => "hashmap",
+        DataType::ArrayMap => "arraymap",
+        DataType::Set => "set",
+        DataType::String => "string",
+    }
+}
+
+// The state shared by all functions called by a script
+struct ScriptContext {
+    db: Arc<DB>,
+    namespace: String,
+    batch: Batch<'static>,
+    // DataType flag + key of declared keys
+    declared: HashSet<Vec<u8>>,
+    // Keys which are written by the script
+    written: Vec<(String, DataType)>,
+}
+
+impl ScriptContext {
+    fn check(&self, key: &str, data_type: DataType) -> ScriptResult<()> {
+        if self
+            .declared
+            .contains(&[&data_type.flag()[..], key.as_bytes()].concat())
+        {
+            Ok(())
+        } else {
+            Err(format!("key `{}` is not declared", key).into())
+        }
+    }
+
+    fn write(&mut self, key: &str, data_type: DataType) {
+        if !self
+            .written
+            .iter()
+            .any(|(k, t)| k == key && t.flag() == data_type.flag())
+        {
+            self.written.push((key.to_owned(), data_type));
+        }
+    }
+}
+
+// Run a script on a batch, the keys are in the namespace
+//
+// All keys which are used by the script must be declared at `keys`.
+// The caller must hold the locks of the keys.
+//
+// Return the encoded result of the script, the batch of its writes, and the keys which
+// are written by the script.
+pub fn run(
+    db: Arc<DB>,
+    namespace: &str,
+    ast: &AST,
+    keys: &[(DataType, String)],
+    args: &[&[u8]],
+) -> Result<(Vec<u8>, Batch<'static>, Vec<(String, DataType)>)> {
+    let context = Arc::new(Mutex::new(ScriptContext {
+        db: db.clone(),
+        namespace: namespace.to_string(),
+        batch: Batch::new(db.clone()),
+        declared: keys
+            .iter()
+            .map(|(data_type, key)| [&data_type.flag()[..], key.as_bytes()].concat())
+            .collect(),
+        written: Vec::new(),
+    }));
+
+    let engine = make_engine(&context);
+    let mut scope = Scope::new();
+    scope.push_constant(
+        "keys",
+        keys.iter()
+            .map(|(_, key)| Dynamic::from(key.clone()))
+            .collect::<Array>(),
+    );
+    scope.push_constant(
+        "args",
+        args.iter().map(|arg| from_bytes(arg)).collect::<Array>(),
+    );
+
+    let value = engine
+        .eval_ast_with_scope::<Dynamic>(&mut scope, ast)
+        .map_err(|err| LodisError::ScriptError(err.to_string()))?;
+    let content = encode(value).map_err(|err| LodisError::ScriptError(err.to_string()))?;
+    drop(engine);
+
+    let mut context = context.lock().unwrap();
+    let batch = std::mem::replace(&mut context.batch, Batch::new(db));
+    let written = std::mem::take(&mut context.written);
+    Ok((content, batch, written))
+}
+
+// Lodis values are binary-safe, so a value is a string if it is utf8, else a blob
+fn from_bytes(value: &[u8]) -> Dynamic {
+    match std::str::from_utf8(value) {
+        Ok(value) => Dynamic::from(value.to_owned()),
+        Err(_) => Dynamic::from_blob(value.to_vec()),
+    }
+}
+
+fn from_option(value: Option<DBValue>) -> Dynamic {
+    value.map(|v| from_bytes(&v)).unwrap_or(Dynamic::UNIT)
+}
+
+fn from_pair(pair: Option<(DBValue, DBValue)>) -> Dynamic {
+    pair.map(|(k, v)| Dynamic::from_array(vec![from_bytes(&k), from_bytes(&v)]))
+        .unwrap_or(Dynamic::UNIT)
+}
+
+fn from_values(values: Vec<DBValue>) -> Array {
+    values.iter().map(|v| from_bytes(v)).collect()
+}
+
+fn from_pairs(pairs: Vec<(DBValue, DBValue)>) -> Array {
+    pairs
+        .into_iter()
+        .map(|pair| from_pair(Some(pair)))
+        .collect()
+}
+
+fn to_bytes(value: Dynamic) -> ScriptResult<Vec<u8>> {
+    if value.is_string() {
+        Ok(value.into_string()?.into_bytes())
+    } else if value.is_blob() {
+        Ok(value.into_blob()?)
+    } else if value.is_int() || value.is_float() || value.is_bool() || value.is_char() {
+        Ok(value.to_string().into_bytes())
+    } else {
+        Err(format!("`{}` can not be a lodis value", value.type_name()).into())
+    }
+}
+
+fn to_u32(value: INT) -> u32 {
+    value.clamp(0, u32::MAX as INT) as u32
+}
+
+// Encode the result of a script
+//
+// An array is encoded as List, nothing as an empty content, others as Bytes
+fn encode(value: Dynamic) -> ScriptResult<Vec<u8>> {
+    if value.is_unit() {
+        Ok(Vec::new())
+    } else if value.is_array() {
+        let mut buf = Vec::new();
+        for item in value.into_array()? {
+            let item = to_bytes(item)?;
+            buf.extend_from_slice(&u32_to_u8x4(item.len() as u32)[..]);
+            buf.extend_from_slice(&item);
+        }
+        Ok(buf)
+    } else {
+        to_bytes(value)
+    }
+}
+
+// An argument of a function called by a script
+trait ScriptArg {
+    type Output;
+
+    fn convert(self) -> ScriptResult<Self::Output>;
+}
+
+impl ScriptArg for Dynamic {
+    type Output = Vec<u8>;
+
+    fn convert(self) -> ScriptResult<Vec<u8>> {
+        to_bytes(self)
+    }
+}
+
+impl ScriptArg for INT {
+    type Output = INT;
+
+    fn convert(self) -> ScriptResult<INT> {
+        Ok(self)
+    }
+}
+
+// Call `f` with the data of the key and the batch of the script
+fn with_data<D, T, F>(
+    context: &Mutex<ScriptContext>,
+    key: &str,
+    data_type: DataType,
+    make: fn(&str, String, Arc<DB>) -> D,
+    f: F,
+) -> ScriptResult<T>
+where
+    F: FnOnce(&D, &mut Batch) -> lodisdb::error::Result<T>,
+{
+    let mut context = context.lock().unwrap();
+    context.check(key, data_type)?;
+    let data = make(&context.namespace, key.to_owned(), context.db.clone());
+    let writes = context.batch.writes();
+    let value = f(&data, &mut context.batch).map_err(|err| err.to_string())?;
+    if context.batch.writes() > writes {
+        context.write(key, data_type);
+    }
+    Ok(value)
+}
+
+macro_rules! register {
+    (
+        $engine:expr, $context:expr, $data:ty, $data_type:expr,
+        $(
+            $fname:expr, |$data_var:ident, $batch:ident $(, $arg:ident: $ty:ty)*| $body:expr;
+        )+
+    ) => {
+        $(
+            let context = $context.clone();
+            $engine.register_fn($fname, move |key: &str $(, $arg: $ty)*| {
+                $(let $arg = ScriptArg::convert($arg)?;)*
+                with_data(
+                    &context,
+                    key,
+                    $data_type,
+                    <$data>::new_in,
+                    |$data_var: &$data, $batch: &mut Batch| $body,
+                )
+            });
+        )+
+    };
+}
+
+// The engine which compiles and runs scripts, without the functions of the data
+fn new_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_ARRAY_SIZE)
+        .set_max_map_size(MAX_MAP_SIZE);
+    engine
+}
+
+fn make_engine(context: &Arc<Mutex<ScriptContext>>) -> Engine {
+    let mut engine = new_engine();
+
+    // List
+    register! {
+        engine, context, List, DataType::List,
+        "lpush", |list, batch, value: Dynamic| {
+            list.push_left_with_batch(&[value], batch).map(|_| ())
+        };
+        "rpush", |list, batch, value: Dynamic| {
+            list.push_with_batch(&[value], batch).map(|_| ())
+        };
+        "lpop", |list, batch| list.pop_left_with_batch(batch).map(from_option);
+        "rpop", |list, batch| list.pop_with_batch(batch).map(from_option);
+        "randpop", |list, batch| list.pop_random_with_batch(batch).map(from_option);
+        "lindex", |list, batch, index: INT| {
+            list.index_with_batch(index, batch).map(from_option)
+        };
+        "lrange", |list, batch, start: INT, end: INT| {
+            list.range_with_batch(to_u32(start), to_u32(end), Direction::Forward, batch)
+                .map(from_values)
+        };
+        "rrange", |list, batch, start: INT, end: INT| {
+            list.range_with_batch(to_u32(start), to_u32(end), Direction::Reverse, batch)
+                .map(from_values)
+        };
+        "lrand", |list, batch| list.random_with_batch(batch).map(from_option);
+        "llen", |list, batch| list.length_with_batch(batch).map(|n| n as INT);
+        "ldel", |list, batch, index: INT| list.delete_with_batch(index, batch);
+        "lrm", |list, batch| list.remove_with_batch(batch);
+    }
+
+    // Map
+    register! {
+        engine, context, Map, DataType::Map,
+        "hget", |map, batch, field: Dynamic| {
+            map.get_with_batch(field, batch).map(from_option)
+        };
+        "hset", |map, batch, field: Dynamic, value: Dynamic| {
+            map.set_with_batch(field, value, batch)
+        };
+        "hsetnx", |map, batch, field: Dynamic, value: Dynamic| {
+            map.setnx_with_batch(field, value, batch)
+        };
+        "hincrby", |map, batch, field: Dynamic, incr: INT| {
+            map.increase_with_batch(field, incr, batch)
+        };
+        "hkeys", |map, batch| map.keys_with_batch(batch).map(from_values);
+        "hvals", |map, batch| map.values_with_batch(batch).map(from_values);
+        "hgetall", |map, batch| map.all_with_batch(batch).map(from_pairs);
+        "hexists", |map, batch, field: Dynamic| {
+            map.exists_with_batch(field, batch)
+        };
+        "hdel", |map, batch, field: Dynamic| {
+            map.delete_with_batch(field, batch)
+        };
+        "hlen", |map, batch| map.length_with_batch(batch).map(|n| n as INT);
+        "hrm", |map, batch| map.remove_with_batch(batch);
+    }
+
+    // ArrayMap
+    register! {
+        engine, context, ArrayMap, DataType::ArrayMap,
+        "alpush", |arraymap, batch, field: Dynamic, value: Dynamic| {
+            arraymap.push_left_with_batch(&[(field, value)], batch)
+        };
+        "alpushnx", |arraymap, batch, field: Dynamic, value: Dynamic| {
+            arraymap.pushnx_left_with_batch(&[(field, value)], batch)
+        };
+        "arpush", |arraymap, batch, field: Dynamic, value: Dynamic| {
+            arraymap.push_with_batch(&[(field, value)], batch)
+        };
+        "arpushnx", |arraymap, batch, field: Dynamic, value: Dynamic| {
+            arraymap.pushnx_with_batch(&[(field, value)], batch)
+        };
+        "aincrby", |arraymap, batch, field: Dynamic, incr: INT| {
+            arraymap.increase_with_batch(field, incr, batch)
+        };
+        "alpop", |arraymap, batch| arraymap.pop_left_with_batch(batch).map(from_pair);
+        "arpop", |arraymap, batch| arraymap.pop_with_batch(batch).map(from_pair);
+        "arandpop", |arraymap, batch| {
+            arraymap.pop_random_with_batch(batch).map(from_pair)
+        };
+        "aget", |arraymap, batch, field: Dynamic| {
+            arraymap.get_with_batch(field, batch).map(from_option)
+        };
+        "arand", |arraymap, batch| arraymap.random_with_batch(batch).map(from_pair);
+        "alrange", |arraymap, batch, start: INT, end: INT| {
+            arraymap
+                .range_with_batch(to_u32(start), to_u32(end), Direction::Forward, batch)
+                .map(from_pairs)
+        };
+        "arrange", |arraymap, batch, start: INT, end: INT| {
+            arraymap
+                .range_with_batch(to_u32(start), to_u32(end), Direction::Reverse, batch)
+                .map(from_pairs)
+        };
+        "akeys", |arraymap, batch| arraymap.keys_with_batch(batch).map(from_values);
+        "avals", |arraymap, batch| arraymap.values_with_batch(batch).map(from_values);
+        "aall", |arraymap, batch| arraymap.all_with_batch(batch).map(from_pairs);
+        "aexists", |arraymap, batch, field: Dynamic| {
+            arraymap.exists_with_batch(field, batch)
+        };
+        "alen", |arraymap, batch| arraymap.length_with_batch(batch).map(|n| n as INT);
+        "adel", |arraymap, batch, field: Dynamic| {
+            arraymap.delete_with_batch(field, batch)
+        };
+        "arm", |arraymap, batch| arraymap.remove_with_batch(batch);
+    }
+
+    engine
+}
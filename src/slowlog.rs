@@ -0,0 +1,156 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{dev::ServiceResponse, web};
+
+use lodisdb::u8x4_to_u32;
+
+use crate::{common::Namespace, stats::command_name};
+
+// Number of params of a command which are kept in an entry
+const MAX_ARGS: usize = 32;
+// Number of bytes of a param which are kept in an entry
+const MAX_ARG_LEN: usize = 128;
+
+// The body of a request, which is put to the extensions of the request by the handler of a
+// command, so that the slow log can summarize its params
+pub struct RequestBody(pub web::Bytes);
+
+// A request which is slower than the threshold
+#[derive(Clone)]
+pub struct SlowEntry {
+    // Increasing id of the entry, which is not reset by `SlowLog::reset`
+    pub id: u64,
+    // Unix timestamp in milliseconds when the request finished
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub command: String,
+    pub namespace: String,
+    pub key: String,
+    // Summary of the params, see `summarize`
+    pub args: Vec<Vec<u8>>,
+}
+
+// The latest requests which are slower than the threshold
+pub struct SlowLog {
+    threshold: Duration,
+    // Number of the latest entries to keep, 0 disables the slow log
+    max_len: usize,
+    entries: Mutex<(u64, VecDeque<SlowEntry>)>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, max_len: usize) -> SlowLog {
+        SlowLog {
+            threshold,
+            max_len,
+            entries: Mutex::new((0, VecDeque::with_capacity(max_len))),
+        }
+    }
+
+    // Record the request of the response if it is handled in `duration` which is not less than
+    // the threshold
+    //
+    // Requests which match no route are not recorded. The duration of a streaming response is the
+    // time until the stream starts.
+    pub fn record(&self, response: &ServiceResponse, duration: Duration) {
+        if self.max_len == 0 || duration < self.threshold {
+            return;
+        }
+        let request = response.request();
+        let command = match request.match_pattern() {
+            Some(pattern) => command_name(&pattern),
+            None => return,
+        };
+        let namespace = Namespace::from_request_head(request)
+            .map(|namespace| namespace.0)
+            .unwrap_or_default();
+        let key = request
+            .match_info()
+            .get("key")
+            .unwrap_or_default()
+            .to_string();
+        let args = request
+            .extensions()
+            .get::<RequestBody>()
+            .map(|body| summarize(&body.0))
+            .unwrap_or_default();
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let mut entries = self.lock();
+        let (next_id, entries) = &mut *entries;
+        if entries.len() == self.max_len {
+            entries.pop_back();
+        }
+        entries.push_front(SlowEntry {
+            id: *next_id,
+            timestamp,
+            duration,
+            command,
+            namespace,
+            key,
+            args,
+        });
+        *next_id += 1;
+    }
+
+    // The latest `count` entries, from the newest one
+    pub fn get(&self, count: usize) -> Vec<SlowEntry> {
+        self.lock().1.iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().1.len()
+    }
+
+    pub fn reset(&self) {
+        self.lock().1.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, (u64, VecDeque<SlowEntry>)> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Summarize the params of the `CONTENT` of a request
+//
+// At most `MAX_ARGS` params are kept, and the last one is replaced by `... (n more params)` if
+// there are more. A param is cut to `MAX_ARG_LEN` bytes and followed by `... (n more bytes)`.
+fn summarize(body: &[u8]) -> Vec<Vec<u8>> {
+    let mut params = Vec::new();
+    let mut index = 0;
+    while index + 4 <= body.len() {
+        let mut len: [u8; 4] = [0; 4];
+        len.clone_from_slice(&body[index..index + 4]);
+        let len = u8x4_to_u32(&len) as usize;
+        index += 4;
+        params.push(&body[index..(index + len).min(body.len())]);
+        index += len;
+    }
+
+    let mut args = Vec::new();
+    for (i, param) in params.iter().enumerate() {
+        if i == MAX_ARGS - 1 && params.len() > MAX_ARGS {
+            args.push(format!("... ({} more params)", params.len() - i).into_bytes());
+            break;
+        }
+        if param.len() > MAX_ARG_LEN {
+            let mut arg = param[..MAX_ARG_LEN].to_vec();
+            arg.extend_from_slice(
+                format!("... ({} more bytes)", param.len() - MAX_ARG_LEN).as_bytes(),
+            );
+            args.push(arg);
+        } else {
+            args.push(param.to_vec());
+        }
+    }
+    args
+}
@@ -1,37 +1,105 @@
 use std::{
-    path::Path,
-    sync::{Arc, Mutex},
-    time::SystemTime,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use lodisdb::{common::DataType, make_db, u64_to_u8x8, Map, DB};
+use lodisdb::{
+    common::DataType, create_checkpoint, make_db_with_wal_ttl, u64_to_u8x8, Batch, Map,
+    ReplicaSnapshot, DB,
+};
 
 use crate::{
-    common::{LODIS_KEY_MAP, LODIS_STRING_MAP, PRIME},
-    error::Result,
+    acl::Acl,
+    cdc::{Cdc, Change},
+    common::{LODIS_KEY_MAP, LODIS_NAMESPACE_MAP, LODIS_STRING_MAP, PRIME},
+    error::{LodisError, Result},
+    pubsub::PubSub,
+    script::Scripts,
+    slowlog::SlowLog,
+    stats::Stats,
+    utils::LodisConfig,
 };
 
 pub struct GlobalState {
     pub db: Arc<DB>,
-    // Store all keys and their data type
-    pub key_map: Map,
+    // Store the namespaces other than the default one, each of them has its own key map
+    pub namespace_map: Map,
     // Global map for all string data type
     pub string_map: Map,
 
     // th-(PRIME + 1) lock is for LODIS_KEY_MAP map
     // th-(PRIME + 2) lock is for LODIS_STRING_MAP map
     pub locks: [Mutex<()>; 10 + PRIME as usize],
+
+    // The time when the server started, so watch tokens from a previous run are not valid
+    pub epoch: u64,
+    // th-i version is increased when data guarded by th-i lock is changed
+    pub versions: Vec<AtomicU64>,
+
+    // Scripts loaded by EVAL or SCRIPTLOAD
+    pub scripts: Scripts,
+
+    // Directory of backups and the number of backups to keep
+    pub backup_path: Option<String>,
+    pub backup_keep: usize,
+    // Only one backup is taken at a time
+    pub backup_lock: Mutex<()>,
+
+    // Directory of checkpoints and the number of checkpoints to keep
+    pub checkpoint_path: Option<String>,
+    pub checkpoint_keep: usize,
+    // Only one checkpoint is created at a time
+    pub checkpoint_lock: Mutex<()>,
+
+    pub db_path: String,
+    // Replicas can tail the write-ahead log only if it is kept
+    pub wal_ttl: u64,
+    // Number of snapshots created for replicas, to name their directories
+    pub replica_snapshots: AtomicU64,
+    // A replica only accepts writes from its primary
+    pub read_only: bool,
+    // Commands do not check the data types of their keys
+    pub mixed_types: bool,
+
+    // Change data capture, None if it is not enabled
+    pub cdc: Option<Cdc>,
+
+    // Subscribers of channels
+    pub pubsub: PubSub,
+
+    pub workers: usize,
+    // Most bytes of the body of PIPELINE
+    pub pipeline_max_size: usize,
+    pub stats: Stats,
+    pub slowlog: SlowLog,
+
+    // Users and their permissions, None if requests are not authenticated
+    pub acl: Option<Acl>,
 }
 
 unsafe impl Sync for GlobalState {}
 unsafe impl Send for GlobalState {}
 
 impl GlobalState {
-    pub fn new<P: AsRef<Path>>(path: P) -> GlobalState {
-        let db = Arc::new(make_db(path));
-        GlobalState {
+    pub fn new(config: &LodisConfig) -> Result<GlobalState> {
+        let db = Arc::new(make_db_with_wal_ttl(&config.db_path, config.wal_ttl));
+        let epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let cdc = if config.cdc_keep > 0 {
+            let cdc = Cdc::new(&db, config.cdc_keep)
+                .map_err(|err| LodisError::Error(format!("Can't read the change log: {}", err)))?;
+            Some(cdc)
+        } else {
+            None
+        };
+        Ok(GlobalState {
             db: db.clone(),
-            key_map: Map::new(LODIS_KEY_MAP.to_string(), db.clone()),
+            namespace_map: Map::new(LODIS_NAMESPACE_MAP.to_string(), db.clone()),
             string_map: Map::new(LODIS_STRING_MAP.to_string(), db.clone()),
             locks: unsafe {
                 let mut arr: [Mutex<()>; 10 + PRIME as usize] =
@@ -41,27 +109,197 @@ impl GlobalState {
                 }
                 arr
             },
+            epoch,
+            versions: (0..PRIME).map(|_| AtomicU64::new(0)).collect(),
+            scripts: Scripts::new(),
+            backup_path: config.backup_path.clone(),
+            backup_keep: config.backup_keep,
+            backup_lock: Mutex::new(()),
+            checkpoint_path: config.checkpoint_path.clone(),
+            checkpoint_keep: config.checkpoint_keep,
+            checkpoint_lock: Mutex::new(()),
+            db_path: config.db_path.clone(),
+            wal_ttl: config.wal_ttl,
+            replica_snapshots: AtomicU64::new(0),
+            read_only: config.replica_of.is_some(),
+            mixed_types: config.mixed_types,
+            cdc,
+            pubsub: PubSub::new(),
+            workers: config.workers,
+            pipeline_max_size: config.pipeline_max_size,
+            stats: Stats::new(),
+            slowlog: SlowLog::new(
+                Duration::from_micros(config.slowlog_threshold),
+                config.slowlog_max_len,
+            ),
+            acl: config.acl.clone(),
+        })
+    }
+
+    // Create a checkpoint of the db and remove old checkpoints, return the checkpoint name
+    pub fn checkpoint(&self) -> Result<String> {
+        let checkpoint_path = self
+            .checkpoint_path
+            .as_ref()
+            .ok_or(LodisError::CheckpointNotConfigured)?;
+        let lock = self.checkpoint_lock.lock();
+        let name = create_checkpoint(&self.db, checkpoint_path, self.checkpoint_keep)?;
+        drop(lock);
+        Ok(name)
+    }
+
+    // Commit the writes of a request, with their changes if change data capture is enabled
+    pub fn commit(&self, batch: Batch, changes: &[Change]) -> Result<()> {
+        match &self.cdc {
+            Some(cdc) if !changes.is_empty() => cdc.commit(batch, changes),
+            _ => Ok(batch.commit()?),
+        }
+    }
+
+    // Create a snapshot of the db for a replica to resync from
+    //
+    // The snapshot is a checkpoint next to the db directory, which is removed after it is sent.
+    pub fn replica_snapshot(&self) -> Result<ReplicaSnapshot> {
+        if self.wal_ttl == 0 {
+            return Err(LodisError::ReplicationNotConfigured);
         }
+        let n = self.replica_snapshots.fetch_add(1, Ordering::SeqCst);
+        let path = format!("{}.replsnapshot-{}-{}", self.db_path, self.epoch, n);
+        Ok(ReplicaSnapshot::create(&self.db, path)?)
+    }
+
+    // Store all keys of the namespace and their data type
+    pub fn key_map(&self, namespace: &str) -> Map {
+        Map::new_in(namespace, LODIS_KEY_MAP.to_string(), self.db.clone())
+    }
+
+    // All namespaces which have keys, the default namespace "" is always the first
+    pub fn namespaces(&self) -> Result<Vec<String>> {
+        let mut namespaces = vec![String::new()];
+        for namespace in self.namespace_map.keys()? {
+            namespaces.push(String::from_utf8_lossy(&namespace).into_owned());
+        }
+        Ok(namespaces)
+    }
+
+    // Lock the key maps and the namespace map, whose lengths are changed by every new or removed
+    // key
+    pub fn lock_key_map(&self) -> MutexGuard<'_, ()> {
+        self.locks[PRIME as usize]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Whether the key is recorded at the key map
+    pub fn has_key_with_batch(
+        &self,
+        namespace: &str,
+        key: &str,
+        data_type: DataType,
+        batch: &Batch,
+    ) -> Result<bool> {
+        Ok(self
+            .key_map(namespace)
+            .exists_with_batch(key_record(key, data_type), batch)?)
     }
 
     // Record all lodisdb data keys
     //
     // Structure
     // Key: DataType + key -> timestamp
-    pub fn add_key<K>(&self, key: K, data_type: DataType) -> Result<()>
-    where
-        K: AsRef<[u8]>,
-    {
+    //
+    // The caller must hold `lock_key_map` until the batch is committed.
+    pub fn add_key_with_batch(
+        &self,
+        namespace: &str,
+        key: &str,
+        data_type: DataType,
+        batch: &mut Batch,
+    ) -> Result<()> {
         let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        let mutex = &self.locks[PRIME as usize];
-        let lock = mutex.lock();
-        self.key_map.setnx(
-            [&data_type.flag()[..], key.as_ref()].concat(),
+        self.key_map(namespace).setnx_with_batch(
+            key_record(key, data_type),
             &u64_to_u8x8(now)[..],
+            batch,
         )?;
+        if !namespace.is_empty() {
+            self.namespace_map
+                .setnx_with_batch(namespace, &u64_to_u8x8(now)[..], batch)?;
+        }
+        Ok(())
+    }
+
+    // Remove the record of a key whose data is removed
+    //
+    // The caller must hold `lock_key_map` until the batch is committed.
+    pub fn remove_key_with_batch(
+        &self,
+        namespace: &str,
+        key: &str,
+        data_type: DataType,
+        batch: &mut Batch,
+    ) -> Result<()> {
+        self.key_map(namespace)
+            .delete_with_batch(key_record(key, data_type), batch)?;
+        Ok(())
+    }
+
+    // Remove the record of a namespace whose keys are all removed
+    pub fn remove_namespace(&self, namespace: &str) -> Result<()> {
+        if self.read_only || namespace.is_empty() {
+            return Ok(());
+        }
+        let lock = self.lock_key_map();
+        if self.key_map(namespace).length()? == 0 {
+            self.namespace_map.delete(namespace)?;
+        }
         Ok(())
     }
+
+    // Lock all locks at the indexes in ascending order, so that two requests locking the
+    // same locks can not deadlock each other
+    pub fn lock_all(&self, indexes: &[usize]) -> Vec<MutexGuard<'_, ()>> {
+        let mut indexes = indexes.to_vec();
+        indexes.sort_unstable();
+        indexes.dedup();
+        let start = Instant::now();
+        let locks = indexes
+            .into_iter()
+            .map(|index| {
+                self.locks[index]
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+            })
+            .collect();
+        self.stats.record_lock_wait(start.elapsed());
+        locks
+    }
+
+    // Mark the data guarded by the locks at the indexes as changed
+    //
+    // The caller must hold the locks.
+    pub fn increase_versions(&self, indexes: &[usize]) {
+        for index in indexes {
+            self.versions[*index].fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // The watch token of the data guarded by th-index lock
+    //
+    // Structure
+    // epoch (8 bytes) + version (8 bytes)
+    pub fn watch_token(&self, index: usize) -> [u8; 16] {
+        let mut token = [0; 16];
+        token[..8].clone_from_slice(&u64_to_u8x8(self.epoch));
+        token[8..].clone_from_slice(&u64_to_u8x8(self.versions[index].load(Ordering::SeqCst)));
+        token
+    }
+}
+
+// The field of a key at the key map
+fn key_record(key: &str, data_type: DataType) -> Vec<u8> {
+    [&data_type.flag()[..], key.as_bytes()].concat()
 }
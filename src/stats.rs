@@ -0,0 +1,276 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::dev::ServiceResponse;
+
+use crate::{common::Command, error::LodisError};
+
+// Upper bounds of the buckets of histograms, in seconds
+const BUCKETS: [f64; 14] = [
+    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05,
+    0.1, 1.0,
+];
+
+// Statistics of the server since it started
+pub struct Stats {
+    started: Instant,
+    // Number of open connections
+    clients: Arc<AtomicU64>,
+    // Number of requests and their latency of each command
+    commands: Mutex<BTreeMap<String, Histogram>>,
+    // Number of executions of each command on a key, including the commands of MULTI, PIPELINE
+    // and the Redis protocol
+    executed: Mutex<BTreeMap<String, u64>>,
+    // Number of errors of each `LodisError` variant
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    // Time which requests wait for the locks of their data
+    lock_wait: Mutex<Histogram>,
+}
+
+// An open connection, the number of clients is decreased when it is dropped with its connection
+pub struct Client {
+    clients: Arc<AtomicU64>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Cumulative histogram of durations, in the Prometheus way
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    // Write the samples of the histogram, `labels` are the labels of the samples without braces
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, bucket
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            clients: Arc::new(AtomicU64::new(0)),
+            commands: Mutex::new(BTreeMap::new()),
+            executed: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+            lock_wait: Mutex::new(Histogram::default()),
+        }
+    }
+
+    pub fn uptime(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    // Count a new connection until the returned client is dropped
+    pub fn connect(&self) -> Client {
+        self.clients.fetch_add(1, Ordering::SeqCst);
+        Client {
+            clients: self.clients.clone(),
+        }
+    }
+
+    pub fn clients(&self) -> u64 {
+        self.clients.load(Ordering::SeqCst)
+    }
+
+    // Count the request of the response, which is handled in `latency`, and its error
+    //
+    // Requests which match no route are not counted. The latency of a streaming response is the
+    // time until the stream starts.
+    pub fn record(&self, response: &ServiceResponse, latency: Duration) {
+        let command = match response.request().match_pattern() {
+            Some(pattern) => command_name(&pattern),
+            None => return,
+        };
+        lock(&self.commands)
+            .entry(command)
+            .or_default()
+            .observe(latency);
+
+        let error = response
+            .response()
+            .error()
+            .and_then(|err| err.as_error::<LodisError>());
+        if let Some(error) = error {
+            *lock(&self.errors).entry(error.name()).or_insert(0) += 1;
+        }
+    }
+
+    // Count the executions of commands on keys, whether they succeed or not
+    pub fn record_commands<I: Iterator<Item = Command>>(&self, commands: I) {
+        let mut executed = lock(&self.executed);
+        for command in commands {
+            *executed.entry(format!("{:?}", command)).or_insert(0) += 1;
+        }
+    }
+
+    pub fn record_lock_wait(&self, wait: Duration) {
+        lock(&self.lock_wait).observe(wait);
+    }
+
+    // The number of requests of each command, ordered by the command names
+    pub fn commands(&self) -> Vec<(String, u64)> {
+        lock(&self.commands)
+            .iter()
+            .map(|(command, latency)| (command.clone(), latency.count))
+            .collect()
+    }
+
+    // The statistics in the Prometheus text format, with the RocksDB properties as gauges
+    pub fn metrics(&self, db_properties: &[(&str, u64)]) -> String {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "lodis_uptime_seconds",
+            "gauge",
+            "Seconds since the server started.",
+        );
+        let _ = writeln!(out, "lodis_uptime_seconds {}", self.uptime());
+        write_metric(
+            &mut out,
+            "lodis_connected_clients",
+            "gauge",
+            "Number of open connections.",
+        );
+        let _ = writeln!(out, "lodis_connected_clients {}", self.clients());
+
+        let commands = lock(&self.commands);
+        write_metric(
+            &mut out,
+            "lodis_requests_total",
+            "counter",
+            "Number of requests of each command.",
+        );
+        for (command, latency) in commands.iter() {
+            let _ = writeln!(
+                out,
+                "lodis_requests_total{{command=\"{}\"}} {}",
+                command, latency.count
+            );
+        }
+        write_metric(
+            &mut out,
+            "lodis_request_duration_seconds",
+            "histogram",
+            "Latency of the requests of each command.",
+        );
+        for (command, latency) in commands.iter() {
+            latency.write(
+                &mut out,
+                "lodis_request_duration_seconds",
+                &format!("command=\"{}\"", command),
+            );
+        }
+        drop(commands);
+
+        write_metric(
+            &mut out,
+            "lodis_commands_total",
+            "counter",
+            "Number of executions of each command on a key, including MULTI, PIPELINE and RESP.",
+        );
+        for (command, count) in lock(&self.executed).iter() {
+            let _ = writeln!(
+                out,
+                "lodis_commands_total{{command=\"{}\"}} {}",
+                command, count
+            );
+        }
+
+        write_metric(
+            &mut out,
+            "lodis_errors_total",
+            "counter",
+            "Number of failed requests of each error.",
+        );
+        for (error, count) in lock(&self.errors).iter() {
+            let _ = writeln!(out, "lodis_errors_total{{error=\"{}\"}} {}", error, count);
+        }
+
+        write_metric(
+            &mut out,
+            "lodis_lock_wait_seconds",
+            "histogram",
+            "Time which requests wait for the locks of their data.",
+        );
+        lock(&self.lock_wait).write(&mut out, "lodis_lock_wait_seconds", "");
+
+        for (property, value) in db_properties {
+            let name = format!("lodis_rocksdb_{}", property.replace('-', "_"));
+            write_metric(
+                &mut out,
+                &name,
+                "gauge",
+                &format!("RocksDB property rocksdb.{}.", property),
+            );
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// The command of a route pattern, e.g. `LPUSH` of `/ns/{namespace}/lpush/{key}`
+pub fn command_name(pattern: &str) -> String {
+    let mut segments = pattern.split('/').filter(|segment| !segment.is_empty());
+    let mut command = segments.next().unwrap_or_default();
+    if command == "ns" {
+        command = segments.nth(1).unwrap_or_default();
+    }
+    command.to_ascii_uppercase()
+}
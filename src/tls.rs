@@ -0,0 +1,274 @@
+use std::{fs, io::BufReader};
+
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+    RootCertStore, ServerConfig,
+};
+
+use crate::error::{LodisError, Result};
+
+// Paths of the PEM files of TLS
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    // Certificate chain of the server
+    pub cert: String,
+    // Private key of the server, PKCS #8 or RSA
+    pub key: String,
+    // Certificates of the CAs which sign the certificates of clients, clients without a
+    // certificate are rejected if it is given
+    pub client_ca: Option<String>,
+}
+
+impl TlsConfig {
+    // The rustls config of the server
+    pub fn server_config(&self) -> Result<ServerConfig> {
+        let verifier = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                let (added, _) = roots
+                    .add_pem_file(&mut open(path)?)
+                    .map_err(|_| tls_error(path, "invalid certificates"))?;
+                if added == 0 {
+                    return Err(tls_error(path, "no certificates"));
+                }
+                AllowAnyAuthenticatedClient::new(roots)
+            }
+            None => NoClientAuth::new(),
+        };
+
+        let mut config = ServerConfig::new(verifier);
+        config
+            .set_single_cert(load_certs(&self.cert)?, load_private_key(&self.key)?)
+            .map_err(|err| tls_error(&self.key, &err.to_string()))?;
+        Ok(config)
+    }
+}
+
+// Paths of the PEM files with which a replica connects to a primary over TLS
+#[derive(Debug, Clone)]
+pub struct ReplicaTlsConfig {
+    // Certificates of the CAs which sign the certificate of the primary
+    pub ca: String,
+    // Certificate chain and private key of the replica, for a primary which requires
+    // client certificates
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+impl ReplicaTlsConfig {
+    // The rustls config of the client of the replica
+    pub fn client_config(&self) -> Result<ClientConfig> {
+        let mut config = ClientConfig::new();
+        let (added, _) = config
+            .root_store
+            .add_pem_file(&mut open(&self.ca)?)
+            .map_err(|_| tls_error(&self.ca, "invalid certificates"))?;
+        if added == 0 {
+            return Err(tls_error(&self.ca, "no certificates"));
+        }
+        if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+            config
+                .set_single_client_cert(load_certs(cert)?, load_private_key(key)?)
+                .map_err(|err| tls_error(key, &err.to_string()))?;
+        }
+        Ok(config)
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let chain = certs(&mut open(path)?).map_err(|_| tls_error(path, "invalid certificates"))?;
+    if chain.is_empty() {
+        return Err(tls_error(path, "no certificates"));
+    }
+    Ok(chain)
+}
+
+// A PKCS #8 or RSA private key
+fn load_private_key(path: &str) -> Result<PrivateKey> {
+    let mut keys =
+        pkcs8_private_keys(&mut open(path)?).map_err(|_| tls_error(path, "invalid private key"))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?)
+            .map_err(|_| tls_error(path, "invalid private key"))?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| tls_error(path, "no private key"))
+}
+
+fn open(path: &str) -> Result<BufReader<fs::File>> {
+    Ok(BufReader::new(
+        fs::File::open(path).map_err(|err| tls_error(path, &err.to_string()))?,
+    ))
+}
+
+fn tls_error(path: &str, message: &str) -> LodisError {
+    LodisError::Error(format!("TLS file {}: {}", path, message))
+}
+
+#[cfg(test)]
+mod test_tls {
+    use std::{
+        fs,
+        io::{Read, Write},
+        path::PathBuf,
+        sync::Arc,
+    };
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DistinguishedName, IsCa};
+    use rustls::{ClientSession, ServerSession, Session};
+    use webpki::DNSNameRef;
+
+    use super::{ReplicaTlsConfig, TlsConfig};
+
+    // PEM files of a CA, a server of `localhost` and a client signed by the CA
+    struct Certs {
+        dir: PathBuf,
+    }
+
+    impl Certs {
+        fn new(name: &str) -> Certs {
+            let dir = std::env::temp_dir().join(name);
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut params = CertificateParams::new(Vec::new());
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name = DistinguishedName::new();
+            let ca = Certificate::from_params(params).unwrap();
+            fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+            for (name, subject) in [("server", "localhost"), ("client", "client")].iter() {
+                let cert =
+                    Certificate::from_params(CertificateParams::new(vec![subject.to_string()]))
+                        .unwrap();
+                fs::write(
+                    dir.join(format!("{}.pem", name)),
+                    cert.serialize_pem_with_signer(&ca).unwrap(),
+                )
+                .unwrap();
+                fs::write(
+                    dir.join(format!("{}.key", name)),
+                    cert.serialize_private_key_pem(),
+                )
+                .unwrap();
+            }
+            Certs { dir }
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.dir.join(name).to_string_lossy().into_owned()
+        }
+
+        fn server(&self, client_ca: bool) -> TlsConfig {
+            TlsConfig {
+                cert: self.path("server.pem"),
+                key: self.path("server.key"),
+                client_ca: if client_ca {
+                    Some(self.path("ca.pem"))
+                } else {
+                    None
+                },
+            }
+        }
+
+        fn client(&self, client_cert: bool) -> ReplicaTlsConfig {
+            ReplicaTlsConfig {
+                ca: self.path("ca.pem"),
+                cert: if client_cert {
+                    Some(self.path("client.pem"))
+                } else {
+                    None
+                },
+                key: if client_cert {
+                    Some(self.path("client.key"))
+                } else {
+                    None
+                },
+            }
+        }
+    }
+
+    impl Drop for Certs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Exchange the TLS records of a client and a server in memory until the handshake is
+    // finished, then send a message from the client to the server
+    fn handshake(server: &TlsConfig, client: &ReplicaTlsConfig) -> Result<Vec<u8>, String> {
+        let mut server = ServerSession::new(&Arc::new(server.server_config().unwrap()));
+        let mut client = ClientSession::new(
+            &Arc::new(client.client_config().unwrap()),
+            DNSNameRef::try_from_ascii_str("localhost").unwrap(),
+        );
+        client.write_all(b"ping").unwrap();
+
+        let mut buf = Vec::new();
+        for _ in 0..10 {
+            let mut records = Vec::new();
+            client.write_tls(&mut records).unwrap();
+            server.read_tls(&mut &records[..]).unwrap();
+            server
+                .process_new_packets()
+                .map_err(|err| err.to_string())?;
+            server.read_to_end(&mut buf).unwrap();
+            if !buf.is_empty() {
+                return Ok(buf);
+            }
+
+            let mut records = Vec::new();
+            server.write_tls(&mut records).unwrap();
+            client.read_tls(&mut &records[..]).unwrap();
+            client
+                .process_new_packets()
+                .map_err(|err| err.to_string())?;
+        }
+        Err("handshake not finished".to_string())
+    }
+
+    #[test]
+    fn test_tls() {
+        let certs = Certs::new("lodis-test-tls");
+        assert_eq!(
+            handshake(&certs.server(false), &certs.client(false)).unwrap(),
+            b"ping"
+        );
+        assert_eq!(
+            handshake(&certs.server(false), &certs.client(true)).unwrap(),
+            b"ping"
+        );
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let certs = Certs::new("lodis-test-mutual-tls");
+        assert_eq!(
+            handshake(&certs.server(true), &certs.client(true)).unwrap(),
+            b"ping"
+        );
+        // Clients without a certificate are rejected
+        assert!(handshake(&certs.server(true), &certs.client(false)).is_err());
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let certs = Certs::new("lodis-test-tls-files");
+        let mut config = certs.server(false);
+        config.key = certs.path("ca.pem");
+        assert!(config.server_config().is_err());
+
+        let mut config = certs.server(true);
+        config.client_ca = Some(certs.path("server.key"));
+        assert!(config.server_config().is_err());
+
+        let mut config = certs.server(false);
+        config.cert = certs.path("missing.pem");
+        assert!(config.server_config().is_err());
+
+        let mut client = certs.client(false);
+        client.ca = certs.path("client.key");
+        assert!(client.client_config().is_err());
+    }
+}
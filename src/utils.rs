@@ -1,25 +1,384 @@
-use std::env;
+use std::{env, fmt::Write, str::FromStr};
 
+use log::LevelFilter;
 use num_cpus;
 
-use crate::error::Result;
+use crate::{
+    acl::Acl,
+    error::{LodisError, Result},
+    logging::LogFormat,
+    tls::{ReplicaTlsConfig, TlsConfig},
+};
+
+const DEFAULT_BACKUP_KEEP: usize = 7;
+const DEFAULT_CHECKPOINT_KEEP: usize = 7;
+const DEFAULT_SLOWLOG_THRESHOLD: u64 = 10_000;
+const DEFAULT_SLOWLOG_MAX_LEN: usize = 128;
+const DEFAULT_RESP_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_PIPELINE_MAX_SIZE: usize = 16 * 1024 * 1024;
 
 #[derive(Debug)]
 pub struct LodisConfig {
     pub db_path: String,
     pub ip_port: String,
     pub workers: usize,
+    // Directory of backups, BACKUP and LISTBACKUPS are not available without it
+    pub backup_path: Option<String>,
+    // Number of the latest backups to keep, 0 keeps all backups
+    pub backup_keep: usize,
+    // Directory of checkpoints, CHECKPOINT is not available without it
+    pub checkpoint_path: Option<String>,
+    // Number of the latest checkpoints to keep, 0 keeps all checkpoints
+    pub checkpoint_keep: usize,
+    // Seconds between scheduled checkpoints, 0 disables the schedule
+    pub checkpoint_interval: u64,
+    // Seconds to keep obsolete write-ahead log files for replicas, 0 disables replication
+    pub wal_ttl: u64,
+    // `ip:port` of the primary, the server is a read-only replica of it if given
+    pub replica_of: Option<String>,
+    // Token of a user of the primary, for a primary with an ACL file
+    pub replica_token: Option<String>,
+    // The replica connects to the primary over TLS if it is given
+    pub replica_tls: Option<ReplicaTlsConfig>,
+    // Number of the latest changes to keep at the change log, 0 disables change data capture
+    pub cdc_keep: u64,
+    // Whether a name can be a key of several data types, for data written before types are checked
+    pub mixed_types: bool,
+    // Least severe level of logs to write
+    pub log_level: LevelFilter,
+    // Format of the lines of logs
+    pub log_format: LogFormat,
+    // Microseconds which a request takes at least to be recorded at the slow log
+    pub slowlog_threshold: u64,
+    // Number of the latest slow requests to keep, 0 disables the slow log
+    pub slowlog_max_len: usize,
+    // Users of the ACL file, requests are not authenticated without it
+    pub acl: Option<Acl>,
+    // The server accepts only TLS connections if it is given
+    pub tls: Option<TlsConfig>,
+    // `ip:port` of the listener of the Redis protocol, it is not started if not given
+    pub resp_ip_port: Option<String>,
+    // Most open connections of the listener of the Redis protocol
+    pub resp_max_connections: usize,
+    // Most bytes of the body of PIPELINE
+    pub pipeline_max_size: usize,
+}
+
+// Config of `lodis restore`, which does not start the server
+#[derive(Debug)]
+pub struct RestoreConfig {
+    pub db_path: String,
+    pub backup_path: String,
+}
+
+// Config of `lodis export` and `lodis import`, which do not start the server
+pub fn get_db_path() -> Result<String> {
+    Ok(env::var("LODIS_DB_PATH")?)
+}
+
+pub fn get_restore_config() -> Result<RestoreConfig> {
+    let db_path = env::var("LODIS_DB_PATH")?;
+    let backup_path = env::var("LODIS_BACKUP_PATH")?;
+    Ok(RestoreConfig {
+        db_path,
+        backup_path,
+    })
+}
+
+// Parse the environment variable `name`, or return `default` if it is not given
+fn parse_var<T: FromStr>(name: &str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| LodisError::Error(format!("{} is not valid: {:?}", name, value))),
+        Err(_) => Ok(default),
+    }
 }
 
 pub fn get_config() -> Result<LodisConfig> {
     let db_path = env::var("LODIS_DB_PATH")?;
     let ip_port = env::var("LODIS_IP_PORT")?;
-    let workers = env::var("LODIS_WORKERS")
-        .map(|n| n.parse().unwrap())
-        .unwrap_or(num_cpus::get());
+    let workers = parse_var("LODIS_WORKERS", num_cpus::get())?;
+    let backup_path = env::var("LODIS_BACKUP_PATH").ok();
+    let backup_keep = parse_var("LODIS_BACKUP_KEEP", DEFAULT_BACKUP_KEEP)?;
+    let checkpoint_path = env::var("LODIS_CHECKPOINT_PATH").ok();
+    let checkpoint_keep = parse_var("LODIS_CHECKPOINT_KEEP", DEFAULT_CHECKPOINT_KEEP)?;
+    let checkpoint_interval = parse_var("LODIS_CHECKPOINT_INTERVAL", 0)?;
+    let wal_ttl = parse_var("LODIS_WAL_TTL", 0)?;
+    let replica_of = env::var("LODIS_REPLICA_OF").ok();
+    let replica_token = env::var("LODIS_REPLICA_TOKEN").ok();
+    let replica_tls = match (
+        env::var("LODIS_REPLICA_TLS_CA").ok(),
+        env::var("LODIS_REPLICA_TLS_CERT").ok(),
+        env::var("LODIS_REPLICA_TLS_KEY").ok(),
+    ) {
+        (Some(ca), cert, key) if cert.is_some() == key.is_some() => {
+            Some(ReplicaTlsConfig { ca, cert, key })
+        }
+        (None, None, None) => None,
+        _ => return Err(LodisError::ConfigError),
+    };
+    let cdc_keep = parse_var("LODIS_CDC_KEEP", 0)?;
+    let mixed_types = parse_var("LODIS_MIXED_TYPES", false)?;
+    let log_level = parse_var("LODIS_LOG_LEVEL", LevelFilter::Info)?;
+    let log_format = parse_var("LODIS_LOG_FORMAT", LogFormat::Text)?;
+    let slowlog_threshold = parse_var("LODIS_SLOWLOG_THRESHOLD", DEFAULT_SLOWLOG_THRESHOLD)?;
+    let slowlog_max_len = parse_var("LODIS_SLOWLOG_MAX_LEN", DEFAULT_SLOWLOG_MAX_LEN)?;
+    let acl = match env::var("LODIS_ACL_FILE") {
+        Ok(path) => Some(Acl::load(&path)?),
+        Err(_) => None,
+    };
+    let tls = match (
+        env::var("LODIS_TLS_CERT").ok(),
+        env::var("LODIS_TLS_KEY").ok(),
+        env::var("LODIS_TLS_CLIENT_CA").ok(),
+    ) {
+        (Some(cert), Some(key), client_ca) => Some(TlsConfig {
+            cert,
+            key,
+            client_ca,
+        }),
+        (None, None, None) => None,
+        _ => return Err(LodisError::ConfigError),
+    };
+    let resp_ip_port = env::var("LODIS_RESP_IP_PORT").ok();
+    let resp_max_connections =
+        parse_var("LODIS_RESP_MAX_CONNECTIONS", DEFAULT_RESP_MAX_CONNECTIONS)?;
+    let pipeline_max_size = parse_var("LODIS_PIPELINE_MAX_SIZE", DEFAULT_PIPELINE_MAX_SIZE)?;
+    // The Redis protocol is plain TCP, its tokens of AUTH are not encrypted
+    let resp_plaintext = parse_var("LODIS_RESP_PLAINTEXT", false)?;
+    if resp_ip_port.is_some() && tls.is_some() && !resp_plaintext {
+        return Err(LodisError::Error(
+            "LODIS_RESP_IP_PORT is not TLS, set LODIS_RESP_PLAINTEXT=true to start it with LODIS_TLS_CERT"
+                .to_string(),
+        ));
+    }
     Ok(LodisConfig {
         db_path,
         ip_port,
         workers,
+        backup_path,
+        backup_keep,
+        checkpoint_path,
+        checkpoint_keep,
+        checkpoint_interval,
+        wal_ttl,
+        replica_of,
+        replica_token,
+        replica_tls,
+        cdc_keep,
+        mixed_types,
+        log_level,
+        log_format,
+        slowlog_threshold,
+        slowlog_max_len,
+        acl,
+        tls,
+        resp_ip_port,
+        resp_max_connections,
+        pipeline_max_size,
     })
 }
+
+// Quote and escape `s` as a JSON string
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Whether `string` matches the glob-style `pattern`
+//
+// `*` matches any bytes, `?` matches one byte, `[abc]`, `[a-z]` and `[^abc]` match one byte
+// in or not in the set, and `\` escapes the next byte.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to retry when a mismatch is after a `*`: the index after the `*`, and the index
+    // of `string` which the `*` matches up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, s));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, string[s]),
+            Some(b'\\') if p + 1 < pattern.len() => {
+                if pattern[p + 1] == string[s] {
+                    Some(p + 2)
+                } else {
+                    None
+                }
+            }
+            Some(c) if *c == string[s] => Some(p + 1),
+            _ => None,
+        };
+        match (matched, star) {
+            (Some(next), _) => {
+                p = next;
+                s += 1;
+            }
+            (None, Some((star_p, star_s))) => {
+                p = star_p;
+                s = star_s + 1;
+                star = Some((star_p, star_s + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// Match `c` against the class starting at `pattern[start]`, which is `[`, return the index
+// after the class if it matches
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<usize> {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            matched |= low <= c && c <= high;
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+    // An unclosed class matches to the end of the pattern
+    if matched != negate {
+        Some((p + 1).min(pattern.len()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test_utils {
+    use std::env;
+
+    use log::LevelFilter;
+
+    use super::{glob_match, match_class, parse_var};
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, string, matched) in [
+            ("", "", true),
+            ("", "a", false),
+            ("*", "", true),
+            ("**", "abc", true),
+            ("abc", "abc", true),
+            ("abc", "abd", false),
+            ("abc", "ab", false),
+            ("a*", "abc", true),
+            ("*c", "abc", true),
+            ("a*c", "ac", true),
+            ("a*c", "abd", false),
+            ("*a*b", "xaaab", true),
+            ("*a*b", "xaaabc", false),
+            ("user:*", "user:1", true),
+            ("user:*", "users:1", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("h?llo*", "hello world", true),
+            ("[abc]", "b", true),
+            ("[abc]", "d", false),
+            ("[abc]", "", false),
+            ("[a-c]x", "bx", true),
+            ("[a-c]x", "dx", false),
+            ("[c-a]", "b", true),
+            ("[^a-c]", "d", true),
+            ("[^a-c]", "b", false),
+            ("[a-]", "-", true),
+            ("[\\]]", "]", true),
+            ("[ab", "a", true),
+            ("[ab", "c", false),
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("\\?\\[", "?[", true),
+            ("a\\", "a\\", true),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), string.as_bytes()),
+                *matched,
+                "pattern: {:?}, string: {:?}",
+                pattern,
+                string
+            );
+        }
+
+        // Keys are binary
+        assert!(glob_match(b"\xff*", b"\xff\x00"));
+        assert!(glob_match(b"[\x00-\x10]", b"\x05"));
+        assert!(!glob_match(b"?", b"\xff\xfe"));
+    }
+
+    #[test]
+    fn test_match_class() {
+        for (pattern, c, next) in [
+            ("[abc]x", b'b', Some(5)),
+            ("[abc]x", b'x', None),
+            ("[^abc]x", b'x', Some(6)),
+            ("[^abc]x", b'a', None),
+            ("[a-z]", b'm', Some(5)),
+            ("[\\-]", b'-', Some(4)),
+            ("[ab", b'b', Some(3)),
+            ("[]", b'a', None),
+            ("[^]", b'a', Some(3)),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                match_class(pattern.as_bytes(), 0, *c),
+                *next,
+                "pattern: {:?}, c: {:?}",
+                pattern,
+                *c as char
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_var() {
+        env::set_var("LODIS_TEST_PARSE_VAR", "12");
+        assert_eq!(parse_var("LODIS_TEST_PARSE_VAR", 0usize).unwrap(), 12);
+        env::set_var("LODIS_TEST_PARSE_VAR", "debug");
+        assert_eq!(
+            parse_var("LODIS_TEST_PARSE_VAR", LevelFilter::Info).unwrap(),
+            LevelFilter::Debug
+        );
+        // A malformed value names the variable
+        let err = parse_var("LODIS_TEST_PARSE_VAR", 0usize).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("LODIS_TEST_PARSE_VAR is not valid: \"debug\""));
+        env::remove_var("LODIS_TEST_PARSE_VAR");
+        assert_eq!(parse_var("LODIS_TEST_PARSE_VAR", 7usize).unwrap(), 7);
+    }
+}